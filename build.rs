@@ -1,5 +1,6 @@
 //! This build script copies the `memory.x` file from the crate root into
-//! a directory where the linker can always find it at build time.
+//! a directory where the linker can always find it at build time. With the `rp2350` feature, it
+//! copies `memory-rp2350.x` in its place.
 //! For many projects this is optional, as the linker always searches the
 //! project root directory -- wherever `Cargo.toml` is. However, if you
 //! are using a workspace or have a more complicated build setup, this
@@ -7,19 +8,59 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also reads the board configuration from `board.toml` and the pin map of the board variant
+//! selected by a `board-*` feature from `boards/`, and generates `config_generated.rs` with the
+//! pin assignments, buffer sizes and default thresholds, and `board_pin_generated.rs` with the
+//! `board_pin!` macro taking them. With the `replay` feature, it extracts the waveform named by
+//! `REPLAY_WAVEFORM` into `replay_waveform.bin`. It generates `log_level_generated.rs` with the
+//! lowest level `DEFMT_LOG` keeps in the firmware. Finally, it generates `version_generated.rs`
+//! with the git commit, build time and enabled features.
 
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use toml::Table;
+
+/// Board variants with a pin map in `boards/`, each selected by the `board-<variant>` feature
+const BOARD_VARIANTS: [&str; 4] = ["proto-v1", "proto-v2", "pico-bare", "pico2"];
+/// Board variants built on the RP2350, which must enable the `rp2350` feature
+const RP2350_VARIANTS: [&str; 1] = ["pico2"];
+/// Target of RP2350 builds. The RP2040 builds for `thumbv6m-none-eabi`.
+const RP2350_TARGET: &str = "thumbv8m.main-none-eabihf";
+/// Variant built when no `board-*` feature is selected
+const DEFAULT_VARIANT: &str = "proto-v2";
+/// Frequency of the detection signal, as `board::SIGNAL_GEN_FREQ_HZ`
+const SIGNAL_GEN_FREQ_HZ: i64 = 100_000;
 
 fn main() {
+    let rp2350 = env::var_os("CARGO_FEATURE_RP2350").is_some();
+    let target = env::var("TARGET").unwrap();
+    if target.starts_with("thumb") {
+        assert_eq!(
+            rp2350,
+            target == RP2350_TARGET,
+            "The `rp2350` feature must be built for {RP2350_TARGET}, and the RP2040 for \
+             thumbv6m-none-eabi, not {target}"
+        );
+    }
+
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let (memory, contents): (&str, &[u8]) = if rp2350 {
+        ("memory-rp2350.x", include_bytes!("memory-rp2350.x"))
+    } else {
+        ("memory.x", include_bytes!("memory.x"))
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(contents)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
@@ -27,5 +68,699 @@ fn main() {
     // any file in the project changes. By specifying `memory.x`
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed={memory}");
+
+    // Generate board constants
+    let mut board: Table = fs::read_to_string("board.toml")
+        .expect("Unable to read board.toml")
+        .parse()
+        .expect("board.toml is not valid TOML");
+    let variant = board_variant();
+    assert_eq!(
+        rp2350,
+        RP2350_VARIANTS.contains(&variant),
+        "Board variant `{variant}` doesn't match the `rp2350` feature, which is only enabled by \
+         the RP2350 board features"
+    );
+    let pins_path = format!("boards/{variant}.toml");
+    let pins: Table = fs::read_to_string(&pins_path)
+        .unwrap_or_else(|err| panic!("Unable to read {pins_path}: {err}"))
+        .parse()
+        .unwrap_or_else(|err| panic!("{pins_path} is not valid TOML: {err}"));
+    assert!(
+        !board.contains_key("pins"),
+        "board.toml: move `[pins]` to the board variant in `boards/`"
+    );
+    board.extend(pins);
+    let (config, board_pin) = generate_config(&board, variant);
+    fs::write(out.join("config_generated.rs"), config).unwrap();
+    fs::write(out.join("board_pin_generated.rs"), board_pin).unwrap();
+    println!("cargo:rerun-if-changed=board.toml");
+    println!("cargo:rerun-if-changed={pins_path}");
+
+    // Embed the recorded waveform for the `replay` feature
+    if env::var_os("CARGO_FEATURE_REPLAY").is_some() {
+        let path = env::var("REPLAY_WAVEFORM").unwrap_or_else(|_| "logs/voltdiv.log".into());
+        fs::write(out.join("replay_waveform.bin"), load_waveform(&path)).unwrap();
+        println!("cargo:rerun-if-changed={path}");
+    }
+    println!("cargo:rerun-if-env-changed=REPLAY_WAVEFORM");
+
+    // Embed the build-time log filter for `logging`
+    fs::write(out.join("log_level_generated.rs"), generate_log_level()).unwrap();
+    println!("cargo:rerun-if-env-changed=DEFMT_LOG");
+
+    // Embed the build information for `version`
+    fs::write(out.join("version_generated.rs"), generate_version()).unwrap();
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    for path in [".git/HEAD", ".git/index"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+/// Generate the lowest level that `DEFMT_LOG` keeps in any module of the firmware, for `logging`.
+/// Entries for other crates are ignored. Without `DEFMT_LOG`, defmt only keeps errors.
+fn generate_log_level() -> String {
+    const LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+    let filter = env::var("DEFMT_LOG").unwrap_or_default();
+    let lowest = filter
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((path, level)) => (path.trim(), level.trim()),
+            // A bare path keeps everything in it
+            None if !LEVELS.contains(&entry) && entry != "off" => (entry, "trace"),
+            None => ("", entry),
+        })
+        .filter(|(path, _)| {
+            let krate = path.split("::").next().unwrap_or_default();
+            ["", "aps490_pfpu2_mini", "embassy", "dual_core"].contains(&krate)
+        })
+        .filter_map(|(_, level)| LEVELS.iter().position(|known| *known == level))
+        .min()
+        .unwrap_or(LEVELS.len() - 1);
+    let mut level = LEVELS[lowest].to_string();
+    level[..1].make_ascii_uppercase();
+    format!(
+        "// Generated by build.rs. Do not edit.\n\n\
+         /// Lowest level kept by the `DEFMT_LOG` filter, anything below is compiled out\n\
+         pub const BUILD_LEVEL: Level = Level::{level};\n"
+    )
+}
+
+/// Generate the git commit, build time and enabled features for `version`. The build time is
+/// `SOURCE_DATE_EPOCH` if set, for reproducible builds, or otherwise when this script last ran.
+fn generate_version() -> String {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let mut git_hash = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or("unknown".into());
+    if git(&["status", "--porcelain", "--untracked-files=no"]).is_some_and(|s| !s.is_empty()) {
+        git_hash.push_str("-dirty");
+    }
+
+    let built_s = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        });
+
+    let manifest: Table = fs::read_to_string("Cargo.toml")
+        .expect("Unable to read Cargo.toml")
+        .parse()
+        .expect("Cargo.toml is not valid TOML");
+    let features: Vec<_> = manifest
+        .get("features")
+        .and_then(|features| features.as_table())
+        .into_iter()
+        .flat_map(|features| features.keys())
+        .filter(|feature| *feature != "default")
+        .filter(|feature| {
+            let var = feature.to_uppercase().replace('-', "_");
+            env::var_os(format!("CARGO_FEATURE_{var}")).is_some()
+        })
+        .collect();
+
+    format!(
+        "// Generated by build.rs. Do not edit.\n\n\
+         /// Git commit the firmware was built from, suffixed `-dirty` if tracked files changed\n\
+         pub const GIT_HASH: &str = {git_hash:?};\n\
+         /// Time of the build, in UTC\n\
+         pub const BUILD_TIME: &str = {:?};\n\
+         /// Cargo features enabled in the build, apart from `default`\n\
+         pub const FEATURES: &[&str] = &{features:?};\n",
+        utc_timestamp(built_s)
+    )
+}
+
+/// Format `secs` since the Unix epoch as an ISO 8601 UTC timestamp
+fn utc_timestamp(secs: u64) -> String {
+    // Civil date from days since the epoch, counting in 400-year eras starting on 1 March
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    let time = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Read the replay waveform at `path`: a defmt log recorded with `trace_avg_samples` if it ends in
+/// `.log`, taking every logged block of samples in order, or otherwise raw samples, one byte each.
+fn load_waveform(path: &str) -> Vec<u8> {
+    let samples = if path.ends_with(".log") {
+        let log = fs::read_to_string(path)
+            .unwrap_or_else(|err| panic!("REPLAY_WAVEFORM: unable to read {path}: {err}"));
+        let mut samples = Vec::new();
+        let mut lines = log.lines();
+        while let Some(line) = lines.next() {
+            if line.contains("Here are the last 250 samples") {
+                let block = lines.next().unwrap_or_default();
+                samples.extend(
+                    block
+                        .trim()
+                        .trim_start_matches('[')
+                        .trim_end_matches(']')
+                        .split(',')
+                        .filter_map(|sample| sample.trim().parse::<u8>().ok()),
+                );
+            }
+        }
+        samples
+    } else {
+        fs::read(path).unwrap_or_else(|err| panic!("REPLAY_WAVEFORM: unable to read {path}: {err}"))
+    };
+    assert!(!samples.is_empty(), "REPLAY_WAVEFORM: no samples in {path}");
+    samples
+}
+
+/// Board variant selected by a `board-*` feature, or [`DEFAULT_VARIANT`] if there is none.
+/// Panics if more than one is selected.
+fn board_variant() -> &'static str {
+    let selected: Vec<_> = BOARD_VARIANTS
+        .into_iter()
+        .filter(|variant| {
+            let feature = variant.to_uppercase().replace('-', "_");
+            env::var_os(format!("CARGO_FEATURE_BOARD_{feature}")).is_some()
+        })
+        .collect();
+    match selected[..] {
+        [] => DEFAULT_VARIANT,
+        [variant] => variant,
+        _ => panic!("Only one board feature can be selected, not {selected:?}"),
+    }
+}
+
+/// Read an integer `key` from `[section]` in `board.toml`, panicking with a descriptive message if
+/// it is missing or outside `range`.
+fn int(board: &Table, section: &str, key: &str, range: std::ops::RangeInclusive<i64>) -> i64 {
+    let value = board
+        .get(section)
+        .and_then(|s| s.get(key))
+        .and_then(|v| v.as_integer())
+        .unwrap_or_else(|| panic!("board.toml: missing integer `{section}.{key}`"));
+    assert!(
+        range.contains(&value),
+        "board.toml: `{section}.{key}` = {value} is outside {range:?}"
+    );
+    value
+}
+
+/// Read a boolean `key` from `[section]` in `board.toml`, panicking with a descriptive message if
+/// it is missing.
+fn boolean(board: &Table, section: &str, key: &str) -> bool {
+    board
+        .get(section)
+        .and_then(|s| s.get(key))
+        .and_then(|v| v.as_bool())
+        .unwrap_or_else(|| panic!("board.toml: missing boolean `{section}.{key}`"))
+}
+
+/// Read a string `key` from `[section]` in `board.toml`, panicking with a descriptive message if
+/// it is missing or not one of `allowed`.
+fn choice<'a>(board: &'a Table, section: &str, key: &str, allowed: &[&str]) -> &'a str {
+    let value = board
+        .get(section)
+        .and_then(|s| s.get(key))
+        .and_then(|v| v.as_str())
+        .unwrap_or_else(|| panic!("board.toml: missing string `{section}.{key}`"));
+    assert!(
+        allowed.contains(&value),
+        "board.toml: `{section}.{key}` = {value:?} is not one of {allowed:?}"
+    );
+    value
+}
+
+/// Generate the contents of `config_generated.rs` and `board_pin_generated.rs` for board
+/// `variant`.
+fn generate_config(board: &Table, variant: &str) -> (String, String) {
+    let status_leds: Vec<i64> = board
+        .get("pins")
+        .and_then(|p| p.get("status_leds"))
+        .and_then(|v| v.as_array())
+        .expect("board.toml: missing array `pins.status_leds`")
+        .iter()
+        .map(|v| {
+            v.as_integer()
+                .expect("board.toml: status LED pins must be integers")
+        })
+        .collect();
+    assert_eq!(
+        status_leds.len(),
+        3,
+        "board.toml: `pins.status_leds` must list 3 pins"
+    );
+    let disable_switch = int(board, "pins", "disable_switch", 0..=29);
+    let ack_button = int(board, "pins", "ack_button", 0..=29);
+    let interlock = int(board, "pins", "interlock", 0..=29);
+    let interlock_b = int(board, "pins", "interlock_b", 0..=29);
+    assert_ne!(
+        interlock, interlock_b,
+        "board.toml: `pins.interlock_b` must differ from `pins.interlock`"
+    );
+    let probe_led = int(board, "pins", "probe_led", 0..=29);
+    let probe_led_b = int(board, "pins", "probe_led_b", 0..=29);
+    assert_ne!(
+        probe_led, probe_led_b,
+        "board.toml: `pins.probe_led_b` must differ from `pins.probe_led`"
+    );
+    let interlock_tripped_level =
+        match choice(board, "interlock", "tripped_level", &["high", "low"]) {
+            "low" => "Low",
+            _ => "High",
+        };
+    let interlock_open_drain =
+        choice(board, "interlock", "drive", &["push_pull", "open_drain"]) == "open_drain";
+    let buzzer = int(board, "pins", "buzzer", 0..=29);
+    let test_inject = int(board, "pins", "test_inject", 0..=29);
+    let power_good = int(board, "pins", "power_good", 0..=29);
+    let config_jumper = int(board, "pins", "config_jumper", 0..=29);
+    let presence = int(board, "pins", "presence", 0..=29);
+    let presence_required = boolean(board, "presence", "required");
+    let blanking = int(board, "pins", "blanking", 0..=29);
+    let correlation = int(board, "pins", "correlation", 0..=29);
+    let trigger = int(board, "pins", "trigger", 0..=29);
+    let blanking_window_ms = int(board, "blanking", "window_ms", 0..=u32::MAX as i64);
+    let blanking_max_per_minute = int(board, "blanking", "max_per_minute", 1..=u16::MAX as i64);
+    let signal_gen = int(board, "pins", "signal_gen", 0..=29);
+    let hil_dac = int(board, "pins", "hil_dac", 0..=29);
+    let i2c_sda = int(board, "pins", "i2c_sda", 0..=29);
+    let i2c_scl = int(board, "pins", "i2c_scl", 0..=29);
+    assert!(
+        i2c_sda % 2 == 0 && i2c_scl % 2 == 1 && (i2c_sda / 2) % 2 == (i2c_scl / 2) % 2,
+        "board.toml: `pins.i2c_sda` and `pins.i2c_scl` must be SDA and SCL of the same I2C block"
+    );
+    let adc_input = int(board, "pins", "adc_input", 26..=29);
+    let adc_input_b = int(board, "pins", "adc_input_b", 26..=29);
+    let adc_input_c = int(board, "pins", "adc_input_c", 26..=29);
+    let self_test_adc = int(board, "pins", "self_test_adc", 26..=29);
+    assert_ne!(
+        adc_input, self_test_adc,
+        "board.toml: `pins.self_test_adc` must differ from `pins.adc_input`"
+    );
+    assert_ne!(
+        (hil_dac / 2) % 8,
+        (signal_gen / 2) % 8,
+        "board.toml: `pins.hil_dac` must be on a different PWM slice from `pins.signal_gen`"
+    );
+    assert!(
+        adc_input_b != adc_input && adc_input_b != self_test_adc,
+        "board.toml: `pins.adc_input_b` must differ from `pins.adc_input` and `pins.self_test_adc`"
+    );
+    assert!(
+        ![adc_input, adc_input_b, self_test_adc].contains(&adc_input_c),
+        "board.toml: `pins.adc_input_c` must differ from `pins.adc_input`, `pins.adc_input_b` and \
+         `pins.self_test_adc`"
+    );
+
+    let clock_profile = match choice(
+        board,
+        "clocks",
+        "profile",
+        &["low_power", "standard", "overclock"],
+    ) {
+        "low_power" => "LowPower",
+        "overclock" => "Overclock",
+        _ => "Standard",
+    };
+    assert!(
+        !(clock_profile == "Overclock" && env::var_os("CARGO_FEATURE_RP2350").is_some()),
+        "board.toml: `clocks.profile` = \"overclock\" raises the core voltage, which isn't \
+         supported on the RP2350"
+    );
+    let longterm_size = int(board, "buffers", "longterm_size", 250..=100_000);
+    assert_eq!(
+        longterm_size % 250,
+        0,
+        "board.toml: `buffers.longterm_size` must be a multiple of 250"
+    );
+    let mains_hz = int(board, "buffers", "mains_hz", 0..=60);
+    assert!(
+        [0, 50, 60].contains(&mains_hz),
+        "board.toml: `buffers.mains_hz` must be 0, 50 or 60"
+    );
+    let avg_buffer_size = if mains_hz == 0 {
+        int(board, "buffers", "avg_buffer_size", 4..=16_000)
+    } else {
+        let cycles = int(board, "buffers", "mains_cycles", 1..=60);
+        let readings_per_period = if env::var_os("CARGO_FEATURE_LOCK_IN").is_some() {
+            4
+        } else {
+            2
+        };
+        let readings = readings_per_period * SIGNAL_GEN_FREQ_HZ * cycles;
+        assert_eq!(
+            readings % mains_hz,
+            0,
+            "board.toml: {cycles} cycles of {mains_hz} Hz isn't a whole number of readings"
+        );
+        let avg_buffer_size = readings / mains_hz;
+        assert!(
+            avg_buffer_size <= 16_000,
+            "board.toml: {cycles} cycles of {mains_hz} Hz take {avg_buffer_size} readings, more \
+             than the 16000 that fit in RAM"
+        );
+        avg_buffer_size
+    };
+    assert_eq!(
+        avg_buffer_size % 4,
+        0,
+        "board.toml: each averaged sample must be a multiple of 4 readings, {avg_buffer_size} \
+         isn't"
+    );
+
+    let trigger_delta = int(board, "detection", "trigger_delta", 1..=255);
+    let warning_delta = int(board, "detection", "warning_delta", 0..=255);
+    let confirm_delta = int(board, "detection", "confirm_delta", 1..=255);
+    let restore_delta = int(board, "detection", "restore_delta", 1..=255);
+    let alert_hold_samples = int(board, "detection", "alert_hold_samples", 0..=longterm_size);
+    let restore_profile = match choice(board, "detection", "restore_profile", &["fast", "slow"]) {
+        "slow" => "Slow",
+        _ => "Fast",
+    };
+    let restore_fast_samples = int(
+        board,
+        "detection",
+        "restore_fast_samples",
+        0..=longterm_size,
+    );
+    let restore_slow_samples = int(
+        board,
+        "detection",
+        "restore_slow_samples",
+        0..=longterm_size,
+    );
+    let latch_alert = boolean(board, "detection", "latch_alert");
+    let auto_clear_ms = int(board, "detection", "auto_clear_ms", 0..=u32::MAX as i64);
+    let min_trip_ms = int(board, "detection", "min_trip_ms", 0..=u32::MAX as i64);
+    let enable_delay_ms = int(board, "detection", "enable_delay_ms", 0..=u32::MAX as i64);
+    let cooldown_ms = int(board, "detection", "cooldown_ms", 0..=u32::MAX as i64);
+    let snooze_ms = int(board, "detection", "snooze_ms", 0..=u32::MAX as i64);
+    let arming_delay_ms = int(board, "detection", "arming_delay_ms", 0..=u32::MAX as i64);
+    let proof_test_hours = int(board, "detection", "proof_test_hours", 0..=u16::MAX as i64);
+    let auto_zero_tau_s = int(board, "detection", "auto_zero_tau_s", 0..=u16::MAX as i64);
+    let plausible_min = int(board, "detection", "plausible_min", 0..=255);
+    let plausible_max = int(board, "detection", "plausible_max", 0..=255);
+    assert!(
+        plausible_min <= plausible_max,
+        "board.toml: `detection.plausible_min` must not be above `detection.plausible_max`"
+    );
+    let implausible_samples = int(
+        board,
+        "detection",
+        "implausible_samples",
+        0..=u16::MAX as i64,
+    );
+    let near_miss_percent = int(board, "detection", "near_miss_percent", 1..=99);
+
+    let i2c_expected: Vec<(i64, &str)> = board
+        .get("i2c")
+        .and_then(|i2c| i2c.get("expected"))
+        .and_then(|expected| expected.as_array())
+        .expect("board.toml: missing array `i2c.expected`")
+        .iter()
+        .map(|peripheral| {
+            let address = peripheral
+                .get("address")
+                .and_then(|v| v.as_integer())
+                .filter(|address| (0x08..=0x77).contains(address))
+                .expect("board.toml: each of `i2c.expected` needs an `address` from 0x08 to 0x77");
+            let name = peripheral
+                .get("name")
+                .and_then(|v| v.as_str())
+                .expect("board.toml: each of `i2c.expected` needs a `name`");
+            (address, name)
+        })
+        .collect();
+    for (idx, (address, _)) in i2c_expected.iter().enumerate() {
+        assert!(
+            !i2c_expected[..idx]
+                .iter()
+                .any(|(other, _)| other == address),
+            "board.toml: I2C address {address:#04x} is listed twice in `i2c.expected`"
+        );
+    }
+
+    let mut generated = format!(
+        "// Generated by build.rs from board.toml and boards/{variant}.toml. Do not edit.\n\n\
+         /// Board variant, selected by a `board-*` feature\n\
+         pub const BOARD_VARIANT: &str = \"{variant}\";\n"
+    );
+    writeln!(
+        generated,
+        "/// GPIO numbers of the status LEDs, for direct register access\n\
+         pub const STATUS_LED_PINS: [u8; 3] = {status_leds:?};"
+    )
+    .unwrap();
+    for (idx, pin) in status_leds.iter().enumerate() {
+        assert!(
+            (0..=29).contains(pin),
+            "board.toml: invalid status LED pin {pin}"
+        );
+        writeln!(
+            generated,
+            "/// Status LED {idx} (GPIO {pin})\n\
+             pub type StatusLed{idx}Pin = crate::hal::gpio::bank0::Gpio{pin};"
+        )
+        .unwrap();
+    }
+    writeln!(
+        generated,
+        "/// Disable switch input (GPIO {disable_switch})\n\
+         pub type DisableSwitchPin = crate::hal::gpio::bank0::Gpio{disable_switch};\n\
+         /// GPIO number of the disable switch, for dormant wake-up\n\
+         pub const DISABLE_SWITCH_PIN: u8 = {disable_switch};\n\
+         /// Acknowledge button input (GPIO {ack_button})\n\
+         pub type AckButtonPin = crate::hal::gpio::bank0::Gpio{ack_button};\n\
+         /// GPIO number of the acknowledge button, for dormant wake-up\n\
+         pub const ACK_BUTTON_PIN: u8 = {ack_button};\n\
+         /// Interlock output (GPIO {interlock})\n\
+         pub type InterlockPin = crate::hal::gpio::bank0::Gpio{interlock};\n\
+         /// Second station's interlock output for `dual_probe` (GPIO {interlock_b})\n\
+         pub type InterlockBPin = crate::hal::gpio::bank0::Gpio{interlock_b};\n\
+         /// GPIO numbers of each station's interlock, for tripping them directly on a fault\n\
+         pub const INTERLOCK_PINS: [u8; 2] = [{interlock}, {interlock_b}];\n\
+         /// Status LED of the first station's probe (GPIO {probe_led})\n\
+         pub type ProbeLedPin = crate::hal::gpio::bank0::Gpio{probe_led};\n\
+         /// Status LED of the second station's probe for `dual_probe` (GPIO {probe_led_b})\n\
+         pub type ProbeLedBPin = crate::hal::gpio::bank0::Gpio{probe_led_b};\n\
+         /// GPIO numbers of each station's probe LED, for the manufacturing test\n\
+         pub const PROBE_LED_PINS: [u8; 2] = [{probe_led}, {probe_led_b}];\n\
+         /// Level of the interlocks while tripped, see `interlock.tripped_level`\n\
+         pub const INTERLOCK_TRIPPED_LEVEL: embedded_hal::digital::PinState =\n    \
+             embedded_hal::digital::PinState::{interlock_tripped_level};\n\
+         /// Whether the interlocks are only ever driven low, and released to an external pull-up\n\
+         /// for high, see `interlock.drive`\n\
+         pub const INTERLOCK_OPEN_DRAIN: bool = {interlock_open_drain};\n\
+         /// Buzzer output (GPIO {buzzer})\n\
+         pub type BuzzerPin = crate::hal::gpio::bank0::Gpio{buzzer};\n\
+         /// GPIO number of the buzzer, for the safe state\n\
+         pub const BUZZER_PIN: u8 = {buzzer};\n\
+         /// Test injection output (GPIO {test_inject})\n\
+         pub type TestInjectPin = crate::hal::gpio::bank0::Gpio{test_inject};\n\
+         /// GPIO number of the test injection output, for the manufacturing test\n\
+         pub const TEST_INJECT_PIN: u8 = {test_inject};\n\
+         /// Power-good input from the VSYS supervisor (GPIO {power_good})\n\
+         pub type PowerGoodPin = crate::hal::gpio::bank0::Gpio{power_good};\n\
+         /// GPIO number of the power-good input, for acknowledging its interrupt\n\
+         pub const POWER_GOOD_PIN: u8 = {power_good};\n\
+         /// Configuration jumper input (GPIO {config_jumper})\n\
+         pub type ConfigJumperPin = crate::hal::gpio::bank0::Gpio{config_jumper};\n\
+         /// GPIO number of the configuration jumper, for reading it outside its owner\n\
+         pub const CONFIG_JUMPER_PIN: u8 = {config_jumper};\n\
+         /// Operator-presence input (GPIO {presence})\n\
+         pub type PresencePin = crate::hal::gpio::bank0::Gpio{presence};\n\
+         /// GPIO number of the operator-presence input, for reading it outside its owner\n\
+         pub const PRESENCE_PIN: u8 = {presence};\n\
+         /// Whether detection is disabled while the operator is absent, see `presence`\n\
+         pub const PRESENCE_REQUIRED: bool = {presence_required};\n\
+         /// Blanking input (GPIO {blanking})\n\
+         pub type BlankingPin = crate::hal::gpio::bank0::Gpio{blanking};\n\
+         /// GPIO number of the blanking input, for reading it outside its owner\n\
+         pub const BLANKING_PIN: u8 = {blanking};\n\
+         /// Longest time detection is blanked by each assertion of the blanking input, or 0 to\n\
+         /// ignore it, see `blanking`\n\
+         pub const BLANKING_WINDOW_MS: u32 = {blanking_window_ms};\n\
+         /// Most blanking windows allowed within a minute, see `blanking`\n\
+         pub const BLANKING_MAX_PER_MINUTE: u16 = {blanking_max_per_minute};\n\
+         /// Event correlation output (GPIO {correlation})\n\
+         pub type CorrelationPin = crate::hal::gpio::bank0::Gpio{correlation};\n\
+         /// GPIO number of the event correlation output, for driving it outside its owner\n\
+         pub const CORRELATION_PIN: u8 = {correlation};\n\
+         /// External trigger input (GPIO {trigger})\n\
+         pub type TriggerPin = crate::hal::gpio::bank0::Gpio{trigger};\n\
+         /// GPIO number of the external trigger input, for reading it outside its owner\n\
+         pub const TRIGGER_PIN: u8 = {trigger};\n\
+         /// PWM slice driving the signal generator (GPIO {signal_gen})\n\
+         pub type SignalPwmSlice = crate::hal::pwm::Pwm{slice};\n\
+         /// PWM channel driving the signal generator (GPIO {signal_gen})\n\
+         pub type SignalPwmChannel = crate::hal::pwm::{channel};\n\
+         /// PWM slice driving the `hil` loopback DAC (GPIO {hil_dac})\n\
+         pub type HilPwmSlice = crate::hal::pwm::Pwm{hil_slice};\n\
+         /// PWM channel driving the `hil` loopback DAC (GPIO {hil_dac})\n\
+         pub type HilPwmChannel = crate::hal::pwm::{hil_channel};\n\
+         /// ADC input (GPIO {adc_input})\n\
+         pub type AdcInputPin = crate::hal::gpio::bank0::Gpio{adc_input};\n\
+         /// Second ADC input for `dual_channel` (GPIO {adc_input_b})\n\
+         pub type AdcInputBPin = crate::hal::gpio::bank0::Gpio{adc_input_b};\n\
+         /// Third ADC input for `triple_channel` (GPIO {adc_input_c})\n\
+         pub type AdcInputCPin = crate::hal::gpio::bank0::Gpio{adc_input_c};\n\
+         /// I2C data pin for `i2c_scan` (GPIO {i2c_sda})\n\
+         pub type I2cSdaPin = crate::hal::gpio::bank0::Gpio{i2c_sda};\n\
+         /// I2C clock pin for `i2c_scan` (GPIO {i2c_scl})\n\
+         pub type I2cSclPin = crate::hal::gpio::bank0::Gpio{i2c_scl};\n\
+         /// I2C block wired to [`I2cSdaPin`] and [`I2cSclPin`]\n\
+         pub type I2cBlock = crate::hal::pac::I2C{i2c_block};\n\
+         /// Index of [`I2cBlock`], for gating the unused block's clock\n\
+         pub const I2C_BLOCK: u8 = {i2c_block};\n\
+         /// Self-test divider ADC input (GPIO {self_test_adc})\n\
+         pub type SelfTestAdcPin = crate::hal::gpio::bank0::Gpio{self_test_adc};\n\
+         /// ADC channel of the self-test divider\n\
+         pub const SELF_TEST_ADC_CHANNEL: u8 = {self_test_channel};\n\
+         \n\
+         /// System clock profile\n\
+         pub const CLOCK_PROFILE: crate::clocks::ClockProfile =\n    \
+             crate::clocks::ClockProfile::{clock_profile};\n\
+         \n\
+         /// Number of averaged samples stored in the long-term buffer\n\
+         pub const LONGTERM_SIZE: usize = {longterm_size};\n\
+         /// Number of raw ADC readings per averaged sample\n\
+         pub const AVG_BUFFER_SIZE: usize = {avg_buffer_size};\n\
+         /// Mains frequency that each averaged sample spans whole cycles of, or 0 if not aligned\n\
+         pub const MAINS_HZ: u32 = {mains_hz};\n\
+         \n\
+         /// Address and name of each peripheral expected on the I2C bus\n\
+         pub const I2C_EXPECTED: &[(u8, &str)] = &{i2c_expected:?};\n\
+         \n\
+         /// Default for [`DetectionConfig::trigger_delta`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_TRIGGER_DELTA: i16 = {trigger_delta};\n\
+         /// Default for [`DetectionConfig::warning_delta`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_WARNING_DELTA: i16 = {warning_delta};\n\
+         /// Default for [`DetectionConfig::confirm_delta`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_CONFIRM_DELTA: i16 = {confirm_delta};\n\
+         /// Default for [`DetectionConfig::restore_delta`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_RESTORE_DELTA: i16 = {restore_delta};\n\
+         /// Default for [`DetectionConfig::alert_hold_samples`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_ALERT_HOLD_SAMPLES: u32 = {alert_hold_samples};\n\
+         /// Default for [`DetectionConfig::restore_profile`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_RESTORE_PROFILE: crate::config::RestoreProfile =\n    \
+             crate::config::RestoreProfile::{restore_profile};\n\
+         /// Default for [`DetectionConfig::restore_fast_samples`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_RESTORE_FAST_SAMPLES: u32 = {restore_fast_samples};\n\
+         /// Default for [`DetectionConfig::restore_slow_samples`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_RESTORE_SLOW_SAMPLES: u32 = {restore_slow_samples};\n\
+         /// Default for [`DetectionConfig::latch_alert`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_LATCH_ALERT: bool = {latch_alert};\n\
+         /// Default for [`DetectionConfig::auto_clear_ms`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_AUTO_CLEAR_MS: u32 = {auto_clear_ms};\n\
+         /// Default for [`DetectionConfig::min_trip_ms`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_MIN_TRIP_MS: u32 = {min_trip_ms};\n\
+         /// Default for [`DetectionConfig::enable_delay_ms`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_ENABLE_DELAY_MS: u32 = {enable_delay_ms};\n\
+         /// Default for [`DetectionConfig::cooldown_ms`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_COOLDOWN_MS: u32 = {cooldown_ms};\n\
+         /// Default for [`DetectionConfig::snooze_ms`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_SNOOZE_MS: u32 = {snooze_ms};\n\
+         /// Default for [`DetectionConfig::arming_delay_ms`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_ARMING_DELAY_MS: u32 = {arming_delay_ms};\n\
+         /// Default for [`DetectionConfig::proof_test_hours`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_PROOF_TEST_HOURS: u16 = {proof_test_hours};\n\
+         /// Default for [`DetectionConfig::auto_zero_tau_s`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_AUTO_ZERO_TAU_S: u16 = {auto_zero_tau_s};\n\
+         /// Default for [`DetectionConfig::plausible_min`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_PLAUSIBLE_MIN: u8 = {plausible_min};\n\
+         /// Default for [`DetectionConfig::plausible_max`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_PLAUSIBLE_MAX: u8 = {plausible_max};\n\
+         /// Default for [`DetectionConfig::implausible_samples`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_IMPLAUSIBLE_SAMPLES: u16 = {implausible_samples};\n\
+         /// Default for [`DetectionConfig::near_miss_percent`](crate::config::DetectionConfig)\n\
+         pub const DEFAULT_NEAR_MISS_PERCENT: u8 = {near_miss_percent};\n",
+        slice = (signal_gen / 2) % 8,
+        channel = if signal_gen % 2 == 0 { "A" } else { "B" },
+        hil_slice = (hil_dac / 2) % 8,
+        hil_channel = if hil_dac % 2 == 0 { "A" } else { "B" },
+        self_test_channel = self_test_adc - 26,
+        i2c_block = (i2c_sda / 2) % 2,
+    )
+    .unwrap();
+
+    // Pins are fields of `crate::hal::gpio::Pins`, so they can only be selected by a macro. It is
+    // included at the crate root, as a macro from `include!` can't be imported by path.
+    let mut board_pin = String::new();
+    writeln!(
+        board_pin,
+        "/// Take a pin assigned in `board.toml` from [`Pins`](crate::hal::gpio::Pins), a PWM\n\
+         /// slice from [`Slices`](crate::hal::pwm::Slices), or the I2C block from\n\
+         /// [`Peripherals`](crate::hal::pac::Peripherals).\n\
+         #[macro_export]\n\
+         macro_rules! board_pin {{\n    \
+             ($pins:expr, status_led_0) => {{ $pins.gpio{} }};\n    \
+             ($pins:expr, status_led_1) => {{ $pins.gpio{} }};\n    \
+             ($pins:expr, status_led_2) => {{ $pins.gpio{} }};\n    \
+             ($pins:expr, disable_switch) => {{ $pins.gpio{disable_switch} }};\n    \
+             ($pins:expr, ack_button) => {{ $pins.gpio{ack_button} }};\n    \
+             ($pins:expr, interlock) => {{ $pins.gpio{interlock} }};\n    \
+             ($pins:expr, interlock_b) => {{ $pins.gpio{interlock_b} }};\n    \
+             ($pins:expr, probe_led) => {{ $pins.gpio{probe_led} }};\n    \
+             ($pins:expr, probe_led_b) => {{ $pins.gpio{probe_led_b} }};\n    \
+             ($pins:expr, buzzer) => {{ $pins.gpio{buzzer} }};\n    \
+             ($pins:expr, test_inject) => {{ $pins.gpio{test_inject} }};\n    \
+             ($pins:expr, power_good) => {{ $pins.gpio{power_good} }};\n    \
+             ($pins:expr, config_jumper) => {{ $pins.gpio{config_jumper} }};\n    \
+             ($pins:expr, presence) => {{ $pins.gpio{presence} }};\n    \
+             ($pins:expr, blanking) => {{ $pins.gpio{blanking} }};\n    \
+             ($pins:expr, correlation) => {{ $pins.gpio{correlation} }};\n    \
+             ($pins:expr, trigger) => {{ $pins.gpio{trigger} }};\n    \
+             ($pins:expr, signal_gen) => {{ $pins.gpio{signal_gen} }};\n    \
+             ($pins:expr, hil_dac) => {{ $pins.gpio{hil_dac} }};\n    \
+             ($pins:expr, adc_input) => {{ $pins.gpio{adc_input} }};\n    \
+             ($pins:expr, adc_input_b) => {{ $pins.gpio{adc_input_b} }};\n    \
+             ($pins:expr, adc_input_c) => {{ $pins.gpio{adc_input_c} }};\n    \
+             ($pins:expr, self_test_adc) => {{ $pins.gpio{self_test_adc} }};\n    \
+             ($pins:expr, i2c_sda) => {{ $pins.gpio{i2c_sda} }};\n    \
+             ($pins:expr, i2c_scl) => {{ $pins.gpio{i2c_scl} }};\n    \
+             ($pac:expr, i2c) => {{ $pac.I2C{i2c_block} }};\n    \
+             ($slices:expr, signal_pwm) => {{ $slices.pwm{slice}.channel_{channel} }};\n    \
+             ($slices:expr, signal_pwm_slice) => {{ $slices.pwm{slice} }};\n    \
+             ($slices:expr, hil_pwm) => {{ $slices.pwm{hil_slice}.channel_{hil_channel} }};\n    \
+             ($slices:expr, hil_pwm_slice) => {{ $slices.pwm{hil_slice} }};\n\
+         }}",
+        status_leds[0],
+        status_leds[1],
+        status_leds[2],
+        slice = (signal_gen / 2) % 8,
+        channel = if signal_gen % 2 == 0 { "a" } else { "b" },
+        hil_slice = (hil_dac / 2) % 8,
+        hil_channel = if hil_dac % 2 == 0 { "a" } else { "b" },
+        i2c_block = (i2c_sda / 2) % 2,
+    )
+    .unwrap();
+    (generated, board_pin)
 }
@@ -0,0 +1,82 @@
+//! Auto-zero stage, removing the slowly varying DC offset of the analog front-end before
+//! detection.
+//!
+//! Op-amp drift shifts every sample by an offset that changes over minutes. [`AutoZero`] tracks it
+//! with an exponential moving average over a time constant much longer than any contact, and
+//! subtracts it from each sample, so the samples passed on to the
+//! [`Detector`](crate::detection_core::Detector) stay centred on [`MIDPOINT`]. Contacts are steps
+//! far faster than the time constant, so they pass through unchanged.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Level that zeroed samples are centred on, leaving room for the signal to move either way
+pub const MIDPOINT: u8 = 128;
+/// Fractional bits of the offset estimate, so a time constant of millions of samples still
+/// follows a drift of a fraction of a count
+const FRACTION_BITS: u32 = 32;
+
+/// Estimate of the DC offset of one probe, subtracted from its samples
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct AutoZero {
+    /// Time constant of the moving average, in samples. 0 bypasses the stage.
+    time_constant: u32,
+    /// Offset estimate with [`FRACTION_BITS`], or [`None`] until the first sample
+    offset: Option<i64>,
+}
+
+impl AutoZero {
+    /// Create a stage with a time constant of `time_constant` samples, or bypassed if 0
+    pub const fn new(time_constant: u32) -> Self {
+        Self {
+            time_constant,
+            offset: None,
+        }
+    }
+
+    /// Change the time constant, keeping the current estimate. 0 bypasses the stage, and the
+    /// estimate starts again from the next sample once it is re-enabled.
+    pub fn set_time_constant(&mut self, time_constant: u32) {
+        self.time_constant = time_constant;
+        if time_constant == 0 {
+            self.offset = None;
+        }
+    }
+
+    /// Whether samples are passed through unchanged
+    pub fn bypassed(&self) -> bool {
+        self.time_constant == 0
+    }
+
+    /// Current offset estimate in counts, rounded, or [`None`] if bypassed or nothing has been
+    /// sampled yet
+    pub fn offset(&self) -> Option<u8> {
+        self.offset
+            .map(|offset| ((offset + (1 << (FRACTION_BITS - 1))) >> FRACTION_BITS) as u8)
+    }
+
+    /// Update the estimate with a new `sample`, and return it with the offset removed, centred on
+    /// [`MIDPOINT`] and clamped to the range of a sample. The first sample sets the estimate, so
+    /// it doesn't need to settle from 0.
+    pub fn apply(&mut self, sample: u8) -> u8 {
+        if self.bypassed() {
+            return sample;
+        }
+        let scaled = (sample as i64) << FRACTION_BITS;
+        let offset = self.offset.get_or_insert(scaled);
+        *offset += (scaled - *offset) / self.time_constant as i64;
+        let offset = self.offset().unwrap_or(sample);
+        (sample as i16 - offset as i16 + MIDPOINT as i16).clamp(0, u8::MAX as i16) as u8
+    }
+}
@@ -0,0 +1,354 @@
+//! Contact detection logic, free of any hardware or logging dependencies.
+//!
+//! Everything here depends only on `core`: the long-term [`RingBuffer`], the [`SampleCounter`],
+//! the [`Thresholds`] and the contact state machine ([`Detector::step`]), so it can be tested or
+//! fuzzed on the host with `cargo test`.
+//!
+//! The firmware wraps it with the hardware glue: its `buffer::Buffers` adds logging and the stored
+//! configuration, and `interrupt::process_sample` maps each [`Event`] onto a `SystemState`
+//! transition.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::ring::RingBuffer;
+
+/// Samples recorded in [`Phase::Calibrating`] before detection is armed (100 ms with 2 ms
+/// averaging)
+pub const SETTLE_SAMPLES: u64 = 50;
+/// Number of recent detection events kept by a [`Detector`]
+pub const DETECTION_EVENTS: usize = 10;
+
+/// Index of a detection event, combined with voltage difference
+pub type DetectionEvent = (SampleCounter, u8);
+
+/// Count of averaged samples recorded since boot, identifying each sample.
+///
+/// The count is 64 bits wide, so at 500 samples/s it takes far longer than the life of the unit to
+/// wrap. All arithmetic wraps regardless, and [`SampleCounter::since`] and
+/// [`SampleCounter::is_after`] stay correct across the wrap, so no counter value is ever an error.
+/// Positions in the long-term buffer are tracked separately by the [`Detector`].
+#[derive(Copy, Clone, Default, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SampleCounter(pub u64);
+
+impl SampleCounter {
+    /// Get current counter value
+    pub fn get_counter(&self) -> u64 {
+        self.0
+    }
+
+    /// Advance to the next sample (mainly used by [`Detector::insert`]), wrapping to 0 after
+    /// [`u64::MAX`].
+    #[inline]
+    pub fn increment(&mut self) {
+        self.0 = self.0.wrapping_add(1);
+    }
+
+    /// The sample `count` samples before this one
+    pub fn before(&self, count: u64) -> Self {
+        Self(self.0.wrapping_sub(count))
+    }
+
+    /// Number of samples from `earlier` to this one
+    pub fn since(&self, earlier: SampleCounter) -> u64 {
+        self.0.wrapping_sub(earlier.0)
+    }
+
+    /// Whether this sample was recorded after `other`, assuming they are less than half the
+    /// counter range apart
+    pub fn is_after(&self, other: SampleCounter) -> bool {
+        let distance = self.since(other);
+        distance != 0 && distance < 1 << 63
+    }
+}
+
+/// Thresholds used by a [`Detector`]. See
+/// the firmware's `config::DetectionConfig` for the meaning of each.
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Thresholds {
+    /// Averaged difference used for detecting contact
+    pub trigger_delta: i16,
+    /// Smaller averaged difference raising a warning, or 0 to disable warnings. Only differences
+    /// below `trigger_delta` warn.
+    pub warning_delta: i16,
+    /// Difference from the sample preceding a trigger needed to confirm a contact or clear event
+    pub confirm_delta: i16,
+    /// Increase relative to the last detection event needed to clear contact
+    pub restore_delta: i16,
+    /// Number of samples after a detection event before the alert can clear. Without
+    /// `restore_samples`, it clears then regardless of the signal.
+    pub alert_hold_samples: u32,
+    /// Consecutive samples the signal must stay restored by `restore_delta` from the last
+    /// detection event before the alert clears, so a momentary rebound during contact doesn't
+    /// clear it. 0 clears as soon as `alert_hold_samples` have passed.
+    pub restore_samples: u32,
+}
+
+/// Detection phases of the contact state machine
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub enum Phase {
+    /// Waiting for [`SETTLE_SAMPLES`] before detection is armed
+    Calibrating,
+    /// Checking for contact
+    Armed,
+    /// Checking for contact after a warning, and for the end of the warning
+    Warning,
+    /// Contact detected, checking for the end of contact
+    Alert,
+}
+
+/// Outcome of a [`Detector::step`], moving the state machine to another [`Phase`]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub enum Event {
+    /// The signal has settled, and detection can be armed
+    Settled,
+    /// Contact was detected on [`Detector::detection_idx`]
+    Contact,
+    /// Contact has ended
+    ContactEnded,
+    /// The signal changed by at least [`Thresholds::warning_delta`], but not enough to detect
+    /// contact
+    Warning,
+    /// No warning has been raised for [`Thresholds::alert_hold_samples`]
+    WarningEnded,
+}
+
+/// A contact detection algorithm, fed one averaged sample at a time. [`Detector`] is the one
+/// that actuates. Others can be qualified against it in shadow mode, where their decisions are only
+/// logged.
+pub trait Algorithm {
+    /// Record the next averaged sample
+    fn insert(&mut self, sample: u8);
+    /// Run the contact state machine on the most recent sample, in `phase`. Returns the [`Event`]
+    /// that moves it to the next phase, if any.
+    fn step(&mut self, phase: Phase) -> Option<Event>;
+    /// Replace the thresholds, which the algorithm may interpret as it sees fit
+    fn set_thresholds(&mut self, thresholds: Thresholds);
+}
+
+/// Long-term buffer of `N` averaged samples, and the detection logic run on it
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Detector<const N: usize> {
+    /// Records samples for long-term and adaptive detection
+    samples: RingBuffer<u8, N>,
+    /// Counter for the most recent sample added to
+    current_sample: SampleCounter,
+    /// Rotates position time stamps for recent detection events, comparable with
+    /// `current_sample`. Most recent event is stored at index 0
+    detection_events: [Option<DetectionEvent>; DETECTION_EVENTS],
+    /// A potential detection event or event clear has been recorded, and the detector is awaiting
+    /// a second sample
+    await_confirm: bool,
+    /// Counter of the most recent sample that raised a warning
+    last_warning: Option<SampleCounter>,
+    /// Consecutive samples restored from the last detection event, see
+    /// [`Thresholds::restore_samples`]
+    restored: u32,
+    /// Thresholds used for detection
+    thresholds: Thresholds,
+}
+
+impl<const N: usize> Detector<N> {
+    /// Create an empty detector using `thresholds`
+    pub const fn new(thresholds: Thresholds) -> Self {
+        Self {
+            samples: RingBuffer::new(0),
+            current_sample: SampleCounter(0),
+            detection_events: [None; DETECTION_EVENTS],
+            await_confirm: false,
+            last_warning: None,
+            restored: 0,
+            thresholds,
+        }
+    }
+
+    /// Replace the thresholds used for detection. Any pending confirmation is discarded.
+    pub fn set_thresholds(&mut self, thresholds: Thresholds) {
+        self.thresholds = thresholds;
+        self.await_confirm = false;
+    }
+
+    /// Thresholds used for detection
+    pub fn thresholds(&self) -> Thresholds {
+        self.thresholds
+    }
+
+    /// Total number of samples inserted
+    pub fn samples_recorded(&self) -> u64 {
+        self.current_sample.get_counter()
+    }
+
+    /// Index of the most recent sample in the long-term buffer
+    pub fn head(&self) -> usize {
+        self.samples.head()
+    }
+
+    /// The long-term buffer, with the most recent sample at [`Detector::head`]
+    pub fn samples(&self) -> &[u8; N] {
+        self.samples.items()
+    }
+
+    /// Index of the sample `back` samples before the head, for `back` up to `N`. See
+    /// [`RingBuffer::back`].
+    pub fn back(&self, back: usize) -> usize {
+        self.samples.back(back)
+    }
+
+    /// Insert a new sample at the head, overwriting the oldest once the buffer is full. Always
+    /// inlined, so it runs from wherever the caller is placed.
+    #[inline(always)]
+    pub fn insert(&mut self, sample: u8) {
+        self.samples.push(sample);
+        self.current_sample.increment();
+    }
+
+    /// Most recent detection event, if any
+    pub fn last_detection(&self) -> Option<DetectionEvent> {
+        self.detection_events[0]
+    }
+
+    /// Run the contact state machine on the most recent sample, in `phase`. Returns the
+    /// [`Event`] that moves it to the next phase, if any.
+    pub fn step(&mut self, phase: Phase) -> Option<Event> {
+        match phase {
+            Phase::Calibrating => {
+                (self.samples_recorded() >= SETTLE_SAMPLES).then_some(Event::Settled)
+            }
+            Phase::Armed if self.detect_contact() => Some(Event::Contact),
+            Phase::Armed => self.detect_warning().then_some(Event::Warning),
+            Phase::Warning if self.detect_contact() => Some(Event::Contact),
+            Phase::Warning => {
+                self.detect_warning();
+                self.detect_end_warning().then_some(Event::WarningEnded)
+            }
+            Phase::Alert => self.detect_end_contact().then_some(Event::ContactEnded),
+        }
+    }
+
+    /// Difference between the most recent sample and the one before it
+    pub fn delta(&self) -> i16 {
+        i16::abs(self.samples.get(1) as i16 - self.samples.latest() as i16)
+    }
+
+    /// Whether the most recent sample changed by at least `percent` of
+    /// [`Thresholds::trigger_delta`], without reaching it. Near misses don't change the phase, but
+    /// trending them shows a probe degrading before it misdetects.
+    pub fn near_miss(&self, percent: u8) -> bool {
+        let (delta, trigger) = (self.delta() as i32, self.thresholds.trigger_delta as i32);
+        delta < trigger && delta * 100 >= trigger * percent as i32
+    }
+
+    /// Check whether the most recent sample changed by at least [`Thresholds::warning_delta`],
+    /// but less than [`Thresholds::trigger_delta`]. Records the sample as the latest warning if so.
+    pub fn detect_warning(&mut self) -> bool {
+        let delta = self.delta();
+        let warning = self.thresholds.warning_delta > 0
+            && delta >= self.thresholds.warning_delta
+            && delta < self.thresholds.trigger_delta;
+        if warning {
+            self.last_warning = Some(self.current_sample);
+        }
+        warning
+    }
+
+    /// Whether at least [`Thresholds::alert_hold_samples`] have been recorded since the last
+    /// warning, or there hasn't been one.
+    pub fn detect_end_warning(&self) -> bool {
+        !self.last_warning.is_some_and(|last_warning| {
+            self.current_sample.since(last_warning) < self.thresholds.alert_hold_samples as u64
+        })
+    }
+
+    /// Analyze the most recent data to determine if a contact event has occurred.
+    ///
+    /// Also updates the record of recent detection events
+    pub fn detect_contact(&mut self) -> bool {
+        if !self.await_confirm {
+            // First contact check
+            if i16::abs(self.samples.get(1) as i16 - self.samples.latest() as i16)
+                >= self.thresholds.trigger_delta
+            {
+                self.await_confirm = true;
+            }
+            return false;
+        } else {
+            // Validation contact check
+            self.await_confirm = false; // Always reset on validation check
+            if i16::abs(self.samples.get(2) as i16 - self.samples.latest() as i16)
+                >= self.thresholds.confirm_delta
+            {
+                // Contact detected!
+                self.add_detection_event();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Analyze the most recent data and contact events to determine when contact ends
+    ///
+    /// An alert will not clear until at least [`Thresholds::alert_hold_samples`] have been
+    /// recorded since the last detection event. This ensures the operator will see the LED light
+    /// up. After that, the signal must also have stayed restored for
+    /// [`Thresholds::restore_samples`] in a row. Always returns `false` if there has been no
+    /// detection event.
+    pub fn detect_end_contact(&mut self) -> bool {
+        let Some(last_detection) = self.detection_events[0] else {
+            return false;
+        };
+        if i16::abs(self.samples.latest() as i16 - last_detection.1 as i16)
+            >= self.thresholds.restore_delta
+        {
+            self.restored = self.restored.saturating_add(1);
+        } else {
+            // A rebound that doesn't last starts the count again
+            self.restored = 0;
+        }
+        if self.current_sample.since(last_detection.0) >= self.thresholds.alert_hold_samples as u64
+            && self.restored >= self.thresholds.restore_samples
+        {
+            self.await_confirm = false;
+            self.restored = 0;
+            return true;
+        }
+        false
+    }
+
+    /// Counter of the sample on which contact was detected
+    pub fn detection_idx(&self) -> SampleCounter {
+        self.current_sample.before(1)
+    }
+
+    /// Add an entry to the detection events, based on the penultimate sample.
+    fn add_detection_event(&mut self) {
+        self.detection_events.rotate_right(1);
+        self.detection_events[0] = Some((self.current_sample, self.samples.latest()));
+        self.restored = 0;
+    }
+}
+
+impl<const N: usize> Algorithm for Detector<N> {
+    fn insert(&mut self, sample: u8) {
+        Detector::insert(self, sample)
+    }
+
+    fn step(&mut self, phase: Phase) -> Option<Event> {
+        Detector::step(self, phase)
+    }
+
+    fn set_thresholds(&mut self, thresholds: Thresholds) {
+        Detector::set_thresholds(self, thresholds)
+    }
+}
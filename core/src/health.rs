@@ -0,0 +1,189 @@
+//! Health scoring of the channels sampling one probe, disqualifying a clearly faulty one.
+//!
+//! With `dual_channel` or `triple_channel`, the probe is sampled through several independent
+//! dividers, so a fault on one channel shows up as that channel behaving differently from the
+//! others. [`HealthMonitor`] counts three symptoms over each window of [`WINDOW_SAMPLES`]:
+//!
+//! - noise: the sum of the changes between consecutive samples, compared with the quietest other
+//!   channel
+//! - rail time: samples pinned at 0 or 255
+//! - disagreement: samples more than [`DISAGREE_TOLERANCE`] from every other channel. With only
+//!   two channels, this can't tell which of them is at fault, so it is left to the cross-check.
+//!
+//! A channel showing any of them is suspect for the window, and loses [`SUSPECT_PENALTY`] from its
+//! score, recovering [`CLEAN_RECOVERY`] for each clean window. Contacts and interference reach
+//! every channel at once, so they don't make any channel suspect. Once a channel's score reaches 0
+//! while it is the only suspect, it is disqualified for good, rather than being left to veto or
+//! spoof detection. At most one channel is ever disqualified, as the others can't then be checked
+//! against each other.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Samples in each scoring window (1 s with 2 ms averaging)
+pub const WINDOW_SAMPLES: u32 = 500;
+/// Score of a healthy channel
+pub const FULL_SCORE: u8 = 100;
+/// Score lost for each suspect window, so a channel suspect for 4 windows in a row is disqualified
+pub const SUSPECT_PENALTY: u8 = 25;
+/// Score recovered for each clean window
+pub const CLEAN_RECOVERY: u8 = 5;
+/// Largest difference from another channel still treated as agreement
+pub const DISAGREE_TOLERANCE: u8 = 4;
+/// Times noisier than the quietest other channel a channel must be to be suspect
+pub const NOISE_RATIO: u32 = 4;
+/// Noise every channel is allowed, whatever the others show: an average change of 1 per sample
+pub const NOISE_FLOOR: u32 = WINDOW_SAMPLES;
+/// Share of a window, in percent, a channel must spend at a rail or in disagreement to be suspect
+pub const SUSPECT_PERCENT: u32 = 50;
+
+/// Symptoms counted for one channel over a window
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Symptoms {
+    /// Sum of the changes between consecutive samples
+    pub noise: u32,
+    /// Samples at 0 or 255
+    pub railed: u32,
+    /// Samples more than [`DISAGREE_TOLERANCE`] from every other channel
+    pub disagreed: u32,
+}
+
+impl Symptoms {
+    /// No symptoms
+    pub const fn new() -> Self {
+        Self {
+            noise: 0,
+            railed: 0,
+            disagreed: 0,
+        }
+    }
+}
+
+/// Scores of `N` channels, and the one disqualified, if any
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HealthMonitor<const N: usize> {
+    /// Score of each channel, from 0 to [`FULL_SCORE`]
+    scores: [u8; N],
+    /// Symptoms of each channel in the window so far
+    window: [Symptoms; N],
+    /// Symptoms of each channel over the last complete window
+    last_window: [Symptoms; N],
+    /// Samples recorded in the window so far
+    samples: u32,
+    /// Previous sample of each channel, for the noise
+    previous: Option<[u8; N]>,
+    /// Channel disqualified, if any
+    disqualified: Option<usize>,
+}
+
+impl<const N: usize> HealthMonitor<N> {
+    /// Every channel healthy, with an empty window
+    pub const fn new() -> Self {
+        Self {
+            scores: [FULL_SCORE; N],
+            window: [Symptoms::new(); N],
+            last_window: [Symptoms::new(); N],
+            samples: 0,
+            previous: None,
+            disqualified: None,
+        }
+    }
+
+    /// Record the next averaged sample of each channel. At the end of each window, the channels
+    /// are scored, and the channel disqualified by it is returned, if any.
+    pub fn record(&mut self, samples: [u8; N]) -> Option<usize> {
+        for (channel, &sample) in samples.iter().enumerate() {
+            let symptoms = &mut self.window[channel];
+            if let Some(previous) = self.previous {
+                symptoms.noise += previous[channel].abs_diff(sample) as u32;
+            }
+            if sample == u8::MIN || sample == u8::MAX {
+                symptoms.railed += 1;
+            }
+            let mut others = (0..N).filter(|&other| other != channel);
+            if N > 1 && others.all(|other| samples[other].abs_diff(sample) > DISAGREE_TOLERANCE) {
+                symptoms.disagreed += 1;
+            }
+        }
+        self.previous = Some(samples);
+        self.samples += 1;
+        if self.samples < WINDOW_SAMPLES {
+            return None;
+        }
+        self.score()
+    }
+
+    /// Score of each channel, from 0 to [`FULL_SCORE`]
+    pub fn scores(&self) -> [u8; N] {
+        self.scores
+    }
+
+    /// Symptoms of each channel over the last complete window
+    pub fn last_window(&self) -> [Symptoms; N] {
+        self.last_window
+    }
+
+    /// Channel disqualified, if any
+    pub fn disqualified(&self) -> Option<usize> {
+        self.disqualified
+    }
+
+    /// Whether `channel` showed any symptom over the last complete window. Disagreement only
+    /// counts with more than two channels.
+    pub fn suspect(&self, channel: usize) -> bool {
+        let symptoms = &self.last_window[channel];
+        let quietest_other = (0..N)
+            .filter(|&other| other != channel)
+            .map(|other| self.last_window[other].noise)
+            .min()
+            .unwrap_or(u32::MAX);
+        let noisy = symptoms.noise > NOISE_FLOOR
+            && symptoms.noise > quietest_other.saturating_mul(NOISE_RATIO);
+        let threshold = WINDOW_SAMPLES * SUSPECT_PERCENT / 100;
+        let disagreed = N > 2 && symptoms.disagreed >= threshold;
+        noisy || symptoms.railed >= threshold || disagreed
+    }
+
+    /// Close the window, scoring each channel on it, and disqualify a channel that has run out of
+    /// score while it is the only suspect
+    fn score(&mut self) -> Option<usize> {
+        self.last_window = self.window;
+        self.window = [Symptoms::new(); N];
+        self.samples = 0;
+        let suspects: [bool; N] = core::array::from_fn(|channel| self.suspect(channel));
+        for (score, suspect) in self.scores.iter_mut().zip(suspects) {
+            *score = if suspect {
+                score.saturating_sub(SUSPECT_PENALTY)
+            } else {
+                score.saturating_add(CLEAN_RECOVERY).min(FULL_SCORE)
+            };
+        }
+        if self.disqualified.is_some() || suspects.iter().filter(|&&suspect| suspect).count() != 1 {
+            return None;
+        }
+        let channel = suspects.iter().position(|&suspect| suspect)?;
+        (self.scores[channel] == 0).then(|| {
+            self.disqualified = Some(channel);
+            channel
+        })
+    }
+}
+
+impl<const N: usize> Default for HealthMonitor<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,142 @@
+//! Status indication, free of any hardware or logging dependencies.
+//!
+//! Like [`detection_core`](crate::detection_core), this module depends only on `core` and the
+//! detection core. The [`StatusLedStates`] shown by the status LEDs are defined here, along with
+//! the [`StatusIndicator`] trait for logic that displays them.
+//!
+//! With the `mock` feature, the [`MockIndicator`] records every pattern it is shown with a
+//! timestamp, so the host tests can check that a sequence of samples produces the expected
+//! Alert/Normal/Error transitions without any LEDs attached.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::detection_core::Phase;
+
+/// LED patterns, each displaying one or more of the firmware's `SystemState`s
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StatusLedStates {
+    /// Green
+    Normal,
+    /// Yellow
+    Alert,
+    /// Red
+    Error,
+    /// None illuminated
+    Disabled,
+}
+
+impl From<Phase> for StatusLedStates {
+    /// Pattern shown in each detection phase, as for the matching `SystemState`
+    fn from(phase: Phase) -> Self {
+        match phase {
+            Phase::Calibrating | Phase::Warning | Phase::Alert => StatusLedStates::Alert,
+            Phase::Armed => StatusLedStates::Normal,
+        }
+    }
+}
+
+/// Displays [`StatusLedStates`] to the operator
+pub trait StatusIndicator {
+    /// Show `leds`, `at_ms` milliseconds since boot. Showing the current pattern again has no
+    /// effect.
+    fn show(&mut self, leds: StatusLedStates, at_ms: u32);
+}
+
+/// A change of the displayed pattern, recorded by the [`MockIndicator`]
+#[cfg(feature = "mock")]
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Transition {
+    /// Milliseconds since boot when the pattern was shown
+    pub at_ms: u32,
+    /// Pattern shown
+    pub leds: StatusLedStates,
+}
+
+/// In-memory [`StatusIndicator`] recording the first `N` transitions, for verifying the
+/// indication logic without hardware
+#[cfg(feature = "mock")]
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct MockIndicator<const N: usize> {
+    /// Recorded transitions, of which the first `len` are valid
+    transitions: [Transition; N],
+    /// Number of recorded transitions
+    len: usize,
+    /// Pattern currently shown, if any
+    current: Option<StatusLedStates>,
+    /// Transitions that didn't fit after the first `N`
+    overflowed: usize,
+}
+
+#[cfg(feature = "mock")]
+impl<const N: usize> MockIndicator<N> {
+    /// Create an indicator that hasn't shown anything yet
+    pub const fn new() -> Self {
+        Self {
+            transitions: [Transition {
+                at_ms: 0,
+                leds: StatusLedStates::Disabled,
+            }; N],
+            len: 0,
+            current: None,
+            overflowed: 0,
+        }
+    }
+
+    /// Every recorded transition, oldest first
+    pub fn transitions(&self) -> &[Transition] {
+        &self.transitions[..self.len]
+    }
+
+    /// Pattern currently shown, if any
+    pub fn current(&self) -> Option<StatusLedStates> {
+        self.current
+    }
+
+    /// Number of transitions that weren't recorded because the first `N` had been
+    pub fn overflowed(&self) -> usize {
+        self.overflowed
+    }
+
+    /// Forget every recorded transition, keeping the current pattern
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.overflowed = 0;
+    }
+}
+
+#[cfg(feature = "mock")]
+impl<const N: usize> Default for MockIndicator<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "mock")]
+impl<const N: usize> StatusIndicator for MockIndicator<N> {
+    fn show(&mut self, leds: StatusLedStates, at_ms: u32) {
+        if self.current == Some(leds) {
+            return;
+        }
+        self.current = Some(leds);
+        match self.transitions.get_mut(self.len) {
+            Some(slot) => {
+                *slot = Transition { at_ms, leds };
+                self.len += 1;
+            }
+            None => self.overflowed += 1,
+        }
+    }
+}
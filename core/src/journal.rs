@@ -0,0 +1,384 @@
+//! Compact record format for the flash event journal.
+//!
+//! Like [`detection_core`](crate::detection_core), this module depends only on `core`, so host
+//! tools can use it to decode a dumped journal sector.
+//!
+//! A sector starts with [`MAGIC`], followed by records packed back to back until the first erased
+//! (`0xFF`) byte. Each record is a tag byte followed by
+//! [LEB128](https://en.wikipedia.org/wiki/LEB128) varints, so small numbers take a single byte:
+//!
+//! - [`TAG_REBASE`], then the absolute time in milliseconds. Written before the first event in a
+//!   sector, and whenever the clock goes backwards (after a reset).
+//! - [`TAG_SEQUENCE`], then the [`Record::seq`] of the next event. Written before the first event
+//!   in a sector, and whenever the sequence skips some (events dropped before they were written).
+//! - Any other tag below `0xFE` is an event [`Record::kind`], then the milliseconds since the
+//!   previous record, then the [`Record::value`]. Its sequence number is one after the previous
+//!   event's.
+//!
+//! A typical event takes 3-5 bytes, against 12 for the fixed-size entries of the firmware's
+//! post-mortem ring. Events in sectors written before sequence numbers were added are numbered
+//! from 0.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Marks the start of a journal sector
+pub const MAGIC: [u8; 4] = *b"JRNL";
+/// Tag of a record setting the absolute time for the records after it
+pub const TAG_REBASE: u8 = 0x00;
+/// Tag of a record setting the sequence number of the event after it
+pub const TAG_SEQUENCE: u8 = 0xFE;
+/// Value of erased flash, ending the records in a sector
+pub const TAG_ERASED: u8 = 0xFF;
+/// Longest encoded record: a tag and two 5-byte varints
+pub const MAX_RECORD_BYTES: usize = 1 + 2 * 5;
+
+/// A journalled event
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Record {
+    /// Sequence number, one more than the event recorded before it, even across boots, so host
+    /// tools can tell when events were lost to rotation or dropped before they were written
+    pub seq: u32,
+    /// Milliseconds since boot
+    pub at_ms: u32,
+    /// What happened, from 1 to 253
+    pub kind: u8,
+    /// Detail of the event, such as an error code or sample value
+    pub value: u32,
+}
+
+/// Events journalled by the firmware, stored as the [`Record::kind`]
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Kind {
+    /// The firmware started. The value is the discriminant of the firmware's reset cause.
+    Boot = 1,
+    /// Contact was detected. The value is the probe in bits 8-15, and the averaged sample in bits
+    /// 0-7.
+    Detection = 2,
+    /// The operator armed detection. The value is the [`Source`].
+    Arm = 3,
+    /// The operator stopped detection. The value is the [`Source`].
+    Disarm = 4,
+    /// The operator reset a latched alert. The value is the [`Source`].
+    Acknowledge = 5,
+    /// The probe signal settled after calibrating, and detection armed. The value is the
+    /// [`Source`].
+    Calibration = 6,
+    /// A new configuration was applied. The value is the [`Source`].
+    ConfigChange = 7,
+    /// The factory configuration was restored. The value is the [`Source`].
+    FactoryReset = 8,
+    /// Events were dropped because too many happened before they could be written. The value is
+    /// the number dropped.
+    Dropped = 9,
+    /// The operator muted the buzzer for a while. The value is the [`Source`].
+    Silence = 10,
+    /// Privileged console commands were unlocked with the PIN, or accepted through the jumper.
+    /// The value is the [`Source`].
+    Unlock = 11,
+    /// A privileged console command, or an unlock with a wrong PIN, was refused. The value is the
+    /// [`Source`].
+    Refused = 12,
+    /// The configuration PIN was changed. The value is the [`Source`].
+    PinChange = 13,
+    /// The serial number, hardware revision or calibration of the unit was provisioned. The value
+    /// is the [`Source`].
+    Provision = 14,
+    /// A fault was simulated from the console for a safety drill. The value is the drill: 1 for a
+    /// contact, 2 for a probe disconnect, 3 for a watchdog timeout.
+    Drill = 15,
+    /// An error stopped detection. The value is the firmware's error code.
+    Fault = 16,
+    /// An edge on the external trigger input marked the sample stream. The value is the low 32
+    /// bits of the firmware's `SampleCounter` at the edge.
+    Marker = 17,
+    /// A stored command macro was run. The value is the macro's slot in bits 8-15, and the
+    /// [`Source`] that ran it in bits 0-7.
+    Macro = 18,
+    /// The power-good input fell, as the supply was about to drop out. The firmware writes it to
+    /// flash straight away, with every event still queued. The value is 0.
+    PowerFail = 19,
+    /// The chip woke from standby, and detection started calibrating again. The value is the
+    /// [`Source`] whose input woke it.
+    Wake = 20,
+}
+
+impl Kind {
+    /// Every kind, in declaration order
+    pub const ALL: [Kind; 20] = [
+        Kind::Boot,
+        Kind::Detection,
+        Kind::Arm,
+        Kind::Disarm,
+        Kind::Acknowledge,
+        Kind::Calibration,
+        Kind::ConfigChange,
+        Kind::FactoryReset,
+        Kind::Dropped,
+        Kind::Silence,
+        Kind::Unlock,
+        Kind::Refused,
+        Kind::PinChange,
+        Kind::Provision,
+        Kind::Drill,
+        Kind::Fault,
+        Kind::Marker,
+        Kind::Macro,
+        Kind::PowerFail,
+        Kind::Wake,
+    ];
+
+    /// Kind stored as `kind`, or [`None`] if it is unknown
+    pub fn from_raw(kind: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|known| *known as u8 == kind)
+    }
+
+    /// Short name for printing
+    pub const fn name(self) -> &'static str {
+        match self {
+            Kind::Boot => "boot",
+            Kind::Detection => "detection",
+            Kind::Arm => "arm",
+            Kind::Disarm => "disarm",
+            Kind::Acknowledge => "acknowledge",
+            Kind::Calibration => "calibration",
+            Kind::ConfigChange => "config change",
+            Kind::FactoryReset => "factory reset",
+            Kind::Dropped => "dropped",
+            Kind::Silence => "silence",
+            Kind::Unlock => "unlock",
+            Kind::Refused => "refused",
+            Kind::PinChange => "PIN change",
+            Kind::Provision => "provision",
+            Kind::Drill => "drill",
+            Kind::Fault => "fault",
+            Kind::Marker => "marker",
+            Kind::Macro => "macro",
+            Kind::PowerFail => "power fail",
+            Kind::Wake => "wake",
+        }
+    }
+
+    /// Whether the operator caused the event, so its value is a [`Source`]
+    pub const fn operator(self) -> bool {
+        matches!(
+            self,
+            Kind::Arm
+                | Kind::Disarm
+                | Kind::Acknowledge
+                | Kind::Calibration
+                | Kind::ConfigChange
+                | Kind::FactoryReset
+                | Kind::Silence
+                | Kind::Unlock
+                | Kind::Refused
+                | Kind::PinChange
+                | Kind::Provision
+                | Kind::Wake
+        )
+    }
+}
+
+/// What caused an operator event, stored as the [`Record::value`]
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Source {
+    /// The firmware, without operator input
+    System = 0,
+    /// The acknowledge button
+    Button = 1,
+    /// The disable switch
+    Switch = 2,
+    /// A console command
+    Console = 3,
+    /// The configuration jumper
+    Jumper = 4,
+    /// The operator-presence input
+    Presence = 5,
+}
+
+impl Source {
+    /// Every source, in declaration order, so each is at the index of its discriminant
+    pub const ALL: [Source; 6] = [
+        Source::System,
+        Source::Button,
+        Source::Switch,
+        Source::Console,
+        Source::Jumper,
+        Source::Presence,
+    ];
+
+    /// Source stored as `value`, or [`None`] if it is unknown
+    pub fn from_raw(value: u32) -> Option<Self> {
+        Self::ALL.get(value as usize).copied()
+    }
+
+    /// Short name for printing
+    pub const fn name(self) -> &'static str {
+        match self {
+            Source::System => "system",
+            Source::Button => "button",
+            Source::Switch => "switch",
+            Source::Console => "console",
+            Source::Jumper => "jumper",
+            Source::Presence => "presence",
+        }
+    }
+}
+
+/// Write `value` as a varint at the start of `out`, returning the bytes written, or [`None`] if
+/// it doesn't fit.
+fn write_varint(mut value: u32, out: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let slot = out.get_mut(len)?;
+        len += 1;
+        if value == 0 {
+            *slot = byte;
+            return Some(len);
+        }
+        *slot = byte | 0x80;
+    }
+}
+
+/// Read a varint from the start of `bytes`, returning it and its length, or [`None`] if it is
+/// truncated or too long for a `u32`.
+fn read_varint(bytes: &[u8]) -> Option<(u32, usize)> {
+    let mut value = 0u32;
+    for (idx, byte) in bytes.iter().enumerate().take(5) {
+        value |= ((byte & 0x7F) as u32) << (7 * idx);
+        if byte & 0x80 == 0 {
+            return Some((value, idx + 1));
+        }
+    }
+    None
+}
+
+/// Packs [`Record`]s for one sector, delta-encoding each timestamp against the previous record
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Encoder {
+    /// Timestamp of the previous record, or [`None`] at the start of a sector
+    last_ms: Option<u32>,
+    /// Sequence number following the previous record's, or [`None`] at the start of a sector
+    next_seq: Option<u32>,
+}
+
+impl Encoder {
+    /// Create an encoder for the start of a new sector, after [`MAGIC`]
+    pub const fn new() -> Self {
+        Self {
+            last_ms: None,
+            next_seq: None,
+        }
+    }
+
+    /// Encode `record` at the start of `out`, preceded by a rebase or a sequence number if needed.
+    /// Returns the bytes written, or [`None`] if `out` is too short or the kind is reserved, in
+    /// which case nothing is recorded and the encoder is unchanged.
+    pub fn encode(&mut self, record: Record, out: &mut [u8]) -> Option<usize> {
+        if matches!(record.kind, TAG_REBASE | TAG_SEQUENCE | TAG_ERASED) {
+            return None;
+        }
+        let mut len = 0;
+        let delta = match self.last_ms {
+            Some(last_ms) if record.at_ms >= last_ms => record.at_ms - last_ms,
+            _ => {
+                *out.first_mut()? = TAG_REBASE;
+                len += 1 + write_varint(record.at_ms, out.get_mut(1..)?)?;
+                0
+            }
+        };
+        if self.next_seq != Some(record.seq) {
+            *out.get_mut(len)? = TAG_SEQUENCE;
+            len += 1 + write_varint(record.seq, out.get_mut(len + 1..)?)?;
+        }
+        *out.get_mut(len)? = record.kind;
+        len += 1;
+        len += write_varint(delta, out.get_mut(len..)?)?;
+        len += write_varint(record.value, out.get_mut(len..)?)?;
+        self.last_ms = Some(record.at_ms);
+        self.next_seq = Some(record.seq.wrapping_add(1));
+        Some(len)
+    }
+}
+
+/// Iterates over the [`Record`]s in a sector's bytes, following [`MAGIC`]. Stops at the first
+/// erased byte, or at a truncated or corrupt record.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Decoder<'a> {
+    /// Bytes not yet decoded
+    bytes: &'a [u8],
+    /// Timestamp of the previous record
+    last_ms: u32,
+    /// Sequence number of the next event
+    next_seq: u32,
+}
+
+impl<'a> Decoder<'a> {
+    /// Decode the records in `sector`, or [`None`] if it doesn't start with [`MAGIC`]
+    pub fn new(sector: &'a [u8]) -> Option<Self> {
+        Some(Self {
+            bytes: sector.strip_prefix(&MAGIC)?,
+            last_ms: 0,
+            next_seq: 0,
+        })
+    }
+
+    /// Bytes left after the records decoded so far
+    pub fn remaining(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+impl Iterator for Decoder<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            let (&tag, rest) = self.bytes.split_first()?;
+            match tag {
+                TAG_ERASED => return None,
+                TAG_REBASE => {
+                    let (at_ms, len) = read_varint(rest)?;
+                    self.last_ms = at_ms;
+                    self.bytes = &rest[len..];
+                }
+                TAG_SEQUENCE => {
+                    let (seq, len) = read_varint(rest)?;
+                    self.next_seq = seq;
+                    self.bytes = &rest[len..];
+                }
+                kind => {
+                    let (delta, delta_len) = read_varint(rest)?;
+                    let (value, value_len) = read_varint(&rest[delta_len..])?;
+                    self.last_ms = self.last_ms.wrapping_add(delta);
+                    self.bytes = &rest[delta_len + value_len..];
+                    let seq = self.next_seq;
+                    self.next_seq = seq.wrapping_add(1);
+                    return Some(Record {
+                        seq,
+                        at_ms: self.last_ms,
+                        kind,
+                        value,
+                    });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Hardware-independent logic of the PFPU2 contact detection firmware.
+//!
+//! Nothing here depends on a HAL or on logging, so the same code runs in the
+//! [`aps490_pfpu2_mini`](https://docs.rs/aps490_pfpu2_mini) firmware, in the host tests and
+//! tools, and on any future microcontroller port. The firmware re-exports each module under the
+//! same name.
+//!
+//! ## Feature flags
+//!
+//! - `defmt`: Derives [`defmt::Format`](https://docs.rs/defmt) for the types the firmware logs.
+//! - `mock`: Provides the [`MockIndicator`](indicator::MockIndicator), which records the LED
+//!   patterns it is shown for checking the indication logic without hardware.
+//! - `serde`: Derives `Serialize` and `Deserialize` for the types the firmware persists in its
+//!   configuration, such as [`RestoreProfile`](protocol::RestoreProfile).
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_std]
+#![warn(missing_docs)]
+
+pub mod auto_zero;
+pub mod detection_core;
+pub mod health;
+pub mod indicator;
+pub mod journal;
+pub mod protocol;
+pub mod ring;
+pub mod sequence;
+pub mod soak;
+pub mod system_state;
+pub mod units;
+pub mod voting;
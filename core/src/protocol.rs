@@ -0,0 +1,886 @@
+//! Console protocol: the [`Command`]s a line of input parses into, the [`Access`] each needs, and
+//! the [`ConsoleError`]s reported back.
+//!
+//! Parsing depends on nothing but the text of the line, so the firmware's console, the host tests
+//! and tools driving a unit over USB all accept and refuse the same lines. The firmware runs the
+//! commands, and lists them in its `console` module. The arguments some commands carry, and the
+//! errors raised running them, are defined here with them.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    system_state::SystemState,
+    units::{Millivolts, ADC_REFERENCE_MV},
+};
+
+/// Longest accepted input line
+pub const LINE_SIZE: usize = 192;
+/// Most samples of each probe `CAPTURE` can ask for, 2 seconds with 2 ms averaging
+pub const CAPTURE_SAMPLES: usize = 1_000;
+/// Number of macro slots
+pub const MACROS: usize = 4;
+/// Most characters in a macro
+pub const MACRO_LEN: usize = 128;
+/// Fewest digits in a PIN
+pub const MIN_DIGITS: usize = 4;
+/// Most digits in a PIN, so its value fits in a `u32`
+pub const MAX_DIGITS: usize = 8;
+/// Most characters in a serial number
+pub const SERIAL_LEN: usize = 16;
+/// Most characters in a hardware revision
+pub const REVISION_LEN: usize = 8;
+/// Largest ADC offset correction either way, in codes
+pub const MAX_ADC_OFFSET: i16 = 127;
+/// Smallest ADC gain correction, in parts per million
+pub const MIN_ADC_GAIN_PPM: u32 = 500_000;
+/// Largest ADC gain correction, in parts per million
+pub const MAX_ADC_GAIN_PPM: u32 = 2_000_000;
+/// Smallest ADC reference, in millivolts
+pub const MIN_ADC_REFERENCE_MV: u16 = 3_000;
+/// Largest ADC reference, in millivolts
+pub const MAX_ADC_REFERENCE_MV: u16 = 3_600;
+
+/// Errors reported back to the console user
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConsoleError {
+    /// Command was not recognized
+    UnknownCommand,
+    /// Command requires an argument that was not provided
+    MissingArgument,
+    /// Argument was not valid hexadecimal
+    InvalidHex,
+    /// Input line exceeded [`LINE_SIZE`]
+    LineTooLong,
+    /// Configuration could not be loaded, stored or transferred
+    Config(ConfigError),
+    /// Command is not available in the current system state
+    InvalidState,
+    /// Command writes flash, which isn't allowed while sampling, see [`Command::writes_flash`]
+    Sampling,
+    /// `CONFIRM` was entered without a command waiting for confirmation
+    NothingToConfirm,
+    /// Command depends on a feature this build was compiled without
+    NotEnabled,
+    /// `HIL` was given a scenario that doesn't exist
+    UnknownScenario,
+    /// `AB` was given a preset that doesn't exist
+    UnknownPreset,
+    /// `LOG` was given a category or level that doesn't exist
+    UnknownLogSetting,
+    /// `EXPORT` was given a format that doesn't exist
+    UnknownExportFormat,
+    /// `CAPTURE` was given a number of samples out of range
+    InvalidCaptureLength,
+    /// `EXPORT` was given a sequence number that isn't a whole number
+    InvalidSequence,
+    /// `DRILL` was given a drill that doesn't exist
+    UnknownDrill,
+    /// `THRESHOLD` was given a threshold that doesn't exist
+    UnknownThreshold,
+    /// `THRESHOLD` was given a voltage that isn't a whole number of millivolts in range
+    InvalidThreshold,
+    /// `RESTORE` was given a profile that doesn't exist
+    UnknownRestoreProfile,
+    /// Command is privileged, and the console is locked
+    Locked,
+    /// `UNLOCK` or `PIN` was given something other than 4 to 8 digits
+    InvalidPin,
+    /// `UNLOCK` was refused
+    Lock(LockError),
+    /// `PROVISION` was refused
+    Provision(ProvisionError),
+    /// `MACRO` or `RUN` was given a slot out of range, or `MACRO` was given steps that are too
+    /// long or not printable
+    InvalidMacro,
+    /// `RUN` was given an empty slot
+    EmptyMacro,
+}
+
+impl ConsoleError {
+    /// Short description printed to the console
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConsoleError::UnknownCommand => "unknown command, try HELP",
+            ConsoleError::MissingArgument => "missing argument",
+            ConsoleError::InvalidHex => "argument is not valid hex",
+            ConsoleError::LineTooLong => "line too long",
+            ConsoleError::Config(ConfigError::NotFound) => "no configuration stored",
+            ConsoleError::Config(ConfigError::Outdated) => {
+                "stored configuration is from another firmware version"
+            }
+            ConsoleError::Config(ConfigError::Corrupted) => "configuration failed CRC check",
+            ConsoleError::Config(ConfigError::Serialization) => {
+                "configuration could not be decoded"
+            }
+            ConsoleError::Config(ConfigError::Invalid) => "configuration setting out of range",
+            ConsoleError::Config(ConfigError::Storage(_)) => "unable to write flash",
+            ConsoleError::InvalidState => "not available in the current state",
+            ConsoleError::Sampling => "not available while sampling, disable detection first",
+            ConsoleError::NothingToConfirm => "nothing to confirm",
+            ConsoleError::NotEnabled => "not enabled in this build",
+            ConsoleError::UnknownScenario => "unknown scenario, try STEP, BOUNCE, BLIP or DRIFT",
+            ConsoleError::UnknownPreset => {
+                "unknown preset, try SENSITIVE, STANDARD, TOLERANT or OFF"
+            }
+            ConsoleError::UnknownLogSetting => "unknown category or level, try LOG ALL TRACE",
+            ConsoleError::UnknownExportFormat => "unknown format, try CSV or JSON",
+            ConsoleError::InvalidCaptureLength => "capture must be 1 to 1000 samples",
+            ConsoleError::InvalidSequence => "sequence number must be a whole number",
+            ConsoleError::UnknownDrill => "unknown drill, try CONTACT, DISCONNECT or WATCHDOG",
+            ConsoleError::UnknownThreshold => {
+                "unknown threshold, try TRIGGER, WARNING, CONFIRM or RESTORE"
+            }
+            ConsoleError::InvalidThreshold => "threshold must be 0 to 3300 mV, and only WARNING 0",
+            ConsoleError::UnknownRestoreProfile => "unknown profile, try FAST or SLOW",
+            ConsoleError::Locked => "locked, enter UNLOCK <pin> or fit the jumper",
+            ConsoleError::InvalidPin => "PIN must be 4 to 8 digits",
+            ConsoleError::Lock(LockError::NoPin) => "no PIN set, fit the jumper",
+            ConsoleError::Lock(LockError::WrongPin) => "wrong PIN",
+            ConsoleError::Lock(LockError::LockedOut) => "too many wrong PINs, fit the jumper",
+            ConsoleError::Provision(ProvisionError::UnknownField) => {
+                "unknown field, try SERIAL, REV, OFFSET, GAIN or REF"
+            }
+            ConsoleError::Provision(ProvisionError::InvalidValue) => {
+                "value too long, has invalid characters or is out of range"
+            }
+            ConsoleError::Provision(ProvisionError::JumperRequired) => {
+                "provisioning needs the jumper fitted"
+            }
+            ConsoleError::Provision(ProvisionError::Storage(_)) => "unable to write flash",
+            ConsoleError::InvalidMacro => {
+                "macro slot must be 0 to 3, with up to 128 printable characters"
+            }
+            ConsoleError::EmptyMacro => "no macro stored in that slot",
+        }
+    }
+}
+
+impl From<ConfigError> for ConsoleError {
+    fn from(err: ConfigError) -> Self {
+        ConsoleError::Config(err)
+    }
+}
+
+impl From<LockError> for ConsoleError {
+    fn from(err: LockError) -> Self {
+        ConsoleError::Lock(err)
+    }
+}
+
+impl From<ProvisionError> for ConsoleError {
+    fn from(err: ProvisionError) -> Self {
+        ConsoleError::Provision(err)
+    }
+}
+
+/// Commands a line may be parsed into
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Access {
+    /// Only queries that leave the system as it is
+    ReadOnly,
+    /// Any command, including [privileged](Command::privileged) ones
+    Privileged,
+}
+
+/// Commands accepted by the console
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command<'a> {
+    /// List available commands
+    Help,
+    /// Print the firmware version and build information
+    Identify,
+    /// Restore and persist the compile-time default configuration
+    FactoryReset,
+    /// Print the active configuration as a blob
+    ConfigExport,
+    /// Persist and apply a hex-encoded configuration blob
+    ConfigImport(&'a str),
+    /// Print the detection thresholds
+    Thresholds,
+    /// Persist and apply a detection threshold in millivolts
+    Threshold(Threshold, Millivolts),
+    /// Print the end-of-contact debounce profiles
+    RestoreProfiles,
+    /// Persist and apply an end-of-contact debounce profile
+    Restore(RestoreProfile),
+    /// Stop sampling and enter low-power standby, until woken by the acknowledge button
+    Standby,
+    /// Request a test-signal injection, run the next time the system is armed
+    Inject,
+    /// Ask to reset a latched alert, which must be confirmed on the next line
+    ResetLatch,
+    /// Confirm the command on the previous line
+    Confirm,
+    /// Mute the buzzer for the configured snooze time
+    Silence,
+    /// Print the cause of the last reset
+    ResetCause,
+    /// Reset the chip with the watchdog
+    Reboot,
+    /// Print the fault table
+    Faults,
+    /// Print the cycle counts measured with the `perf` feature
+    Perf,
+    /// Print the `DMA_IRQ_0` latency histogram
+    Latency,
+    /// Empty the `DMA_IRQ_0` latency histogram
+    LatencyClear,
+    /// Request a loopback scenario by name, or print the latest outcome
+    Hil(Option<&'a str>),
+    /// Compare a candidate detector preset by name, stop with `OFF`, or print the divergences
+    /// counted
+    Ab(Option<&'a str>),
+    /// Print the most recent divergences of the candidate detector as CSV
+    AbReport,
+    /// Scan the I2C bus
+    I2cScan,
+    /// Print the uptime statistics
+    Uptime,
+    /// Print the histogram of each probe
+    Histogram,
+    /// Print the near miss counts and the most recent ones
+    NearMiss,
+    /// Print the release [`sequence`](crate::sequence) of each interlock
+    Sequence,
+    /// Print the soak test summary
+    Soak,
+    /// Start a new soak test
+    SoakStart,
+    /// Stop the soak test in progress
+    SoakStop,
+    /// Print the logging level of each category
+    Log,
+    /// Set the logging level of a category, or `ALL`, by name
+    LogLevel(&'a str, &'a str),
+    /// Stream a live view of detection
+    Watch,
+    /// Stream the event journal in a format, from a sequence number on
+    Export(ExportFormat, u32),
+    /// Freeze and stream the most recent samples
+    Capture(usize),
+    /// Unlock configuration changes with the PIN
+    Unlock(ConfigPin),
+    /// Lock configuration changes
+    Lock,
+    /// Persist a new configuration PIN
+    Pin(ConfigPin),
+    /// Print the provisioning of the unit
+    Provisioning,
+    /// Persist a [`Field`] of the provisioning
+    Provision(Field<'a>),
+    /// Ask to simulate a fault for a [`Drill`], which must be confirmed on the next line
+    Drill(Drill),
+    /// Print the stored macros
+    Macros,
+    /// Persist the steps of a macro slot, or empty it with [`None`]
+    Macro(usize, Option<&'a str>),
+    /// Run the steps of a macro slot
+    Run(usize),
+    /// Print the clocks stamping the log and the journal
+    Time,
+}
+
+impl<'a> Command<'a> {
+    /// Parse a single line of input, refusing [privileged](Command::privileged) commands with
+    /// [`ConsoleError::Locked`] unless `access` allows them.
+    pub fn parse(line: &'a str, access: Access) -> Result<Self, ConsoleError> {
+        let command = Self::parse_any(line)?;
+        if command.privileged() && access == Access::ReadOnly {
+            return Err(ConsoleError::Locked);
+        }
+        Ok(command)
+    }
+
+    /// Parse a single line of input into any command
+    fn parse_any(line: &'a str) -> Result<Self, ConsoleError> {
+        // The steps of a macro are kept whole, spaces and all
+        let (first, rest) = split_word(line);
+        if first.eq_ignore_ascii_case("MACRO") {
+            return Self::parse_macro(rest);
+        }
+        let mut words = line.split_ascii_whitespace();
+        let (first, second, arg) = (words.next(), words.next(), words.next());
+        if words.next().is_some() {
+            return Err(ConsoleError::UnknownCommand);
+        }
+
+        if keyword(first, "HELP") && second.is_none() {
+            Ok(Command::Help)
+        } else if keyword(first, "*IDN?") && second.is_none() {
+            Ok(Command::Identify)
+        } else if keyword(first, "STANDBY") && second.is_none() {
+            Ok(Command::Standby)
+        } else if keyword(first, "INJECT") && second.is_none() {
+            Ok(Command::Inject)
+        } else if keyword(first, "CONFIRM") && second.is_none() {
+            Ok(Command::Confirm)
+        } else if keyword(first, "SILENCE") && second.is_none() {
+            Ok(Command::Silence)
+        } else if keyword(first, "RESET") && keyword(second, "LATCH") && arg.is_none() {
+            Ok(Command::ResetLatch)
+        } else if keyword(first, "RESET") && keyword(second, "CAUSE") && arg.is_none() {
+            Ok(Command::ResetCause)
+        } else if keyword(first, "REBOOT") && second.is_none() {
+            Ok(Command::Reboot)
+        } else if keyword(first, "FAULTS") && second.is_none() {
+            Ok(Command::Faults)
+        } else if keyword(first, "PERF") && second.is_none() {
+            Ok(Command::Perf)
+        } else if keyword(first, "LATENCY") && second.is_none() {
+            Ok(Command::Latency)
+        } else if keyword(first, "LATENCY") && keyword(second, "CLEAR") && arg.is_none() {
+            Ok(Command::LatencyClear)
+        } else if keyword(first, "HIL") && arg.is_none() {
+            Ok(Command::Hil(second))
+        } else if keyword(first, "AB") && keyword(second, "REPORT") && arg.is_none() {
+            Ok(Command::AbReport)
+        } else if keyword(first, "AB") && arg.is_none() {
+            Ok(Command::Ab(second))
+        } else if keyword(first, "I2C") && keyword(second, "SCAN") && arg.is_none() {
+            Ok(Command::I2cScan)
+        } else if keyword(first, "UPTIME") && second.is_none() {
+            Ok(Command::Uptime)
+        } else if keyword(first, "HISTOGRAM") && second.is_none() {
+            Ok(Command::Histogram)
+        } else if keyword(first, "NEAR") && keyword(second, "MISS") && arg.is_none() {
+            Ok(Command::NearMiss)
+        } else if keyword(first, "SEQUENCE") && second.is_none() {
+            Ok(Command::Sequence)
+        } else if keyword(first, "SOAK") && second.is_none() {
+            Ok(Command::Soak)
+        } else if keyword(first, "SOAK") && keyword(second, "START") && arg.is_none() {
+            Ok(Command::SoakStart)
+        } else if keyword(first, "SOAK") && keyword(second, "STOP") && arg.is_none() {
+            Ok(Command::SoakStop)
+        } else if keyword(first, "LOG") && second.is_none() {
+            Ok(Command::Log)
+        } else if keyword(first, "LOG") {
+            second
+                .zip(arg)
+                .map(|(category, level)| Command::LogLevel(category, level))
+                .ok_or(ConsoleError::MissingArgument)
+        } else if keyword(first, "WATCH") && second.is_none() {
+            Ok(Command::Watch)
+        } else if keyword(first, "EXPORT") {
+            let format = second
+                .ok_or(ConsoleError::MissingArgument)
+                .and_then(|name| {
+                    ExportFormat::from_name(name).ok_or(ConsoleError::UnknownExportFormat)
+                })?;
+            let from_seq = arg.map_or(Ok(0), |seq| {
+                seq.parse().map_err(|_| ConsoleError::InvalidSequence)
+            })?;
+            Ok(Command::Export(format, from_seq))
+        } else if keyword(first, "CAPTURE") && arg.is_none() {
+            second
+                .ok_or(ConsoleError::MissingArgument)?
+                .parse()
+                .ok()
+                .filter(|samples| (1..=CAPTURE_SAMPLES).contains(samples))
+                .map(Command::Capture)
+                .ok_or(ConsoleError::InvalidCaptureLength)
+        } else if keyword(first, "FACTORY") && keyword(second, "RESET") && arg.is_none() {
+            Ok(Command::FactoryReset)
+        } else if keyword(first, "CONFIG") && keyword(second, "EXPORT") && arg.is_none() {
+            Ok(Command::ConfigExport)
+        } else if keyword(first, "CONFIG") && keyword(second, "IMPORT") {
+            arg.map(Command::ConfigImport)
+                .ok_or(ConsoleError::MissingArgument)
+        } else if keyword(first, "THRESHOLD") && second.is_none() {
+            Ok(Command::Thresholds)
+        } else if keyword(first, "THRESHOLD") {
+            let value = arg.ok_or(ConsoleError::MissingArgument)?;
+            let threshold =
+                Threshold::parse(second.unwrap_or("")).ok_or(ConsoleError::UnknownThreshold)?;
+            value
+                .parse()
+                .ok()
+                .map(Millivolts)
+                .filter(|voltage| threshold.valid(*voltage))
+                .map(|voltage| Command::Threshold(threshold, voltage))
+                .ok_or(ConsoleError::InvalidThreshold)
+        } else if keyword(first, "RESTORE") && second.is_none() {
+            Ok(Command::RestoreProfiles)
+        } else if keyword(first, "RESTORE") && arg.is_none() {
+            second
+                .and_then(RestoreProfile::parse)
+                .map(Command::Restore)
+                .ok_or(ConsoleError::UnknownRestoreProfile)
+        } else if keyword(first, "UNLOCK") && arg.is_none() {
+            pin_argument(second).map(Command::Unlock)
+        } else if keyword(first, "LOCK") && second.is_none() {
+            Ok(Command::Lock)
+        } else if keyword(first, "PIN") && arg.is_none() {
+            pin_argument(second).map(Command::Pin)
+        } else if keyword(first, "PROVISION") && second.is_none() {
+            Ok(Command::Provisioning)
+        } else if keyword(first, "PROVISION") {
+            let value = arg.ok_or(ConsoleError::MissingArgument)?;
+            let field = Field::parse(second.unwrap_or(""), value)?;
+            Ok(Command::Provision(field))
+        } else if keyword(first, "DRILL") && arg.is_none() {
+            let name = second.ok_or(ConsoleError::MissingArgument)?;
+            Drill::parse(name)
+                .map(Command::Drill)
+                .ok_or(ConsoleError::UnknownDrill)
+        } else if keyword(first, "RUN") && arg.is_none() {
+            macro_slot(second).map(Command::Run)
+        } else if keyword(first, "TIME") && second.is_none() {
+            Ok(Command::Time)
+        } else {
+            Err(ConsoleError::UnknownCommand)
+        }
+    }
+
+    /// Parse the arguments of `MACRO`
+    fn parse_macro(args: &'a str) -> Result<Self, ConsoleError> {
+        let (slot, steps) = split_word(args);
+        if slot.is_empty() {
+            return Ok(Command::Macros);
+        }
+        let slot = macro_slot(Some(slot))?;
+        let steps = steps.trim_end();
+        if steps.is_empty() {
+            Err(ConsoleError::MissingArgument)
+        } else if steps.eq_ignore_ascii_case("CLEAR") {
+            Ok(Command::Macro(slot, None))
+        } else if valid_macro(steps) {
+            Ok(Command::Macro(slot, Some(steps)))
+        } else {
+            Err(ConsoleError::InvalidMacro)
+        }
+    }
+
+    /// Whether the command changes the configuration or the state of the system, so it needs
+    /// [`Access::Privileged`]. Queries, and locking or unlocking the console, are not privileged.
+    pub fn privileged(&self) -> bool {
+        match self {
+            Command::FactoryReset
+            | Command::ConfigImport(_)
+            | Command::Threshold(..)
+            | Command::Restore(_)
+            | Command::Standby
+            | Command::Inject
+            | Command::ResetLatch
+            | Command::Confirm
+            | Command::Silence
+            | Command::Reboot
+            | Command::LatencyClear
+            | Command::SoakStart
+            | Command::SoakStop
+            | Command::Hil(Some(_))
+            | Command::Ab(Some(_))
+            | Command::LogLevel(..)
+            | Command::Pin(_)
+            | Command::Provision(_)
+            | Command::Drill(_)
+            | Command::Macro(..) => true,
+            Command::Help
+            | Command::Identify
+            | Command::ConfigExport
+            | Command::Thresholds
+            | Command::RestoreProfiles
+            | Command::ResetCause
+            | Command::Faults
+            | Command::Perf
+            | Command::Latency
+            | Command::Hil(None)
+            | Command::Ab(None)
+            | Command::AbReport
+            | Command::I2cScan
+            | Command::Uptime
+            | Command::Histogram
+            | Command::NearMiss
+            | Command::Sequence
+            | Command::Soak
+            | Command::Log
+            | Command::Watch
+            | Command::Export(..)
+            | Command::Capture(_)
+            | Command::Unlock(_)
+            | Command::Lock
+            | Command::Provisioning
+            | Command::Macros
+            | Command::Run(_)
+            | Command::Time => false,
+        }
+    }
+
+    /// Whether the command persists anything to flash. The firmware refuses it with
+    /// [`ConsoleError::Sampling`] while sampling.
+    pub fn writes_flash(&self) -> bool {
+        matches!(
+            self,
+            Command::FactoryReset
+                | Command::ConfigImport(_)
+                | Command::Threshold(..)
+                | Command::Restore(_)
+                | Command::Pin(_)
+                | Command::Provision(_)
+                | Command::Macro(..)
+        )
+    }
+}
+
+/// Case-insensitive match of a command word
+fn keyword(word: Option<&str>, expected: &str) -> bool {
+    word.is_some_and(|word| word.eq_ignore_ascii_case(expected))
+}
+
+/// Split the first word off `line`, returning it and the rest of the line after any whitespace
+fn split_word(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    let end = line
+        .find(|c: char| c.is_ascii_whitespace())
+        .unwrap_or(line.len());
+    (&line[..end], line[end..].trim_start())
+}
+
+/// Parse the slot argument of `MACRO` or `RUN`
+fn macro_slot(word: Option<&str>) -> Result<usize, ConsoleError> {
+    word.ok_or(ConsoleError::MissingArgument)?
+        .parse()
+        .ok()
+        .filter(|slot| *slot < MACROS)
+        .ok_or(ConsoleError::InvalidMacro)
+}
+
+/// Parse the PIN argument of `UNLOCK` or `PIN`
+fn pin_argument(word: Option<&str>) -> Result<ConfigPin, ConsoleError> {
+    ConfigPin::parse(word.ok_or(ConsoleError::MissingArgument)?).ok_or(ConsoleError::InvalidPin)
+}
+
+/// Whether the steps of a macro fit in a slot, and only hold printable ASCII
+pub fn valid_macro(steps: &str) -> bool {
+    steps.len() <= MACRO_LEN
+        && steps
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() || byte == b' ')
+}
+
+/// One of the detection thresholds, as named by the `THRESHOLD` console command
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Threshold {
+    /// Change that raises contact
+    Trigger,
+    /// Smaller change that raises a warning
+    Warning,
+    /// Change that confirms contact
+    Confirm,
+    /// Change below which contact has ended
+    Restore,
+}
+
+impl Threshold {
+    /// Every threshold, in the order they are listed
+    pub const ALL: [Self; 4] = [
+        Threshold::Trigger,
+        Threshold::Warning,
+        Threshold::Confirm,
+        Threshold::Restore,
+    ];
+
+    /// Threshold called `name`, ignoring case
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|threshold| name.eq_ignore_ascii_case(threshold.name()))
+    }
+
+    /// Name used by the `THRESHOLD` console command
+    pub const fn name(self) -> &'static str {
+        match self {
+            Threshold::Trigger => "TRIGGER",
+            Threshold::Warning => "WARNING",
+            Threshold::Confirm => "CONFIRM",
+            Threshold::Restore => "RESTORE",
+        }
+    }
+
+    /// Range of millivolts accepted. Only warnings can be disabled with 0.
+    pub fn valid(self, voltage: Millivolts) -> bool {
+        let min = if self == Threshold::Warning { 0 } else { 1 };
+        (min..=ADC_REFERENCE_MV as i32).contains(&voltage.0)
+    }
+}
+
+/// Debounce of the end of contact, as named by the `RESTORE` console command
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RestoreProfile {
+    /// Few samples restored in a row, for probes that lift cleanly
+    Fast,
+    /// More samples restored in a row, for probes that bounce on release
+    Slow,
+}
+
+impl RestoreProfile {
+    /// Profile called `name`, ignoring case
+    pub fn parse(name: &str) -> Option<Self> {
+        [RestoreProfile::Fast, RestoreProfile::Slow]
+            .into_iter()
+            .find(|profile| name.eq_ignore_ascii_case(profile.name()))
+    }
+
+    /// Name used by the `RESTORE` console command
+    pub const fn name(self) -> &'static str {
+        match self {
+            RestoreProfile::Fast => "FAST",
+            RestoreProfile::Slow => "SLOW",
+        }
+    }
+}
+
+/// Text format of an export of the event journal
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ExportFormat {
+    /// Comma-separated values, with a header row
+    Csv,
+    /// A JSON object, holding an array of events
+    Json,
+}
+
+impl ExportFormat {
+    /// Format called `name`, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("CSV") {
+            Some(ExportFormat::Csv)
+        } else if name.eq_ignore_ascii_case("JSON") {
+            Some(ExportFormat::Json)
+        } else {
+            None
+        }
+    }
+}
+
+/// PIN unlocking privileged console commands, formatted as `****` so it is never logged
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct ConfigPin {
+    /// Number of digits, so leading zeros count
+    digits: u8,
+    /// Digits as a decimal number
+    value: u32,
+}
+
+impl ConfigPin {
+    /// Parse a PIN of [`MIN_DIGITS`] to [`MAX_DIGITS`] decimal digits, or [`None`] if `text` is
+    /// anything else
+    pub fn parse(text: &str) -> Option<Self> {
+        if !(MIN_DIGITS..=MAX_DIGITS).contains(&text.len())
+            || !text.bytes().all(|byte| byte.is_ascii_digit())
+        {
+            return None;
+        }
+        Some(Self {
+            digits: text.len() as u8,
+            value: text.parse().ok()?,
+        })
+    }
+
+    /// PIN of `digits` digits with the decimal `value`, as returned by [`ConfigPin::digits`] and
+    /// [`ConfigPin::value`], or [`None`] if that isn't a valid PIN
+    pub fn from_parts(digits: u8, value: u32) -> Option<Self> {
+        ((MIN_DIGITS..=MAX_DIGITS).contains(&(digits as usize))
+            && (value as u64) < 10u64.pow(digits as u32))
+        .then_some(Self { digits, value })
+    }
+
+    /// Number of digits, so leading zeros count
+    pub fn digits(&self) -> u8 {
+        self.digits
+    }
+
+    /// Digits as a decimal number
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for ConfigPin {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "****");
+    }
+}
+
+/// One value of the provisioning of a unit, as set by `PROVISION <field> <value>`
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Field<'a> {
+    /// `SERIAL`: up to [`SERIAL_LEN`] letters, digits, `-` or `.`
+    Serial(&'a str),
+    /// `REV`: up to [`REVISION_LEN`] letters, digits, `-` or `.`
+    Revision(&'a str),
+    /// `OFFSET`: ADC offset correction in codes, up to [`MAX_ADC_OFFSET`] either way
+    AdcOffset(i16),
+    /// `GAIN`: ADC gain correction in parts per million, from [`MIN_ADC_GAIN_PPM`] to
+    /// [`MAX_ADC_GAIN_PPM`]
+    AdcGain(u32),
+    /// `REF`: ADC reference in millivolts, from [`MIN_ADC_REFERENCE_MV`] to
+    /// [`MAX_ADC_REFERENCE_MV`]
+    AdcReference(u16),
+}
+
+impl<'a> Field<'a> {
+    /// Parse the field called `name`, ignoring case, set to `value`
+    pub fn parse(name: &str, value: &'a str) -> Result<Self, ProvisionError> {
+        if name.eq_ignore_ascii_case("SERIAL") {
+            valid_text(value, SERIAL_LEN)
+                .then_some(Field::Serial(value))
+                .ok_or(ProvisionError::InvalidValue)
+        } else if name.eq_ignore_ascii_case("REV") {
+            valid_text(value, REVISION_LEN)
+                .then_some(Field::Revision(value))
+                .ok_or(ProvisionError::InvalidValue)
+        } else if name.eq_ignore_ascii_case("OFFSET") {
+            value
+                .parse()
+                .ok()
+                .filter(|offset| (-MAX_ADC_OFFSET..=MAX_ADC_OFFSET).contains(offset))
+                .map(Field::AdcOffset)
+                .ok_or(ProvisionError::InvalidValue)
+        } else if name.eq_ignore_ascii_case("GAIN") {
+            value
+                .parse()
+                .ok()
+                .filter(|gain| (MIN_ADC_GAIN_PPM..=MAX_ADC_GAIN_PPM).contains(gain))
+                .map(Field::AdcGain)
+                .ok_or(ProvisionError::InvalidValue)
+        } else if name.eq_ignore_ascii_case("REF") {
+            value
+                .parse()
+                .ok()
+                .filter(|reference| {
+                    (MIN_ADC_REFERENCE_MV..=MAX_ADC_REFERENCE_MV).contains(reference)
+                })
+                .map(Field::AdcReference)
+                .ok_or(ProvisionError::InvalidValue)
+        } else {
+            Err(ProvisionError::UnknownField)
+        }
+    }
+}
+
+/// Whether `text` fits in `len` characters, and only holds letters, digits, `-` and `.`
+fn valid_text(text: &str, len: usize) -> bool {
+    text.len() <= len
+        && text
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'.')
+}
+
+/// Fault simulated by a safety drill, stored as the value of its journal record
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Drill {
+    /// `CONTACT`: contact on a probe
+    Contact = 1,
+    /// `DISCONNECT`: a disconnected probe
+    Disconnect = 2,
+    /// `WATCHDOG`: a watchdog timeout
+    Watchdog = 3,
+}
+
+impl Drill {
+    /// Drill called `name`, ignoring case
+    pub fn parse(name: &str) -> Option<Self> {
+        [Drill::Contact, Drill::Disconnect, Drill::Watchdog]
+            .into_iter()
+            .find(|drill| name.eq_ignore_ascii_case(drill.name()))
+    }
+
+    /// Name used by the `DRILL` console command
+    pub const fn name(self) -> &'static str {
+        match self {
+            Drill::Contact => "CONTACT",
+            Drill::Disconnect => "DISCONNECT",
+            Drill::Watchdog => "WATCHDOG",
+        }
+    }
+
+    /// Whether the drill can run in `current`
+    pub fn available(self, current: SystemState) -> bool {
+        match self {
+            Drill::Contact => current.armed(),
+            Drill::Disconnect => current.sampling(),
+            Drill::Watchdog => true,
+        }
+    }
+}
+
+/// Errors raised while accessing persistent storage
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StorageError {
+    /// Offset does not fall on a sector boundary inside the storage region, or when programming
+    /// pages, data is not whole pages starting on a page boundary
+    Misaligned,
+    /// Data does not fit in a single sector, or when programming pages, in the storage region
+    TooLarge,
+    /// The flash must be erased before it can be programmed
+    NotErased,
+}
+
+/// Errors raised while loading, storing or transferring the configuration
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigError {
+    /// No configuration has been stored in flash
+    NotFound,
+    /// The configuration in flash was stored with another version of its layout, by older or
+    /// newer firmware, and can't be migrated
+    Outdated,
+    /// The blob failed its CRC check
+    Corrupted,
+    /// The blob could not be encoded or decoded
+    Serialization,
+    /// A setting is out of range
+    Invalid,
+    /// Flash could not be written
+    Storage(StorageError),
+}
+
+/// Reasons an unlock was refused
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LockError {
+    /// No PIN has been set, so only the jumper unlocks
+    NoPin,
+    /// The PIN entered doesn't match
+    WrongPin,
+    /// Too many wrong PINs were entered in a row, so the jumper must be fitted to unlock
+    LockedOut,
+}
+
+/// Reasons a [`Field`] couldn't be provisioned
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProvisionError {
+    /// The field name doesn't exist
+    UnknownField,
+    /// The value is too long, has invalid characters, or is out of range
+    InvalidValue,
+    /// The configuration jumper isn't fitted
+    JumperRequired,
+    /// Flash could not be written
+    Storage(StorageError),
+}
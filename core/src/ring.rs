@@ -0,0 +1,89 @@
+//! Fixed-size ring buffer with constant-time index math.
+//!
+//! [`RingBuffer`] wraps its indices with a compare and a conditional subtract instead of `%`. The
+//! RP2040 has no divide instruction, so a remainder by a length such as the long-term buffer's is
+//! a call to the runtime's division routine, taking a different time for each index. The compare
+//! takes the same few cycles for any length.
+//!
+//! The RP2040's SIO interpolator was considered for the wrap, but its lanes only shift, mask and
+//! add: a mask only wraps lengths that are a power of two, and `buffers.longterm_size` is a
+//! multiple of 250. It would also tie this crate to the hardware.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Ring of the `N` most recent items, with the latest at [`RingBuffer::head`]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct RingBuffer<T, const N: usize> {
+    /// Items, the oldest overwritten by each [`RingBuffer::push`]
+    items: [T; N],
+    /// Index of the latest item
+    head: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    /// Create a ring with every item set to `fill`
+    pub const fn new(fill: T) -> Self {
+        Self {
+            items: [fill; N],
+            head: 0,
+        }
+    }
+
+    /// Index of the latest item in [`RingBuffer::items`]
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+    /// Every item, with the latest at [`RingBuffer::head`]
+    pub fn items(&self) -> &[T; N] {
+        &self.items
+    }
+
+    /// Index of the item pushed `back` items before the latest. `back` must be at most `N`, which
+    /// wraps around to the latest again.
+    #[inline]
+    pub fn back(&self, back: usize) -> usize {
+        debug_assert!(back <= N, "ring buffer index {back} is past its length {N}");
+        let back = back.min(N);
+        let index = self.head + N - back;
+        if index >= N {
+            index - N
+        } else {
+            index
+        }
+    }
+
+    /// The item pushed `back` items before the latest, see [`RingBuffer::back`]
+    #[inline]
+    pub fn get(&self, back: usize) -> T {
+        self.items[self.back(back)]
+    }
+
+    /// The latest item
+    #[inline]
+    pub fn latest(&self) -> T {
+        self.items[self.head]
+    }
+
+    /// Push `item` as the latest, overwriting the oldest
+    #[inline]
+    pub fn push(&mut self, item: T) {
+        self.head += 1;
+        if self.head == N {
+            self.head = 0;
+        }
+        self.items[self.head] = item;
+    }
+}
@@ -0,0 +1,163 @@
+//! Release sequencing of an interlock, so the tool it gates is never re-enabled instantly.
+//!
+//! Tripping always takes effect at once. Releasing goes through up to two timed stages, with the
+//! interlock still tripped:
+//!
+//! 1. [`Stage::CoolingDown`], for [`Timing::cooldown_ms`], only if the interlock was tripped by an
+//!    alert. Another trip during the cool-down doesn't cancel it, so it restarts on the next
+//!    release.
+//! 2. [`Stage::Enabling`], for [`Timing::enable_delay_ms`], on every release.
+//!
+//! Asking for a trip during either stage returns straight to [`Stage::Tripped`]. With both times
+//! 0, the interlock is released as soon as it is asked to be.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Lengths of the timed stages of a release
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Timing {
+    /// Milliseconds the interlock stays tripped before every release
+    pub enable_delay_ms: u32,
+    /// Milliseconds the interlock stays tripped after an alert, before the enable delay
+    pub cooldown_ms: u32,
+}
+
+/// Stage of an interlock's release
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Stage {
+    /// Tripped, and asked to stay tripped
+    Tripped,
+    /// Tripped after an alert until `until_ms`, then [`Stage::Enabling`]
+    CoolingDown {
+        /// Time the cool-down ends
+        until_ms: u32,
+    },
+    /// Tripped until `until_ms`, then released
+    Enabling {
+        /// Time the interlock is released
+        until_ms: u32,
+    },
+    /// Released
+    Enabled,
+}
+
+impl Stage {
+    /// Name shown on the console
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Tripped => "TRIPPED",
+            Stage::CoolingDown { .. } => "COOLING",
+            Stage::Enabling { .. } => "ENABLING",
+            Stage::Enabled => "ENABLED",
+        }
+    }
+
+    /// Milliseconds left in a timed stage at `now_ms`, or 0 in the others
+    pub fn remaining_ms(self, now_ms: u32) -> u32 {
+        match self {
+            Stage::CoolingDown { until_ms } | Stage::Enabling { until_ms } => {
+                (until_ms.wrapping_sub(now_ms) as i32).max(0) as u32
+            }
+            Stage::Tripped | Stage::Enabled => 0,
+        }
+    }
+
+    /// Whether the interlock is tripped in this stage
+    pub fn tripped(self) -> bool {
+        self != Stage::Enabled
+    }
+}
+
+/// Release sequence of one interlock
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sequencer {
+    /// Current stage
+    stage: Stage,
+    /// Whether the interlock was tripped by an alert whose cool-down hasn't completed
+    alerted: bool,
+}
+
+impl Sequencer {
+    /// Start tripped, without an alert to cool down from
+    pub const fn new() -> Self {
+        Self {
+            stage: Stage::Tripped,
+            alerted: false,
+        }
+    }
+
+    /// Current stage
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    /// Record an alert, so the next release cools down first
+    pub fn alert(&mut self) {
+        self.alerted = true;
+    }
+
+    /// Whether a timed stage is running, and [`Sequencer::update`] must be polled to finish it
+    pub fn pending(&self) -> bool {
+        matches!(
+            self.stage,
+            Stage::CoolingDown { .. } | Stage::Enabling { .. }
+        )
+    }
+
+    /// Move the sequence on at `now_ms`, with the interlock asked to be `tripped`, using `timing`
+    /// for any stage started. Returns whether the interlock is tripped.
+    pub fn update(&mut self, tripped: bool, now_ms: u32, timing: Timing) -> bool {
+        if tripped {
+            self.stage = Stage::Tripped;
+            return true;
+        }
+        let expired = |until_ms: u32| now_ms.wrapping_sub(until_ms) as i32 >= 0;
+        if self.stage == Stage::Tripped && self.alerted {
+            self.stage = Stage::CoolingDown {
+                until_ms: now_ms.wrapping_add(timing.cooldown_ms),
+            };
+        }
+        // The enable delay runs from the end of the cool-down, however late it is polled
+        let mut enable_from_ms = now_ms;
+        if let Stage::CoolingDown { until_ms } = self.stage {
+            if !expired(until_ms) {
+                return true;
+            }
+            self.alerted = false;
+            self.stage = Stage::Tripped;
+            enable_from_ms = until_ms;
+        }
+        if self.stage == Stage::Tripped {
+            self.stage = Stage::Enabling {
+                until_ms: enable_from_ms.wrapping_add(timing.enable_delay_ms),
+            };
+        }
+        if let Stage::Enabling { until_ms } = self.stage {
+            if expired(until_ms) {
+                self.stage = Stage::Enabled;
+            }
+        }
+        self.stage.tripped()
+    }
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,111 @@
+//! Statistics accumulated over a long soak test, for justifying the margin between the noise of
+//! the front-end and the detection thresholds.
+//!
+//! [`SoakStats`] is fed the same samples and deltas the [`Detector`] checks while armed, without
+//! changing detection. It keeps the distribution of the deltas between consecutive samples, the
+//! largest delta, the [near misses](Detector::near_miss) and the mean and standard deviation of the
+//! samples, so hours of data fit in a few hundred bytes.
+//!
+//! [`Detector`]: crate::detection_core::Detector
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Bins of the delta distribution. Bin `n` counts deltas of `n` codes, and the last bin counts
+/// every delta of at least `DELTA_BINS - 1`.
+pub const DELTA_BINS: usize = 16;
+
+/// Statistics of one probe over a soak test
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SoakStats {
+    /// Samples recorded
+    samples: u64,
+    /// Sum of the samples, for their mean
+    sum: u64,
+    /// Sum of the squares of the samples, for their standard deviation
+    sum_squares: u64,
+    /// Distribution of the deltas, see [`DELTA_BINS`]
+    deltas: [u32; DELTA_BINS],
+    /// Largest delta
+    max_delta: i16,
+    /// Deltas that were [near misses](crate::detection_core::Detector::near_miss)
+    near_misses: u32,
+}
+
+impl SoakStats {
+    /// No samples recorded
+    pub const fn new() -> Self {
+        Self {
+            samples: 0,
+            sum: 0,
+            sum_squares: 0,
+            deltas: [0; DELTA_BINS],
+            max_delta: 0,
+            near_misses: 0,
+        }
+    }
+
+    /// Record `sample`, which changed by `delta` from the one before, and whether that was a
+    /// `near_miss`
+    pub fn record(&mut self, sample: u8, delta: i16, near_miss: bool) {
+        self.samples += 1;
+        self.sum += sample as u64;
+        self.sum_squares += sample as u64 * sample as u64;
+        let bin = (delta.max(0) as usize).min(DELTA_BINS - 1);
+        self.deltas[bin] = self.deltas[bin].saturating_add(1);
+        self.max_delta = self.max_delta.max(delta);
+        if near_miss {
+            self.near_misses = self.near_misses.saturating_add(1);
+        }
+    }
+
+    /// Samples recorded
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Distribution of the deltas, see [`DELTA_BINS`]
+    pub fn deltas(&self) -> &[u32; DELTA_BINS] {
+        &self.deltas
+    }
+
+    /// Largest delta recorded
+    pub fn max_delta(&self) -> i16 {
+        self.max_delta
+    }
+
+    /// Deltas that were near misses
+    pub fn near_misses(&self) -> u32 {
+        self.near_misses
+    }
+
+    /// Mean of the samples, in hundredths of a code
+    pub fn mean_centi(&self) -> u32 {
+        if self.samples == 0 {
+            return 0;
+        }
+        (self.sum * 100 / self.samples) as u32
+    }
+
+    /// Standard deviation of the samples, the noise of the front-end, in hundredths of a code
+    pub fn std_dev_centi(&self) -> u32 {
+        if self.samples == 0 {
+            return 0;
+        }
+        let (samples, sum) = (self.samples as u128, self.sum as u128);
+        let spread = samples * self.sum_squares as u128 - sum * sum;
+        (spread * 10_000 / (samples * samples)).isqrt() as u32
+    }
+}
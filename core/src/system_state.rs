@@ -0,0 +1,139 @@
+//! Overall state of the system, and the transitions allowed between states.
+//!
+//! The firmware's state machine moves between these states, and drives the status LEDs, interlock
+//! outputs, buzzer and sampler from them. The rules are kept here, free of any hardware, so the
+//! host tests and tools can check them.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::indicator::StatusLedStates;
+
+/// Overall system state
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SystemState {
+    /// Initializing peripherals
+    Booting,
+    /// Sampling, but waiting for [`SETTLE_SAMPLES`](crate::detection_core::SETTLE_SAMPLES) and
+    /// then the configured arming delay before detection is armed
+    Calibrating,
+    /// Checking for contact
+    Armed,
+    /// Signal change above the warning threshold, but below the contact threshold, on at least one
+    /// probe. Still checking for contact, with the interlock released. Clears once no probe has
+    /// warned for the configured alert hold.
+    Warning,
+    /// Contact detected, clears when contact ends
+    Alert,
+    /// Contact ended while alerts are configured to latch, held until reset by the operator
+    Latched,
+    /// Unrecoverable error, sampling stopped until power cycled
+    Error,
+    /// Disabled by the operator, sampling stopped
+    Disabled,
+    /// Low-power standby, entered by the operator. Sampling is stopped and the LEDs are off until
+    /// the chip wakes from dormant.
+    Standby,
+}
+
+impl SystemState {
+    /// Every state, in declaration order, so each is at the index of its discriminant
+    pub const ALL: [SystemState; 9] = [
+        SystemState::Booting,
+        SystemState::Calibrating,
+        SystemState::Armed,
+        SystemState::Warning,
+        SystemState::Alert,
+        SystemState::Latched,
+        SystemState::Error,
+        SystemState::Disabled,
+        SystemState::Standby,
+    ];
+
+    /// Whether the state machine may move from `self` to `to`.
+    ///
+    /// [`SystemState::Error`] and [`SystemState::Disabled`] can be entered from any other state,
+    /// but nothing leaves [`SystemState::Error`].
+    pub fn can_transition(self, to: SystemState) -> bool {
+        match (self, to) {
+            (SystemState::Error, _) => false,
+            (_, SystemState::Error | SystemState::Disabled) => true,
+            (SystemState::Booting, SystemState::Calibrating)
+            | (SystemState::Calibrating, SystemState::Armed)
+            | (SystemState::Armed, SystemState::Warning | SystemState::Alert)
+            | (SystemState::Warning, SystemState::Armed | SystemState::Alert)
+            | (SystemState::Alert, SystemState::Armed | SystemState::Latched)
+            | (SystemState::Latched, SystemState::Armed)
+            | (
+                SystemState::Calibrating
+                | SystemState::Armed
+                | SystemState::Warning
+                | SystemState::Disabled,
+                SystemState::Standby,
+            )
+            | (SystemState::Standby, SystemState::Calibrating)
+            | (SystemState::Disabled, SystemState::Armed) => true,
+            _ => false,
+        }
+    }
+
+    /// LED pattern displayed in this state
+    pub const fn led(self) -> StatusLedStates {
+        match self {
+            SystemState::Booting | SystemState::Calibrating => StatusLedStates::Alert,
+            SystemState::Armed => StatusLedStates::Normal,
+            SystemState::Warning | SystemState::Alert | SystemState::Latched => {
+                StatusLedStates::Alert
+            }
+            SystemState::Error => StatusLedStates::Error,
+            SystemState::Disabled | SystemState::Standby => StatusLedStates::Disabled,
+        }
+    }
+
+    /// Whether the interlock stops the saw. Only released while armed, including after a
+    /// warning, or when detection has been disabled by the operator.
+    pub const fn interlock_tripped(self) -> bool {
+        !matches!(
+            self,
+            SystemState::Armed | SystemState::Warning | SystemState::Disabled
+        )
+    }
+
+    /// Whether detection is armed, with or without a warning raised
+    pub const fn armed(self) -> bool {
+        matches!(self, SystemState::Armed | SystemState::Warning)
+    }
+
+    /// Whether the buzzer sounds continuously. In [`SystemState::Warning`] it only beeps.
+    pub const fn buzzer_on(self) -> bool {
+        matches!(self, SystemState::Alert | SystemState::Latched)
+    }
+
+    /// Whether the buzzer sounds at all, continuously or in beeps, so it can be snoozed
+    pub const fn audible(self) -> bool {
+        matches!(
+            self,
+            SystemState::Warning | SystemState::Alert | SystemState::Latched
+        )
+    }
+
+    /// Whether the sampler runs
+    pub fn sampling(self) -> bool {
+        !matches!(
+            self,
+            SystemState::Error | SystemState::Disabled | SystemState::Standby
+        )
+    }
+}
@@ -0,0 +1,111 @@
+//! Physical units for thresholds and durations, so they can be given in millivolts and
+//! milliseconds rather than raw ADC codes and sample counts.
+//!
+//! An averaged sample is an 8-bit code, with [`ADC_FULL_SCALE_CODES`] spanning the
+//! [`ADC_REFERENCE_MV`] of the ADC. [`AdcScale`] converts between the two, and [`Millis`] converts
+//! durations to and from a number of samples at the sample rate. Conversions round to the nearest
+//! unit and saturate, so they never overflow.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Reference voltage of the RP2040 ADC, the full scale of a sample
+pub const ADC_REFERENCE_MV: u32 = 3_300;
+/// Codes spanning [`ADC_REFERENCE_MV`] in an averaged sample
+pub const ADC_FULL_SCALE_CODES: u32 = 256;
+
+/// A voltage, or a difference between two, in millivolts
+#[derive(Copy, Clone, Default, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Millivolts(pub i32);
+
+/// A duration in milliseconds
+#[derive(Copy, Clone, Default, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Millis(pub u32);
+
+impl Millis {
+    /// Duration of `samples` taken at `sample_rate_hz`
+    pub const fn from_samples(samples: u64, sample_rate_hz: u32) -> Self {
+        let rate = sample_rate_hz as u64;
+        let ms = samples.saturating_mul(1000).saturating_add(rate / 2) / rate;
+        Self(if ms > u32::MAX as u64 {
+            u32::MAX
+        } else {
+            ms as u32
+        })
+    }
+
+    /// Number of samples taken at `sample_rate_hz` over this duration
+    pub const fn to_samples(self, sample_rate_hz: u32) -> u64 {
+        div_round(self.0 as i64 * sample_rate_hz as i64, 1000) as u64
+    }
+}
+
+/// Conversion between the codes of an averaged sample and the voltage at the ADC
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AdcScale {
+    /// Voltage spanned by `full_scale_codes`, in millivolts
+    pub reference_mv: u32,
+    /// Codes spanning `reference_mv`
+    pub full_scale_codes: u32,
+}
+
+impl AdcScale {
+    /// Scale of an uncorrected RP2040 ADC
+    pub const NOMINAL: Self = Self {
+        reference_mv: ADC_REFERENCE_MV,
+        full_scale_codes: ADC_FULL_SCALE_CODES,
+    };
+
+    /// Voltage represented by a difference of `codes`
+    pub const fn millivolts(&self, codes: i16) -> Millivolts {
+        Millivolts(div_round(
+            codes as i64 * self.reference_mv as i64,
+            self.full_scale_codes as i64,
+        ) as i32)
+    }
+
+    /// Difference in codes closest to `voltage`, saturating at the range of an [`i16`]
+    pub const fn codes(&self, voltage: Millivolts) -> i16 {
+        let codes = div_round(
+            voltage.0 as i64 * self.full_scale_codes as i64,
+            self.reference_mv as i64,
+        );
+        if codes > i16::MAX as i64 {
+            i16::MAX
+        } else if codes < i16::MIN as i64 {
+            i16::MIN
+        } else {
+            codes as i16
+        }
+    }
+}
+
+impl Default for AdcScale {
+    fn default() -> Self {
+        Self::NOMINAL
+    }
+}
+
+/// `numerator / denominator`, rounded to the nearest integer with halves away from zero.
+/// `denominator` must be positive.
+const fn div_round(numerator: i64, denominator: i64) -> i64 {
+    if numerator >= 0 {
+        (numerator + denominator / 2) / denominator
+    } else {
+        (numerator - denominator / 2) / denominator
+    }
+}
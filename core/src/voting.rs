@@ -0,0 +1,179 @@
+//! Two-out-of-three voting between three channels sampling the same probe.
+//!
+//! With `triple_channel`, the probe is wired to three ADC inputs through independent dividers.
+//! Each channel has its own small [`Detector`], which runs the contact state machine on that
+//! channel alone. The [`Voter`] sits above them, and only reports contact once two of the three
+//! channels are in [`Phase::Alert`], so a single faulty channel can neither raise a false alert nor
+//! hide a real contact.
+//!
+//! A channel whose readings show it has failed is [faulted](Voter::fault), and no longer counts.
+//! Voting then degrades to [one out of two](Mode::OneOutOfTwo) on the remaining channels, which
+//! errs on the side of tripping. With fewer than two healthy channels, voting has
+//! [failed](Mode::Failed), and the probe is treated as being in contact.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::detection_core::{DetectionEvent, Detector, Event, Phase, Thresholds};
+
+/// Channels voted on
+pub const CHANNELS: usize = 3;
+/// Samples kept by the detector of each channel. Detection only looks back two samples, and the
+/// long-term buffer of the probe is kept by its own detector.
+pub const CHANNEL_HISTORY: usize = 8;
+
+/// How the channels are combined, set by how many of them are healthy
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    /// All three channels are healthy, and two must agree
+    TwoOutOfThree,
+    /// One channel has faulted, and either of the other two is enough
+    OneOutOfTwo,
+    /// Fewer than two channels are healthy, so the vote can't be trusted
+    Failed,
+}
+
+impl Mode {
+    /// Name shown on the console
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::TwoOutOfThree => "2OO3",
+            Mode::OneOutOfTwo => "1OO2",
+            Mode::Failed => "FAILED",
+        }
+    }
+}
+
+/// Detectors of the three channels, and the vote between them
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Voter {
+    /// Detector of each channel
+    detectors: [Detector<CHANNEL_HISTORY>; CHANNELS],
+    /// Phase of each channel's own state machine
+    phases: [Phase; CHANNELS],
+    /// Channels that have faulted, and no longer count
+    faulted: [bool; CHANNELS],
+    /// Detection event of the channel that completed the most recent contact vote
+    last_detection: Option<DetectionEvent>,
+}
+
+impl Voter {
+    /// Create a voter with every channel healthy and calibrating, using `thresholds`
+    pub const fn new(thresholds: Thresholds) -> Self {
+        Self {
+            detectors: [Detector::new(thresholds); CHANNELS],
+            phases: [Phase::Calibrating; CHANNELS],
+            faulted: [false; CHANNELS],
+            last_detection: None,
+        }
+    }
+
+    /// Replace the thresholds of every channel
+    pub fn set_thresholds(&mut self, thresholds: Thresholds) {
+        for detector in self.detectors.iter_mut() {
+            detector.set_thresholds(thresholds);
+        }
+    }
+
+    /// Stop counting `channel`. Returns whether it was healthy until now. Faults last until the
+    /// voter is recreated.
+    pub fn fault(&mut self, channel: usize) -> bool {
+        !core::mem::replace(&mut self.faulted[channel], true)
+    }
+
+    /// Channels that have faulted
+    pub fn faulted(&self) -> [bool; CHANNELS] {
+        self.faulted
+    }
+
+    /// First healthy channel, whose samples stand in for the probe's, if any
+    pub fn reference(&self) -> Option<usize> {
+        self.faulted.iter().position(|&faulted| !faulted)
+    }
+
+    /// Phase of each channel's own state machine
+    pub fn phases(&self) -> [Phase; CHANNELS] {
+        self.phases
+    }
+
+    /// How the channels are currently combined
+    pub fn mode(&self) -> Mode {
+        match self.faulted.iter().filter(|&&faulted| !faulted).count() {
+            CHANNELS => Mode::TwoOutOfThree,
+            2 => Mode::OneOutOfTwo,
+            _ => Mode::Failed,
+        }
+    }
+
+    /// Record the next averaged sample of each channel, and step the state machine of each
+    /// healthy channel on it. Faulted channels are left as they were.
+    pub fn insert(&mut self, samples: [u8; CHANNELS]) {
+        let channels = self.detectors.iter_mut().zip(&mut self.phases);
+        for (((detector, phase), sample), faulted) in channels.zip(samples).zip(self.faulted) {
+            if faulted {
+                continue;
+            }
+            detector.insert(sample);
+            *phase = match detector.step(*phase) {
+                Some(Event::Settled | Event::ContactEnded | Event::WarningEnded) => Phase::Armed,
+                Some(Event::Contact) => Phase::Alert,
+                Some(Event::Warning) => Phase::Warning,
+                None => *phase,
+            };
+        }
+    }
+
+    /// Whether enough healthy channels are in [`Phase::Alert`] for the [`Mode`]. Always `true`
+    /// once voting has failed.
+    pub fn contact(&self) -> bool {
+        let required = match self.mode() {
+            Mode::TwoOutOfThree => 2,
+            Mode::OneOutOfTwo => 1,
+            Mode::Failed => return true,
+        };
+        self.alerted().count() >= required
+    }
+
+    /// Combine the channels into the [`Event`] moving the probe on from `phase`, in place of the
+    /// `event` raised by the probe's own detector.
+    ///
+    /// The probe's detector still settles the probe, and its warnings are passed on, but contact
+    /// is only detected and ended by the vote. A contact seen by that detector alone is dropped.
+    pub fn vote(&mut self, phase: Phase, event: Option<Event>) -> Option<Event> {
+        match phase {
+            Phase::Calibrating => event,
+            Phase::Armed | Phase::Warning if self.contact() => {
+                self.last_detection = self
+                    .alerted()
+                    .filter_map(|channel| self.detectors[channel].last_detection())
+                    .max_by_key(|(sample, _)| *sample);
+                Some(Event::Contact)
+            }
+            Phase::Armed | Phase::Warning => event.filter(|event| *event != Event::Contact),
+            Phase::Alert => (!self.contact()).then_some(Event::ContactEnded),
+        }
+    }
+
+    /// Detection event of the channel whose alert completed the most recent contact vote, if any
+    pub fn last_detection(&self) -> Option<DetectionEvent> {
+        self.last_detection
+    }
+
+    /// Healthy channels in [`Phase::Alert`]
+    fn alerted(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..CHANNELS)
+            .filter(|&channel| !self.faulted[channel] && self.phases[channel] == Phase::Alert)
+    }
+}
@@ -0,0 +1,79 @@
+//! Prints the records in a dumped journal sector, or several back to back:
+//!
+//! ```text
+//! cargo run --bin decode_journal -- journal.bin
+//! ```
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{env, fs, process::ExitCode};
+
+use aps490_pfpu2_host_tests::journal::{Decoder, Kind, Record, Source};
+
+/// Size of a flash sector
+const SECTOR_SIZE: usize = 4096;
+
+fn main() -> ExitCode {
+    let Some(path) = env::args().nth(1) else {
+        eprintln!("usage: decode_journal <sector dump>");
+        return ExitCode::FAILURE;
+    };
+    let dump = match fs::read(&path) {
+        Ok(dump) => dump,
+        Err(err) => {
+            eprintln!("unable to read {path}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    for (idx, sector) in dump.chunks(SECTOR_SIZE).enumerate() {
+        let Some(records) = Decoder::new(sector) else {
+            println!("sector {idx}: not a journal sector");
+            continue;
+        };
+        println!("sector {idx}:");
+        let mut decoded = records.clone();
+        for record in &mut decoded {
+            println!(
+                "  #{:<8} {:>10} ms  {}",
+                record.seq,
+                record.at_ms,
+                describe(&record)
+            );
+        }
+        let unused = decoded.remaining();
+        if unused.iter().any(|byte| *byte != 0xFF) {
+            println!("  stopped at a corrupt record, {} bytes left", unused.len());
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Name the kind of `record`, and the source of an operator event, falling back to the raw
+/// numbers for anything unknown
+fn describe(record: &Record) -> String {
+    match Kind::from_raw(record.kind) {
+        Some(Kind::Detection) => format!(
+            "detection  probe {}  sample {}",
+            record.value >> 8,
+            record.value & 0xFF
+        ),
+        Some(kind) if kind.operator() => match Source::from_raw(record.value) {
+            Some(source) => format!("{}  by {}", kind.name(), source.name()),
+            None => format!("{}  source {}", kind.name(), record.value),
+        },
+        Some(kind) => format!("{}  value {}", kind.name(), record.value),
+        None => format!("kind {:>3}  value {}", record.kind, record.value),
+    }
+}
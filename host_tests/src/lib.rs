@@ -0,0 +1,29 @@
+//! Host-side tests for the hardware-independent parts of the firmware, in `pfpu2-core`. The
+//! modules are re-exported from the same crate the firmware builds for the RP2040, so they are
+//! tested exactly as they run there:
+//!
+//! ```text
+//! cd host_tests && cargo test
+//! ```
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_std]
+#![warn(missing_docs)]
+
+pub use pfpu2_core::{
+    auto_zero, detection_core, health, indicator, journal, protocol, ring, sequence, soak,
+    system_state, units, voting,
+};
@@ -0,0 +1,85 @@
+//! Checks the auto-zero stage removing the DC offset of the front-end.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_host_tests::auto_zero::{AutoZero, MIDPOINT};
+
+/// Time constant used by most tests, 2 s with 2 ms averaging
+const TIME_CONSTANT: u32 = 1000;
+
+#[test]
+fn bypassed_passes_samples_through() {
+    let mut auto_zero = AutoZero::new(0);
+    for sample in [0, 37, 200, 255] {
+        assert_eq!(auto_zero.apply(sample), sample);
+    }
+    assert_eq!(auto_zero.offset(), None);
+}
+
+#[test]
+fn first_sample_is_centred() {
+    let mut auto_zero = AutoZero::new(TIME_CONSTANT);
+    assert_eq!(auto_zero.apply(200), MIDPOINT);
+    assert_eq!(auto_zero.offset(), Some(200));
+}
+
+#[test]
+fn contact_step_passes_through() {
+    let mut auto_zero = AutoZero::new(TIME_CONSTANT);
+    for _ in 0..100 {
+        auto_zero.apply(200);
+    }
+    // A step of 50 counts, held for 300 ms, is barely followed by the estimate
+    let stepped: Vec<u8> = (0..150).map(|_| auto_zero.apply(150)).collect();
+    assert_eq!(stepped[0], MIDPOINT - 50);
+    assert!(stepped.iter().all(|&sample| sample <= MIDPOINT - 40));
+}
+
+#[test]
+fn slow_drift_is_removed() {
+    let mut auto_zero = AutoZero::new(TIME_CONSTANT);
+    let mut zeroed = 0;
+    // Drift up by 20 counts, one count every 5 time constants
+    for level in 100..=120 {
+        for _ in 0..5 * TIME_CONSTANT {
+            zeroed = auto_zero.apply(level);
+        }
+    }
+    assert_eq!(zeroed, MIDPOINT);
+    assert_eq!(auto_zero.offset(), Some(120));
+}
+
+#[test]
+fn output_is_clamped() {
+    let mut auto_zero = AutoZero::new(TIME_CONSTANT);
+    auto_zero.apply(10);
+    assert_eq!(auto_zero.apply(255), u8::MAX);
+
+    let mut auto_zero = AutoZero::new(TIME_CONSTANT);
+    auto_zero.apply(250);
+    assert_eq!(auto_zero.apply(0), 0);
+}
+
+#[test]
+fn bypass_restarts_the_estimate() {
+    let mut auto_zero = AutoZero::new(TIME_CONSTANT);
+    auto_zero.apply(200);
+    auto_zero.set_time_constant(0);
+    assert!(auto_zero.bypassed());
+    assert_eq!(auto_zero.apply(90), 90);
+
+    auto_zero.set_time_constant(TIME_CONSTANT);
+    assert_eq!(auto_zero.apply(90), MIDPOINT);
+}
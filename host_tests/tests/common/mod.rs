@@ -0,0 +1,56 @@
+//! Helpers shared by the detection tests, running samples through a [`Detector`] the way the
+//! firmware state machine does.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Each test binary only uses some of the helpers
+#![allow(dead_code)]
+
+use aps490_pfpu2_host_tests::detection_core::{Detector, Event, Phase, Thresholds};
+
+/// Phase the firmware moves to when the detector raises `event`
+pub fn next_phase(event: Event) -> Phase {
+    match event {
+        Event::Settled | Event::ContactEnded | Event::WarningEnded => Phase::Armed,
+        Event::Contact => Phase::Alert,
+        Event::Warning => Phase::Warning,
+    }
+}
+
+/// Run `samples` through an armed detector, following its phase, and return the events raised
+/// with the index of the sample that raised them. The buffer is first filled with the first sample,
+/// as it would be after calibrating.
+pub fn events(thresholds: Thresholds, samples: &[u8]) -> Vec<(usize, Event)> {
+    let mut detector = Detector::<64>::new(thresholds);
+    for _ in 0..64 {
+        detector.insert(samples[0]);
+    }
+    let mut phase = Phase::Armed;
+    let mut events = Vec::new();
+    for (idx, &sample) in samples.iter().enumerate() {
+        detector.insert(sample);
+        let Some(event) = detector.step(phase) else {
+            continue;
+        };
+        phase = next_phase(event);
+        events.push((idx, event));
+    }
+    events
+}
+
+/// A steady signal of `len` samples at `level`
+pub fn steady(level: u8, len: usize) -> Vec<u8> {
+    vec![level; len]
+}
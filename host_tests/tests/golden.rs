@@ -0,0 +1,268 @@
+//! Golden-waveform regression corpus for the detection core.
+//!
+//! Each case in `host_tests/golden/` is a pair of files:
+//!
+//! - `<case>.samples`: the averaged samples, in order, as decimal bytes separated by commas or
+//!   whitespace. Square brackets are ignored, so `Here are the last 250 samples` blocks from a
+//!   `trace_avg_samples` log can be pasted in as they are. `#` starts a comment.
+//! - `<case>.events`: the expected [`Event`]s, one per line as `<sample> <event>`, where `<sample>`
+//!   is the 1-based number of the sample that produced it. `#` starts a comment.
+//!
+//! The samples are run through a [`Detector`] using the `[detection]` defaults in `board.toml`,
+//! with the same phase changes as the firmware state machine. A report of every case is printed,
+//! and the test fails if any event moved, appeared or disappeared, so a threshold change can't
+//! silently regress a known recording. When a change is intended, rewrite the expected events and
+//! review the diff:
+//!
+//! ```text
+//! GOLDEN_BLESS=1 cargo test --test golden
+//! ```
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod common;
+
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use aps490_pfpu2_host_tests::detection_core::{Detector, Event, Phase, Thresholds};
+
+/// Long-term buffer length. Detection only looks a few samples back, so this doesn't need to
+/// match the board.
+const RING_SIZE: usize = 1000;
+
+/// An event, and the 1-based number of the sample that produced it
+type Timed = (u64, Event);
+
+/// Detection settings from the `[detection]` section of `board.toml`
+struct Defaults {
+    /// Thresholds passed to the [`Detector`]
+    thresholds: Thresholds,
+    /// Whether the end of contact latches the alert, which stops detection
+    latch_alert: bool,
+}
+
+impl Defaults {
+    /// Read the defaults from `board.toml`. Only the flat `key = value` lines of `[detection]`
+    /// are needed, so they are parsed by hand.
+    fn load() -> Self {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../board.toml");
+        let board = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("unable to read {}: {err}", path.display()));
+        let mut section = "";
+        let mut detection = Vec::new();
+        for line in board.lines().map(|line| uncomment(line).trim()) {
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name;
+            } else if let (Some((key, value)), "detection") = (line.split_once('='), section) {
+                detection.push((key.trim(), value.trim()));
+            }
+        }
+        let value = |key: &str| {
+            detection
+                .iter()
+                .find(|(other, _)| *other == key)
+                .map(|(_, value)| *value)
+                .unwrap_or_else(|| panic!("board.toml: missing `detection.{key}`"))
+        };
+        let int = |key: &str| {
+            value(key)
+                .parse::<i64>()
+                .unwrap_or_else(|err| panic!("board.toml: `detection.{key}`: {err}"))
+        };
+        Defaults {
+            thresholds: Thresholds {
+                trigger_delta: int("trigger_delta") as i16,
+                warning_delta: int("warning_delta") as i16,
+                confirm_delta: int("confirm_delta") as i16,
+                restore_delta: int("restore_delta") as i16,
+                alert_hold_samples: int("alert_hold_samples") as u32,
+                restore_samples: match value("restore_profile") {
+                    "\"slow\"" => int("restore_slow_samples") as u32,
+                    _ => int("restore_fast_samples") as u32,
+                },
+            },
+            latch_alert: value("latch_alert") == "true",
+        }
+    }
+}
+
+/// `line`, up to any `#` comment
+fn uncomment(line: &str) -> &str {
+    line.split_once('#').map_or(line, |(code, _)| code)
+}
+
+/// Parse a `.samples` fixture
+fn parse_samples(text: &str) -> Vec<u8> {
+    text.lines()
+        .flat_map(|line| uncomment(line).split([',', '[', ']']))
+        .flat_map(str::split_whitespace)
+        .map(|sample| {
+            sample
+                .parse()
+                .unwrap_or_else(|err| panic!("invalid sample `{sample}`: {err}"))
+        })
+        .collect()
+}
+
+/// Parse an `.events` fixture
+fn parse_events(text: &str) -> Vec<Timed> {
+    text.lines()
+        .map(|line| uncomment(line).trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (sample, event) = line
+                .split_once(char::is_whitespace)
+                .unwrap_or_else(|| panic!("expected `<sample> <event>`, found `{line}`"));
+            let sample = sample
+                .parse()
+                .unwrap_or_else(|err| panic!("invalid sample number `{sample}`: {err}"));
+            let event = match event.trim() {
+                "Settled" => Event::Settled,
+                "Contact" => Event::Contact,
+                "ContactEnded" => Event::ContactEnded,
+                "Warning" => Event::Warning,
+                "WarningEnded" => Event::WarningEnded,
+                other => panic!("unknown event `{other}`"),
+            };
+            (sample, event)
+        })
+        .collect()
+}
+
+/// Format events as an `.events` fixture
+fn format_events(events: &[Timed]) -> String {
+    let mut text = String::from("# sample event\n");
+    for (sample, event) in events {
+        let _ = writeln!(text, "{sample} {event:?}");
+    }
+    text
+}
+
+/// Run `samples` through the detector, moving between phases as the firmware does
+fn run(samples: &[u8], defaults: &Defaults) -> Vec<Timed> {
+    let mut detector = Detector::<RING_SIZE>::new(defaults.thresholds);
+    let mut phase = Some(Phase::Calibrating);
+    let mut events = Vec::new();
+    for &sample in samples {
+        detector.insert(sample);
+        let Some(event) = phase.and_then(|current| detector.step(current)) else {
+            continue;
+        };
+        events.push((detector.samples_recorded(), event));
+        phase = match event {
+            // A latched alert waits for the operator, so nothing more is detected
+            Event::ContactEnded if defaults.latch_alert => None,
+            event => Some(common::next_phase(event)),
+        };
+    }
+    events
+}
+
+/// Describe how `actual` differs from `expected`, or `None` if they match
+fn compare(expected: &[Timed], actual: &[Timed]) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let mut report = String::new();
+    if expected.len() != actual.len() {
+        let _ = writeln!(
+            report,
+            "    expected {} events, detected {}",
+            expected.len(),
+            actual.len()
+        );
+    }
+    for idx in 0..expected.len().max(actual.len()) {
+        match (expected.get(idx), actual.get(idx)) {
+            (Some(want), Some(got)) if want == got => {}
+            (Some(want), Some(got)) => {
+                let _ = writeln!(report, "    expected {want:?}, detected {got:?}");
+            }
+            (Some(want), None) => {
+                let _ = writeln!(report, "    expected {want:?}, not detected");
+            }
+            (None, Some(got)) => {
+                let _ = writeln!(report, "    unexpected {got:?}");
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    Some(report)
+}
+
+/// Every `.samples` fixture in the corpus, in name order
+fn corpus() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("golden");
+    let mut cases: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|err| panic!("unable to read {}: {err}", dir.display()))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "samples"))
+        .collect();
+    cases.sort();
+    cases
+}
+
+#[test]
+fn golden_corpus() {
+    let defaults = Defaults::load();
+    let bless = env::var_os("GOLDEN_BLESS").is_some();
+    let cases = corpus();
+    assert!(!cases.is_empty(), "golden corpus is empty");
+
+    let mut report = String::new();
+    let mut failed = 0;
+    for samples_path in &cases {
+        let name = samples_path.file_stem().unwrap().to_string_lossy();
+        let events_path = samples_path.with_extension("events");
+        let samples = parse_samples(&fs::read_to_string(samples_path).unwrap());
+        let actual = run(&samples, &defaults);
+
+        if bless {
+            fs::write(&events_path, format_events(&actual)).unwrap();
+            let _ = writeln!(report, "BLESS {name}: {} events", actual.len());
+            continue;
+        }
+        let expected = fs::read_to_string(&events_path)
+            .map(|text| parse_events(&text))
+            .unwrap_or_else(|err| panic!("unable to read {}: {err}", events_path.display()));
+        match compare(&expected, &actual) {
+            None => {
+                let _ = writeln!(
+                    report,
+                    "PASS  {name}: {} samples, {} events",
+                    samples.len(),
+                    actual.len()
+                );
+            }
+            Some(diff) => {
+                failed += 1;
+                let _ = write!(report, "FAIL  {name}: {} samples\n{diff}", samples.len());
+            }
+        }
+    }
+
+    println!("Golden corpus ({} cases):\n{report}", cases.len());
+    assert!(
+        failed == 0,
+        "{failed} of {} golden cases changed, see the report above. If intended, rerun with \
+         GOLDEN_BLESS=1 and review the .events diff.",
+        cases.len()
+    );
+}
@@ -0,0 +1,122 @@
+//! Checks the health scoring of channels, and the disqualification of a clearly faulty one.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_host_tests::health::{
+    HealthMonitor, CLEAN_RECOVERY, FULL_SCORE, SUSPECT_PENALTY, WINDOW_SAMPLES,
+};
+
+/// Record `windows` whole windows of samples made by `samples` from the index of each sample,
+/// returning the channels disqualified with the window that disqualified them
+fn run<const N: usize>(
+    monitor: &mut HealthMonitor<N>,
+    windows: u32,
+    samples: impl Fn(u32) -> [u8; N],
+) -> Vec<(u32, usize)> {
+    (0..windows * WINDOW_SAMPLES)
+        .filter_map(|idx| {
+            monitor
+                .record(samples(idx))
+                .map(|channel| (idx / WINDOW_SAMPLES, channel))
+        })
+        .collect()
+}
+
+/// Alternates by 1 around 200, within the noise allowed every channel
+fn quiet(idx: u32) -> u8 {
+    200 + (idx % 2) as u8
+}
+
+/// Alternates by 20 around 200
+fn noisy(idx: u32) -> u8 {
+    190 + 20 * (idx % 2) as u8
+}
+
+#[test]
+fn healthy_channels_keep_full_scores() {
+    let mut monitor = HealthMonitor::<3>::new();
+    assert_eq!(run(&mut monitor, 10, |idx| [quiet(idx); 3]), []);
+    assert_eq!(monitor.scores(), [FULL_SCORE; 3]);
+}
+
+#[test]
+fn common_steps_are_not_symptoms() {
+    let mut monitor = HealthMonitor::<3>::new();
+    // A contact every 50 samples reaches every channel at once
+    let contacts = |idx: u32| if idx % 100 < 50 { [200; 3] } else { [150; 3] };
+    assert_eq!(run(&mut monitor, 10, contacts), []);
+    assert_eq!(monitor.scores(), [FULL_SCORE; 3]);
+}
+
+#[test]
+fn disagreeing_channel_is_disqualified() {
+    let mut monitor = HealthMonitor::<3>::new();
+    let drifted = |idx: u32| [quiet(idx), quiet(idx) - 30, quiet(idx)];
+    // Suspect for 4 windows in a row runs the score out on the fourth
+    assert_eq!(run(&mut monitor, 6, drifted), [(3, 1)]);
+    assert_eq!(monitor.disqualified(), Some(1));
+    assert_eq!(monitor.scores(), [FULL_SCORE, 0, FULL_SCORE]);
+}
+
+#[test]
+fn railed_channel_is_disqualified() {
+    let mut monitor = HealthMonitor::<2>::new();
+    assert_eq!(run(&mut monitor, 4, |idx| [quiet(idx), 0]), [(3, 1)]);
+}
+
+#[test]
+fn noisy_channel_is_disqualified() {
+    let mut monitor = HealthMonitor::<2>::new();
+    assert_eq!(
+        run(&mut monitor, 4, |idx| [noisy(idx), quiet(idx)]),
+        [(3, 0)]
+    );
+    assert!(monitor.last_window()[0].noise > monitor.last_window()[1].noise);
+}
+
+#[test]
+fn two_disagreeing_channels_are_left_to_the_cross_check() {
+    let mut monitor = HealthMonitor::<2>::new();
+    // Either could be at fault, so neither is suspect
+    assert_eq!(
+        run(&mut monitor, 10, |idx| [quiet(idx), quiet(idx) - 30]),
+        []
+    );
+    assert_eq!(monitor.scores(), [FULL_SCORE; 2]);
+    assert_eq!(monitor.last_window()[1].disagreed, WINDOW_SAMPLES);
+}
+
+#[test]
+fn intermittent_fault_recovers() {
+    let mut monitor = HealthMonitor::<3>::new();
+    run(&mut monitor, 1, |idx| [quiet(idx), 0, quiet(idx)]);
+    assert_eq!(monitor.scores()[1], FULL_SCORE - SUSPECT_PENALTY);
+    run(&mut monitor, 2, |idx| [quiet(idx); 3]);
+    assert_eq!(
+        monitor.scores()[1],
+        FULL_SCORE - SUSPECT_PENALTY + 2 * CLEAN_RECOVERY
+    );
+}
+
+#[test]
+fn only_one_channel_is_disqualified() {
+    let mut monitor = HealthMonitor::<3>::new();
+    assert_eq!(
+        run(&mut monitor, 4, |idx| [quiet(idx), 0, quiet(idx)]),
+        [(3, 1)]
+    );
+    assert_eq!(run(&mut monitor, 10, |idx| [0, 0, quiet(idx)]), []);
+    assert_eq!(monitor.disqualified(), Some(1));
+}
@@ -0,0 +1,124 @@
+//! Checks the LED patterns produced by sample sequences, recorded by the [`MockIndicator`].
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "mock")]
+
+mod common;
+
+use aps490_pfpu2_host_tests::{
+    detection_core::{Detector, Phase, Thresholds, SETTLE_SAMPLES},
+    indicator::{
+        MockIndicator, StatusIndicator,
+        StatusLedStates::{self, Alert, Error, Normal},
+        Transition,
+    },
+};
+use common::{next_phase, steady};
+
+/// Milliseconds per averaged sample
+const SAMPLE_MS: u32 = 2;
+/// Samples an alert is held for
+const HOLD: u32 = 150;
+/// Firmware default thresholds
+const THRESHOLDS: Thresholds = Thresholds {
+    trigger_delta: 2,
+    warning_delta: 0,
+    confirm_delta: 1,
+    restore_delta: 2,
+    alert_hold_samples: HOLD,
+    restore_samples: 0,
+};
+
+/// Run `samples` through a detector, showing the pattern for each phase as the firmware does.
+/// A fault is raised on sample `fault_at`, if given, which shows the error pattern for good.
+fn indicate(samples: &[u8], fault_at: Option<usize>) -> MockIndicator<16> {
+    let mut detector = Detector::<64>::new(THRESHOLDS);
+    let mut indicator = MockIndicator::new();
+    let mut phase = Some(Phase::Calibrating);
+    indicator.show(StatusLedStates::from(Phase::Calibrating), 0);
+    for (idx, &sample) in samples.iter().enumerate() {
+        let at_ms = (idx as u32 + 1) * SAMPLE_MS;
+        if fault_at == Some(idx) {
+            phase = None;
+            indicator.show(Error, at_ms);
+        }
+        let Some(current) = phase else {
+            continue;
+        };
+        detector.insert(sample);
+        phase = Some(detector.step(current).map_or(current, next_phase));
+        if let Some(phase) = phase {
+            indicator.show(phase.into(), at_ms);
+        }
+    }
+    indicator
+}
+
+/// Shorthand for a [`Transition`] on sample `sample`
+fn at(sample: u32, leds: StatusLedStates) -> Transition {
+    Transition {
+        at_ms: sample * SAMPLE_MS,
+        leds,
+    }
+}
+
+#[test]
+fn calibration_then_armed() {
+    let indicator = indicate(&steady(200, 100), None);
+    assert_eq!(
+        indicator.transitions(),
+        [at(0, Alert), at(SETTLE_SAMPLES as u32, Normal)]
+    );
+}
+
+#[test]
+fn contact_alerts_until_held() {
+    let mut samples = steady(200, 100);
+    samples.extend([0; 20]);
+    samples.extend(steady(200, 300));
+    let indicator = indicate(&samples, None);
+    // The drop on sample 101 is confirmed on 102, and the alert clears once it has been held
+    assert_eq!(
+        indicator.transitions(),
+        [
+            at(0, Alert),
+            at(SETTLE_SAMPLES as u32, Normal),
+            at(102, Alert),
+            at(102 + HOLD, Normal),
+        ]
+    );
+    assert_eq!(indicator.overflowed(), 0);
+}
+
+#[test]
+fn glitch_is_not_shown() {
+    let mut samples = steady(200, 100);
+    samples.push(0);
+    samples.extend(steady(200, 100));
+    let indicator = indicate(&samples, None);
+    assert_eq!(indicator.current(), Some(Normal));
+    assert_eq!(indicator.transitions().len(), 2);
+}
+
+#[test]
+fn fault_shows_error_for_good() {
+    let mut samples = steady(200, 100);
+    samples.extend([0; 20]);
+    samples.extend(steady(200, 300));
+    let indicator = indicate(&samples, Some(110));
+    assert_eq!(indicator.transitions().last(), Some(&at(111, Error)));
+    assert_eq!(indicator.current(), Some(Error));
+}
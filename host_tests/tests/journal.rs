@@ -0,0 +1,183 @@
+//! Round trips [`Record`]s through the journal format, and checks how densely they pack.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_host_tests::journal::{
+    Decoder, Encoder, Kind, Record, Source, MAGIC, MAX_RECORD_BYTES, TAG_ERASED, TAG_REBASE,
+    TAG_SEQUENCE,
+};
+
+/// Size of a flash sector
+const SECTOR_SIZE: usize = 4096;
+
+/// Encode `records` into an erased sector, stopping once one doesn't fit. Returns the sector and
+/// the number of records written.
+fn fill_sector(records: impl IntoIterator<Item = Record>) -> (Vec<u8>, usize) {
+    let mut sector = vec![TAG_ERASED; SECTOR_SIZE];
+    sector[..MAGIC.len()].copy_from_slice(&MAGIC);
+    let mut encoder = Encoder::new();
+    let mut used = MAGIC.len();
+    let mut written = 0;
+    for record in records {
+        match encoder.encode(record, &mut sector[used..]) {
+            Some(len) => used += len,
+            None => break,
+        }
+        written += 1;
+    }
+    (sector, written)
+}
+
+/// A state change every few seconds, as in normal operation
+fn typical(count: u32) -> impl Iterator<Item = Record> {
+    (0..count).map(|idx| Record {
+        seq: 500 + idx,
+        at_ms: 1_000 + idx * 3_700,
+        kind: 1 + (idx % 3) as u8,
+        value: idx % 8,
+    })
+}
+
+#[test]
+fn round_trip() {
+    let records: Vec<_> = typical(100).collect();
+    let (sector, written) = fill_sector(records.iter().copied());
+    assert_eq!(written, records.len());
+    let decoded: Vec<_> = Decoder::new(&sector).unwrap().collect();
+    assert_eq!(decoded, records);
+}
+
+#[test]
+fn rebases_after_reset() {
+    let records = [
+        Record {
+            seq: 0,
+            at_ms: 90_000,
+            kind: 1,
+            value: 2,
+        },
+        // The clock restarted from zero
+        Record {
+            seq: 1,
+            at_ms: 150,
+            kind: 2,
+            value: u32::MAX,
+        },
+        Record {
+            seq: 2,
+            at_ms: 400,
+            kind: 3,
+            value: 0,
+        },
+    ];
+    let (sector, _) = fill_sector(records);
+    assert!(Decoder::new(&sector).unwrap().eq(records));
+}
+
+#[test]
+fn reserved_kinds_are_rejected() {
+    let mut out = [0; MAX_RECORD_BYTES];
+    for kind in [TAG_REBASE, TAG_SEQUENCE, TAG_ERASED] {
+        let record = Record {
+            seq: 0,
+            at_ms: 0,
+            kind,
+            value: 0,
+        };
+        assert_eq!(Encoder::new().encode(record, &mut out), None);
+    }
+}
+
+#[test]
+fn event_kinds_round_trip() {
+    for kind in Kind::ALL {
+        assert!(![TAG_REBASE, TAG_SEQUENCE, TAG_ERASED].contains(&(kind as u8)));
+        assert_eq!(Kind::from_raw(kind as u8), Some(kind));
+    }
+    for source in Source::ALL {
+        assert_eq!(Source::from_raw(source as u32), Some(source));
+    }
+    assert_eq!(Kind::from_raw(TAG_REBASE), None);
+    assert_eq!(Source::from_raw(Source::ALL.len() as u32), None);
+}
+
+#[test]
+fn records_fit_in_max_size() {
+    let mut out = [0; MAX_RECORD_BYTES];
+    let mut encoder = Encoder::new();
+    let record = Record {
+        seq: u32::MAX - 1,
+        at_ms: u32::MAX,
+        kind: 1,
+        value: u32::MAX,
+    };
+    // The first record also carries the rebase and the sequence number
+    assert!(encoder
+        .encode(record, &mut [0; 3 * MAX_RECORD_BYTES])
+        .is_some());
+    let next = Record {
+        seq: u32::MAX,
+        ..record
+    };
+    assert_eq!(encoder.encode(next, &mut out), Some(1 + 1 + 5));
+}
+
+#[test]
+fn sequence_gaps_round_trip() {
+    let mut records: Vec<_> = typical(10).collect();
+    // Events dropped before they were written
+    for record in &mut records[4..] {
+        record.seq += 3;
+    }
+    let (sector, written) = fill_sector(records.iter().copied());
+    assert_eq!(written, records.len());
+    assert!(Decoder::new(&sector).unwrap().eq(records.iter().copied()));
+    // Only the start of the sector and the gap carry a sequence number
+    let (contiguous, _) = fill_sector(typical(10));
+    let used = |sector: &[u8]| sector.iter().filter(|byte| **byte != TAG_ERASED).count();
+    assert_eq!(used(&sector), used(&contiguous) + 1 + 2);
+}
+
+#[test]
+fn records_without_sequence_count_from_zero() {
+    // Written before sequence numbers: a rebase, then two events
+    let mut sector = MAGIC.to_vec();
+    sector.extend([TAG_REBASE, 10, 1, 0, 7, 2, 5, 0, TAG_ERASED]);
+    let seqs: Vec<_> = Decoder::new(&sector)
+        .unwrap()
+        .map(|record| record.seq)
+        .collect();
+    assert_eq!(seqs, [0, 1]);
+}
+
+#[test]
+fn truncated_record_ends_decoding() {
+    let (mut sector, written) = fill_sector(typical(3));
+    let mut decoder = Decoder::new(&sector).unwrap();
+    decoder.by_ref().for_each(drop);
+    let end = SECTOR_SIZE - decoder.remaining().len();
+    // Cut the last record short, as if power failed while it was programmed
+    sector.truncate(end - 1);
+    assert_eq!(Decoder::new(&sector).unwrap().count(), written - 1);
+}
+
+#[test]
+fn packs_more_than_fixed_entries() {
+    let (_, written) = fill_sector(typical(u32::MAX));
+    // Fixed 12-byte entries, as in the post-mortem ring
+    let fixed = (SECTOR_SIZE - MAGIC.len()) / 12;
+    println!("{written} records per sector, against {fixed} fixed entries");
+    assert!(written > 2 * fixed);
+}
@@ -0,0 +1,126 @@
+//! Checks the console protocol: how lines parse into commands, and which need privileged access.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_host_tests::{
+    protocol::{
+        Access, Command, ConfigPin, ConsoleError, Drill, ExportFormat, Field, ProvisionError,
+        Threshold,
+    },
+    units::Millivolts,
+};
+
+/// Parse `line` with privileged access
+fn parse(line: &str) -> Result<Command<'_>, ConsoleError> {
+    Command::parse(line, Access::Privileged)
+}
+
+#[test]
+fn commands_ignore_case_and_spacing() {
+    assert_eq!(parse("help"), Ok(Command::Help));
+    assert_eq!(parse("  Reset   Latch "), Ok(Command::ResetLatch));
+    assert_eq!(
+        parse("export json 42"),
+        Ok(Command::Export(ExportFormat::Json, 42))
+    );
+    assert_eq!(parse("HELP ME"), Err(ConsoleError::UnknownCommand));
+    assert_eq!(parse("RESET LATCH NOW"), Err(ConsoleError::UnknownCommand));
+}
+
+#[test]
+fn privileged_commands_need_access() {
+    for line in [
+        "FACTORY RESET",
+        "THRESHOLD TRIGGER 100",
+        "DRILL CONTACT",
+        "CONFIRM",
+    ] {
+        assert_eq!(
+            Command::parse(line, Access::ReadOnly),
+            Err(ConsoleError::Locked),
+            "{line}"
+        );
+        assert!(parse(line).is_ok(), "{line}");
+    }
+    for line in ["*IDN?", "THRESHOLD", "UNLOCK 1234", "LOCK", "RUN 0", "AB"] {
+        assert!(Command::parse(line, Access::ReadOnly).is_ok(), "{line}");
+    }
+}
+
+#[test]
+fn arguments_are_checked() {
+    assert_eq!(
+        parse("THRESHOLD warning 0"),
+        Ok(Command::Threshold(Threshold::Warning, Millivolts(0)))
+    );
+    assert_eq!(
+        parse("THRESHOLD TRIGGER 0"),
+        Err(ConsoleError::InvalidThreshold)
+    );
+    assert_eq!(
+        parse("THRESHOLD GAIN 10"),
+        Err(ConsoleError::UnknownThreshold)
+    );
+    assert_eq!(parse("CAPTURE 1000"), Ok(Command::Capture(1000)));
+    assert_eq!(parse("CAPTURE 0"), Err(ConsoleError::InvalidCaptureLength));
+    assert_eq!(parse("DRILL watchdog"), Ok(Command::Drill(Drill::Watchdog)));
+    assert_eq!(parse("DRILL"), Err(ConsoleError::MissingArgument));
+    assert_eq!(parse("UNLOCK 123"), Err(ConsoleError::InvalidPin));
+    assert_eq!(
+        parse("PROVISION REF 3300"),
+        Ok(Command::Provision(Field::AdcReference(3_300)))
+    );
+    assert_eq!(
+        parse("PROVISION COLOUR RED"),
+        Err(ConsoleError::Provision(ProvisionError::UnknownField))
+    );
+}
+
+#[test]
+fn macro_steps_are_kept_whole() {
+    assert_eq!(parse("MACRO"), Ok(Command::Macros));
+    assert_eq!(
+        parse("MACRO 1 LOG ALL DEBUG;  WATCH"),
+        Ok(Command::Macro(1, Some("LOG ALL DEBUG;  WATCH")))
+    );
+    assert_eq!(parse("macro 1 clear"), Ok(Command::Macro(1, None)));
+    assert_eq!(parse("MACRO 4 HELP"), Err(ConsoleError::InvalidMacro));
+    assert_eq!(parse("MACRO 0"), Err(ConsoleError::MissingArgument));
+}
+
+#[test]
+fn pin_keeps_leading_zeros() {
+    let pin = ConfigPin::parse("0042").unwrap();
+    assert_eq!((pin.digits(), pin.value()), (4, 42));
+    assert_ne!(Some(pin), ConfigPin::parse("00042"));
+    assert_eq!(ConfigPin::from_parts(pin.digits(), pin.value()), Some(pin));
+    assert_eq!(ConfigPin::from_parts(4, 10_000), None);
+    assert_eq!(ConfigPin::parse("12a4"), None);
+}
+
+#[test]
+fn unknown_field_lists_every_field() {
+    let hint = ConsoleError::Provision(ProvisionError::UnknownField).as_str();
+    for (name, value) in [
+        ("SERIAL", "PFPU2-0001"),
+        ("REV", "B"),
+        ("OFFSET", "0"),
+        ("GAIN", "1000000"),
+        ("REF", "3300"),
+    ] {
+        assert!(Field::parse(name, value).is_ok(), "{name}");
+        assert!(hint.contains(name), "{name}");
+    }
+}
@@ -0,0 +1,102 @@
+//! Checks the debounce of the end of contact, once the alert hold has passed.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod common;
+
+use aps490_pfpu2_host_tests::detection_core::{Event, Thresholds};
+use common::{events, steady};
+
+/// Samples an alert is held for
+const HOLD: u32 = 20;
+/// Detects contact on a drop of 5, and needs 4 samples restored by 2 to end it
+const THRESHOLDS: Thresholds = Thresholds {
+    trigger_delta: 5,
+    warning_delta: 0,
+    confirm_delta: 1,
+    restore_delta: 2,
+    alert_hold_samples: HOLD,
+    restore_samples: 4,
+};
+
+/// Contact from sample 10, confirmed on sample 11 at 150
+fn contact() -> Vec<u8> {
+    let mut samples = steady(200, 10);
+    samples.extend(steady(150, 5));
+    samples
+}
+
+#[test]
+fn ends_once_restored_after_the_hold() {
+    let mut samples = contact();
+    samples.extend(steady(150, 30));
+    samples.extend(steady(200, 10));
+    // Restored from sample 45, for the fourth time on 48
+    assert_eq!(
+        events(THRESHOLDS, &samples),
+        [(11, Event::Contact), (48, Event::ContactEnded)]
+    );
+}
+
+#[test]
+fn waits_for_the_hold_when_restored_early() {
+    let mut samples = contact();
+    samples.extend(steady(200, 40));
+    assert_eq!(
+        events(THRESHOLDS, &samples),
+        [
+            (11, Event::Contact),
+            (11 + HOLD as usize, Event::ContactEnded)
+        ]
+    );
+}
+
+#[test]
+fn rebound_restarts_the_count() {
+    let mut samples = contact();
+    samples.extend(steady(150, 30));
+    samples.extend(steady(200, 3));
+    samples.extend(steady(150, 2));
+    samples.extend(steady(200, 10));
+    // The first rebound lasts 3 samples, and the second is restored for the fourth time on 53
+    assert_eq!(
+        events(THRESHOLDS, &samples),
+        [(11, Event::Contact), (53, Event::ContactEnded)]
+    );
+}
+
+#[test]
+fn stays_in_contact_until_restored() {
+    let mut samples = contact();
+    samples.extend(steady(150, 200));
+    assert_eq!(events(THRESHOLDS, &samples), [(11, Event::Contact)]);
+}
+
+#[test]
+fn zero_samples_end_with_the_hold() {
+    let thresholds = Thresholds {
+        restore_samples: 0,
+        ..THRESHOLDS
+    };
+    let mut samples = contact();
+    samples.extend(steady(150, 30));
+    assert_eq!(
+        events(thresholds, &samples),
+        [
+            (11, Event::Contact),
+            (11 + HOLD as usize, Event::ContactEnded)
+        ]
+    );
+}
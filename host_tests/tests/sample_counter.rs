@@ -0,0 +1,121 @@
+//! Property tests for the [`SampleCounter`] wrap math, the [`RingBuffer`] and the [`Detector`]
+//! ring buffer indices.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_host_tests::{
+    detection_core::{Detector, SampleCounter, Thresholds},
+    ring::RingBuffer,
+};
+use proptest::prelude::*;
+
+/// Ring buffer length that doesn't divide evenly into the counter range
+const RING_SIZE: usize = 7;
+/// Thresholds that never trigger, so inserts only exercise the ring buffer
+const QUIET: Thresholds = Thresholds {
+    trigger_delta: i16::MAX,
+    warning_delta: 0,
+    confirm_delta: i16::MAX,
+    restore_delta: i16::MAX,
+    alert_hold_samples: u32::MAX,
+    restore_samples: 0,
+};
+
+/// Counter values clustered around the wrap, as well as anywhere in the range
+fn counter() -> impl Strategy<Value = SampleCounter> {
+    prop_oneof![
+        any::<u64>(),
+        (0..1024u64).prop_map(|offset| u64::MAX - offset),
+        0..1024u64,
+    ]
+    .prop_map(SampleCounter)
+}
+
+proptest! {
+    #[test]
+    fn before_and_since_are_inverses(counter in counter(), count in any::<u64>()) {
+        let earlier = counter.before(count);
+        prop_assert_eq!(counter.since(earlier), count);
+        prop_assert_eq!(SampleCounter(earlier.0.wrapping_add(count)), counter);
+    }
+
+    #[test]
+    fn increment_advances_by_one(counter in counter()) {
+        let mut next = counter;
+        next.increment();
+        prop_assert_eq!(next.since(counter), 1);
+        prop_assert_eq!(next.before(1), counter);
+        prop_assert!(next.is_after(counter));
+        prop_assert!(!counter.is_after(next));
+    }
+
+    #[test]
+    fn distance_grows_with_each_increment(counter in counter(), steps in 1..512u64) {
+        let mut later = counter;
+        for step in 1..=steps {
+            later.increment();
+            prop_assert_eq!(later.since(counter), step);
+            prop_assert!(later.is_after(counter));
+        }
+    }
+
+    #[test]
+    fn is_after_is_antisymmetric(counter in counter(), distance in 1..(1u64 << 63)) {
+        let later = SampleCounter(counter.0.wrapping_add(distance));
+        prop_assert!(later.is_after(counter));
+        prop_assert!(!counter.is_after(later));
+        prop_assert!(!counter.is_after(counter));
+    }
+
+    #[test]
+    fn ring_indices_stay_in_range(
+        samples in prop::collection::vec(any::<u8>(), 0..64),
+        back in 0..=RING_SIZE,
+    ) {
+        let mut detector = Detector::<RING_SIZE>::new(QUIET);
+        for sample in &samples {
+            detector.insert(*sample);
+            prop_assert!(detector.head() < RING_SIZE);
+            prop_assert!(detector.back(back) < RING_SIZE);
+        }
+        prop_assert_eq!(detector.samples_recorded(), samples.len() as u64);
+    }
+
+    #[test]
+    fn back_finds_recent_samples(samples in prop::collection::vec(any::<u8>(), 1..64)) {
+        let mut detector = Detector::<RING_SIZE>::new(QUIET);
+        for sample in &samples {
+            detector.insert(*sample);
+        }
+        for (back, expected) in samples.iter().rev().take(RING_SIZE).enumerate() {
+            prop_assert_eq!(detector.samples()[detector.back(back)], *expected);
+        }
+        prop_assert_eq!(detector.back(0), detector.head());
+        prop_assert_eq!(detector.back(RING_SIZE), detector.head());
+    }
+
+    #[test]
+    fn ring_keeps_the_latest_items(items in prop::collection::vec(any::<u32>(), 1..64)) {
+        let mut ring = RingBuffer::<u32, RING_SIZE>::new(0);
+        for item in &items {
+            ring.push(*item);
+        }
+        prop_assert_eq!(ring.latest(), *items.last().unwrap());
+        for (back, expected) in items.iter().rev().take(RING_SIZE).enumerate() {
+            prop_assert_eq!(ring.get(back), *expected);
+        }
+        prop_assert_eq!(ring.back(RING_SIZE), ring.head());
+    }
+}
@@ -0,0 +1,83 @@
+//! Checks the release sequencing of an interlock: the cool-down after alerts and the enable delay.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_host_tests::sequence::{Sequencer, Stage, Timing};
+
+/// Enable delay of 1 s, and cool-down of 5 s
+const TIMING: Timing = Timing {
+    enable_delay_ms: 1_000,
+    cooldown_ms: 5_000,
+};
+
+/// Ask `sequencer` to release every 10 ms from `from_ms`, returning the time it was released
+fn release(sequencer: &mut Sequencer, from_ms: u32) -> u32 {
+    (0..)
+        .map(|step| from_ms.wrapping_add(10 * step))
+        .find(|&now_ms| !sequencer.update(false, now_ms, TIMING))
+        .unwrap()
+}
+
+#[test]
+fn no_delays_release_at_once() {
+    let mut sequencer = Sequencer::new();
+    sequencer.alert();
+    assert!(!sequencer.update(false, 0, Timing::default()));
+    assert_eq!(sequencer.stage(), Stage::Enabled);
+}
+
+#[test]
+fn release_waits_for_the_enable_delay() {
+    let mut sequencer = Sequencer::new();
+    assert!(sequencer.update(false, 100, TIMING));
+    assert_eq!(sequencer.stage(), Stage::Enabling { until_ms: 1_100 });
+    assert_eq!(sequencer.stage().remaining_ms(600), 500);
+    assert_eq!(release(&mut sequencer, 100), 1_100);
+}
+
+#[test]
+fn alert_cools_down_before_the_enable_delay() {
+    let mut sequencer = Sequencer::new();
+    sequencer.alert();
+    assert!(sequencer.update(false, 0, TIMING));
+    assert_eq!(sequencer.stage(), Stage::CoolingDown { until_ms: 5_000 });
+    // Polled late, the enable delay still runs from the end of the cool-down
+    assert!(sequencer.update(false, 5_500, TIMING));
+    assert_eq!(sequencer.stage(), Stage::Enabling { until_ms: 6_000 });
+    assert_eq!(release(&mut sequencer, 5_500), 6_000);
+    // Only the alert's release cools down
+    sequencer.update(true, 7_000, TIMING);
+    assert_eq!(release(&mut sequencer, 7_000), 8_000);
+}
+
+#[test]
+fn trip_restarts_the_sequence() {
+    let mut sequencer = Sequencer::new();
+    sequencer.alert();
+    sequencer.update(false, 0, TIMING);
+    assert!(sequencer.update(true, 3_000, TIMING));
+    assert_eq!(sequencer.stage(), Stage::Tripped);
+    // The cool-down wasn't completed, so it starts over
+    assert_eq!(release(&mut sequencer, 4_000), 10_000);
+}
+
+#[test]
+fn sequence_survives_timer_wrap() {
+    let mut sequencer = Sequencer::new();
+    let start = u32::MAX - 500;
+    sequencer.update(false, start, TIMING);
+    assert!(sequencer.pending());
+    assert_eq!(release(&mut sequencer, start), start.wrapping_add(1_000));
+}
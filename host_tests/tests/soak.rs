@@ -0,0 +1,99 @@
+//! Checks the statistics accumulated over a soak test.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_host_tests::{
+    detection_core::{Detector, Thresholds},
+    soak::{SoakStats, DELTA_BINS},
+};
+
+/// Detects contact from a change of 10
+const THRESHOLDS: Thresholds = Thresholds {
+    trigger_delta: 10,
+    warning_delta: 0,
+    confirm_delta: 1,
+    restore_delta: 2,
+    alert_hold_samples: 150,
+    restore_samples: 0,
+};
+
+/// Feed `samples` through a detector, recording each in a fresh [`SoakStats`]
+fn soak(samples: &[u8]) -> SoakStats {
+    let mut detector = Detector::<64>::new(THRESHOLDS);
+    for _ in 0..64 {
+        detector.insert(samples[0]);
+    }
+    let mut stats = SoakStats::new();
+    for &sample in samples {
+        detector.insert(sample);
+        stats.record(sample, detector.delta(), detector.near_miss(80));
+    }
+    stats
+}
+
+#[test]
+fn empty_stats_are_zero() {
+    let stats = SoakStats::new();
+    assert_eq!(stats.samples(), 0);
+    assert_eq!(stats.mean_centi(), 0);
+    assert_eq!(stats.std_dev_centi(), 0);
+}
+
+#[test]
+fn steady_signal_has_no_noise() {
+    let stats = soak(&[200; 100]);
+    assert_eq!(stats.samples(), 100);
+    assert_eq!(stats.mean_centi(), 20_000);
+    assert_eq!(stats.std_dev_centi(), 0);
+    assert_eq!(stats.deltas()[0], 100);
+    assert_eq!(stats.max_delta(), 0);
+}
+
+#[test]
+fn alternating_signal_has_unit_noise() {
+    let samples: Vec<u8> = (0..100).map(|idx| 199 + 2 * (idx % 2)).collect();
+    let stats = soak(&samples);
+    assert_eq!(stats.mean_centi(), 20_000);
+    assert_eq!(stats.std_dev_centi(), 100);
+    // The first sample follows the buffer filled with itself
+    assert_eq!(stats.deltas()[0], 1);
+    assert_eq!(stats.deltas()[2], 99);
+    assert_eq!(stats.max_delta(), 2);
+    assert_eq!(stats.near_misses(), 0);
+}
+
+#[test]
+fn large_deltas_share_the_last_bin() {
+    let stats = soak(&[100, 150, 100, 130]);
+    assert_eq!(stats.deltas()[DELTA_BINS - 1], 3);
+    assert_eq!(stats.max_delta(), 50);
+}
+
+#[test]
+fn near_misses_stop_short_of_the_trigger() {
+    let stats = soak(&[100, 108, 99, 100, 110, 103]);
+    // 8 and 9 are near misses, 1 and 7 are too small, and 10 detects contact
+    assert_eq!(stats.near_misses(), 2);
+}
+
+#[test]
+fn near_miss_share_is_configurable() {
+    let mut detector = Detector::<64>::new(THRESHOLDS);
+    detector.insert(100);
+    detector.insert(95);
+    assert!(!detector.near_miss(80));
+    assert!(detector.near_miss(50));
+    assert!(!detector.near_miss(51));
+}
@@ -0,0 +1,71 @@
+//! Checks the transitions allowed between system states, and what each state drives.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_host_tests::system_state::SystemState;
+
+#[test]
+fn all_is_in_declaration_order() {
+    for (idx, state) in SystemState::ALL.into_iter().enumerate() {
+        assert_eq!(state as usize, idx);
+    }
+}
+
+#[test]
+fn nothing_leaves_error() {
+    for to in SystemState::ALL {
+        assert!(!SystemState::Error.can_transition(to));
+    }
+}
+
+#[test]
+fn error_and_disabled_are_entered_from_anywhere_else() {
+    for from in SystemState::ALL
+        .into_iter()
+        .filter(|from| *from != SystemState::Error)
+    {
+        assert!(from.can_transition(SystemState::Error));
+        assert!(from.can_transition(SystemState::Disabled));
+    }
+}
+
+#[test]
+fn detection_cycle_is_allowed() {
+    let cycle = [
+        SystemState::Booting,
+        SystemState::Calibrating,
+        SystemState::Armed,
+        SystemState::Warning,
+        SystemState::Alert,
+        SystemState::Latched,
+        SystemState::Armed,
+    ];
+    for pair in cycle.windows(2) {
+        assert!(pair[0].can_transition(pair[1]), "{:?}", pair);
+    }
+    assert!(!SystemState::Booting.can_transition(SystemState::Armed));
+    assert!(!SystemState::Alert.can_transition(SystemState::Standby));
+}
+
+#[test]
+fn interlock_only_released_while_armed_or_disabled() {
+    for state in SystemState::ALL {
+        let released = matches!(
+            state,
+            SystemState::Armed | SystemState::Warning | SystemState::Disabled
+        );
+        assert_eq!(state.interlock_tripped(), !released, "{:?}", state);
+    }
+}
@@ -0,0 +1,75 @@
+//! Checks the conversions between ADC codes, sample counts and physical units.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_host_tests::units::{AdcScale, Millis, Millivolts};
+
+/// Sample rate with 2 ms averaging
+const RATE_HZ: u32 = 500;
+
+#[test]
+fn half_scale_is_half_the_reference() {
+    assert_eq!(AdcScale::NOMINAL.millivolts(128), Millivolts(1650));
+    assert_eq!(AdcScale::NOMINAL.millivolts(-128), Millivolts(-1650));
+    assert_eq!(AdcScale::NOMINAL.codes(Millivolts(1650)), 128);
+}
+
+#[test]
+fn codes_round_to_nearest() {
+    // One code is 12.89 mV
+    assert_eq!(AdcScale::NOMINAL.millivolts(1), Millivolts(13));
+    assert_eq!(AdcScale::NOMINAL.codes(Millivolts(6)), 0);
+    assert_eq!(AdcScale::NOMINAL.codes(Millivolts(7)), 1);
+    assert_eq!(AdcScale::NOMINAL.codes(Millivolts(-7)), -1);
+}
+
+#[test]
+fn codes_round_trip() {
+    for codes in -255..=255 {
+        let voltage = AdcScale::NOMINAL.millivolts(codes);
+        assert_eq!(AdcScale::NOMINAL.codes(voltage), codes);
+    }
+}
+
+#[test]
+fn codes_saturate() {
+    assert_eq!(AdcScale::NOMINAL.codes(Millivolts(i32::MAX)), i16::MAX);
+    assert_eq!(AdcScale::NOMINAL.codes(Millivolts(i32::MIN)), i16::MIN);
+}
+
+#[test]
+fn other_scales_convert() {
+    let scale = AdcScale {
+        reference_mv: 3_000,
+        full_scale_codes: 4_096,
+    };
+    assert_eq!(scale.millivolts(2_048), Millivolts(1_500));
+    assert_eq!(scale.codes(Millivolts(3_000)), 4_096);
+}
+
+#[test]
+fn durations_convert_at_the_sample_rate() {
+    assert_eq!(Millis(300).to_samples(RATE_HZ), 150);
+    assert_eq!(Millis::from_samples(150, RATE_HZ), Millis(300));
+    // Rounded to the nearest sample
+    assert_eq!(Millis(3).to_samples(RATE_HZ), 2);
+    assert_eq!(Millis(1).to_samples(RATE_HZ), 1);
+}
+
+#[test]
+fn durations_saturate() {
+    assert_eq!(Millis::from_samples(u64::MAX, RATE_HZ), Millis(u32::MAX));
+    assert_eq!(Millis(u32::MAX).to_samples(RATE_HZ), 1 << 31);
+}
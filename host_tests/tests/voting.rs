@@ -0,0 +1,129 @@
+//! Checks the two-out-of-three vote between channels, and its fallback as channels fault.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use aps490_pfpu2_host_tests::{
+    detection_core::{Event, Phase, Thresholds, SETTLE_SAMPLES},
+    voting::{Mode, Voter, CHANNELS},
+};
+
+/// Samples an alert is held for
+const HOLD: u32 = 20;
+/// Detects contact on a drop of 5, and needs 4 samples restored by 2 to end it
+const THRESHOLDS: Thresholds = Thresholds {
+    trigger_delta: 5,
+    warning_delta: 0,
+    confirm_delta: 1,
+    restore_delta: 2,
+    alert_hold_samples: HOLD,
+    restore_samples: 4,
+};
+
+/// A voter whose channels have all settled at 200
+fn settled() -> Voter {
+    let mut voter = Voter::new(THRESHOLDS);
+    for _ in 0..=SETTLE_SAMPLES {
+        voter.insert([200; CHANNELS]);
+    }
+    assert_eq!(voter.phases(), [Phase::Armed; CHANNELS]);
+    voter
+}
+
+/// Insert `samples` for `len` samples, returning the first vote that isn't [`None`] with the
+/// index of the sample it was raised on, starting from `phase`
+fn vote(
+    voter: &mut Voter,
+    phase: Phase,
+    samples: [u8; CHANNELS],
+    len: usize,
+) -> Option<(usize, Event)> {
+    (0..len).find_map(|idx| {
+        voter.insert(samples);
+        voter.vote(phase, None).map(|event| (idx, event))
+    })
+}
+
+#[test]
+fn one_channel_is_outvoted() {
+    let mut voter = settled();
+    assert_eq!(vote(&mut voter, Phase::Armed, [150, 200, 200], 50), None);
+    assert_eq!(voter.phases()[0], Phase::Alert);
+}
+
+#[test]
+fn two_channels_detect_contact() {
+    let mut voter = settled();
+    // Confirmed on the second sample at 150
+    assert_eq!(
+        vote(&mut voter, Phase::Armed, [150, 200, 150], 50),
+        Some((1, Event::Contact))
+    );
+    assert!(voter
+        .last_detection()
+        .is_some_and(|(_, value)| value == 150));
+}
+
+#[test]
+fn contact_ends_once_one_channel_remains() {
+    let mut voter = settled();
+    vote(&mut voter, Phase::Armed, [150, 150, 150], 2);
+    // One channel restoring leaves two in contact
+    let ended = vote(&mut voter, Phase::Alert, [200, 150, 150], 50);
+    assert_eq!(ended, None);
+    // The second channel is restored for the fourth time on sample 3
+    let ended = vote(&mut voter, Phase::Alert, [200, 200, 150], 50);
+    assert_eq!(ended, Some((3, Event::ContactEnded)));
+}
+
+#[test]
+fn own_contact_is_dropped_and_warnings_kept() {
+    let mut voter = settled();
+    voter.insert([200; CHANNELS]);
+    assert_eq!(voter.vote(Phase::Armed, Some(Event::Contact)), None);
+    assert_eq!(
+        voter.vote(Phase::Armed, Some(Event::Warning)),
+        Some(Event::Warning)
+    );
+    assert_eq!(
+        voter.vote(Phase::Calibrating, Some(Event::Settled)),
+        Some(Event::Settled)
+    );
+}
+
+#[test]
+fn faulted_channel_degrades_to_one_out_of_two() {
+    let mut voter = settled();
+    assert_eq!(voter.mode(), Mode::TwoOutOfThree);
+    assert!(voter.fault(1));
+    assert!(!voter.fault(1));
+    assert_eq!(voter.mode(), Mode::OneOutOfTwo);
+    assert_eq!(voter.reference(), Some(0));
+    // Either healthy channel is now enough, while the faulted one is ignored
+    assert_eq!(vote(&mut voter, Phase::Armed, [200, 150, 200], 50), None);
+    assert_eq!(
+        vote(&mut voter, Phase::Armed, [200, 150, 150], 50),
+        Some((1, Event::Contact))
+    );
+}
+
+#[test]
+fn two_faulted_channels_fail_tripped() {
+    let mut voter = settled();
+    voter.fault(0);
+    voter.fault(2);
+    assert_eq!(voter.mode(), Mode::Failed);
+    assert_eq!(voter.reference(), Some(1));
+    assert!(voter.contact());
+}
@@ -0,0 +1,90 @@
+//! Checks the warning level raised by signal changes below the contact threshold.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod common;
+
+use aps490_pfpu2_host_tests::detection_core::{Event, Thresholds};
+use common::{events, steady};
+
+/// Samples a warning or alert is held for
+const HOLD: u32 = 150;
+/// Warns on a change of 2-4, and detects contact from 5
+const THRESHOLDS: Thresholds = Thresholds {
+    trigger_delta: 5,
+    warning_delta: 2,
+    confirm_delta: 1,
+    restore_delta: 2,
+    alert_hold_samples: HOLD,
+    restore_samples: 0,
+};
+
+#[test]
+fn small_change_warns_until_held() {
+    let mut samples = steady(200, 50);
+    samples.extend(steady(197, 300));
+    assert_eq!(
+        events(THRESHOLDS, &samples),
+        [
+            (50, Event::Warning),
+            (50 + HOLD as usize, Event::WarningEnded)
+        ]
+    );
+}
+
+#[test]
+fn repeated_warnings_extend_the_hold() {
+    let mut samples = steady(200, 50);
+    samples.extend(steady(197, 100));
+    samples.extend(steady(200, 300));
+    assert_eq!(
+        events(THRESHOLDS, &samples),
+        [
+            (50, Event::Warning),
+            (150 + HOLD as usize, Event::WarningEnded)
+        ]
+    );
+}
+
+#[test]
+fn contact_during_warning_alerts() {
+    let mut samples = steady(200, 50);
+    samples.extend(steady(197, 50));
+    samples.extend(steady(150, 10));
+    // The drop on sample 100 is confirmed on 101
+    assert_eq!(
+        events(THRESHOLDS, &samples),
+        [(50, Event::Warning), (101, Event::Contact)]
+    );
+}
+
+#[test]
+fn contact_does_not_warn() {
+    let mut samples = steady(200, 50);
+    samples.extend(steady(150, 10));
+    assert_eq!(events(THRESHOLDS, &samples), [(51, Event::Contact)]);
+}
+
+#[test]
+fn disabled_by_zero_delta() {
+    let thresholds = Thresholds {
+        warning_delta: 0,
+        ..THRESHOLDS
+    };
+    let mut samples = steady(200, 50);
+    samples.extend(steady(197, 50));
+    samples.push(199);
+    assert_eq!(events(thresholds, &samples), []);
+}
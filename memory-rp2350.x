@@ -0,0 +1,50 @@
+MEMORY {
+    /* The image definition in `.start_block` must be in the first 4 KiB, where the boot ROM looks
+     * for it. The RP2350 has no second-stage bootloader. */
+    FLASH   : ORIGIN = 0x10000000, LENGTH = 4096K - 64K
+    /* Persistent configuration, see `storage::STORAGE_OFFSET` */
+    STORAGE : ORIGIN = 0x103F0000, LENGTH = 64K
+    RAM     : ORIGIN = 0x20000000, LENGTH = 512K
+}
+
+/* ### Code run from RAM
+ * As in `memory.x`, hot code on the sampling path is placed in `.data.ram_func`, and copied to
+ * striped SRAM at reset. The flash routines in `storage` also run from there, while XIP is off. */
+
+SECTIONS {
+    /* ### Boot ROM info
+     * Goes after the vector table, to keep it in the first 4 KiB of flash. */
+    .start_block : ALIGN(4)
+    {
+        __start_block_addr = .;
+        KEEP(*(.start_block));
+        KEEP(*(.boot_info));
+    } > FLASH
+} INSERT AFTER .vector_table;
+
+/* Move `.text` to start after the boot info */
+_stext = ADDR(.start_block) + SIZEOF(.start_block);
+
+SECTIONS {
+    /* ### Picotool binary info entries */
+    .bi_entries : ALIGN(4)
+    {
+        __bi_entries_start = .;
+        KEEP(*(.bi_entries));
+        . = ALIGN(4);
+        __bi_entries_end = .;
+    } > FLASH
+} INSERT AFTER .text;
+
+SECTIONS {
+    /* ### Boot ROM extra info
+     * Goes after everything in flash, closing the block loop started in `.start_block`. */
+    .end_block : ALIGN(4)
+    {
+        __end_block_addr = .;
+        KEEP(*(.end_block));
+    } > FLASH
+} INSERT AFTER .uninit;
+
+PROVIDE(start_to_end = __end_block_addr - __start_block_addr);
+PROVIDE(end_to_start = __start_block_addr - __end_block_addr);
@@ -1,11 +1,19 @@
 MEMORY {
-    BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+    BOOT2   : ORIGIN = 0x10000000, LENGTH = 0x100
+    FLASH   : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 64K
+    /* Persistent configuration, see `storage::STORAGE_OFFSET` */
+    STORAGE : ORIGIN = 0x101F0000, LENGTH = 64K
+    RAM     : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
 EXTERN(BOOT2_FIRMWARE)
 
+/* ### Code run from RAM
+ * Hot code on the sampling path is placed in `.data.ram_func` with `#[link_section]`. The runtime
+ * collects `.data.*` into `.data`, which is copied from flash to striped SRAM at reset, so the code
+ * runs without stalling on XIP cache misses. Anything it calls outside that section still runs
+ * from flash. Statics, including the DMA and long-term buffers, are already in striped SRAM. */
+
 SECTIONS {
     /* ### Boot loader */
     .boot2 ORIGIN(BOOT2) :
@@ -0,0 +1,210 @@
+//! Flash driver for the storage region reserved at the end of flash by the firmware's memory map.
+//!
+//! On the RP2040, flash is written with [rp2040-flash](https://docs.rs/rp2040-flash). The RP2350
+//! has no second-stage bootloader to copy back, so sectors are written through its boot ROM from a
+//! RAM function, which saves the XIP read setup left by the boot ROM and restores it afterwards.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::debug;
+use pfpu2_core::protocol::StorageError;
+#[cfg(not(feature = "rp2350"))]
+use rp2040_flash::flash;
+
+/// Address where flash is mapped through XIP
+pub const XIP_BASE: u32 = 0x1000_0000;
+/// Smallest erasable unit of flash
+pub const SECTOR_SIZE: usize = 4096;
+/// Smallest programmable unit of flash
+pub const PAGE_SIZE: usize = 256;
+/// Offset of the storage region from the start of flash, the last 64 KiB of the Pico's 2 MiB. Must
+/// match the `STORAGE` region in the firmware's `memory.x`.
+#[cfg(not(feature = "rp2350"))]
+pub const STORAGE_OFFSET: u32 = 0x001F_0000;
+/// Offset of the storage region from the start of flash, the last 64 KiB of the Pico 2's 4 MiB.
+/// Must match the `STORAGE` region in the firmware's `memory-rp2350.x`.
+#[cfg(feature = "rp2350")]
+pub const STORAGE_OFFSET: u32 = 0x003F_0000;
+/// Size of the storage region
+pub const STORAGE_SIZE: usize = 0x1_0000;
+
+/// Read `len` bytes of flash starting at `offset`.
+pub fn read(offset: u32, len: usize) -> &'static [u8] {
+    // SAFETY: the storage region is always mapped through XIP, and is never used by the linker
+    unsafe { core::slice::from_raw_parts((XIP_BASE + offset) as *const u8, len) }
+}
+
+/// Erase the sector at `offset` and program it with `data`.
+///
+/// Interrupts are disabled for the duration, as nothing may execute from flash while it is being
+/// written. Expect this to take tens of milliseconds.
+pub fn write_sector(offset: u32, data: &[u8]) -> Result<(), StorageError> {
+    if offset < STORAGE_OFFSET
+        || offset as usize > STORAGE_OFFSET as usize + STORAGE_SIZE - SECTOR_SIZE
+        || !(offset as usize).is_multiple_of(SECTOR_SIZE)
+    {
+        return Err(StorageError::Misaligned);
+    } else if data.len() > SECTOR_SIZE {
+        return Err(StorageError::TooLarge);
+    }
+
+    debug!("critical_section: write flash sector {=u32:#x}", offset);
+    #[cfg(not(feature = "rp2350"))]
+    critical_section::with(|_| {
+        // SAFETY: interrupts are disabled and core1 is not executing from flash
+        unsafe { flash::flash_range_erase(offset, SECTOR_SIZE as u32, true) };
+        for (page_idx, chunk) in data.chunks(PAGE_SIZE).enumerate() {
+            let mut page = [0xFFu8; PAGE_SIZE];
+            page[..chunk.len()].copy_from_slice(chunk);
+            unsafe {
+                flash::flash_range_program(offset + (page_idx * PAGE_SIZE) as u32, &page, true)
+            };
+        }
+    });
+    #[cfg(feature = "rp2350")]
+    {
+        // Whole pages are programmed straight from `data`, and the rest from a padded copy, as
+        // nothing may be copied with code in flash once XIP is off
+        let whole = data.len() / PAGE_SIZE * PAGE_SIZE;
+        let mut tail = [0xFFu8; PAGE_SIZE];
+        tail[..data.len() - whole].copy_from_slice(&data[whole..]);
+        let tail = (whole < SECTOR_SIZE).then_some(&tail);
+        let rom = rom::Routines::find();
+        critical_section::with(|_| {
+            // SAFETY: interrupts are disabled, core1 is not executing from flash, and `data` and
+            // `tail` are in RAM
+            unsafe { rom.write(offset, true, &data[..whole], tail) };
+        });
+    }
+    Ok(())
+}
+
+/// Program `pages` at `offset` without erasing, into flash already erased there. Programming can
+/// only clear bits, so any byte already programmed must be given its current value.
+///
+/// Interrupts are disabled for the duration. A page takes under a millisecond, so unlike
+/// [`write_sector`], this fits in the time left once the supply fails.
+pub fn program_pages(offset: u32, pages: &[u8]) -> Result<(), StorageError> {
+    if offset < STORAGE_OFFSET
+        || !(offset as usize).is_multiple_of(PAGE_SIZE)
+        || !pages.len().is_multiple_of(PAGE_SIZE)
+    {
+        return Err(StorageError::Misaligned);
+    } else if (offset as usize).saturating_add(pages.len()) > STORAGE_OFFSET as usize + STORAGE_SIZE
+    {
+        return Err(StorageError::TooLarge);
+    }
+
+    debug!(
+        "critical_section: program {=usize} flash pages at {=u32:#x}",
+        pages.len() / PAGE_SIZE,
+        offset
+    );
+    #[cfg(not(feature = "rp2350"))]
+    critical_section::with(|_| {
+        // SAFETY: interrupts are disabled, core1 is not executing from flash, and `pages` is in RAM
+        unsafe { flash::flash_range_program(offset, pages, true) };
+    });
+    #[cfg(feature = "rp2350")]
+    {
+        let rom = rom::Routines::find();
+        critical_section::with(|_| {
+            // SAFETY: interrupts are disabled, core1 is not executing from flash, and `pages` is in
+            // RAM
+            unsafe { rom.write(offset, false, pages, None) };
+        });
+    }
+    Ok(())
+}
+
+/// Flash routines of the RP2350 boot ROM
+#[cfg(feature = "rp2350")]
+mod rom {
+    use super::{PAGE_SIZE, SECTOR_SIZE};
+    use rp235x_hal::{pac, rom_data};
+
+    /// Serial flash command erasing a 4 KiB sector
+    const SECTOR_ERASE: u8 = 0x20;
+
+    /// Entry points of the boot ROM flash routines. The lookup runs from flash, so they are all
+    /// found before XIP is turned off.
+    pub struct Routines {
+        /// Restore the QSPI pads to the flash interface
+        connect_internal_flash: unsafe extern "C" fn(),
+        /// Leave execute-in-place, so commands can be sent to the flash
+        flash_exit_xip: unsafe extern "C" fn(),
+        /// Erase a range with the given block size and erase command
+        flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+        /// Program a range from a buffer in RAM
+        flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+        /// Invalidate the XIP cache after the flash has changed
+        flash_flush_cache: unsafe extern "C" fn(),
+        /// Return to execute-in-place with a slow generic read
+        flash_enter_cmd_xip: unsafe extern "C" fn(),
+    }
+
+    impl Routines {
+        /// Look up every routine in the boot ROM table
+        pub fn find() -> Self {
+            Self {
+                connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+                flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+                flash_range_erase: rom_data::flash_range_erase::ptr(),
+                flash_range_program: rom_data::flash_range_program::ptr(),
+                flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+                flash_enter_cmd_xip: rom_data::flash_enter_cmd_xip::ptr(),
+            }
+        }
+
+        /// Erase the sector at `offset` if `erase` is set, program `pages` from `offset` and any
+        /// `tail` after them, then restore XIP with the read setup it had before.
+        ///
+        /// # Safety
+        ///
+        /// Interrupts must be disabled, nothing else may execute from flash, and `pages` and `tail`
+        /// must be in RAM. `pages` must be a whole number of pages, leaving room in the sector for
+        /// `tail`.
+        #[inline(never)]
+        #[link_section = ".data.ram_func"]
+        pub unsafe fn write(
+            &self,
+            offset: u32,
+            erase: bool,
+            pages: &[u8],
+            tail: Option<&[u8; PAGE_SIZE]>,
+        ) {
+            let qmi = &*pac::QMI::ptr();
+            let timing = qmi.m0_timing().read().bits();
+            let rcmd = qmi.m0_rcmd().read().bits();
+            let rfmt = qmi.m0_rfmt().read().bits();
+
+            (self.connect_internal_flash)();
+            (self.flash_exit_xip)();
+            if erase {
+                (self.flash_range_erase)(offset, SECTOR_SIZE, SECTOR_SIZE as u32, SECTOR_ERASE);
+            }
+            (self.flash_range_program)(offset, pages.as_ptr(), pages.len());
+            if let Some(tail) = tail {
+                (self.flash_range_program)(offset + pages.len() as u32, tail.as_ptr(), PAGE_SIZE);
+            }
+            (self.flash_flush_cache)();
+            (self.flash_enter_cmd_xip)();
+
+            qmi.m0_timing().write(|w| w.bits(timing));
+            qmi.m0_rcmd().write(|w| w.bits(rcmd));
+            qmi.m0_rfmt().write(|w| w.bits(rfmt));
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Hardware glue shared by the PFPU2 firmware binaries on the RP2040 and RP2350.
+//!
+//! This holds the drivers that only depend on the chip, not on the board or on how the firmware
+//! is scheduled, so they can be reused by each binary of the
+//! [`aps490_pfpu2_mini`](https://docs.rs/aps490_pfpu2_mini) firmware and by any future firmware
+//! for the same chips. Errors are reported with the types in
+//! [`pfpu2_core`], which the companion tools also understand.
+//!
+//! ## Feature flags
+//!
+//! - `rp2350`: Builds for the RP2350 with [rp235x-hal](https://docs.rs/rp235x-hal), rather than
+//!   the RP2040. Needs `--target thumbv8m.main-none-eabihf`.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_std]
+#![warn(missing_docs)]
+
+pub mod flash;
@@ -0,0 +1,337 @@
+//! Live A/B comparison of detection (feature `ab_compare`), for trialling new thresholds or a new
+//! detection [`Algorithm`] in the field without risking a missed detection.
+//!
+//! A [`Candidate`] runs in shadow mode alongside the production [`Detector`] of each probe, over
+//! the same averaged samples, with the thresholds of a [`Preset`]. Only the production detector
+//! drives the state machine and interlock. The candidate's decisions are just compared against
+//! it: whenever one is in contact and the other isn't for [`TOLERANCE_SAMPLES`], the divergence is
+//! logged and added to the [`Tally`]. Once the detectors agree again, it is added to the
+//! [`report`] of the last [`REPORT_SIZE`] divergences.
+//!
+//! The candidate is chosen with the `AB <preset>` console command, and stopped with `AB OFF`. `AB`
+//! on its own prints the tally, and `AB REPORT` the report, as CSV. The candidate only keeps the
+//! last [`CANDIDATE_SIZE`] samples, as detection never looks further back. To qualify another
+//! algorithm, change [`Candidate`] to it.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::{Cell, RefCell};
+
+use critical_section::Mutex;
+use defmt::Format;
+use heapless::Deque;
+
+use crate::{
+    buffer::{ProbeSamples, PROBES},
+    config::DetectionConfig,
+    detection_core::{Algorithm, Detector, Event, Phase, Thresholds},
+    log,
+};
+
+/// Samples kept by each candidate [`Detector`]
+pub const CANDIDATE_SIZE: usize = 64;
+/// Divergences kept for [`report`], enough to fit one console response
+pub const REPORT_SIZE: usize = 6;
+/// Samples the two detectors may disagree for before it counts as a divergence (20 ms with 2 ms
+/// averaging), so a confirmation a sample apart isn't reported
+pub const TOLERANCE_SAMPLES: u64 = 10;
+
+/// Change of candidate waiting to be picked up, where `Some(None)` stops the comparison
+static REQUESTED: Mutex<Cell<Option<Option<Preset>>>> = Mutex::new(Cell::new(None));
+/// Divergences counted since the candidate was chosen
+static TALLY: Mutex<Cell<Tally>> = Mutex::new(Cell::new(Tally::new()));
+/// Most recent divergences that have ended, oldest first
+static REPORT: Mutex<RefCell<Deque<Divergence, REPORT_SIZE>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+/// Algorithm run in shadow mode by the firmware
+pub type Candidate = Detector<CANDIDATE_SIZE>;
+
+/// Thresholds trialled by the candidate detector
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Preset {
+    /// Trigger and confirm a step of one count less than the production configuration
+    Sensitive,
+    /// The factory defaults from `board.toml`, to check a site's own configuration against them
+    Standard,
+    /// Trigger and confirm a step of one count more than the production configuration
+    Tolerant,
+}
+
+impl Preset {
+    /// Every preset
+    pub const ALL: [Preset; 3] = [Preset::Sensitive, Preset::Standard, Preset::Tolerant];
+
+    /// Console name of the preset
+    pub const fn name(self) -> &'static str {
+        match self {
+            Preset::Sensitive => "SENSITIVE",
+            Preset::Standard => "STANDARD",
+            Preset::Tolerant => "TOLERANT",
+        }
+    }
+
+    /// Preset called `name`, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|preset| preset.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Candidate thresholds, derived from the `production` configuration
+    pub fn thresholds(self, production: &DetectionConfig) -> Thresholds {
+        let mut thresholds = production.thresholds();
+        match self {
+            Preset::Sensitive => {
+                thresholds.trigger_delta = (thresholds.trigger_delta - 1).max(1);
+                thresholds.confirm_delta = (thresholds.confirm_delta - 1).max(1);
+            }
+            Preset::Standard => thresholds = DetectionConfig::DEFAULT.thresholds(),
+            Preset::Tolerant => {
+                thresholds.trigger_delta += 1;
+                thresholds.confirm_delta += 1;
+            }
+        }
+        thresholds
+    }
+}
+
+/// Divergences between the candidate and production detectors
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub struct Tally {
+    /// Candidate being trialled, or [`None`] if the comparison is stopped
+    pub preset: Option<Preset>,
+    /// Times the candidate detected contact that production didn't
+    pub candidate_only: u32,
+    /// Times production detected contact that the candidate missed
+    pub production_only: u32,
+}
+
+impl Tally {
+    /// No candidate, and nothing counted
+    const fn new() -> Self {
+        Self {
+            preset: None,
+            candidate_only: 0,
+            production_only: 0,
+        }
+    }
+}
+
+/// Trial `preset` as the candidate, or stop the comparison if [`None`]. Taken up on the next
+/// sample.
+pub fn request(preset: Option<Preset>) {
+    critical_section::with(|cs| REQUESTED.borrow(cs).set(Some(preset)));
+}
+
+/// Candidate being trialled, and the divergences counted since it was chosen
+pub fn tally() -> Tally {
+    critical_section::with(|cs| TALLY.borrow(cs).get())
+}
+
+/// The last [`REPORT_SIZE`] divergences that have ended since the candidate was chosen, oldest
+/// first
+pub fn report() -> Deque<Divergence, REPORT_SIZE> {
+    critical_section::with(|cs| REPORT.borrow_ref(cs).clone())
+}
+
+/// A disagreement between the detectors that lasted at least [`TOLERANCE_SAMPLES`]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub struct Divergence {
+    /// Probe the detectors disagreed on
+    pub probe: usize,
+    /// Sample on which they started to disagree
+    pub since: u64,
+    /// Samples until they agreed again
+    pub samples: u64,
+    /// Whether the candidate was the one in contact
+    pub candidate_contact: bool,
+}
+
+/// A disagreement on one probe that hasn't ended yet
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+struct Open {
+    /// Sample on which the detectors started to disagree
+    since: u64,
+    /// Whether the candidate is the one in contact
+    candidate_contact: bool,
+    /// Whether it has lasted [`TOLERANCE_SAMPLES`], and been logged
+    reported: bool,
+}
+
+/// Candidate algorithm for each probe, compared against production after every sample
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Comparison<A = Candidate> {
+    /// Candidate being trialled, or [`None`] if stopped
+    preset: Option<Preset>,
+    /// Candidate algorithm of each probe
+    candidates: [A; PROBES],
+    /// Phase of each candidate, following its own events
+    phases: [Phase; PROBES],
+    /// Current disagreement on each probe, if any
+    diverged: [Option<Open>; PROBES],
+}
+
+impl<A: Algorithm> Comparison<A> {
+    /// Create a stopped comparison of `candidates`, one per probe
+    pub fn new(candidates: [A; PROBES]) -> Self {
+        Self {
+            preset: None,
+            candidates,
+            phases: [Phase::Armed; PROBES],
+            diverged: [None; PROBES],
+        }
+    }
+
+    /// Derive the candidate thresholds again after the `production` configuration changed
+    pub fn set_production(&mut self, production: &DetectionConfig) {
+        if let Some(preset) = self.preset {
+            for candidate in self.candidates.iter_mut() {
+                candidate.set_thresholds(preset.thresholds(production));
+            }
+        }
+    }
+
+    /// Insert a new sample from each probe into the candidates
+    pub fn insert(&mut self, samples: ProbeSamples) {
+        for (candidate, sample) in self.candidates.iter_mut().zip(samples) {
+            candidate.insert(sample);
+        }
+    }
+
+    /// Step the candidates on the sample numbered `sample`, and compare them to the `production`
+    /// phase of each probe after it was stepped. Probes that aren't armed, including while
+    /// production calibrates, aren't compared.
+    pub fn step(
+        &mut self,
+        production: [Option<Phase>; PROBES],
+        sample: u64,
+        config: &DetectionConfig,
+    ) {
+        if let Some(preset) = critical_section::with(|cs| REQUESTED.borrow(cs).take()) {
+            self.select(preset, config);
+        }
+        if self.preset.is_none() {
+            return;
+        }
+
+        for (probe, production) in production.into_iter().enumerate() {
+            let production = match production {
+                None | Some(Phase::Calibrating) => {
+                    self.phases[probe] = Phase::Armed;
+                    self.diverged[probe] = None;
+                    continue;
+                }
+                Some(phase) => phase,
+            };
+            self.phases[probe] = match self.candidates[probe].step(self.phases[probe]) {
+                Some(Event::Contact) => Phase::Alert,
+                Some(Event::Warning) => Phase::Warning,
+                Some(Event::Settled | Event::ContactEnded | Event::WarningEnded) => Phase::Armed,
+                None => self.phases[probe],
+            };
+            let candidate_contact = self.phases[probe] == Phase::Alert;
+            self.compare(probe, candidate_contact, production == Phase::Alert, sample);
+        }
+    }
+
+    /// Start trialling `preset`, or stop if [`None`], clearing the tally and report
+    fn select(&mut self, preset: Option<Preset>, config: &DetectionConfig) {
+        self.preset = preset;
+        self.phases = [Phase::Armed; PROBES];
+        self.diverged = [None; PROBES];
+        self.set_production(config);
+        critical_section::with(|cs| {
+            TALLY.borrow(cs).set(Tally {
+                preset,
+                ..Tally::new()
+            });
+            REPORT.borrow_ref_mut(cs).clear();
+        });
+        match preset {
+            Some(preset) => log!(Detection, info, "A/B comparison against {}", preset),
+            None => log!(Detection, info, "A/B comparison stopped"),
+        }
+    }
+
+    /// Track whether the detectors of `probe` agree on contact as of `sample`
+    fn compare(
+        &mut self,
+        probe: usize,
+        candidate_contact: bool,
+        production_contact: bool,
+        sample: u64,
+    ) {
+        let diverged = &mut self.diverged[probe];
+        match diverged {
+            _ if candidate_contact == production_contact => {
+                if let Some(open) = diverged.take().filter(|open| open.reported) {
+                    let divergence = Divergence {
+                        probe,
+                        since: open.since,
+                        samples: sample - open.since,
+                        candidate_contact: open.candidate_contact,
+                    };
+                    log!(
+                        Detection,
+                        info,
+                        "A/B: detectors agree again on probe {=usize} after {=u64} samples",
+                        probe,
+                        divergence.samples
+                    );
+                    critical_section::with(|cs| {
+                        let mut report = REPORT.borrow_ref_mut(cs);
+                        if report.is_full() {
+                            report.pop_front();
+                        }
+                        let _ = report.push_back(divergence);
+                    });
+                }
+            }
+            None => {
+                *diverged = Some(Open {
+                    since: sample,
+                    candidate_contact,
+                    reported: false,
+                })
+            }
+            Some(open) if !open.reported && sample - open.since >= TOLERANCE_SAMPLES => {
+                open.reported = true;
+                let candidate = open.candidate_contact;
+                log!(
+                    Detection,
+                    warn,
+                    "A/B: {=str} detected contact on probe {=usize} from sample {=u64}, {=str} \
+                     didn't",
+                    if candidate { "candidate" } else { "production" },
+                    probe,
+                    open.since,
+                    if candidate { "production" } else { "candidate" }
+                );
+                critical_section::with(|cs| {
+                    let tally = TALLY.borrow(cs);
+                    let mut counts = tally.get();
+                    if candidate {
+                        counts.candidate_only = counts.candidate_only.saturating_add(1);
+                    } else {
+                        counts.production_only = counts.production_only.saturating_add(1);
+                    }
+                    tally.set(counts);
+                });
+            }
+            Some(_) => {}
+        }
+    }
+}
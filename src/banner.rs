@@ -0,0 +1,281 @@
+//! Boot banner describing the firmware, board and configuration, so every captured log is
+//! self-describing.
+//!
+//! The banner is logged over defmt at boot by [`report`], and written to the console by
+//! [`interrupt::poll_console`](crate::interrupt::poll_console) each time a host opens the port.
+//! It is one block of `key value` lines, starting with `PFPU2 BANNER` and ending with the CRC-32
+//! (as used by [`storage::crc32`]) of every byte after the first line, like an
+//! [`export`](crate::export):
+//!
+//! ```text
+//! PFPU2 BANNER
+//! firmware 0.1.0
+//! commit 3f2a91c04d7e
+//! built 2024-03-28T17:02:11Z
+//! features triple_status,persist_panic
+//! board proto-v2
+//! unit PFPU2-0007 rev B
+//! clock_hz 125000000
+//! config 5D0C1E77
+//! detection.trigger_delta 128
+//! ...
+//! buffers.avg 200
+//! ...
+//! pins.status_leds 2,3,4
+//! ...
+//! time_us 81234567
+//! CRC32 8A431392
+//! ```
+//!
+//! Keys are never renamed or reused, and new ones are only added before `time_us`, so older host
+//! tools keep parsing it. Detection settings are named as in `board.toml`, and millivolt
+//! thresholds are only listed when they are set. `time_us` is the [time](scheduler::now_us) the
+//! banner was written, which lines the defmt timestamps and the journal up with the host's
+//! capture.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt::{self, Write};
+
+use defmt::info;
+use embedded_hal::digital::PinState;
+use heapless::String;
+
+#[cfg(feature = "i2c_scan")]
+use crate::config::board::I2C_EXPECTED;
+#[cfg(not(feature = "minimal"))]
+use crate::provision;
+use crate::{
+    buffer::{
+        ADC_CHANNELS, AVG_BUFFER_SIZE, DMA_BUFFER_SIZE, LONGTERM_SIZE, PROBES, SAMPLE_RATE_HZ,
+    },
+    config::{
+        board::{
+            ACK_BUTTON_PIN, BLANKING_PIN, BOARD_VARIANT, BUZZER_PIN, CLOCK_PROFILE,
+            CONFIG_JUMPER_PIN, CORRELATION_PIN, DISABLE_SWITCH_PIN, INTERLOCK_OPEN_DRAIN,
+            INTERLOCK_PINS, INTERLOCK_TRIPPED_LEVEL, POWER_GOOD_PIN, PRESENCE_PIN, PROBE_LED_PINS,
+            STATUS_LED_PINS, TEST_INJECT_PIN, TRIGGER_PIN,
+        },
+        Config,
+    },
+    scheduler, storage, version,
+};
+
+/// First line of the banner
+const FIRST_LINE: &str = "PFPU2 BANNER\r\n";
+/// Longest banner line logged over defmt, longer ones are cut short
+const LOG_LINE_SIZE: usize = 256;
+
+/// Log the banner over defmt, for the active `config`. Call once during startup.
+pub fn report(config: &Config) {
+    let mut logger = Logger {
+        line: String::new(),
+    };
+    let _ = write(config, &mut logger);
+}
+
+/// Write the banner to `out`, for the active `config`
+pub fn write(config: &Config, out: &mut impl Write) -> fmt::Result {
+    out.write_str(FIRST_LINE)?;
+    let mut block = Checksummed {
+        out: &mut *out,
+        crc: u32::MAX,
+    };
+    write_fields(config, &mut block)?;
+    let crc = !block.crc;
+    write!(out, "CRC32 {:08X}\r\n", crc)
+}
+
+/// Write every line between the first and the CRC
+fn write_fields(config: &Config, out: &mut impl Write) -> fmt::Result {
+    #[cfg(not(feature = "minimal"))]
+    let provisioning = provision::get();
+    #[cfg(not(feature = "minimal"))]
+    let (serial, revision) = (provisioning.serial(), provisioning.revision());
+    // Not provisioned, as it has no console
+    #[cfg(feature = "minimal")]
+    let (serial, revision) = ("-", "-");
+    write!(
+        out,
+        "firmware {}\r\ncommit {}\r\nbuilt {}\r\nfeatures ",
+        version::VERSION,
+        version::GIT_HASH,
+        version::BUILD_TIME
+    )?;
+    version::write_features(out)?;
+    write!(
+        out,
+        "\r\nboard {}\r\nunit {} rev {}\r\nclock_hz {}\r\nconfig {:08X}\r\n",
+        BOARD_VARIANT,
+        serial,
+        revision,
+        CLOCK_PROFILE.sys_freq_hz(),
+        version::config_hash(config)
+    )?;
+
+    let detection = &config.detection;
+    let settings: [(&str, i64); 19] = [
+        ("trigger_delta", detection.trigger_delta.into()),
+        ("warning_delta", detection.warning_delta.into()),
+        ("confirm_delta", detection.confirm_delta.into()),
+        ("restore_delta", detection.restore_delta.into()),
+        ("alert_hold_samples", detection.alert_hold_samples.into()),
+        (
+            "restore_fast_samples",
+            detection.restore_fast_samples.into(),
+        ),
+        (
+            "restore_slow_samples",
+            detection.restore_slow_samples.into(),
+        ),
+        ("auto_clear_ms", detection.auto_clear_ms.into()),
+        ("min_trip_ms", detection.min_trip_ms.into()),
+        ("enable_delay_ms", detection.enable_delay_ms.into()),
+        ("cooldown_ms", detection.cooldown_ms.into()),
+        ("snooze_ms", detection.snooze_ms.into()),
+        ("arming_delay_ms", detection.arming_delay_ms.into()),
+        ("proof_test_hours", detection.proof_test_hours.into()),
+        ("auto_zero_tau_s", detection.auto_zero_tau_s.into()),
+        ("plausible_min", detection.plausible_min.into()),
+        ("plausible_max", detection.plausible_max.into()),
+        ("implausible_samples", detection.implausible_samples.into()),
+        ("near_miss_percent", detection.near_miss_percent.into()),
+    ];
+    for (key, value) in settings {
+        write!(out, "detection.{} {}\r\n", key, value)?;
+    }
+    write!(
+        out,
+        "detection.restore_profile {}\r\ndetection.latch_alert {}\r\n",
+        detection.restore_profile.name(),
+        detection.latch_alert
+    )?;
+    if let Some(millivolts) = detection.millivolts {
+        write!(
+            out,
+            "detection.trigger_mv {}\r\ndetection.warning_mv {}\r\n\
+             detection.confirm_mv {}\r\ndetection.restore_mv {}\r\n",
+            millivolts.trigger_mv,
+            millivolts.warning_mv,
+            millivolts.confirm_mv,
+            millivolts.restore_mv
+        )?;
+    }
+
+    write!(
+        out,
+        "buffers.avg {}\r\nbuffers.longterm {}\r\nbuffers.dma {}\r\n\
+         probes {}\r\nadc_channels {}\r\nsample_rate_hz {}\r\n",
+        AVG_BUFFER_SIZE, LONGTERM_SIZE, DMA_BUFFER_SIZE, PROBES, ADC_CHANNELS, SAMPLE_RATE_HZ
+    )?;
+
+    write!(out, "pins.status_leds ")?;
+    write_list(out, &STATUS_LED_PINS)?;
+    write!(out, "\r\npins.interlocks ")?;
+    write_list(out, &INTERLOCK_PINS[..PROBES])?;
+    write!(out, "\r\npins.probe_leds ")?;
+    write_list(out, &PROBE_LED_PINS[..PROBES])?;
+    write!(
+        out,
+        "\r\ninterlock.tripped_level {}\r\ninterlock.drive {}\r\n",
+        match INTERLOCK_TRIPPED_LEVEL {
+            PinState::High => "high",
+            PinState::Low => "low",
+        },
+        if INTERLOCK_OPEN_DRAIN {
+            "open_drain"
+        } else {
+            "push_pull"
+        }
+    )?;
+    let pins = [
+        ("buzzer", BUZZER_PIN),
+        ("ack_button", ACK_BUTTON_PIN),
+        ("disable_switch", DISABLE_SWITCH_PIN),
+        ("test_inject", TEST_INJECT_PIN),
+        ("power_good", POWER_GOOD_PIN),
+        ("config_jumper", CONFIG_JUMPER_PIN),
+        ("presence", PRESENCE_PIN),
+        ("blanking", BLANKING_PIN),
+        ("correlation", CORRELATION_PIN),
+        ("trigger", TRIGGER_PIN),
+    ];
+    for (key, pin) in pins {
+        write!(out, "pins.{} {}\r\n", key, pin)?;
+    }
+    #[cfg(feature = "i2c_scan")]
+    {
+        write!(out, "i2c.expected ")?;
+        for (idx, (address, name)) in I2C_EXPECTED.iter().enumerate() {
+            if idx > 0 {
+                out.write_char(',')?;
+            }
+            write!(out, "{:#04x}:{}", address, name)?;
+        }
+        write!(out, "\r\n")?;
+    }
+    write!(out, "time_us {}\r\n", scheduler::now_us())
+}
+
+/// Write `values` to `out`, separated by commas
+fn write_list(out: &mut impl Write, values: &[u8]) -> fmt::Result {
+    for (idx, value) in values.iter().enumerate() {
+        if idx > 0 {
+            out.write_char(',')?;
+        }
+        write!(out, "{}", value)?;
+    }
+    Ok(())
+}
+
+/// Passes writes on to `out`, keeping the CRC-32 of everything written
+struct Checksummed<'a, W: Write> {
+    /// Destination of the writes
+    out: &'a mut W,
+    /// CRC-32 so far, see [`storage::crc32_update`]
+    crc: u32,
+}
+
+impl<W: Write> Write for Checksummed<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.crc = storage::crc32_update(self.crc, s.as_bytes());
+        self.out.write_str(s)
+    }
+}
+
+/// Logs each line written to it over defmt
+struct Logger {
+    /// Line written so far, without its line ending
+    line: String<LOG_LINE_SIZE>,
+}
+
+impl Write for Logger {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '\r' => {}
+                '\n' => {
+                    info!("{=str}", self.line.as_str());
+                    self.line.clear();
+                }
+                // Too long a line is cut short, rather than ending the banner
+                _ => {
+                    let _ = self.line.push(c);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! Alternate binary, which splits the firmware across both cores (see
+//! [`multicore`](aps490_pfpu2_mini::multicore)).
+//!
+//! Core0 runs RTIC with the acquisition tasks only, and core1 runs the detection loop.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_std]
+#![no_main]
+#![warn(missing_docs)]
+#![cfg_attr(docsrs, feature(doc_cfg), feature(doc_auto_cfg), feature(doc_cfg_hide))]
+
+#[cfg(feature = "rp2350")]
+use aps490_pfpu2_mini::hal::block::ImageDef;
+#[allow(unused_imports)]
+use defmt_rtt as _;
+#[cfg(not(feature = "persist_panic"))]
+#[allow(unused_imports)]
+use panic_probe as _;
+
+/// Second-stage bootloader, from [rp2040-boot2](https://docs.rs/rp2040-boot2)
+#[cfg(not(feature = "rp2350"))]
+#[link_section = ".boot2"]
+#[used]
+pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
+/// Image definition, which the RP2350 boot ROM looks for to run the firmware as a secure Arm
+/// executable
+#[cfg(feature = "rp2350")]
+#[link_section = ".start_block"]
+#[used]
+pub static IMAGE_DEF: ImageDef = ImageDef::secure_exe();
+
+/// RTIC application on core0. Tasks run at the following priorities:
+///
+/// 3. `DMA_IRQ_0`: averaging, sending samples to core1
+/// 1. `SIO_IRQ_PROC0`: pause/resume commands from core1
+#[rtic::app(device = aps490_pfpu2_mini::hal::pac, peripherals = true)]
+mod app {
+    use aps490_pfpu2_mini::{
+        board::Board,
+        hal::{
+            multicore::{Multicore, Stack},
+            pac,
+            sio::SioFifo,
+            Sio,
+        },
+        irq::{self, Isr},
+        multicore::{self, Core1, RemoteSampler, CORE1_STACK_WORDS},
+        power, reset,
+        sampler::Sampler,
+    };
+    use defmt::info;
+
+    // Task priorities must match the table in `irq`
+    const _: () = assert!(Isr::IoIrqBank0.priority() == 4);
+    const _: () = assert!(Isr::DmaIrq0.priority() == 3);
+    const _: () = assert!(Isr::SioIrqProc0.priority() == 1);
+
+    /// Resources shared between tasks
+    #[shared]
+    struct Shared {
+        /// Signal generator and ADC transfer
+        sampler: Sampler,
+        /// Core0 end of the SIO FIFO
+        fifo: SioFifo,
+    }
+
+    /// Resources owned by a single task
+    #[local]
+    struct Local {}
+
+    /// System initialization, then starts core1
+    #[init(local = [core1_stack: Stack<CORE1_STACK_WORDS> = Stack::new()])]
+    fn init(cx: init::Context) -> (Shared, Local) {
+        info!("Detection system startup (dual core)");
+        reset::decode();
+        let Board {
+            mut sampler,
+            buffers,
+            mut state,
+            indicators,
+            config,
+            self_test,
+            #[cfg(feature = "disable_switch")]
+            disable_switch,
+            ack_button,
+            injector,
+            usb_dev,
+            usb_serial,
+            mut sio_fifo,
+            mut psm,
+            mut ppb,
+            ..
+        } = Board::init(cx.device).unwrap();
+
+        // Begin normal system operation, then hand detection over to core1
+        state.begin(&mut sampler, self_test);
+        let core1_resources = Core1 {
+            buffers,
+            state,
+            indicators,
+            config,
+            usb_dev,
+            usb_serial,
+            #[cfg(feature = "disable_switch")]
+            disable_switch,
+            ack_button,
+            injector,
+        };
+        let mut mc = Multicore::new(&mut psm, &mut ppb, &mut sio_fifo);
+        let cores = mc.cores();
+        cores[1]
+            .spawn(&mut cx.local.core1_stack.mem, move || {
+                // SAFETY: core1 only uses its own end of the SIO FIFO
+                let pac = unsafe { pac::Peripherals::steal() };
+                let sio = Sio::new(pac.SIO);
+                core1_resources.run(RemoteSampler::new(sio.fifo))
+            })
+            .expect("Unable to start core1");
+
+        (
+            Shared {
+                sampler,
+                fifo: sio_fifo,
+            },
+            Local {},
+        )
+    }
+
+    /// All functionality in tasks, and on core1
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+
+    /// Trips the interlock as soon as the supply starts to fail. Core1 latches the error.
+    #[task(binds = IO_IRQ_BANK0, priority = 4)]
+    fn io_irq_bank0(_: io_irq_bank0::Context) {
+        irq::timed(Isr::IoIrqBank0, power::on_power_fail);
+    }
+
+    /// Reads ADC values, calculates averages and sends them to core1
+    #[task(binds = DMA_IRQ_0, priority = 3, shared = [sampler, fifo])]
+    fn dma_irq_0(cx: dma_irq_0::Context) {
+        irq::timed(Isr::DmaIrq0, || {
+            (cx.shared.sampler, cx.shared.fifo).lock(multicore::send_readings)
+        });
+    }
+
+    /// Pauses or resumes the sampler when requested by core1
+    #[task(binds = SIO_IRQ_PROC0, priority = 1, shared = [sampler, fifo])]
+    fn sio_irq_proc0(cx: sio_irq_proc0::Context) {
+        irq::timed(Isr::SioIrqProc0, || {
+            (cx.shared.sampler, cx.shared.fifo).lock(multicore::receive_commands)
+        });
+    }
+}
@@ -0,0 +1,380 @@
+//! Alternate binary running the firmware as async tasks on the Embassy executor.
+//!
+//! Interrupt handlers only mask themselves and wake the task that services them, so all detection
+//! logic runs in thread mode, shared with the RTIC binary through [`interrupt`](handlers):
+//!
+//! - `sampling`: completes each ADC transfer and queues the averaged sample
+//! - `detection`: records samples, checks for contact and updates the [`SystemState`]
+//! - `disable_switch`: polls the disable switch every 20 ms (requires feature `disable_switch`)
+//! - `usb_console`: serves the USB console
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_std]
+#![no_main]
+#![warn(missing_docs)]
+
+use core::{cell::RefCell, iter};
+
+#[cfg(feature = "rp2350")]
+use aps490_pfpu2_mini::hal::block::ImageDef;
+#[cfg(feature = "disable_switch")]
+use aps490_pfpu2_mini::interrupt::DisableSwitch;
+use aps490_pfpu2_mini::{
+    board::{Board, TimerBlock},
+    buffer::Buffers,
+    capture::Capture,
+    config::Config,
+    console::{Console, ConsoleBackend},
+    deadline::{Instant, Stamped},
+    error::Result,
+    event_log, events,
+    hal::pac::{self, interrupt, Interrupt, NVIC},
+    injection::Injector,
+    interrupt::{self as handlers, AckButton, ConsoleSerial, ConsoleUsbDevice, QueuedSample},
+    irq,
+    journal::Kind,
+    power, reset,
+    sampler::Sampler,
+    scheduler::STATS_PERIOD_MS,
+    stack,
+    state::{StateMachine, SystemIndicators, SystemState},
+    uptime,
+    watch::{self, Snapshot},
+};
+use critical_section::Mutex;
+use defmt::info;
+#[allow(unused_imports)]
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+#[cfg(not(feature = "persist_panic"))]
+#[allow(unused_imports)]
+use panic_probe as _;
+#[cfg(not(feature = "rp2350"))]
+use rtic_monotonics::rp2040::prelude::*;
+#[cfg(feature = "rp2350")]
+use rtic_monotonics::rp235x::prelude::*;
+
+/// Second-stage bootloader, from [rp2040-boot2](https://docs.rs/rp2040-boot2)
+#[cfg(not(feature = "rp2350"))]
+#[link_section = ".boot2"]
+#[used]
+pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
+/// Image definition, which the RP2350 boot ROM looks for to run the firmware as a secure Arm
+/// executable
+#[cfg(feature = "rp2350")]
+#[link_section = ".start_block"]
+#[used]
+pub static IMAGE_DEF: ImageDef = ImageDef::secure_exe();
+
+#[cfg(not(feature = "rp2350"))]
+rp2040_timer_monotonic!(Mono);
+#[cfg(feature = "rp2350")]
+rp235x_timer_monotonic!(Mono);
+
+/// Resources used for detection, shared between tasks
+struct Detection {
+    /// Signal generator and ADC transfer
+    sampler: Sampler,
+    /// Buffers for analyzing readings
+    buffers: &'static mut Buffers,
+    /// System state and interlock
+    state: StateMachine,
+}
+
+/// Detection resources, locked by each task
+static DETECTION: Mutex<RefCell<Option<Detection>>> = Mutex::new(RefCell::new(None));
+/// Active configuration, loaded from flash at startup
+static CONFIG: Mutex<RefCell<Config>> = Mutex::new(RefCell::new(Config::DEFAULT));
+
+/// Raised by `DMA_IRQ_0` when a transfer completes, with the time of the interrupt
+static READINGS_READY: Signal<CriticalSectionRawMutex, Instant> = Signal::new();
+/// Raised by `USBCTRL_IRQ` when the USB device needs servicing
+static USB_READY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Averaged samples waiting for detection, or the error raised while collecting them
+static SAMPLES: Channel<CriticalSectionRawMutex, QueuedSample, 8> = Channel::new();
+
+/// Run `f` with the detection resources
+fn with_detection<R>(f: impl FnOnce(&mut Detection) -> R) -> R {
+    critical_section::with(|cs| {
+        f(DETECTION
+            .borrow_ref_mut(cs)
+            .as_mut()
+            .expect("Detection resources not initialized"))
+    })
+}
+
+/// System initialization, then spawns the tasks
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("Detection system startup (Embassy)");
+    reset::decode();
+    let Board {
+        mut sampler,
+        buffers,
+        mut state,
+        indicators,
+        config,
+        self_test,
+        #[cfg(feature = "disable_switch")]
+        disable_switch,
+        ack_button,
+        injector,
+        usb_dev,
+        usb_serial,
+        resets,
+        ..
+    } = Board::init(pac::Peripherals::take().unwrap()).unwrap();
+    // SAFETY: the HAL `Timer` in `Board` is never used by this binary, so the monotonic has sole
+    // use of the alarms
+    Mono::start(unsafe { TimerBlock::steal() }, &resets);
+    // The executor leaves every interrupt at the same priority
+    irq::set_priorities(&mut pac::CorePeripherals::take().unwrap().NVIC);
+
+    // Begin normal system operation
+    state.begin(&mut sampler, self_test);
+    critical_section::with(|cs| {
+        CONFIG.replace(cs, config);
+        DETECTION.replace(
+            cs,
+            Some(Detection {
+                sampler,
+                buffers,
+                state,
+            }),
+        );
+    });
+
+    spawner.must_spawn(sampling());
+    spawner.must_spawn(detection(injector));
+    spawner.must_spawn(stack_watch());
+    spawner.must_spawn(update_indicators(indicators));
+    spawner.must_spawn(usb_console(usb_dev, usb_serial));
+    #[cfg(feature = "disable_switch")]
+    spawner.must_spawn(disable_switch_poll(disable_switch));
+    spawner.must_spawn(ack_button_poll(ack_button));
+
+    unsafe {
+        NVIC::unmask(Interrupt::IO_IRQ_BANK0);
+        NVIC::unmask(Interrupt::DMA_IRQ_0);
+        NVIC::unmask(Interrupt::USBCTRL_IRQ);
+    }
+}
+
+/// Completes each ADC transfer, and queues the averaged sample for [`detection`]
+#[embassy_executor::task]
+async fn sampling() {
+    loop {
+        let completed = READINGS_READY.wait().await;
+        let sample_avg = with_detection(|d| d.sampler.complete_transfer());
+        // The transfer has been acknowledged, so the next one can interrupt
+        unsafe { NVIC::unmask(Interrupt::DMA_IRQ_0) }
+        SAMPLES
+            .send(Stamped {
+                value: sample_avg,
+                completed,
+            })
+            .await;
+    }
+}
+
+/// Records averaged samples, checks for contact and updates the [`SystemState`], then checks any
+/// test injection
+#[embassy_executor::task]
+async fn detection(mut injector: Injector) {
+    loop {
+        let first = SAMPLES.receive().await;
+        // Take everything already queued, so the critical section is entered once per wakeup
+        let pending = SAMPLES.len();
+        with_detection(|d| {
+            let samples = iter::once(first)
+                .chain(iter::from_fn(|| SAMPLES.try_receive().ok()))
+                .take(pending + 1);
+            handlers::process_batch(samples, &mut d.sampler, d.buffers, &mut d.state);
+            injector
+                .poll(d.state.state(), d.buffers)
+                .unwrap_or_else(|err| d.state.fail(&mut d.sampler, err))
+        });
+        watch::wake();
+    }
+}
+
+/// Logs the stack headroom and [`uptime`] statistics every [`STATS_PERIOD_MS`], and flushes the
+/// [`event_log`] while sampling is stopped. Latches an error if the stack runs low.
+#[embassy_executor::task]
+async fn stack_watch() {
+    loop {
+        Mono::delay(u64::from(STATS_PERIOD_MS).millis()).await;
+        let current = with_detection(|d| d.state.state());
+        uptime::report(current);
+        event_log::flush(current);
+        stack::check().unwrap_or_else(|err| with_detection(|d| d.state.fail(&mut d.sampler, err)));
+    }
+}
+
+/// Logs state changes and updates the LEDs and buzzer every 10 ms, outside the detection lock.
+/// In [`SystemState::Standby`], leaves the chip [dormant](power::dormant) until woken.
+#[embassy_executor::task]
+async fn update_indicators(mut indicators: SystemIndicators) {
+    loop {
+        Mono::delay(10.millis()).await;
+        let current = with_detection(|d| d.state.state());
+        indicators.update(current);
+        events::dispatch();
+        if current == SystemState::Standby {
+            uptime::report(current);
+            event_log::flush(current);
+            let woken_by = power::dormant(power::WAKE_PINS);
+            event_log::operator(Kind::Wake, power::wake_source(woken_by));
+            with_detection(|d| {
+                d.state
+                    .transition(
+                        SystemState::Calibrating,
+                        &mut d.sampler,
+                        "Woke from standby",
+                    )
+                    .unwrap_or_else(|err| d.state.fail(&mut d.sampler, err))
+            });
+        }
+    }
+}
+
+/// Polls the disable switch every 20 ms
+#[cfg(feature = "disable_switch")]
+#[embassy_executor::task]
+async fn disable_switch_poll(mut switch: DisableSwitch) {
+    loop {
+        Mono::delay(20.millis()).await;
+        with_detection(|d| {
+            handlers::check_disable_switch(&mut switch, &mut d.sampler, &mut d.state)
+                .unwrap_or_else(|err| d.state.fail(&mut d.sampler, err))
+        });
+    }
+}
+
+/// Polls the acknowledge button and the operator's presence every 20 ms, latches any power
+/// failure, and wakes the console while a `WATCH` is running
+#[embassy_executor::task]
+async fn ack_button_poll(mut button: AckButton) {
+    loop {
+        Mono::delay(20.millis()).await;
+        let (snooze_ms, timing) = critical_section::with(|cs| {
+            let detection = CONFIG.borrow_ref(cs).detection;
+            (detection.snooze_ms, detection.sequence_timing())
+        });
+        with_detection(|d| {
+            handlers::check_power_good(&d.state)
+                .and_then(|()| handlers::check_stretch(&mut d.state))
+                .and_then(|()| handlers::check_sequence(&mut d.state, timing))
+                .and_then(|()| handlers::check_presence(&mut d.sampler, &mut d.state))
+                .and_then(|()| {
+                    handlers::check_ack_button(
+                        &mut button,
+                        20,
+                        snooze_ms,
+                        &mut d.sampler,
+                        &mut d.state,
+                    )
+                })
+                .unwrap_or_else(|err| d.state.fail(&mut d.sampler, err))
+        });
+    }
+}
+
+/// Serves the USB console
+#[embassy_executor::task]
+async fn usb_console(mut usb_dev: ConsoleUsbDevice, mut usb_serial: ConsoleSerial) {
+    let mut console = Console::new();
+    loop {
+        USB_READY.wait().await;
+        handlers::poll_console(
+            &mut usb_dev,
+            &mut usb_serial,
+            &mut console,
+            &mut StaticBackend,
+        );
+        unsafe { NVIC::unmask(Interrupt::USBCTRL_IRQ) }
+    }
+}
+
+/// [`ConsoleBackend`] for the statics shared by the tasks
+struct StaticBackend;
+
+impl ConsoleBackend for StaticBackend {
+    fn config(&mut self) -> Config {
+        critical_section::with(|cs| *CONFIG.borrow_ref(cs))
+    }
+
+    fn apply_config(&mut self, config: Config) {
+        critical_section::with(|cs| CONFIG.replace(cs, config));
+        with_detection(|d| d.buffers.set_detection_config(config.detection));
+    }
+
+    fn enter_standby(&mut self) -> Result<()> {
+        with_detection(|d| {
+            d.state.transition(
+                SystemState::Standby,
+                &mut d.sampler,
+                "Standby requested by console",
+            )
+        })
+    }
+
+    fn reset_latch(&mut self) -> Result<()> {
+        with_detection(|d| {
+            d.state
+                .reset_latch(&mut d.sampler, "Latched alert reset by console")
+        })
+    }
+
+    fn snooze(&mut self, duration_ms: u32) -> bool {
+        with_detection(|d| d.state.snooze(duration_ms))
+    }
+
+    fn snapshot(&mut self) -> Snapshot {
+        with_detection(|d| Snapshot::take(d.buffers, &d.state))
+    }
+
+    fn capture(&mut self, samples: usize) -> Capture {
+        with_detection(|d| Capture::take(d.buffers, samples))
+    }
+
+    fn can_write_flash(&mut self) -> bool {
+        !with_detection(|d| d.state.state().sampling())
+    }
+}
+
+/// Trips the interlock as soon as the supply starts to fail
+#[interrupt]
+fn IO_IRQ_BANK0() {
+    power::on_power_fail();
+}
+
+/// Wakes [`sampling`]. The interrupt stays masked until the transfer is acknowledged.
+#[interrupt]
+fn DMA_IRQ_0() {
+    NVIC::mask(Interrupt::DMA_IRQ_0);
+    READINGS_READY.signal(Instant::now());
+}
+
+/// Wakes [`usb_console`]. The interrupt stays masked until the device has been polled.
+#[interrupt]
+fn USBCTRL_IRQ() {
+    NVIC::mask(Interrupt::USBCTRL_IRQ);
+    USB_READY.signal(());
+}
@@ -0,0 +1,207 @@
+//! Built-in self-test, run once by [`Board::init`](crate::board::Board::init) before sampling
+//! starts.
+//!
+//! Each [`Check`] exercises one part of the hardware the detection path depends on. Any failure is
+//! kept in [`SelfTest`], and [`StateMachine::begin`](crate::state::StateMachine::begin) then
+//! enters [`SystemState::Error`](crate::state::SystemState::Error) instead of arming.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cortex_m::singleton;
+use defmt::{error, info, Format};
+
+use crate::{
+    components::{LedControl, StatusLedStates},
+    config::{board::STATUS_LED_PINS, Config, ConfigError},
+    error::{Error, Result},
+    hal::{
+        dma::{single_buffer, SingleChannel},
+        pac,
+    },
+};
+
+/// Expected 12-bit reading from the self-test divider, which sits at mid-rail
+pub const ADC_MIDRAIL: u16 = 2048;
+/// Allowed deviation from [`ADC_MIDRAIL`], about 10% of full scale
+pub const ADC_TOLERANCE: u16 = 410;
+/// Words copied by the DMA check
+const DMA_TEST_WORDS: usize = 8;
+/// Patterns written to each spare watchdog scratch register
+const SCRATCH_PATTERNS: [u32; 2] = [0x5555_5555, 0xAAAA_AAAA];
+
+/// A single self-test check
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Check {
+    /// Every status LED pattern reads back on the pads
+    Leds,
+    /// The ADC reads the self-test divider at mid-rail
+    Adc,
+    /// A DMA channel completes a memory-to-memory transfer
+    Dma,
+    /// The stored configuration, if any, passes its CRC check
+    ConfigCrc,
+    /// The spare watchdog scratch registers hold their values
+    WatchdogScratch,
+}
+
+impl Check {
+    /// Code reported for a failure of this check
+    pub const fn code(self) -> u8 {
+        match self {
+            Check::Leds => 0x01,
+            Check::Adc => 0x02,
+            Check::Dma => 0x03,
+            Check::ConfigCrc => 0x04,
+            Check::WatchdogScratch => 0x05,
+        }
+    }
+}
+
+/// Outcome of the checks run so far
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Format)]
+pub struct SelfTest {
+    /// First check that failed
+    failed: Option<Check>,
+}
+
+impl SelfTest {
+    /// No checks run yet
+    pub const fn new() -> Self {
+        Self { failed: None }
+    }
+
+    /// Log the outcome of `check`, keeping it if it is the first failure.
+    pub fn record(&mut self, check: Check, passed: bool) {
+        if passed {
+            info!("Self-test passed: {}", check);
+        } else {
+            error!(
+                "Self-test failed: {} (code {=u8:#04x})",
+                check,
+                check.code()
+            );
+            self.failed.get_or_insert(check);
+        }
+    }
+
+    /// Returns [`Error::SelfTest`] with the first failed check, if any
+    pub fn result(&self) -> Result<()> {
+        self.failed
+            .map_or(Ok(()), |check| Err(Error::SelfTest(check)))
+    }
+}
+
+/// Show every LED pattern, checking that each pin's pad reads back the level being driven, then
+/// return to [`StatusLedStates::Alert`] as shown while booting.
+pub fn check_leds(leds: &mut impl LedControl) -> bool {
+    // SAFETY: read-only access to the SIO GPIO registers
+    let sio = unsafe { &*pac::SIO::ptr() };
+    let mask = STATUS_LED_PINS
+        .iter()
+        .fold(0u32, |mask, pin| mask | 1 << pin);
+    let mut shown = StatusLedStates::Alert;
+    let mut passed = true;
+    for pattern in [
+        StatusLedStates::Normal,
+        StatusLedStates::Error,
+        StatusLedStates::Disabled,
+        StatusLedStates::Alert,
+    ] {
+        match leds.set_led(&shown, pattern) {
+            Ok(new) => shown = new,
+            Err(_) => return false,
+        }
+        let driven = sio.gpio_out().read().bits() & mask;
+        passed &= sio.gpio_in().read().bits() & mask == driven;
+    }
+    passed
+}
+
+/// Take a single 12-bit reading from ADC `channel`, and check it is within [`ADC_TOLERANCE`] of
+/// [`ADC_MIDRAIL`]. The ADC must be enabled and idle, and the channel's pin set as an ADC input.
+pub fn check_adc(channel: u8) -> bool {
+    // SAFETY: the ADC is not yet used by the sampler, and the conversion is complete before
+    // returning
+    let adc = unsafe { &*pac::ADC::ptr() };
+    while adc.cs().read().ready().bit_is_clear() {}
+    adc.cs()
+        .modify(|_, w| unsafe { w.ainsel().bits(channel) }.start_once().set_bit());
+    while adc.cs().read().ready().bit_is_clear() {}
+    let cs = adc.cs().read();
+    let reading = adc.result().read().result().bits();
+    info!("Self-test divider reads {=u16}", reading);
+    !cs.err().bit_is_set() && reading.abs_diff(ADC_MIDRAIL) <= ADC_TOLERANCE
+}
+
+/// Copy a test pattern through DMA channel `ch`, and check it arrives intact.
+pub fn check_dma<CH: SingleChannel>(ch: CH) -> bool {
+    let (Some(from), Some(to)) = (
+        singleton!(: [u32; DMA_TEST_WORDS] = [0; DMA_TEST_WORDS]),
+        singleton!(: [u32; DMA_TEST_WORDS] = [0; DMA_TEST_WORDS]),
+    ) else {
+        return false;
+    };
+    for (idx, word) in from.iter_mut().enumerate() {
+        *word = 0xA5A5_0000 | idx as u32;
+    }
+    let (_, from, to) = single_buffer::Config::new(ch, from, to).start().wait();
+    from == to
+}
+
+/// Check the result of [`Config::load`]. A missing configuration passes, as the defaults are used,
+/// and so does one stored by firmware with another [`Config::VERSION`].
+pub fn check_config(loaded: &core::result::Result<Config, ConfigError>) -> bool {
+    !matches!(
+        loaded,
+        Err(ConfigError::Corrupted | ConfigError::Serialization | ConfigError::Invalid)
+    )
+}
+
+/// Write test patterns to the scratch registers not used by [`liveness`](crate::liveness) or the
+/// bootrom, restoring their values afterwards.
+pub fn check_watchdog_scratch() -> bool {
+    // SAFETY: scratch1-3 are not used by the firmware, the HAL or the bootrom, and are restored
+    let watchdog = unsafe { &*pac::WATCHDOG::ptr() };
+    // Each scratch register has its own type, so they are passed as accessors
+    check_scratch(
+        || watchdog.scratch1().read().bits(),
+        |bits| {
+            watchdog.scratch1().write(|w| unsafe { w.bits(bits) });
+        },
+    ) & check_scratch(
+        || watchdog.scratch2().read().bits(),
+        |bits| {
+            watchdog.scratch2().write(|w| unsafe { w.bits(bits) });
+        },
+    ) & check_scratch(
+        || watchdog.scratch3().read().bits(),
+        |bits| {
+            watchdog.scratch3().write(|w| unsafe { w.bits(bits) });
+        },
+    )
+}
+
+/// Check that each of the [`SCRATCH_PATTERNS`] reads back from a scratch register, then restore
+/// its original value
+fn check_scratch(read: impl Fn() -> u32, write: impl Fn(u32)) -> bool {
+    let saved = read();
+    let mut passed = true;
+    for pattern in SCRATCH_PATTERNS {
+        write(pattern);
+        passed &= read() == pattern;
+    }
+    write(saved);
+    passed
+}
@@ -0,0 +1,122 @@
+//! Detection blanking input, asserted by external equipment around a known noisy actuation, such
+//! as the solenoid of a pneumatic fixture.
+//!
+//! While the input is high, samples are discarded before they reach the detector, for at most
+//! [`BLANKING_WINDOW_MS`] after it was asserted. Blanking must stay rare, as contact can't be
+//! detected while it lasts, so over-use of the input is itself a fault: holding it past the window,
+//! or opening more than [`BLANKING_MAX_PER_MINUTE`] windows in a minute, raises
+//! [`Error::BlankingOveruse`]. See [`BlankingMonitor::check`].
+//!
+//! Only samples taken while detection is armed are blanked. An alert already raised is never
+//! hidden. Boards that set `blanking.window_ms` to 0 never read the input.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::{warn, Format};
+
+pub use crate::config::board::{BLANKING_MAX_PER_MINUTE, BLANKING_WINDOW_MS};
+use crate::{
+    config::board::{BlankingPin, BLANKING_PIN},
+    error::{Error, Result},
+    hal::{
+        gpio::{FunctionNull, FunctionSio, Pin, PullDown, SioInput},
+        pac,
+    },
+};
+
+/// Period over which blanking windows are counted against [`BLANKING_MAX_PER_MINUTE`]
+const MINUTE_MS: u32 = 60_000;
+
+/// Blanking input, pulled down so an unconnected input never blanks
+pub type BlankingInput = Pin<BlankingPin, FunctionSio<SioInput>, PullDown>;
+
+/// Take the blanking input, pulled down with its Schmitt trigger enabled
+pub fn monitor(pin: Pin<BlankingPin, FunctionNull, PullDown>) -> BlankingInput {
+    let input = pin.into_pull_down_input();
+    input.set_schmitt_enabled(true);
+    input
+}
+
+/// Whether external equipment is asserting the blanking input
+pub fn asserted() -> bool {
+    // SAFETY: read-only access to the SIO GPIO input register
+    let sio = unsafe { &*pac::SIO::ptr() };
+    sio.gpio_in().read().bits() & 1 << BLANKING_PIN != 0
+}
+
+/// Tracks the blanking windows opened by the input
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub struct BlankingMonitor {
+    /// [`scheduler::now_ms`](crate::scheduler::now_ms) at which the current window opened, while
+    /// the input is asserted
+    opened_ms: Option<u32>,
+    /// Start of the minute the windows are being counted in
+    minute_ms: u32,
+    /// Windows opened since `minute_ms`
+    windows: u16,
+}
+
+impl BlankingMonitor {
+    /// No windows opened yet
+    pub const fn new() -> Self {
+        Self {
+            opened_ms: None,
+            minute_ms: 0,
+            windows: 0,
+        }
+    }
+
+    /// Check the input, `asserted` at `now_ms`.
+    ///
+    /// Returns whether the current sample is blanked, or [`Error::BlankingOveruse`] if the input
+    /// is held past [`BLANKING_WINDOW_MS`], or asserted for more than [`BLANKING_MAX_PER_MINUTE`]
+    /// windows in a minute. Always `Ok(false)` if blanking is disabled for the board.
+    // The window is a board setting, so comparisons against it are constant where it is 0
+    #[allow(clippy::absurd_extreme_comparisons)]
+    pub fn check(&mut self, asserted: bool, now_ms: u32) -> Result<bool> {
+        if BLANKING_WINDOW_MS == 0 {
+            return Ok(false);
+        }
+        if !asserted {
+            self.opened_ms = None;
+            return Ok(false);
+        }
+
+        let Some(opened_ms) = self.opened_ms else {
+            if now_ms.wrapping_sub(self.minute_ms) >= MINUTE_MS {
+                self.minute_ms = now_ms;
+                self.windows = 0;
+            }
+            self.windows = self.windows.saturating_add(1);
+            if self.windows > BLANKING_MAX_PER_MINUTE {
+                warn!(
+                    "Blanking asserted {=u16} times within a minute, over the limit of {=u16}",
+                    self.windows, BLANKING_MAX_PER_MINUTE
+                );
+                return Err(Error::BlankingOveruse);
+            }
+            self.opened_ms = Some(now_ms);
+            return Ok(true);
+        };
+        if now_ms.wrapping_sub(opened_ms) >= BLANKING_WINDOW_MS {
+            warn!(
+                "Blanking held past its {=u32} ms window",
+                BLANKING_WINDOW_MS
+            );
+            return Err(Error::BlankingOveruse);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,494 @@
+//! Board bring-up, shared by every binary.
+//!
+//! [`Board::init`] configures the clocks, pins, signal generator, ADC transfer and USB console
+//! (except with `minimal`), and creates the static buffers, exactly once. Calling it again returns
+//! [`Error::AlreadyInitialized`] before any hardware is touched, so startup ordering bugs fail
+//! early rather than part way through. Peripherals the firmware doesn't use are handed back in
+//! [`Board`] for the binary. With `mfg_test`, holding the acknowledge button at power-up makes it
+//! run the [manufacturing test](crate::mfg_test) instead of returning.
+//!
+//! The firmware builds for the RP2040, or for the RP2350 with the `rp2350` feature (selected by
+//! `board-pico2`). The peripherals that moved or were renamed between the two, the timer, USB
+//! controller, chip reset flags and their interrupts, are resolved here and in
+//! [`clocks`](crate::clocks), so the rest of the crate uses [`Timer`], [`TimerBlock`] and the
+//! functions below without checking the chip.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(not(feature = "minimal"))]
+use cortex_m::singleton;
+use defmt::{info, warn};
+use embedded_hal::pwm::SetDutyCycle;
+#[cfg(not(feature = "minimal"))]
+use usb_device::{
+    bus::UsbBusAllocator,
+    device::{StringDescriptors, UsbDeviceBuilder, UsbVidPid},
+};
+#[cfg(not(feature = "minimal"))]
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+#[cfg(feature = "i2c_scan")]
+use crate::hal::i2c::I2C;
+#[cfg(not(feature = "minimal"))]
+use crate::hal::usb::UsbBus;
+#[cfg(feature = "hil")]
+use crate::hil::{self, Loopback};
+#[cfg(feature = "i2c_scan")]
+use crate::i2c_scan;
+#[cfg(not(feature = "minimal"))]
+use crate::interrupt::{ConsoleSerial, ConsoleUsbDevice};
+#[cfg(feature = "mfg_test")]
+use crate::mfg_test;
+use crate::{
+    banner,
+    bist::{self, Check, SelfTest},
+    blanking,
+    buffer::{create_avg_buffers, Buffers},
+    clocks::{self, ClockProfile},
+    components::{LedControl, StatusLeds},
+    config::{
+        board::{BOARD_VARIANT, CLOCK_PROFILE, SELF_TEST_ADC_CHANNEL},
+        Config,
+    },
+    correlation,
+    error::{Error, Result},
+    hal::{
+        adc::{Adc, AdcPin},
+        dma::{double_buffer, DMAExt, SingleChannel},
+        fugit::HertzU32,
+        gpio::Pins,
+        pac::{self, Interrupt},
+        prelude::*,
+        pwm::Slices,
+        sio::SioFifo,
+        Sio, Watchdog,
+    },
+    injection::Injector,
+    interrupt::{AckButton, DisableSwitch},
+    irq::ADC_SAMPLE_RATE_HZ,
+    power, presence,
+    sampler::{ReadingsBuffer, Sampler},
+    stack,
+    state::{init_interlock, init_probe_led, StateMachine, SystemIndicators},
+    trigger,
+};
+#[cfg(not(feature = "minimal"))]
+use crate::{event_log, lock, provision, uptime};
+
+/// External high-speed crystal on the pico board is 12Mhz
+pub const XOSC_FREQ_HZ: u32 = 12_000_000;
+/// System clock the signal and ADC timing were designed around. The board has always run faster,
+/// with the signal scaled up to match (see [`Board::init`]).
+pub const SYS_CLOCK_FREQ: u32 = 24_000_000;
+/// Frequency of detection signal is 100 kHz
+pub const SIGNAL_GEN_FREQ_HZ: u32 = 100_000;
+/// Generic CDC-ACM VID/PID from [pid.codes](https://pid.codes/1209/0001/), for testing only
+#[cfg(not(feature = "minimal"))]
+pub const CONSOLE_VID_PID: UsbVidPid = UsbVidPid(0x1209, 0x0001);
+
+/// Microcontroller the firmware is built for
+#[cfg(not(feature = "rp2350"))]
+pub const CHIP: &str = "RP2040";
+/// Microcontroller the firmware is built for
+#[cfg(feature = "rp2350")]
+pub const CHIP: &str = "RP2350";
+
+/// Microsecond timer peripheral, `TIMER` on the RP2040
+#[cfg(not(feature = "rp2350"))]
+pub type TimerBlock = pac::TIMER;
+/// Microsecond timer peripheral, the first of the two on the RP2350
+#[cfg(feature = "rp2350")]
+pub type TimerBlock = pac::TIMER0;
+/// Microsecond timer, driving [`TimerBlock`]
+#[cfg(not(feature = "rp2350"))]
+pub type Timer = crate::hal::Timer;
+/// Microsecond timer, driving [`TimerBlock`]
+#[cfg(feature = "rp2350")]
+pub type Timer = crate::hal::Timer<crate::hal::timer::CopyableTimer0>;
+/// Alarm 0 interrupt of [`TimerBlock`]
+#[cfg(not(feature = "rp2350"))]
+pub const TIMER_ALARM_IRQ: Interrupt = Interrupt::TIMER_IRQ_0;
+/// Alarm 0 interrupt of [`TimerBlock`]
+#[cfg(feature = "rp2350")]
+pub const TIMER_ALARM_IRQ: Interrupt = Interrupt::TIMER0_IRQ_0;
+/// Interrupt for data in core0's end of the SIO FIFO
+#[cfg(not(feature = "rp2350"))]
+pub const SIO_FIFO_IRQ: Interrupt = Interrupt::SIO_IRQ_PROC0;
+/// Interrupt for data in core0's end of the SIO FIFO, shared by both cores on the RP2350
+#[cfg(feature = "rp2350")]
+pub const SIO_FIFO_IRQ: Interrupt = Interrupt::SIO_IRQ_FIFO;
+
+/// Set by the first call to [`Board::init`]
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Chip-level causes of the last reset, apart from the watchdog
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct ChipReset {
+    /// Reset by the debugger
+    pub debugger: bool,
+    /// Reset by the RUN pin
+    pub run_pin: bool,
+    /// Power-on or brown-out reset
+    pub power_on: bool,
+}
+
+impl ChipReset {
+    /// Read the reset flags, from `VREG_AND_CHIP_RESET.CHIP_RESET`
+    #[cfg(not(feature = "rp2350"))]
+    pub fn read() -> Self {
+        // SAFETY: read-only access to the reset reason register
+        let chip = unsafe { (*pac::VREG_AND_CHIP_RESET::ptr()).chip_reset().read() };
+        Self {
+            debugger: chip.had_psm_restart().bit_is_set(),
+            run_pin: chip.had_run().bit_is_set(),
+            power_on: chip.had_por().bit_is_set(),
+        }
+    }
+
+    /// Read the reset flags, from `POWMAN.CHIP_RESET`
+    #[cfg(feature = "rp2350")]
+    pub fn read() -> Self {
+        // SAFETY: read-only access to the reset reason register
+        let chip = unsafe { (*pac::POWMAN::ptr()).chip_reset().read() };
+        Self {
+            debugger: chip.had_dp_reset_req().bit_is_set(),
+            run_pin: chip.had_run_low().bit_is_set(),
+            power_on: chip.had_por().bit_is_set() || chip.had_bor().bit_is_set(),
+        }
+    }
+}
+
+/// Whether [`TimerBlock`] is out of reset and counting
+#[cfg(not(feature = "rp2350"))]
+pub fn timer_running() -> bool {
+    // SAFETY: read-only access to a register with no side effects
+    unsafe {
+        (*pac::RESETS::ptr())
+            .reset_done()
+            .read()
+            .timer()
+            .bit_is_set()
+    }
+}
+
+/// Whether [`TimerBlock`] is out of reset and counting
+#[cfg(feature = "rp2350")]
+pub fn timer_running() -> bool {
+    // SAFETY: read-only access to a register with no side effects
+    unsafe {
+        (*pac::RESETS::ptr())
+            .reset_done()
+            .read()
+            .timer0()
+            .bit_is_set()
+    }
+}
+
+/// Handles to the initialized hardware and buffers
+pub struct Board {
+    /// Signal generator and ADC transfer, already running
+    pub sampler: Sampler,
+    /// Buffers for analyzing readings
+    pub buffers: &'static mut Buffers,
+    /// System state and interlock. Starts in [`Booting`](crate::state::SystemState::Booting).
+    pub state: StateMachine,
+    /// Status LEDs and buzzer, displaying `state`
+    pub indicators: SystemIndicators,
+    /// Configuration loaded from flash, or the defaults if none could be loaded
+    pub config: Config,
+    /// Outcome of the [built-in self-test](crate::bist). Pass it to [`StateMachine::begin`], which
+    /// refuses to arm if any check failed.
+    pub self_test: SelfTest,
+    /// Disable switch input, with pull-down and Schmitt trigger
+    pub disable_switch: DisableSwitch,
+    /// Acknowledge button, with pull-down and Schmitt trigger
+    pub ack_button: AckButton,
+    /// Test-signal injection output, driven low. With `hil`, also plays the loopback scenarios.
+    pub injector: Injector,
+    /// USB device serving the console
+    #[cfg(not(feature = "minimal"))]
+    pub usb_dev: ConsoleUsbDevice,
+    /// USB serial port serving the console
+    #[cfg(not(feature = "minimal"))]
+    pub usb_serial: ConsoleSerial,
+    /// Actual system clock frequency, set by the [`ClockProfile`] in `board.toml`
+    pub system_clock_freq: HertzU32,
+    /// Microsecond timer
+    pub timer: Timer,
+    /// Watchdog, not yet started
+    pub watchdog: Watchdog,
+    /// Core0 end of the SIO FIFO
+    pub sio_fifo: SioFifo,
+    /// Unused power-on state machine, needed to start core1
+    pub psm: pac::PSM,
+    /// Unused private peripheral bus, needed to start core1
+    pub ppb: pac::PPB,
+    /// Subsystem resets, for initializing the unused peripherals
+    pub resets: pac::RESETS,
+}
+
+impl Board {
+    /// Initialize the board from the device peripherals.
+    ///
+    /// Returns [`Error::AlreadyInitialized`] if called more than once, [`Error::Clocks`] if the
+    /// clocks can't be started, [`Error::Gpio`] if the ADC input is invalid, or [`Error::Usb`] if
+    /// the USB device can't be built. Self-test failures are kept in [`Board::self_test`].
+    pub fn init(mut pac: pac::Peripherals) -> Result<Self> {
+        cortex_m::interrupt::free(|_| {
+            if INITIALIZED.load(Ordering::Relaxed) {
+                return Err(Error::AlreadyInitialized);
+            }
+            INITIALIZED.store(true, Ordering::Relaxed);
+            Ok(())
+        })?;
+
+        stack::paint();
+        #[cfg(feature = "perf")]
+        crate::perf::start_counter();
+        let mut watchdog = Watchdog::new(pac.WATCHDOG);
+        let sio = Sio::new(pac.SIO);
+
+        // Raise the core voltage before the clock
+        #[cfg(not(feature = "rp2350"))]
+        clocks::set_voltage(CLOCK_PROFILE, &mut pac.VREG_AND_CHIP_RESET);
+        let clocks = clocks::init(
+            CLOCK_PROFILE,
+            pac.XOSC,
+            pac.CLOCKS,
+            pac.PLL_SYS,
+            pac.PLL_USB,
+            &mut pac.RESETS,
+            &mut watchdog,
+        )?;
+        info!("Chip: {=str}", CHIP);
+        info!("Board variant: {=str}", BOARD_VARIANT);
+        power::gate_unused_clocks();
+        power::configure_brownout();
+        // The recorded data and thresholds come from the standard 125 MHz clock, with the signal
+        // scaled up from SYS_CLOCK_FREQ. Every profile keeps the signal at that frequency.
+        let sysclk_rescale = ClockProfile::Standard.sys_freq_hz() as f32 / SYS_CLOCK_FREQ as f32;
+        let signal_freq = SIGNAL_GEN_FREQ_HZ as f32 * sysclk_rescale;
+        let system_clock_freq = clocks.system_clock.freq();
+        #[cfg(not(feature = "rp2350"))]
+        let timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+        #[cfg(feature = "rp2350")]
+        let timer = Timer::new_timer0(pac.TIMER0, &mut pac.RESETS, &clocks);
+        let pins = Pins::new(
+            pac.IO_BANK0,
+            pac.PADS_BANK0,
+            sio.gpio_bank0,
+            &mut pac.RESETS,
+        );
+
+        // Setup status LEDs, interlocks, probe LEDs and buzzer
+        let mut self_test = SelfTest::new();
+        let mut status_leds = StatusLeds::init(
+            board_pin!(pins, status_led_0),
+            board_pin!(pins, status_led_1),
+            board_pin!(pins, status_led_2),
+        );
+        self_test.record(Check::Leds, bist::check_leds(&mut status_leds));
+        self_test.record(Check::WatchdogScratch, bist::check_watchdog_scratch());
+        #[cfg(not(feature = "dual_probe"))]
+        let (interlocks, probe_leds) = (
+            [init_interlock(board_pin!(pins, interlock))],
+            [init_probe_led(board_pin!(pins, probe_led))],
+        );
+        #[cfg(feature = "dual_probe")]
+        let (interlocks, probe_leds) = (
+            [
+                init_interlock(board_pin!(pins, interlock)),
+                init_interlock(board_pin!(pins, interlock_b)),
+            ],
+            [
+                init_probe_led(board_pin!(pins, probe_led)),
+                init_probe_led(board_pin!(pins, probe_led_b)),
+            ],
+        );
+        let (state, indicators) = StateMachine::new(
+            status_leds,
+            interlocks,
+            probe_leds,
+            board_pin!(pins, buzzer),
+        )?;
+        let disable_switch = board_pin!(pins, disable_switch).into_pull_down_input();
+        disable_switch.set_schmitt_enabled(true); // Debouncing
+        let ack_input = board_pin!(pins, ack_button).into_pull_down_input();
+        ack_input.set_schmitt_enabled(true);
+        let ack_button = AckButton::new(ack_input);
+        #[cfg(feature = "mfg_test")]
+        let mfg_requested = mfg_test::requested(&timer);
+        let injector = Injector::new(board_pin!(pins, test_inject));
+        let _power_good = power::monitor_power_good(board_pin!(pins, power_good));
+        #[cfg(not(feature = "minimal"))]
+        let _config_jumper = lock::monitor_jumper(board_pin!(pins, config_jumper));
+        let _presence = presence::monitor(board_pin!(pins, presence));
+        let _blanking = blanking::monitor(board_pin!(pins, blanking));
+        let _correlation = correlation::init(board_pin!(pins, correlation));
+        let _trigger = trigger::monitor(board_pin!(pins, trigger));
+        #[cfg(feature = "i2c_scan")]
+        i2c_scan::init(I2C::new_controller(
+            board_pin!(pac, i2c),
+            board_pin!(pins, i2c_sda).reconfigure(),
+            board_pin!(pins, i2c_scl).reconfigure(),
+            HertzU32::kHz(i2c_scan::BUS_FREQ_KHZ),
+            &mut pac.RESETS,
+            system_clock_freq,
+        ));
+
+        // Initialize and start signal generator
+        let mut pwm_slices = Slices::new(pac.PWM, &mut pac.RESETS);
+        board_pin!(pwm_slices, signal_pwm_slice)
+            // Ex. 24 MHz clock generates 100 kHz signal ->  240 clk cycles per PWM cycle (`top`)
+            // with 50% duty cycle
+            .set_top(((system_clock_freq.to_Hz() as f32 / signal_freq) - 1.0) as u16);
+        board_pin!(pwm_slices, signal_pwm_slice).enable();
+        let mut signal_gen = board_pin!(pwm_slices, signal_pwm);
+        signal_gen.output_to(board_pin!(pins, signal_gen));
+        signal_gen.set_duty_cycle_percent(50)?;
+        #[cfg(feature = "hil")]
+        let injector = {
+            // The RC filter on the bench fixture turns the duty cycle into a level
+            board_pin!(pwm_slices, hil_pwm_slice).set_top(hil::DAC_TOP);
+            board_pin!(pwm_slices, hil_pwm_slice).enable();
+            let mut dac = board_pin!(pwm_slices, hil_pwm);
+            dac.output_to(board_pin!(pins, hil_dac));
+            injector.with_loopback(Loopback::new(dac)?)
+        };
+
+        // Setup ADC pins, DMA, buffers
+        let mut adc = Adc::new(pac.ADC, &mut pac.RESETS);
+        let mut adc_pin0 = AdcPin::new(board_pin!(pins, adc_input).into_floating_input())
+            .map_err(|_| Error::Gpio)?;
+        #[cfg(any(
+            feature = "dual_channel",
+            feature = "dual_probe",
+            feature = "triple_channel"
+        ))]
+        let adc_pin_b = AdcPin::new(board_pin!(pins, adc_input_b).into_floating_input())
+            .map_err(|_| Error::Gpio)?;
+        #[cfg(feature = "triple_channel")]
+        let adc_pin_c = AdcPin::new(board_pin!(pins, adc_input_c).into_floating_input())
+            .map_err(|_| Error::Gpio)?;
+        let _self_test_pin = AdcPin::new(board_pin!(pins, self_test_adc).into_floating_input())
+            .map_err(|_| Error::Gpio)?;
+        self_test.record(Check::Adc, bist::check_adc(SELF_TEST_ADC_CHANNEL));
+        let mut dma = pac.DMA.split(&mut pac.RESETS);
+        self_test.record(Check::Dma, bist::check_dma(dma.ch2));
+        #[cfg(not(feature = "minimal"))]
+        {
+            uptime::load();
+            event_log::load();
+            lock::load();
+            provision::load();
+        }
+        let loaded = Config::load();
+        self_test.record(Check::ConfigCrc, bist::check_config(&loaded));
+        let config = loaded.unwrap_or_else(|err| {
+            warn!("Unable to load configuration ({}), using defaults", err);
+            Config::DEFAULT
+        });
+        banner::report(&config);
+        let buffers = Buffers::init(config.detection)?;
+
+        // Setup first transfer
+        let [avg_buffer, spare_buffer] = create_avg_buffers()?.map(ReadingsBuffer::new);
+        let readings_fifo = adc.build_fifo().set_channel(&mut adc_pin0);
+        // Alternate with the second input, starting from the first
+        #[cfg(any(feature = "dual_channel", feature = "dual_probe"))]
+        let readings_fifo = readings_fifo.round_robin((&adc_pin0, &adc_pin_b));
+        // Cycle through all three inputs, starting from the first
+        #[cfg(feature = "triple_channel")]
+        let readings_fifo = readings_fifo.round_robin((&adc_pin0, &adc_pin_b, &adc_pin_c));
+        let mut readings_fifo = readings_fifo
+            // Ex. 24 MHz clock at 200 ksamples/s (2x SIGNAL_GEN_FREQ_HZ) -> sample every 120 clk
+            // cycles, or 60 at the 400 ksamples/s of two inputs or `lock_in`. Three inputs share
+            // 200 ksamples/s. The ADC clock doesn't change with the clock profile, so neither does
+            // the divider.
+            .clock_divider(
+                ((SYS_CLOCK_FREQ as f32 / ADC_SAMPLE_RATE_HZ as f32) - 1.0) as u16,
+                0,
+            )
+            .shift_8bit()
+            .enable_dma()
+            .start_paused();
+        // Each channel triggers the other, alternating between the buffers
+        dma.ch0.enable_irq0();
+        dma.ch1.enable_irq0();
+        let adc_dma_transfer = double_buffer::Config::new(
+            (dma.ch0, dma.ch1),
+            readings_fifo.dma_read_target(),
+            avg_buffer,
+        );
+        let readings = adc_dma_transfer.start().write_next(spare_buffer);
+        let sampler = Sampler::new(signal_gen, readings);
+        readings_fifo.resume();
+
+        // Setup USB serial console
+        #[cfg(all(not(feature = "minimal"), not(feature = "rp2350")))]
+        let (usb_regs, usb_dpram) = (pac.USBCTRL_REGS, pac.USBCTRL_DPRAM);
+        #[cfg(all(not(feature = "minimal"), feature = "rp2350"))]
+        let (usb_regs, usb_dpram) = (pac.USB, pac.USB_DPRAM);
+        #[cfg(not(feature = "minimal"))]
+        let usb_bus = singleton!(: UsbBusAllocator<UsbBus> = UsbBusAllocator::new(UsbBus::new(
+            usb_regs,
+            usb_dpram,
+            clocks.usb_clock,
+            true,
+            &mut pac.RESETS,
+        )))
+        .ok_or(Error::AlreadyInitialized)?;
+        #[cfg(not(feature = "minimal"))]
+        let usb_serial = SerialPort::new(usb_bus);
+        #[cfg(not(feature = "minimal"))]
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, CONSOLE_VID_PID)
+            .strings(&[StringDescriptors::default()
+                .manufacturer("PFPU2")
+                .product("Brain detection system")
+                .serial_number("APS490")])
+            .map_err(|_| Error::Usb)?
+            .device_class(USB_CLASS_CDC)
+            .build();
+        #[cfg(feature = "mfg_test")]
+        if mfg_requested {
+            mfg_test::run(usb_dev, usb_serial, timer);
+        }
+
+        Ok(Self {
+            sampler,
+            buffers,
+            state,
+            indicators,
+            config,
+            self_test,
+            disable_switch,
+            ack_button,
+            injector,
+            #[cfg(not(feature = "minimal"))]
+            usb_dev,
+            #[cfg(not(feature = "minimal"))]
+            usb_serial,
+            system_clock_freq,
+            timer,
+            watchdog,
+            sio_fifo: sio.fifo,
+            psm: pac.PSM,
+            ppb: pac.PPB,
+            resets: pac.RESETS,
+        })
+    }
+}
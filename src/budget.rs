@@ -0,0 +1,81 @@
+//! Compile-time RAM budget and sizing checks.
+//!
+//! Every large static is counted in [`STATIC_BYTES`] and checked against the `RAM` region in
+//! `memory.x`, leaving [`STACK_RESERVE_BYTES`] for the core0 stack. `flip-link` places the stack
+//! below the statics, so buffers that are too large still link, and only fault once the stack runs
+//! into them. With these assertions, a `board.toml` or feature combination that doesn't fit fails
+//! the build instead. Add any new buffer, queue or capture window to [`STATIC_BYTES`].
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::mem::size_of;
+
+#[cfg(feature = "dual_core")]
+use crate::multicore::CORE1_STACK_WORDS;
+use crate::{
+    buffer::{Buffers, DMA_BUFFER_SIZE, LONGTERM_SIZE},
+    deadline::Stage,
+    interrupt::{SampleQueue, SAMPLE_QUEUE_SIZE},
+    irq::TRANSFER_PERIOD_US,
+    postmortem,
+    stack::MIN_FREE_BYTES,
+    state::{StateChangeQueue, STATE_CHANGE_QUEUE_SIZE},
+};
+
+/// Size of the `RAM` region in `memory.x`
+#[cfg(not(feature = "rp2350"))]
+pub const RAM_BYTES: usize = 256 * 1024;
+/// Size of the `RAM` region in `memory-rp2350.x`
+#[cfg(feature = "rp2350")]
+pub const RAM_BYTES: usize = 512 * 1024;
+/// RAM kept free for the core0 stack, which every interrupt handler runs on
+pub const STACK_RESERVE_BYTES: usize = 16 * 1024;
+/// Allowance for statics too small to count individually: USB and console buffers, the defmt
+/// RTT buffer, HAL and RTIC state, and the fault and timing tables
+pub const OTHER_STATICS_BYTES: usize = 16 * 1024;
+
+/// Core1 stack of the `dual_core` binary
+#[cfg(feature = "dual_core")]
+const CORE1_STACK_BYTES: usize = CORE1_STACK_WORDS * 4;
+/// Core1 stack of the `dual_core` binary
+#[cfg(not(feature = "dual_core"))]
+const CORE1_STACK_BYTES: usize = 0;
+
+/// RAM taken by the large statics: the long-term buffer, both DMA buffers, the sample and state
+/// change queues, the post-mortem ring and the core1 stack
+pub const STATIC_BYTES: usize = size_of::<Buffers>()
+    + 2 * DMA_BUFFER_SIZE
+    + size_of::<SampleQueue>()
+    + size_of::<StateChangeQueue>()
+    + postmortem::RING_BYTES
+    + CORE1_STACK_BYTES;
+
+/// RAM left over once the statics, allowance and stack reserve are taken
+pub const SPARE_BYTES: usize =
+    RAM_BYTES.saturating_sub(STATIC_BYTES + OTHER_STATICS_BYTES + STACK_RESERVE_BYTES);
+
+const _: () = assert!(
+    STATIC_BYTES + OTHER_STATICS_BYTES + STACK_RESERVE_BYTES <= RAM_BYTES,
+    "static buffers leave too little RAM for the stack, reduce the sizes in board.toml"
+);
+// The stack monitor must be able to warn before the reserve runs out
+const _: () = assert!(MIN_FREE_BYTES as usize * 2 <= STACK_RESERVE_BYTES);
+// Detection compares the latest sample with the two before it
+const _: () = assert!(LONGTERM_SIZE > 2);
+// A heapless queue holds one less item than its size
+const _: () = assert!(SAMPLE_QUEUE_SIZE >= 2 && STATE_CHANGE_QUEUE_SIZE >= 2);
+// The sample queue must absorb a full pipeline deadline of samples before dropping any
+const _: () =
+    assert!((SAMPLE_QUEUE_SIZE as u32 - 1) * TRANSFER_PERIOD_US >= Stage::Pipeline.budget_us());
@@ -1,13 +1,13 @@
 //! Buffers for recording data from the ADC, and tracking long-term averages from the detection system.
 
 // Copyright 2024 Cameron Rodriguez
-// 
+//
 // Licensed under the Apache License, Version 2.0 (the "License");
 // you may not use this file except in compliance with the License.
 // You may obtain a copy of the License at
-// 
+//
 // http://www.apache.org/licenses/LICENSE-2.0
-// 
+//
 // Unless required by applicable law or agreed to in writing, software
 // distributed under the License is distributed on an "AS IS" BASIS,
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
@@ -15,291 +15,515 @@
 // limitations under the License.
 
 use cortex_m::singleton;
-#[allow(unused_imports)]
-use defmt::trace;
-use defmt::{debug, warn, Format, Formatter};
-
-#[cfg(feature = "rgba_status")]
-use crate::components::Rgba;
-#[cfg(feature = "triple_status")]
-use crate::components::Triple;
+use defmt::{Format, Formatter};
+
+#[cfg(feature = "ab_compare")]
+use crate::ab_compare::{Candidate, Comparison};
+pub use crate::detection_core::{DetectionEvent, SampleCounter};
+#[cfg(not(feature = "minimal"))]
+use crate::histogram;
+#[cfg(feature = "triple_channel")]
+use crate::voting::{Mode, Voter, CHANNELS};
 use crate::{
-    components::{StatusLed, StatusLedBase},
-    interrupt::BUFFERS,
+    auto_zero::AutoZero,
+    blanking::BlankingMonitor,
+    config::{
+        self,
+        board::{self, MAINS_HZ},
+        DetectionConfig, Threshold,
+    },
+    detection_core::{Detector, Event, Phase},
+    error::{Error, Result},
+    irq::TRANSFER_PERIOD_US,
+    log,
+    perf::{self, Section},
+    plausibility::PlausibilityMonitor,
+    sequence::Timing,
+    units::Millis,
 };
 
 /// Number of samples stored in the long-term buffer. Should be a multiple of 250 for tracing purposes
 ///
-/// Currently set to 45k averaged samples (90 s with 2 ms averaging)
-pub const LONGTERM_SIZE: usize = 45000;
+/// Set in `board.toml`, currently 45k averaged samples (90 s with 2 ms averaging)
+pub const LONGTERM_SIZE: usize = board::LONGTERM_SIZE;
 
-/// Index of a detection event, combined with voltage difference
-pub type DetectionEvent = (SampleCounter, u8);
+/// Number of ADC readings averaged into each sample, set in `board.toml`
+pub const AVG_BUFFER_SIZE: usize = board::AVG_BUFFER_SIZE;
 
-/// Monotonic counter indicating the position of averaged samples in the buffer
-#[derive(Copy, Clone, Default, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
-pub struct SampleCounter(pub usize);
+/// Number of probes supervised, 2 with the `dual_probe` feature
+pub const PROBES: usize = if cfg!(feature = "dual_probe") { 2 } else { 1 };
 
-impl SampleCounter {
-    /// Get current counter value
-    pub fn get_counter(&self) -> usize {
-        self.0
-    }
+/// Number of ADC inputs sampled, 2 with the `dual_channel` or `dual_probe` feature, or 3 with
+/// `triple_channel`
+pub const ADC_CHANNELS: usize = if cfg!(feature = "triple_channel") {
+    3
+} else if cfg!(any(feature = "dual_channel", feature = "dual_probe")) {
+    2
+} else {
+    1
+};
 
-    /// Increment counter (mainly used by [`Buffers.current_sample`](Buffers)). An error will be
-    /// raised if any counter reaches [`usize::MAX`].
-    pub fn increment(&mut self) {
-        match self.0.checked_add(1) {
-            None => critical_section::with(|cs| {
-                debug!("critical_section: counter set_error overflow");
-                #[cfg(feature = "rgba_status")]
-                StatusLedBase::<Rgba>::set_error(
-                    cs,
-                    Some("No ADC transfer in progress! Unable to collect latest readings"),
-                );
-                #[cfg(feature = "triple_status")]
-                StatusLedBase::<Triple>::set_error(
-                    cs,
-                    Some("No ADC transfer in progress! Unable to collect latest readings"),
-                );
-            }),
-            Some(new_counter) => self.0 = new_counter,
-        }
-    }
+/// Interval between logs of the [`AutoZero`] offsets, in samples (1 minute)
+const OFFSET_LOG_SAMPLES: u32 = samples_in(Millis(60_000)) as u32;
+
+/// One averaged sample from each of the [`PROBES`]
+pub type ProbeSamples = [u8; PROBES];
+
+/// One averaged sample from each of the [`ADC_CHANNELS`] voted on with `triple_channel`, or
+/// [`None`] from a channel whose readings show it has failed
+#[cfg(feature = "triple_channel")]
+pub type ChannelSamples = [Option<u8>; ADC_CHANNELS];
 
-    /// Add with defined wrapping. Result will be within range \[0, `limit` - 1\].
+/// Averaged samples from one transfer, passed from the [`Sampler`](crate::sampler::Sampler) to
+/// detection: [`ChannelSamples`] with `triple_channel`, otherwise [`ProbeSamples`]
+#[cfg(not(feature = "triple_channel"))]
+pub type TransferSamples = ProbeSamples;
+/// Averaged samples from one transfer, passed from the [`Sampler`](crate::sampler::Sampler) to
+/// detection: [`ChannelSamples`] with `triple_channel`, otherwise [`ProbeSamples`]
+#[cfg(feature = "triple_channel")]
+pub type TransferSamples = ChannelSamples;
+
+/// Averaged samples per second, set by the readings averaged into each. Sample counts in the
+/// configuration, such as [`DetectionConfig::alert_hold_samples`], are counted at this rate, so
+/// they need scaling if the averaging window changes.
+pub const SAMPLE_RATE_HZ: u32 = 1_000_000 / TRANSFER_PERIOD_US;
+
+/// Averaged samples recorded over `duration`, at [`SAMPLE_RATE_HZ`]
+pub const fn samples_in(duration: Millis) -> u64 {
+    duration.to_samples(SAMPLE_RATE_HZ)
+}
+
+/// Time taken to record `samples` averaged samples, at [`SAMPLE_RATE_HZ`]
+pub const fn duration_of(samples: u64) -> Millis {
+    Millis::from_samples(samples, SAMPLE_RATE_HZ)
+}
+
+/// Number of raw ADC readings in each DMA transfer, [`AVG_BUFFER_SIZE`] from each of the
+/// [`ADC_CHANNELS`] interleaved
+pub const DMA_BUFFER_SIZE: usize = AVG_BUFFER_SIZE * ADC_CHANNELS;
+
+/// Various buffers used for managing signal samples. Wraps a [`Detector`] for each of the
+/// [`PROBES`] with logging and the stored [`DetectionConfig`].
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Buffers {
+    /// Checks each sample is plausible before it reaches the detectors
+    plausibility: PlausibilityMonitor,
+    /// Windows opened by the blanking input
+    blanking: BlankingMonitor,
+    /// DC offset removed from each probe's samples before detection
+    auto_zero: [AutoZero; PROBES],
+    /// Long-term buffer and detection logic, one per probe
+    detectors: [Detector<LONGTERM_SIZE>; PROBES],
+    /// Thresholds used for detection, updated whenever a new [`Config`](crate::config::Config) is
+    /// applied
+    detection_config: DetectionConfig,
+    /// Samples left until the [`AutoZero`] offsets are next logged, counted down instead of
+    /// dividing the 64-bit sample count on every sample
+    offset_log_countdown: u32,
+    /// Candidate detectors compared against the production ones
+    #[cfg(feature = "ab_compare")]
+    comparison: Comparison,
+    /// Detectors of the `triple_channel` channels, voting on contact in place of the probe's
+    #[cfg(feature = "triple_channel")]
+    voter: Voter,
+}
+
+impl Buffers {
+    /// Initialize the buffers in a [`singleton`], using the thresholds from `detection_config`,
+    /// [resolved](DetectionConfig::resolve) at the unit's [ADC scale](config::adc_scale).
     ///
-    /// Used to index [`Buffers.longterm_buffer`](Buffers)
-    pub fn wrapping_counter_add(&self, rhs: usize, limit: usize) -> usize {
-        if self.0 + rhs >= limit {
-            rhs - (limit - self.0)
+    /// Returns [`Error::AlreadyInitialized`] if the buffers have already been initialized.
+    pub fn init(detection_config: DetectionConfig) -> Result<&'static mut Self> {
+        let detection_config = detection_config.resolve(config::adc_scale());
+        if MAINS_HZ == 0 {
+            log!(
+                Detection,
+                info,
+                "Averaging {=usize} readings per sample, {=u32} samples/s",
+                AVG_BUFFER_SIZE,
+                SAMPLE_RATE_HZ
+            );
         } else {
-            self.0 + rhs
+            log!(
+                Detection,
+                info,
+                "Averaging {=usize} readings per sample over whole {=u32} Hz mains cycles, {=u32} \
+                 samples/s",
+                AVG_BUFFER_SIZE,
+                MAINS_HZ,
+                SAMPLE_RATE_HZ
+            );
         }
+        log!(
+            Detection,
+            info,
+            "Trigger {=i32} mV, warning {=i32} mV, restore {=i32} mV, alert hold {=u32} ms",
+            detection_config.voltage(Threshold::Trigger).0,
+            detection_config.voltage(Threshold::Warning).0,
+            detection_config.voltage(Threshold::Restore).0,
+            detection_config.alert_hold().0
+        );
+        singleton!(:Buffers = Self {
+            plausibility: PlausibilityMonitor::new(),
+            blanking: BlankingMonitor::new(),
+            auto_zero: [AutoZero::new(auto_zero_samples(&detection_config)); PROBES],
+            detectors: [Detector::new(detection_config.thresholds()); PROBES],
+            detection_config,
+            offset_log_countdown: OFFSET_LOG_SAMPLES,
+            #[cfg(feature = "ab_compare")]
+            comparison: Comparison::new([Candidate::new(detection_config.thresholds()); PROBES]),
+            #[cfg(feature = "triple_channel")]
+            voter: Voter::new(detection_config.thresholds()),
+        })
+        .ok_or(Error::AlreadyInitialized)
     }
 
-    /// Subtract with defined wrapping. Limit will be within range \[0, `limit` - 1\].
-    ///
-    /// Used to index [`Buffers.longterm_buffer`](Buffers)
-    pub fn wrapping_counter_sub(&self, rhs: usize, limit: usize) -> usize {
-        if self.0.wrapping_sub(rhs) >= limit {
-            limit - (rhs - self.0)
-        } else {
-            self.0 - rhs
+    /// Replace the thresholds used for detection, [resolved](DetectionConfig::resolve) at the
+    /// unit's [ADC scale](config::adc_scale). Any pending confirmation is discarded.
+    pub fn set_detection_config(&mut self, detection_config: DetectionConfig) {
+        let detection_config = detection_config.resolve(config::adc_scale());
+        self.detection_config = detection_config;
+        for auto_zero in self.auto_zero.iter_mut() {
+            auto_zero.set_time_constant(auto_zero_samples(&detection_config));
+        }
+        for detector in self.detectors.iter_mut() {
+            detector.set_thresholds(detection_config.thresholds());
         }
+        #[cfg(feature = "ab_compare")]
+        self.comparison.set_production(&detection_config);
+        #[cfg(feature = "triple_channel")]
+        self.voter.set_thresholds(detection_config.thresholds());
     }
-}
 
-/// Various buffers used for managing signal samples
-#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
-pub struct Buffers {
-    /// Records samples for long-term and adaptive detection.
-    longterm_buffer: [u8; LONGTERM_SIZE],
-    /// Counter for the most recent sample added to
-    current_sample: SampleCounter,
-    /// Rotates position time stamps for up to 10 recent detection events, comparable with `current_sample`.
-    /// Most recent event is stored at index 0
-    detection_events: [Option<DetectionEvent>; 10],
-    /// A potential detection event or event clear has been recorded, and the system is awaiting a
-    /// second sample
-    await_confirm: bool,
-}
+    /// Whether alerts latch once contact ends, see [`DetectionConfig::latch_alert`]
+    pub fn latch_alert(&self) -> bool {
+        self.detection_config.latch_alert
+    }
 
-impl Buffers {
-    /// Initial averaged difference used for detecting contact.
-    ///
-    /// Ex. a trigger delta of 128 on a 3.3V signal requires that the average voltage range has
-    /// decreased by approximately 1.65V. Current values are based on experimental data and account
-    /// for signal drift.
-    const INIT_TRIGGER_DELTA: i16 = 2;
-    /// Initial averaged difference to restore
-    /// [`StatusLedStates::Normal`](crate::components::StatusLedStates::Normal).
+    /// Milliseconds before a latched alert clears, see [`DetectionConfig::auto_clear_ms`]
+    pub fn auto_clear_ms(&self) -> u32 {
+        self.detection_config.auto_clear_ms
+    }
+
+    /// Shortest time an interlock stays tripped for each contact, see
+    /// [`DetectionConfig::min_trip_ms`]
+    pub fn min_trip_ms(&self) -> u32 {
+        self.detection_config.min_trip_ms
+    }
+
+    /// Release sequencing of the interlocks, see [`DetectionConfig::enable_delay_ms`] and
+    /// [`DetectionConfig::cooldown_ms`]
+    pub fn sequence_timing(&self) -> Timing {
+        self.detection_config.sequence_timing()
+    }
+
+    /// Milliseconds the buzzer is muted for by a snooze, see [`DetectionConfig::snooze_ms`]
+    pub fn snooze_ms(&self) -> u32 {
+        self.detection_config.snooze_ms
+    }
+
+    /// Milliseconds between the signal settling and detection arming, see
+    /// [`DetectionConfig::arming_delay_ms`]
+    pub fn arming_delay_ms(&self) -> u32 {
+        self.detection_config.arming_delay_ms
+    }
+
+    /// Hours armed between proof tests, see [`DetectionConfig::proof_test_hours`]
+    pub fn proof_test_hours(&self) -> u16 {
+        self.detection_config.proof_test_hours
+    }
+
+    /// Total number of samples inserted. Every probe is sampled together, so this is the same for
+    /// each of them.
+    pub fn samples_recorded(&self) -> u64 {
+        self.detectors[0].samples_recorded()
+    }
+
+    /// Index of the most recent sample in the long-term buffers
+    pub fn head(&self) -> usize {
+        self.detectors[0].head()
+    }
+
+    /// Most recent sample from `probe`
+    pub fn latest(&self, probe: usize) -> u8 {
+        let detector = &self.detectors[probe];
+        detector.samples()[detector.head()]
+    }
+
+    /// Difference between the most recent sample from `probe` and the one before it
+    pub fn delta(&self, probe: usize) -> i16 {
+        self.detectors[probe].delta()
+    }
+
+    /// Whether the most recent sample from `probe` was a near miss, see
+    /// [`DetectionConfig::near_miss_percent`]
+    pub fn near_miss(&self, probe: usize) -> bool {
+        self.detectors[probe].near_miss(self.detection_config.near_miss_percent)
+    }
+
+    /// Sample from `probe`, `back` samples before the most recent
+    pub fn recent(&self, probe: usize, back: usize) -> u8 {
+        let detector = &self.detectors[probe];
+        detector.samples()[detector.back(back)]
+    }
+
+    /// Mean of the last `samples` from `probe`, including the most recent, up to the whole
+    /// long-term buffer
+    pub fn mean(&self, probe: usize, samples: usize) -> u8 {
+        let detector = &self.detectors[probe];
+        let samples = samples.clamp(1, LONGTERM_SIZE);
+        let sum: u32 = (0..samples)
+            .map(|back| detector.samples()[detector.back(back)] as u32)
+            .sum();
+        (sum / samples as u32) as u8
+    }
+
+    /// Check a new sample from each probe is within the plausible range, see
+    /// [`PlausibilityMonitor::check`]. Only insert the samples if this returns `Ok(true)`.
+    pub fn check_plausible(&mut self, samples: ProbeSamples) -> Result<bool> {
+        self.plausibility.check(samples, &self.detection_config)
+    }
+
+    /// Check whether the blanking input blanks the current sample, see
+    /// [`BlankingMonitor::check`]
+    pub fn check_blanking(&mut self, asserted: bool, now_ms: u32) -> Result<bool> {
+        self.blanking.check(asserted, now_ms)
+    }
+
+    /// Fault each of the `channels` that read [`None`], and return the sample of the first healthy
+    /// channel, which stands in for the probe's in the long-term buffer. Logged as an error the
+    /// first time each channel faults.
     ///
-    /// This is the increase in voltage relative to the last detection event. Current values are
-    /// based on experimental data and account for signal drift.
-    const INIT_RESTORE_DELTA: i16 = 2;
-    /// Panic message raised if buffers are not available
-    pub const NO_BUFFER_PANIC_MSG: &'static str =
-        "Buffers have not been initialized or are not currently available in mutex";
-
-    /// Initialize [`BUFFERS`] with a [`singleton`]
-    pub fn init() {
-        match singleton!(:Buffers = Self {
-            longterm_buffer: [0u8; LONGTERM_SIZE],
-            current_sample: SampleCounter::default(),
-            detection_events: [None; 10],
-            await_confirm: false
-        }) {
-            Some(init_buffers) => {
-                debug!("critical_section: init buffers");
-                critical_section::with(|cs| BUFFERS.replace(cs, Some(init_buffers)));
+    /// Returns [`Error::VoteFailed`] once fewer than two channels are healthy.
+    #[cfg(feature = "triple_channel")]
+    pub fn qualify(&mut self, channels: ChannelSamples) -> Result<ProbeSamples> {
+        for (channel, sample) in channels.into_iter().enumerate() {
+            if sample.is_none() && self.voter.fault(channel) {
+                log!(
+                    Detection,
+                    error,
+                    "Channel {=usize} faulted, now voting {=str}",
+                    channel,
+                    self.voter.mode().name()
+                );
+            }
+        }
+        match self.voter.reference() {
+            Some(channel) if self.voter.mode() != Mode::Failed => {
+                Ok([channels[channel].unwrap_or_default()])
             }
-            None => warn!("Buffers have already been initiated"),
+            _ => Err(Error::VoteFailed),
         }
     }
 
-    /// Returns [`SampleCounter::get_counter`] wrapped to [`LONGTERM_SIZE`]
-    pub fn current_wrapped(&self) -> SampleCounter {
-        SampleCounter(self.current_sample.get_counter() % LONGTERM_SIZE)
+    /// Record a new sample from each of the `channels`, and step the state machine of each healthy
+    /// channel, see [`Voter::insert`]. Call alongside [`Buffers::insert`], after
+    /// [`Buffers::qualify`].
+    #[cfg(feature = "triple_channel")]
+    pub fn insert_channels(&mut self, channels: ChannelSamples) {
+        let samples: [u8; CHANNELS] =
+            core::array::from_fn(|channel| channels[channel].unwrap_or_default());
+        self.voter.insert(samples);
     }
 
-    /// Insert a new sample at the head
-    pub fn insert(&mut self, sample: u8) {
-        let new_head = self
-            .current_wrapped()
-            .wrapping_counter_add(1, LONGTERM_SIZE);
-        self.longterm_buffer[new_head] = sample;
-        self.current_sample.increment();
+    /// Insert a new sample from each probe at the head, overwriting the oldest once the buffers
+    /// are full. The [`AutoZero`] offset is removed first, except from the
+    /// [`histogram`](crate::histogram), which shows the raw front-end output.
+    pub fn insert(&mut self, samples: ProbeSamples) {
+        #[cfg(not(feature = "minimal"))]
+        histogram::record(samples);
+        let samples: ProbeSamples =
+            core::array::from_fn(|probe| self.auto_zero[probe].apply(samples[probe]));
+        for (detector, sample) in self.detectors.iter_mut().zip(samples) {
+            insert_sample(detector, sample);
+        }
+        #[cfg(feature = "ab_compare")]
+        self.comparison.insert(samples);
+
+        self.offset_log_countdown -= 1;
+        if self.offset_log_countdown == 0 {
+            self.offset_log_countdown = OFFSET_LOG_SAMPLES;
+            self.log_offsets();
+        }
 
         #[cfg(feature = "trace_avg_samples")]
-        if self.current_sample.get_counter() % 250 == 0 {
-            self.trace_avg_samples();
+        if self.samples_recorded().is_multiple_of(250) {
+            for probe in 0..PROBES {
+                self.trace_avg_samples(probe);
+            }
+        }
+    }
+
+    /// Log the DC offset removed from each probe, unless the auto-zero stage is bypassed
+    fn log_offsets(&self) {
+        for (probe, auto_zero) in self.auto_zero.iter().enumerate() {
+            if let Some(offset) = auto_zero.offset() {
+                log!(
+                    Detection,
+                    info,
+                    "Auto-zero offset of probe {=usize}: {=u8}",
+                    probe,
+                    offset
+                );
+            }
         }
     }
 
-    /// Log average voltage samples for debugging
+    /// Log average voltage samples from `probe` for debugging. The first probe keeps the message
+    /// that [`replay`](crate::replay) waveforms are read from, so only its samples are replayed.
     #[cfg(any(doc, feature = "trace_avg_samples"))]
-    pub fn trace_avg_samples(&self) {
-        let first_sample = self
-            .current_wrapped()
-            .wrapping_counter_sub(250, LONGTERM_SIZE);
-        let new_samples = self
-            .longterm_buffer
+    pub fn trace_avg_samples(&self, probe: usize) {
+        let detector = &self.detectors[probe];
+        let first_sample = detector.back(250);
+        let new_samples = detector
+            .samples()
             .get(first_sample..first_sample + 250)
             .unwrap();
-        trace!("Here are the last 250 samples:\n{=[u8]}", new_samples)
-    }
-
-    /// Analyze the most recent data to determine if a contact event has occurred.
-    ///
-    /// Also updates the record of recent detection events
-    pub fn detect_contact(&mut self) -> bool {
-        debug!("Checking for contact");
-        if !self.await_confirm {
-            // First contact check
-            let prev_sample = self.current_sample.wrapping_counter_sub(1, LONGTERM_SIZE);
-            if i16::abs(
-                self.longterm_buffer[prev_sample] as i16
-                    - self.longterm_buffer[self.current_sample.get_counter()] as i16,
-            ) >= Self::INIT_TRIGGER_DELTA
-            {
-                self.await_confirm = true;
-            }
-            return false;
+        if probe == 0 {
+            log!(
+                Detection,
+                trace,
+                "Here are the last 250 samples:\n{=[u8]}",
+                new_samples
+            )
         } else {
-            // Validation contact check
-            self.await_confirm = false; // Always reset on validation check
-            let prev_high_sample = self.current_sample.wrapping_counter_sub(2, LONGTERM_SIZE);
-            if i16::abs(
-                self.longterm_buffer[prev_high_sample] as i16
-                    - self.longterm_buffer[self.current_sample.get_counter()] as i16,
-            ) >= 1
-            {
-                // Contact detected!
-                self.add_detection_event();
-                return true;
-            }
+            log!(
+                Detection,
+                trace,
+                "Last 250 samples from probe {=usize}:\n{=[u8]}",
+                probe,
+                new_samples
+            )
         }
-        false
     }
 
-    /// Analyze the most recent data and contact events to determine when contact ends
-    ///
-    /// A detection [`StatusLedStates::Alert`](crate::components::StatusLedStates::Alert) will not clear until at least 150 samples (300 milliseconds with 2 ms
-    /// averaging) have been recorded. This ensures the operator will see the LED light up.
-    pub fn detect_end_contact(&mut self) -> bool {
-        debug!("Checking for end of contact");
-        if let Some(last_detection) = self.detection_events[0] {
-            if self
-                .current_sample
-                .wrapping_counter_sub(last_detection.0.get_counter(), LONGTERM_SIZE)
-                >= 150
-            {
-                self.await_confirm = false;
-                return true;
-            } else if !self.await_confirm
-                && i16::abs(
-                    self.longterm_buffer[self.current_sample.get_counter()] as i16
-                        - last_detection.1 as i16,
-                ) >= Self::INIT_RESTORE_DELTA
-            {
-                // First clear check
-                self.await_confirm = true;
-            }
-            return false;
-        } else if self.await_confirm {
-            // Validation clear check
-            self.await_confirm = false;
-            if let Some(last_detection) = self.detection_events[0] {
-                if i16::abs(
-                    self.longterm_buffer[self.current_sample.get_counter()] as i16
-                        - last_detection.1 as i16,
-                ) >= 1
-                {
-                    // Contact cleared!
-                    return true;
+    /// Run the contact state machine of `probe` on its most recent sample, see [`Detector::step`].
+    /// With `triple_channel`, contact is detected and ended by the vote between the channels
+    /// instead, see [`Voter::vote`].
+    pub fn step(&mut self, probe: usize, phase: Phase) -> Option<Event> {
+        let last_detection = self.last_detection(probe);
+        let detector = &mut self.detectors[probe];
+        match phase {
+            Phase::Calibrating => {}
+            Phase::Armed => log!(Detection, debug, "Checking for contact"),
+            Phase::Warning => log!(Detection, debug, "Checking for contact or end of warning"),
+            Phase::Alert => {
+                log!(Detection, debug, "Checking for end of contact");
+                if last_detection.is_none() {
+                    log!(
+                        Detection,
+                        warn,
+                        "End contact detection was called before any detection events have \
+                         occurred."
+                    );
                 }
             }
-        } else {
-            warn!("End contact detection was called before any detection events have occurred.");
         }
-        false
+        let event = perf::measure(Section::Detect, || detector.step(phase));
+        #[cfg(feature = "triple_channel")]
+        let event = self.voter.vote(phase, event);
+        event
+    }
+
+    /// Step the candidate detectors and compare them to the `production` phase of each probe, see
+    /// [`Comparison::step`]
+    #[cfg(feature = "ab_compare")]
+    pub fn compare(&mut self, production: [Option<Phase>; PROBES]) {
+        let sample = self.samples_recorded();
+        self.comparison
+            .step(production, sample, &self.detection_config);
     }
 
-    /// Shortcut to return index of a successful detection sample.
+    /// Most recent detection event on `probe`, if any. With `triple_channel`, this is the event of
+    /// the channel that completed the vote.
+    #[cfg_attr(feature = "triple_channel", allow(unused_variables))]
+    pub fn last_detection(&self, probe: usize) -> Option<DetectionEvent> {
+        #[cfg(not(feature = "triple_channel"))]
+        let detection = self.detectors[probe].last_detection();
+        #[cfg(feature = "triple_channel")]
+        let detection = self.voter.last_detection();
+        detection
+    }
+
+    /// Shortcut to return the counter of a successful detection sample on `probe`.
     ///
     ///```no_run
-    /// use std::cell::RefCell;
-    /// use critical_section::Mutex;
-    /// use aps490_pfpu2_mini::buffer::Buffers;
-    /// 
-    /// pub static BUFFERS: Mutex<RefCell<Option<&'static mut Buffers>>> = Mutex::new(RefCell::new(None));
+    /// use aps490_pfpu2_mini::{
+    ///     buffer::{Buffers, PROBES},
+    ///     config::DetectionConfig,
+    /// };
     ///
-    /// Buffers::init();
-    /// critical_section::with(|cs| BUFFERS.borrow_ref_mut(cs).as_mut().unwrap().insert(12));
-    /// critical_section::with(|cs| {
-    ///    let buf = BUFFERS.borrow_ref_mut(cs).as_mut().unwrap();
-    ///    assert_eq!(buf.detection_idx(), buf.current_wrapped().get_counter() - 1)
-    /// });
+    /// let buf = Buffers::init(DetectionConfig::default()).unwrap();
+    /// buf.insert([12; PROBES]);
+    /// assert_eq!(buf.detection_idx(0).get_counter(), buf.samples_recorded() - 1)
     ///```
-    pub fn detection_idx(&self) -> usize {
-        self.current_sample.get_counter() - 1
+    pub fn detection_idx(&self, probe: usize) -> SampleCounter {
+        self.detectors[probe].detection_idx()
     }
+}
 
-    /// Add an entry to the `detection_events` array, based on the penultimate sample.
-    fn add_detection_event(&mut self) {
-        self.detection_events.rotate_right(1);
-        self.detection_events[0] = Some((
-            self.current_sample,
-            self.longterm_buffer[self.current_sample.get_counter()],
-        ));
-    }
+/// Insert `sample` into `detector`. [`Detector::insert`] is inlined here, so it runs from RAM
+/// with the rest of the sampling path (see `memory.x`) while the detection core has no link
+/// sections of its own.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+fn insert_sample(detector: &mut Detector<LONGTERM_SIZE>, sample: u8) {
+    detector.insert(sample);
 }
 
-/// Newtype to send formatted error messages when [`Buffers::detect_contact`] is successful.
+/// Newtype to send formatted error messages when [`Buffers::step`] detects contact, with the
+/// sample and the probe it was detected on.
 #[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
-pub struct DetectionMsg(pub SampleCounter);
+pub struct DetectionMsg(pub SampleCounter, pub usize);
 
 impl DetectionMsg {
-    /// Create a detection message:
+    /// Create a detection message for `probe`:
     ///
     /// > "contact detected on sample {[`Buffers::detection_idx`]}! Adding to detection events"`
-    pub fn create(buffer: &Buffers) -> Self {
-        Self(SampleCounter(buffer.detection_idx()))
+    pub fn create(buffer: &Buffers, probe: usize) -> Self {
+        Self(buffer.detection_idx(probe), probe)
     }
 }
 
 impl Format for DetectionMsg {
     fn format(&self, fmt: Formatter) {
-        defmt::write!(
-            fmt,
-            "contact detected on sample {}! Adding to detection events",
-            self.0
-        )
+        if PROBES > 1 {
+            defmt::write!(
+                fmt,
+                "contact detected on sample {} of probe {=usize}! Adding to detection events",
+                self.0,
+                self.1
+            )
+        } else {
+            defmt::write!(
+                fmt,
+                "contact detected on sample {}! Adding to detection events",
+                self.0
+            )
+        }
     }
 }
 
-/// Creates a [`singleton`] buffer for ADC DMA transfers
-pub fn create_avg_buffer() -> Option<&'static mut [u8; 4000]> {
-    singleton!(: [u8; 4000] = [0u8; 4000])
+/// Pair of ADC DMA buffers, word-aligned so they can be averaged a word at a time
+#[repr(C, align(4))]
+struct AvgBuffers([[u8; DMA_BUFFER_SIZE]; 2]);
+
+// Each buffer starts on a word, and its words never straddle two periods of the signal
+const _: () = assert!(DMA_BUFFER_SIZE.is_multiple_of(4 * ADC_CHANNELS));
+
+/// Creates a [`singleton`] pair of word-aligned buffers for ADC DMA transfers, so one can be
+/// averaged in place while the other is filled
+///
+/// Returns [`Error::AlreadyInitialized`] if the buffers have already been created.
+pub fn create_avg_buffers() -> Result<[&'static mut [u8; DMA_BUFFER_SIZE]; 2]> {
+    let AvgBuffers([first, second]) =
+        singleton!(: AvgBuffers = AvgBuffers([[0u8; DMA_BUFFER_SIZE]; 2]))
+            .ok_or(Error::AlreadyInitialized)?;
+    Ok([first, second])
+}
+
+/// Time constant of the [`AutoZero`] stage in samples, from
+/// [`DetectionConfig::auto_zero_tau_s`]
+fn auto_zero_samples(detection_config: &DetectionConfig) -> u32 {
+    samples_in(Millis(detection_config.auto_zero_tau_s as u32 * 1000)) as u32
 }
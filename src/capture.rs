@@ -0,0 +1,136 @@
+//! Waveform captures taken on request, with `CAPTURE <n>` on the console.
+//!
+//! Unlike the detection events kept by the [`postmortem`](crate::postmortem) trace, a capture is
+//! started by the operator, whenever they want to see the signal. [`Capture::take`] copies the
+//! most recent `n` averaged samples of each probe out of the long-term buffers into a dedicated
+//! buffer, while the caller holds them for as long as the copy takes, so acquisition never pauses.
+//! The frozen copy is then streamed over the console as CSV, continued each time the USB device
+//! is polled, and ends with the CRC-32 (as used by [`storage::crc32`]) of every byte before it:
+//!
+//! ```text
+//! # capture 3 samples from 81250
+//! sample,probe0
+//! 81250,127
+//! 81251,126
+//! 81252,129
+//! CRC32 2D3C1F0A
+//! ```
+//!
+//! Samples are numbered by their [`SampleCounter`](crate::buffer::SampleCounter), so they line
+//! up with [markers](crate::trigger) in the journal. As in the detector, the
+//! [`auto_zero`](crate::auto_zero) offset has already been removed.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt::Write;
+
+use heapless::String;
+
+pub use crate::protocol::CAPTURE_SAMPLES;
+use crate::{
+    buffer::{Buffers, LONGTERM_SIZE, PROBES},
+    console::ConsoleOutput,
+    storage,
+};
+
+/// Longest line written by a [`Capture`]
+const LINE_SIZE: usize = 96;
+
+/// A single line of a [`Capture`]
+type Line = String<LINE_SIZE>;
+
+/// Frozen copy of the most recent samples, and progress through streaming it
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Capture {
+    /// Samples of each probe, from the oldest
+    samples: [[u8; CAPTURE_SAMPLES]; PROBES],
+    /// Samples held of each probe
+    len: usize,
+    /// Sample counter of the oldest sample held
+    first: u64,
+    /// Next line to write: the header, then each sample, then the CRC
+    line: usize,
+    /// CRC-32 of the lines written so far, before the final inversion
+    crc: u32,
+}
+
+impl Capture {
+    /// Copy the most recent `samples` of each probe out of `buffers`, up to [`CAPTURE_SAMPLES`]
+    /// and the samples recorded so far
+    pub fn take(buffers: &Buffers, samples: usize) -> Self {
+        let recorded = buffers.samples_recorded();
+        let len = samples
+            .min(CAPTURE_SAMPLES)
+            .min(LONGTERM_SIZE)
+            .min(recorded as usize);
+        let mut capture = Self {
+            samples: [[0; CAPTURE_SAMPLES]; PROBES],
+            len,
+            first: recorded + 1 - len as u64,
+            line: 0,
+            crc: u32::MAX,
+        };
+        for (probe, samples) in capture.samples.iter_mut().enumerate() {
+            for (idx, sample) in samples[..len].iter_mut().enumerate() {
+                *sample = buffers.recent(probe, len - 1 - idx);
+            }
+        }
+        capture
+    }
+
+    /// Whether the whole capture has been written
+    pub fn done(&self) -> bool {
+        self.line > self.len + 1
+    }
+
+    /// Write as many whole lines to `out` as fit
+    pub fn fill(&mut self, out: &mut ConsoleOutput) {
+        while !self.done() {
+            let line = self.next_line();
+            if out.push_str(&line).is_err() {
+                return;
+            }
+            if self.line <= self.len {
+                self.crc = storage::crc32_update(self.crc, line.as_bytes());
+            }
+            self.line += 1;
+        }
+    }
+
+    /// Produce the current line, without moving on to the next
+    fn next_line(&self) -> Line {
+        let mut line = Line::new();
+        if self.line == 0 {
+            let _ = write!(
+                line,
+                "# capture {} samples from {}\r\nsample",
+                self.len, self.first
+            );
+            for probe in 0..PROBES {
+                let _ = write!(line, ",probe{}", probe);
+            }
+        } else if self.line <= self.len {
+            let idx = self.line - 1;
+            let _ = write!(line, "{}", self.first + idx as u64);
+            for samples in &self.samples {
+                let _ = write!(line, ",{}", samples[idx]);
+            }
+        } else {
+            let _ = write!(line, "CRC32 {:08X}", !self.crc);
+        }
+        let _ = write!(line, "\r\n");
+        line
+    }
+}
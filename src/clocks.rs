@@ -0,0 +1,158 @@
+//! System clock profiles.
+//!
+//! The [`ClockProfile`] is selected with `clocks.profile` in `board.toml`, and applied by
+//! [`init`] in [`Board::init`](crate::board::Board::init). Power-sensitive builds can underclock
+//! to [`ClockProfile::LowPower`], and builds doing heavy streaming over the console can overclock
+//! to [`ClockProfile::Overclock`]. Only the system and peripheral clocks change: the ADC and USB
+//! clocks run from `PLL_USB` at [`ADC_CLOCK_HZ`] in every profile, keeping the ADC in spec and its
+//! sample rate fixed.
+//!
+//! The RP2350 runs the same PLL settings, but its core voltage is left at the default, so it
+//! can't use [`ClockProfile::Overclock`] (`build.rs` rejects it). Its watchdog and timers each
+//! have their own tick generator, which [`init`] starts alongside the watchdog's.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::{info, Format};
+
+#[cfg(not(feature = "rp2350"))]
+use crate::hal::{pac::vreg_and_chip_reset::vreg::VSEL_A, vreg};
+use crate::{
+    board::XOSC_FREQ_HZ,
+    error::{Error, Result},
+    hal::{
+        clocks::ClocksManager,
+        fugit::RateExtU32,
+        pac,
+        pll::{common_configs::PLL_USB_48MHZ, setup_pll_blocking, PLLConfig},
+        xosc::setup_xosc_blocking,
+        Watchdog,
+    },
+};
+
+/// ADC and USB clock frequency, from `PLL_USB` in every profile
+pub const ADC_CLOCK_HZ: u32 = 48_000_000;
+/// Crystal cycles per microsecond tick of the watchdog and timer
+const TICK_CYCLES: u32 = XOSC_FREQ_HZ / 1_000_000;
+
+/// System clock frequency, and the core voltage needed to run it
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum ClockProfile {
+    /// 48 MHz, for the lowest power draw
+    LowPower,
+    /// 125 MHz, the SDK default
+    Standard,
+    /// 200 MHz, with the core voltage raised to 1.15 V
+    Overclock,
+}
+
+impl ClockProfile {
+    /// System clock frequency in Hz
+    pub const fn sys_freq_hz(self) -> u32 {
+        match self {
+            ClockProfile::LowPower => 48_000_000,
+            ClockProfile::Standard => 125_000_000,
+            ClockProfile::Overclock => 200_000_000,
+        }
+    }
+
+    /// `PLL_SYS` settings producing [`sys_freq_hz`](ClockProfile::sys_freq_hz) from the crystal
+    fn pll_config(self) -> PLLConfig {
+        match self {
+            // 1440 MHz VCO / 6 / 5
+            ClockProfile::LowPower => PLLConfig {
+                vco_freq: 1440.MHz(),
+                refdiv: 1,
+                post_div1: 6,
+                post_div2: 5,
+            },
+            // 1500 MHz VCO / 6 / 2, as the SDK
+            ClockProfile::Standard => PLLConfig {
+                vco_freq: 1500.MHz(),
+                refdiv: 1,
+                post_div1: 6,
+                post_div2: 2,
+            },
+            // 1200 MHz VCO / 6 / 1
+            ClockProfile::Overclock => PLLConfig {
+                vco_freq: 1200.MHz(),
+                refdiv: 1,
+                post_div1: 6,
+                post_div2: 1,
+            },
+        }
+    }
+
+    /// Core voltage for the profile. Only overclocking needs more than the 1.10 V default.
+    #[cfg(not(feature = "rp2350"))]
+    fn voltage(self) -> VSEL_A {
+        match self {
+            ClockProfile::LowPower | ClockProfile::Standard => VSEL_A::VOLTAGE1_10,
+            ClockProfile::Overclock => VSEL_A::VOLTAGE1_15,
+        }
+    }
+}
+
+/// Set the core voltage needed by `profile`. Call before [`init`], so the voltage is already high
+/// enough when the clock is raised.
+#[cfg(not(feature = "rp2350"))]
+pub fn set_voltage(profile: ClockProfile, vreg_dev: &mut pac::VREG_AND_CHIP_RESET) {
+    vreg::set_voltage(vreg_dev, profile.voltage());
+}
+
+/// Start the crystal and both PLLs, and run the system and peripheral clocks at `profile`.
+///
+/// Returns [`Error::Clocks`] if the crystal or either PLL fails to start, or the clocks can't be
+/// switched over.
+pub fn init(
+    profile: ClockProfile,
+    xosc_dev: pac::XOSC,
+    clocks_dev: pac::CLOCKS,
+    pll_sys_dev: pac::PLL_SYS,
+    pll_usb_dev: pac::PLL_USB,
+    resets: &mut pac::RESETS,
+    watchdog: &mut Watchdog,
+) -> Result<ClocksManager> {
+    let xosc = setup_xosc_blocking(xosc_dev, XOSC_FREQ_HZ.Hz()).map_err(|_| Error::Clocks)?;
+    // The watchdog and timer count microseconds from the crystal
+    watchdog.enable_tick_generation(TICK_CYCLES as _);
+
+    let mut clocks = ClocksManager::new(clocks_dev);
+    let pll_sys = setup_pll_blocking(
+        pll_sys_dev,
+        xosc.operating_frequency(),
+        profile.pll_config(),
+        &mut clocks,
+        resets,
+    )
+    .map_err(|_| Error::Clocks)?;
+    let pll_usb = setup_pll_blocking(
+        pll_usb_dev,
+        xosc.operating_frequency(),
+        PLL_USB_48MHZ,
+        &mut clocks,
+        resets,
+    )
+    .map_err(|_| Error::Clocks)?;
+    clocks
+        .init_default(&xosc, &pll_sys, &pll_usb)
+        .map_err(|_| Error::Clocks)?;
+    info!(
+        "System clock running at {=u32} Hz ({})",
+        profile.sys_freq_hz(),
+        profile
+    );
+    Ok(clocks)
+}
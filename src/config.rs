@@ -0,0 +1,441 @@
+//! Runtime configuration, persisted in flash and portable between units as a
+//! [postcard](https://docs.rs/postcard) blob.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::{info, warn, Format};
+use serde::{Deserialize, Serialize};
+
+pub use crate::protocol::{ConfigError, RestoreProfile, Threshold};
+#[cfg(not(feature = "minimal"))]
+use crate::provision;
+use crate::{
+    buffer,
+    detection_core::Thresholds,
+    sequence::Timing,
+    storage::{self, CONFIG_OFFSET},
+    units::{AdcScale, Millis, Millivolts, ADC_FULL_SCALE_CODES, ADC_REFERENCE_MV},
+};
+
+/// Pin assignments, buffer sizes and default thresholds for the board variant, generated by
+/// `build.rs` from `board.toml` and the variant's pin map in `boards/`.
+pub mod board {
+    include!(concat!(env!("OUT_DIR"), "/config_generated.rs"));
+}
+
+/// Longest postcard varint encoding of a 16-bit integer, including the zigzag of an `i16`
+const VARINT_16_MAX: usize = 3;
+/// Longest postcard varint encoding of a 32-bit integer
+const VARINT_32_MAX: usize = 5;
+
+/// Thresholds used by [`Buffers`](crate::buffer::Buffers) to detect contact
+#[derive(
+    Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format, Serialize, Deserialize,
+)]
+pub struct DetectionConfig {
+    /// Averaged difference used for detecting contact.
+    ///
+    /// Ex. a trigger delta of 128 on a 3.3V signal requires that the average voltage range has
+    /// decreased by approximately 1.65V. Current values are based on experimental data and account
+    /// for signal drift.
+    pub trigger_delta: i16,
+    /// Smaller averaged difference that raises a warning: the yellow LED and a soft beep, without
+    /// tripping the interlock (see [`SystemState::Warning`]). Only differences below
+    /// `trigger_delta` warn. 0 disables warnings.
+    ///
+    /// [`SystemState::Warning`]: crate::state::SystemState::Warning
+    pub warning_delta: i16,
+    /// Difference from the sample preceding a trigger needed to confirm a contact or clear event
+    pub confirm_delta: i16,
+    /// Averaged difference to restore
+    /// [`StatusLedStates::Normal`](crate::components::StatusLedStates::Normal).
+    ///
+    /// This is the increase in voltage relative to the last detection event. Current values are
+    /// based on experimental data and account for signal drift.
+    pub restore_delta: i16,
+    /// Number of samples after a detection event before the alert can clear (150 samples is 300
+    /// milliseconds with 2 ms averaging).
+    pub alert_hold_samples: u32,
+    /// Whether the signal must then stay restored for `restore_fast_samples` or
+    /// `restore_slow_samples` in a row before contact ends, see [`Thresholds::restore_samples`]
+    pub restore_profile: RestoreProfile,
+    /// Consecutive restored samples that end contact with [`RestoreProfile::Fast`]
+    pub restore_fast_samples: u32,
+    /// Consecutive restored samples that end contact with [`RestoreProfile::Slow`]
+    pub restore_slow_samples: u32,
+    /// Once contact ends, hold the alert in
+    /// [`SystemState::Latched`](crate::state::SystemState::Latched) until the operator resets it,
+    /// rather than rearming. Alerts raised by a test [injection](crate::injection) latch too.
+    pub latch_alert: bool,
+    /// With [`latch_alert`](Self::latch_alert), milliseconds after contact ends before a latched
+    /// alert clears by itself and detection rearms, as if the operator had reset it. 0 waits for
+    /// the operator. Lets each site choose between the two in its configuration.
+    pub auto_clear_ms: u32,
+    /// Shortest time in milliseconds an interlock stays tripped from each detection, even if
+    /// contact ends sooner, so slow external loggers and PLC input cards can't miss it. 0 releases
+    /// it as soon as contact ends. See [`StateMachine::release_stretched`].
+    ///
+    /// [`StateMachine::release_stretched`]: crate::state::StateMachine::release_stretched
+    pub min_trip_ms: u32,
+    /// Milliseconds an interlock stays tripped before every release, so the gated tool is never
+    /// re-enabled the instant it is allowed to be. 0 releases it at once. See
+    /// [`sequence`](crate::sequence).
+    pub enable_delay_ms: u32,
+    /// Milliseconds an interlock stays tripped after an alert, before the enable delay, so a
+    /// spindle isn't re-enabled straight after a contact. 0 skips the cool-down.
+    pub cooldown_ms: u32,
+    /// Milliseconds the buzzer stays muted once silenced by the operator, see
+    /// [`StateMachine::snooze`](crate::state::StateMachine::snooze)
+    pub snooze_ms: u32,
+    /// Milliseconds detection waits to arm once the signal has settled, with the interlock tripped
+    /// and a countdown on the LEDs, so the analog front-end settles after power-up. 0 arms
+    /// straight away. See [`StateMachine::settled`](crate::state::StateMachine::settled).
+    pub arming_delay_ms: u32,
+    /// Hours spent armed between automatic proof tests (see [`injection`](crate::injection)), or
+    /// 0 to only test on request
+    pub proof_test_hours: u16,
+    /// Time constant in seconds of the [`auto_zero`](crate::auto_zero) stage, which removes the
+    /// slowly varying DC offset of the front-end before detection. 0 bypasses it, for bring-up.
+    pub auto_zero_tau_s: u16,
+    /// Lowest plausible averaged sample. Samples below it are kept from the detector, see
+    /// [`plausibility`](crate::plausibility).
+    pub plausible_min: u8,
+    /// Highest plausible averaged sample. Samples above it are kept from the detector.
+    pub plausible_max: u8,
+    /// Consecutive samples outside the plausible range before
+    /// [`Error::SignalOutOfRange`](crate::error::Error::SignalOutOfRange) is raised (50 ms with
+    /// 2 ms averaging)
+    pub implausible_samples: u16,
+    /// Share of `trigger_delta`, in percent, that a change must reach without detecting contact
+    /// to be recorded as a [near miss](crate::near_miss)
+    pub near_miss_percent: u8,
+    /// Thresholds set in millivolts at the ADC, in place of `trigger_delta`, `warning_delta`,
+    /// `confirm_delta` and `restore_delta`. They are converted to codes at the [`adc_scale`] each
+    /// time the configuration is applied, so they keep their meaning when the unit's calibration
+    /// changes. [`None`] uses the codes as they are.
+    pub millivolts: Option<MillivoltThresholds>,
+}
+
+/// Detection thresholds in millivolts, see [`DetectionConfig::millivolts`]
+#[derive(
+    Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format, Serialize, Deserialize,
+)]
+pub struct MillivoltThresholds {
+    /// Replaces [`DetectionConfig::trigger_delta`]
+    pub trigger_mv: i16,
+    /// Replaces [`DetectionConfig::warning_delta`]
+    pub warning_mv: i16,
+    /// Replaces [`DetectionConfig::confirm_delta`]
+    pub confirm_mv: i16,
+    /// Replaces [`DetectionConfig::restore_delta`]
+    pub restore_mv: i16,
+}
+
+/// Scale of the corrected samples of this unit, using the ADC reference it was
+/// [provisioned](provision) with, or the nominal [`ADC_REFERENCE_MV`] in the `minimal` build
+pub fn adc_scale() -> AdcScale {
+    #[cfg(not(feature = "minimal"))]
+    let reference_mv = provision::get().adc_reference_mv();
+    #[cfg(feature = "minimal")]
+    let reference_mv = ADC_REFERENCE_MV;
+    AdcScale {
+        reference_mv,
+        full_scale_codes: ADC_FULL_SCALE_CODES,
+    }
+}
+
+impl DetectionConfig {
+    /// Longest postcard encoding of the configuration, with every field at its widest. Integers are
+    /// varints, up to 3 bytes for 16 bits and 5 for 32, while a `u8`, a `bool`, an enum variant and
+    /// the [`Option`] tag take a byte each.
+    pub const MAX_ENCODED_SIZE: usize = 9 * VARINT_32_MAX // sample counts and delays
+        + 3 * VARINT_16_MAX // proof test, auto-zero and implausible sample counts
+        + 4 * VARINT_16_MAX // deltas
+        + 3 // plausible range and near miss percentage
+        + 2 // restore profile and alert latching
+        + 1 + 4 * VARINT_16_MAX; // millivolt thresholds
+    /// Compile-time defaults, from `board.toml`
+    pub const DEFAULT: Self = Self {
+        trigger_delta: board::DEFAULT_TRIGGER_DELTA,
+        warning_delta: board::DEFAULT_WARNING_DELTA,
+        confirm_delta: board::DEFAULT_CONFIRM_DELTA,
+        restore_delta: board::DEFAULT_RESTORE_DELTA,
+        alert_hold_samples: board::DEFAULT_ALERT_HOLD_SAMPLES,
+        restore_profile: board::DEFAULT_RESTORE_PROFILE,
+        restore_fast_samples: board::DEFAULT_RESTORE_FAST_SAMPLES,
+        restore_slow_samples: board::DEFAULT_RESTORE_SLOW_SAMPLES,
+        latch_alert: board::DEFAULT_LATCH_ALERT,
+        auto_clear_ms: board::DEFAULT_AUTO_CLEAR_MS,
+        min_trip_ms: board::DEFAULT_MIN_TRIP_MS,
+        enable_delay_ms: board::DEFAULT_ENABLE_DELAY_MS,
+        cooldown_ms: board::DEFAULT_COOLDOWN_MS,
+        snooze_ms: board::DEFAULT_SNOOZE_MS,
+        arming_delay_ms: board::DEFAULT_ARMING_DELAY_MS,
+        proof_test_hours: board::DEFAULT_PROOF_TEST_HOURS,
+        auto_zero_tau_s: board::DEFAULT_AUTO_ZERO_TAU_S,
+        plausible_min: board::DEFAULT_PLAUSIBLE_MIN,
+        plausible_max: board::DEFAULT_PLAUSIBLE_MAX,
+        implausible_samples: board::DEFAULT_IMPLAUSIBLE_SAMPLES,
+        near_miss_percent: board::DEFAULT_NEAR_MISS_PERCENT,
+        millivolts: None,
+    };
+
+    /// Thresholds passed to the [`Detector`](crate::detection_core::Detector)
+    pub fn thresholds(&self) -> Thresholds {
+        Thresholds {
+            trigger_delta: self.trigger_delta,
+            warning_delta: self.warning_delta,
+            confirm_delta: self.confirm_delta,
+            restore_delta: self.restore_delta,
+            alert_hold_samples: self.alert_hold_samples,
+            restore_samples: self.restore_samples(),
+        }
+    }
+
+    /// Consecutive restored samples that end contact with the
+    /// [`restore_profile`](Self::restore_profile)
+    pub fn restore_samples(&self) -> u32 {
+        match self.restore_profile {
+            RestoreProfile::Fast => self.restore_fast_samples,
+            RestoreProfile::Slow => self.restore_slow_samples,
+        }
+    }
+
+    /// Release sequencing of the interlocks, from [`enable_delay_ms`](Self::enable_delay_ms) and
+    /// [`cooldown_ms`](Self::cooldown_ms)
+    pub fn sequence_timing(&self) -> Timing {
+        Timing {
+            enable_delay_ms: self.enable_delay_ms,
+            cooldown_ms: self.cooldown_ms,
+        }
+    }
+
+    /// Threshold in codes, as used by the detector
+    pub fn codes(&self, threshold: Threshold) -> i16 {
+        match threshold {
+            Threshold::Trigger => self.trigger_delta,
+            Threshold::Warning => self.warning_delta,
+            Threshold::Confirm => self.confirm_delta,
+            Threshold::Restore => self.restore_delta,
+        }
+    }
+
+    /// Threshold as a voltage at the ADC: as set if [`millivolts`](Self::millivolts) are, or else
+    /// converted from codes at the [`adc_scale`]
+    pub fn voltage(&self, threshold: Threshold) -> Millivolts {
+        let Some(millivolts) = self.millivolts else {
+            return adc_scale().millivolts(self.codes(threshold));
+        };
+        Millivolts(match threshold {
+            Threshold::Trigger => millivolts.trigger_mv,
+            Threshold::Warning => millivolts.warning_mv,
+            Threshold::Confirm => millivolts.confirm_mv,
+            Threshold::Restore => millivolts.restore_mv,
+        } as i32)
+    }
+
+    /// Set `threshold` to `voltage`. If the thresholds were set in codes, the others are converted
+    /// to millivolts first, so all of them follow the calibration from now on.
+    pub fn set_voltage(&mut self, threshold: Threshold, voltage: Millivolts) {
+        let mut millivolts = self.millivolts.unwrap_or_else(|| MillivoltThresholds {
+            trigger_mv: self.voltage(Threshold::Trigger).0 as i16,
+            warning_mv: self.voltage(Threshold::Warning).0 as i16,
+            confirm_mv: self.voltage(Threshold::Confirm).0 as i16,
+            restore_mv: self.voltage(Threshold::Restore).0 as i16,
+        });
+        let mv = voltage.0 as i16;
+        match threshold {
+            Threshold::Trigger => millivolts.trigger_mv = mv,
+            Threshold::Warning => millivolts.warning_mv = mv,
+            Threshold::Confirm => millivolts.confirm_mv = mv,
+            Threshold::Restore => millivolts.restore_mv = mv,
+        }
+        self.millivolts = Some(millivolts);
+    }
+
+    /// Copy with the thresholds in codes converted from [`millivolts`](Self::millivolts) at
+    /// `scale`, if they are set. A threshold above 0 mV is at least 1 code, so it can't disable
+    /// detection by rounding down.
+    pub fn resolve(&self, scale: AdcScale) -> Self {
+        let Some(millivolts) = self.millivolts else {
+            return *self;
+        };
+        let codes = |mv: i16| match scale.codes(Millivolts(mv as i32)) {
+            0 if mv > 0 => 1,
+            codes => codes,
+        };
+        Self {
+            trigger_delta: codes(millivolts.trigger_mv),
+            warning_delta: codes(millivolts.warning_mv),
+            confirm_delta: codes(millivolts.confirm_mv),
+            restore_delta: codes(millivolts.restore_mv),
+            ..*self
+        }
+    }
+
+    /// Whether every setting is in the range `build.rs` accepts from `board.toml`, so a blob that
+    /// passes its CRC can't disable detection or overrun the long-term buffer. Only the warning
+    /// threshold can be 0.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let longterm = 0..=board::LONGTERM_SIZE as u32;
+        let thresholds_valid = match self.millivolts {
+            Some(_) => Threshold::ALL
+                .into_iter()
+                .all(|threshold| threshold.valid(self.voltage(threshold))),
+            None => Threshold::ALL.into_iter().all(|threshold| {
+                let min = i16::from(threshold != Threshold::Warning);
+                (min..=255).contains(&self.codes(threshold))
+            }),
+        };
+        let valid = thresholds_valid
+            && longterm.contains(&self.alert_hold_samples)
+            && longterm.contains(&self.restore_fast_samples)
+            && longterm.contains(&self.restore_slow_samples)
+            && self.plausible_min <= self.plausible_max
+            && (1..=99).contains(&self.near_miss_percent);
+        valid.then_some(()).ok_or(ConfigError::Invalid)
+    }
+
+    /// [`alert_hold_samples`](Self::alert_hold_samples) as a duration, at the
+    /// [sample rate](buffer::SAMPLE_RATE_HZ)
+    pub fn alert_hold(&self) -> Millis {
+        buffer::duration_of(self.alert_hold_samples as u64)
+    }
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Complete system configuration
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    Hash,
+    Ord,
+    PartialOrd,
+    Eq,
+    PartialEq,
+    Format,
+    Serialize,
+    Deserialize,
+)]
+pub struct Config {
+    /// Detection thresholds
+    pub detection: DetectionConfig,
+}
+
+impl Config {
+    /// Compile-time defaults, restored by a factory reset
+    pub const DEFAULT: Self = Self {
+        detection: DetectionConfig::DEFAULT,
+    };
+    /// Maximum size of a serialized blob: the longest encoding of the
+    /// [`DetectionConfig`](DetectionConfig::MAX_ENCODED_SIZE), and the trailing CRC
+    pub const MAX_BLOB_SIZE: usize = DetectionConfig::MAX_ENCODED_SIZE + 4;
+    /// Version of the layout of [`Config`], stored with it in flash. Increment it whenever a field
+    /// is added, removed or reordered, and migrate the older versions in [`Config::load`].
+    pub const VERSION: u16 = 1;
+    /// Marks a configuration record in flash ("PFPC")
+    const MAGIC: u32 = 0x4350_4650;
+    /// Marked the records stored before [`Config::VERSION`] was added, whose layout is unknown
+    /// ("PFPU")
+    const UNVERSIONED_MAGIC: u32 = 0x5550_4650;
+    /// Magic number, version and blob length precede the blob in flash
+    const HEADER_SIZE: usize = 8;
+
+    /// Load the configuration persisted in flash, rejecting settings out of range.
+    ///
+    /// Returns [`ConfigError::Outdated`] for a configuration stored with another
+    /// [`Config::VERSION`], including those stored before it was added.
+    pub fn load() -> Result<Self, ConfigError> {
+        let header = storage::read(CONFIG_OFFSET, Self::HEADER_SIZE);
+        match u32::from_le_bytes([header[0], header[1], header[2], header[3]]) {
+            Self::MAGIC => {}
+            Self::UNVERSIONED_MAGIC => return Err(ConfigError::Outdated),
+            _ => return Err(ConfigError::NotFound),
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != Self::VERSION {
+            warn!(
+                "Configuration stored with version {=u16}, expected {=u16}",
+                version,
+                Self::VERSION
+            );
+            return Err(ConfigError::Outdated);
+        }
+        let blob_len = u16::from_le_bytes([header[6], header[7]]) as usize;
+        if blob_len > Self::MAX_BLOB_SIZE {
+            return Err(ConfigError::Corrupted);
+        }
+        Self::from_blob(storage::read(
+            CONFIG_OFFSET + Self::HEADER_SIZE as u32,
+            blob_len,
+        ))
+    }
+
+    /// Persist the configuration to flash.
+    pub fn store(&self) -> Result<(), ConfigError> {
+        let mut record = [0u8; Self::HEADER_SIZE + Self::MAX_BLOB_SIZE];
+        let blob_len = self
+            .to_blob((&mut record[Self::HEADER_SIZE..]).try_into().unwrap())?
+            .len();
+        record[..4].copy_from_slice(&Self::MAGIC.to_le_bytes());
+        record[4..6].copy_from_slice(&Self::VERSION.to_le_bytes());
+        record[6..Self::HEADER_SIZE].copy_from_slice(&(blob_len as u16).to_le_bytes());
+
+        storage::write_sector(CONFIG_OFFSET, &record[..Self::HEADER_SIZE + blob_len])
+            .map_err(ConfigError::Storage)
+    }
+
+    /// Restore and persist the compile-time defaults.
+    pub fn factory_reset() -> Result<Self, ConfigError> {
+        info!("Restoring factory configuration");
+        Self::DEFAULT.store()?;
+        Ok(Self::DEFAULT)
+    }
+
+    /// Serialize into a postcard blob followed by a little-endian CRC-32, for export to other
+    /// units.
+    pub fn to_blob<'a>(
+        &self,
+        buf: &'a mut [u8; Self::MAX_BLOB_SIZE],
+    ) -> Result<&'a [u8], ConfigError> {
+        let payload_len = postcard::to_slice(self, &mut buf[..Self::MAX_BLOB_SIZE - 4])
+            .map_err(|_| ConfigError::Serialization)?
+            .len();
+        let crc = storage::crc32(&buf[..payload_len]);
+        buf[payload_len..payload_len + 4].copy_from_slice(&crc.to_le_bytes());
+        Ok(&buf[..payload_len + 4])
+    }
+
+    /// Deserialize a blob created by [`Config::to_blob`], rejecting settings out of range.
+    pub fn from_blob(blob: &[u8]) -> Result<Self, ConfigError> {
+        if blob.len() < 4 {
+            return Err(ConfigError::Corrupted);
+        }
+        let (payload, crc) = blob.split_at(blob.len() - 4);
+        if storage::crc32(payload).to_le_bytes() != crc {
+            return Err(ConfigError::Corrupted);
+        }
+        let config: Self = postcard::from_bytes(payload).map_err(|_| ConfigError::Serialization)?;
+        config.detection.validate()?;
+        Ok(config)
+    }
+}
@@ -0,0 +1,1003 @@
+//! Line-based command console, served over USB serial by
+//! [`interrupt::poll_console`](crate::interrupt::poll_console).
+//!
+//! Commands are case-insensitive, and terminated by a carriage return or newline. Each time a host
+//! opens the port, the [`banner`](crate::banner) describing the unit is written first.
+//!
+//! Queries that only read the state are always accepted, so monitoring tools can poll freely.
+//! Commands marked *privileged* change the configuration or the state of the system, and are
+//! refused by [`Command::parse`] unless the console is unlocked with `UNLOCK`, or the
+//! configuration jumper is fitted (see [`lock`]). Besides those below, `STANDBY`, `INJECT`,
+//! `RESET LATCH`, `DRILL`, `CONFIRM` and `REBOOT` are privileged.
+//!
+//! Commands that persist anything to flash are also refused while sampling, as writing a sector
+//! stops every interrupt for tens of milliseconds, including acquisition and the power-fail
+//! handler (see [`Command::writes_flash`]). Disable detection first.
+//!
+//! - `HELP`: List available commands
+//! - `*IDN?`: Identify the unit on a standard first line of manufacturer, product, provisioned
+//!   serial number and firmware version with the git commit, followed by the build time, board
+//!   variant, hardware revision and [hash](crate::version::config_hash) of the active
+//!   configuration, then the enabled features (see [`version`](crate::version) and [`provision`])
+//! - `FACTORY RESET`: Restore and persist the compile-time default configuration, and clear the
+//!   usage totals. *Privileged.*
+//! - `CONFIG EXPORT`: Print the active configuration as a hex-encoded blob, formatted as a
+//!   `CONFIG IMPORT` command that can be pasted into another unit
+//! - `CONFIG IMPORT <hex>`: Validate, persist and apply a configuration blob. *Privileged.*
+//! - `THRESHOLD`: Print each detection threshold in millivolts and in the codes used by the
+//!   detector, and whether they were set in millivolts or codes
+//! - `THRESHOLD <name> <mV>`: Persist and apply the `TRIGGER`, `WARNING`, `CONFIRM` or `RESTORE`
+//!   threshold in millivolts at the ADC, converting the others to millivolts if they were set in
+//!   codes (see [`DetectionConfig::millivolts`](crate::config::DetectionConfig::millivolts)).
+//!   *Privileged.*
+//! - `RESTORE`: Print the samples each end-of-contact debounce profile needs restored in a row,
+//!   and the active profile
+//! - `RESTORE <profile>`: Persist and apply the `FAST` or `SLOW` profile (see
+//!   [`RestoreProfile`](crate::config::RestoreProfile)). *Privileged.*
+//! - `FAULTS`: List every error code raised since boot, with its count and the time of its first
+//!   and last occurrence (see [`fault`])
+//! - `PERF`: Print the minimum, average and maximum cycles spent in each measured section of the
+//!   detection path, with the `perf` feature (see [`perf`](crate::perf))
+//! - `LATENCY`: Print the histogram of `DMA_IRQ_0` latency, with the `irq_latency` feature (see
+//!   [`irq`](crate::irq)). `LATENCY CLEAR` empties it, to start a new measurement, and is
+//!   privileged.
+//! - `HIL <scenario>`: Play a loopback scenario (`STEP`, `BOUNCE`, `BLIP` or `DRIFT`) the next time
+//!   the system is armed, with the `hil` feature. *Privileged.* `HIL` on its own prints the outcome
+//!   of the most recent one (see [`hil`](crate::hil))
+//! - `AB <preset>`: Compare a candidate detector using the `SENSITIVE`, `STANDARD` or `TOLERANT`
+//!   thresholds against production, with the `ab_compare` feature. `AB OFF` stops it. Both are
+//!   privileged. `AB` on its own prints the divergences counted so far, and `AB REPORT` lists the
+//!   most recent ones as CSV (see [`ab_compare`](crate::ab_compare))
+//! - `I2C SCAN`: Probe the I2C bus, and list each expected peripheral as `ok` or `missing`, then
+//!   any unexpected address that responded, with the `i2c_scan` feature (see
+//!   [`i2c_scan`](crate::i2c_scan))
+//! - `UPTIME`: Print the time since boot, the time spent in each state, and the detections and
+//!   time armed since boot and since the last factory reset (see [`uptime`])
+//! - `HISTOGRAM`: Print the averaged samples counted from each probe over the last hour, then the
+//!   share of them in each bin, in per mille, rounded up so an occupied bin is never 0 (see
+//!   [`histogram`](crate::histogram))
+//! - `NEAR MISS`: Print the near misses counted for each probe since boot, then the most recent
+//!   of them as CSV, with their time, sample, probe and delta (see
+//!   [`near_miss`](crate::near_miss))
+//! - `SEQUENCE`: Print the release [sequence](crate::sequence) stage of each probe's interlock, and
+//!   the milliseconds left in it, then the configured enable delay and cool-down
+//! - `SOAK START`: Clear and start the soak test statistics of every armed probe, and `SOAK STOP`
+//!   freeze them. Both are privileged. `SOAK` on its own prints the duration, then for each probe
+//!   the samples recorded, their mean and standard deviation, the largest delta and the near
+//!   misses, then the count of each delta from 0 up, the last bin counting every larger delta (see
+//!   [`soak_test`](crate::soak_test))
+//! - `LOG`: Print the minimum level logged for each category, then the rate limit and the
+//!   messages it has dropped since boot
+//! - `LOG <category> <level>`: Only log messages in `SAMPLING`, `DETECTION`, `LEDS`, `COMMS` or
+//!   `ALL` categories at `TRACE`, `DEBUG`, `INFO`, `WARN` or `ERROR` and above (see [`logging`]).
+//!   *Privileged.*
+//! - `SILENCE`: Mute the buzzer during an alert or warning for the configured
+//!   [`snooze_ms`](crate::config::DetectionConfig::snooze_ms), leaving the LEDs and interlock
+//!   as they are. *Privileged.*
+//! - `WATCH`: Stream numbered lines of the state, the latest average, baseline and difference of
+//!   each probe, and the detections since boot, 10 times a second until any key is pressed (see
+//!   [`watch`](crate::watch))
+//! - `EXPORT CSV`, `EXPORT JSON`: Stream the [`event_log`] in either format, ending with a CRC
+//!   (see [`export`](crate::export)). Entering another command stops it.
+//! - `EXPORT <format> <seq>`: Stream the events from sequence number `seq` on, to fetch again
+//!   those lost from an earlier export
+//! - `CAPTURE <n>`: Freeze a copy of the most recent 1 to 1000 averaged samples of each probe,
+//!   without pausing acquisition, and stream it as CSV ending with a CRC (see
+//!   [`capture`](crate::capture)). Entering another command stops it.
+//! - `UNLOCK <pin>`: Accept privileged commands until `LOCK`, or until 10 minutes pass without
+//!   one. Refused after 5 wrong PINs in a row, until the jumper is fitted (see [`lock`]).
+//! - `LOCK`: Refuse privileged commands again
+//! - `PIN <new>`: Persist a new PIN of 4 to 8 digits. *Privileged.*
+//! - `PROVISION`: Print the serial number, hardware revision and ADC calibration of the unit (see
+//!   [`provision`])
+//! - `PROVISION <field> <value>`: Persist the `SERIAL` number, hardware `REV`, ADC `OFFSET` in
+//!   codes, ADC `GAIN` in parts per million or ADC `REF` in millivolts, then reapply the
+//!   configuration so thresholds set in millivolts follow the calibration. *Privileged*, and only
+//!   accepted while the configuration jumper is fitted.
+//! - `DRILL <drill>`: Simulate a `CONTACT`, a probe `DISCONNECT` or a `WATCHDOG` timeout for a
+//!   safety drill, once confirmed with `CONFIRM` on the next line (see [`drill`]). *Privileged.*
+//! - `MACRO`: Print the steps of each stored macro (see [`macros`])
+//! - `MACRO <n> <steps>`, `MACRO <n> CLEAR`: Persist up to 128 characters of commands separated
+//!   by `;` in slot 0 to 3, or empty it. *Privileged.*
+//! - `RUN <n>`: Run the commands stored in slot `n` in turn, echoing each, and stopping at the
+//!   first that fails. Each step needs the same access as if it were typed, and `CONFIRM` is
+//!   refused, so a confirmation always takes a line of its own.
+//! - `TIME`: Print the microseconds since boot on the clock that stamps every log message, and the
+//!   milliseconds that stamp the journal, so a host can line its timeline up with the unit's (see
+//!   [`scheduler`])
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt::Write;
+
+use heapless::{String, Vec};
+
+#[cfg(feature = "ab_compare")]
+use crate::ab_compare::{self, Preset};
+#[cfg(feature = "hil")]
+use crate::hil::{self, Scenario};
+#[cfg(feature = "irq_latency")]
+use crate::irq::{self, LATENCY_BUCKETS_US};
+#[cfg(feature = "perf")]
+use crate::perf::{self, Section};
+pub use crate::protocol::{Access, Command, ConsoleError, LINE_SIZE};
+use crate::{
+    buffer::PROBES,
+    capture::Capture,
+    config::{self, board::BOARD_VARIANT, Config, ConfigError, Threshold},
+    drill::{self, Drill},
+    error::Result as SystemResult,
+    event_log,
+    export::Export,
+    fault, histogram, injection,
+    journal::{Kind, Source},
+    lock::{self, Lock},
+    log,
+    logging::{self, Category, Level},
+    macros::{self, MACROS},
+    near_miss, provision, reset, scheduler, soak_test,
+    state::{self, SystemState},
+    uptime, version,
+    watch::{Snapshot, Watch},
+};
+#[cfg(feature = "i2c_scan")]
+use crate::{config::board::I2C_EXPECTED, i2c_scan};
+
+/// Size of the response buffer for a single line
+pub const OUTPUT_SIZE: usize = 512;
+
+// `CONFIG EXPORT` prints a `CONFIG IMPORT` line that must be accepted back, with the blob in hex
+const _: () = assert!("CONFIG IMPORT ".len() + 2 * Config::MAX_BLOB_SIZE <= LINE_SIZE);
+
+/// Buffer for the response to a single line of input
+pub type ConsoleOutput = String<OUTPUT_SIZE>;
+
+/// Access to the system state needed by console commands, implemented by each executor.
+pub trait ConsoleBackend {
+    /// Active configuration
+    fn config(&mut self) -> Config;
+    /// Make `config` the active configuration, updating any initialized buffers
+    fn apply_config(&mut self, config: Config);
+    /// Enter [`SystemState::Standby`](crate::state::SystemState::Standby)
+    fn enter_standby(&mut self) -> SystemResult<()>;
+    /// Reset a latched alert, see [`StateMachine::reset_latch`]
+    ///
+    /// [`StateMachine::reset_latch`]: crate::state::StateMachine::reset_latch
+    fn reset_latch(&mut self) -> SystemResult<()>;
+    /// Mute the buzzer for `duration_ms`, returning `false` if it isn't sounding, see
+    /// [`StateMachine::snooze`]
+    ///
+    /// [`StateMachine::snooze`]: crate::state::StateMachine::snooze
+    fn snooze(&mut self, duration_ms: u32) -> bool;
+    /// Current values for a [`Command::Watch`] line
+    fn snapshot(&mut self) -> Snapshot;
+    /// Copy of the most recent `samples` for a [`Command::Capture`], see [`Capture::take`]
+    fn capture(&mut self, samples: usize) -> Capture;
+    /// Whether commands that [write flash](Command::writes_flash) may run, only while the
+    /// [`SystemState`] doesn't sample
+    fn can_write_flash(&mut self) -> bool;
+}
+
+/// Run `command`, writing any response to `out`. [`Command::Confirm`], [`Command::Watch`],
+/// [`Command::Export`], [`Command::Capture`], [`Command::Unlock`], [`Command::Lock`] and
+/// [`Command::Run`] are handled by the
+/// [`Console`], which knows the previous line, continues the stream over several responses,
+/// holds the [`Lock`] and runs macros line by line. Access was checked by [`Command::parse`].
+pub fn execute(
+    command: Command,
+    backend: &mut impl ConsoleBackend,
+    out: &mut impl Write,
+) -> Result<(), ConsoleError> {
+    if command.writes_flash() && !backend.can_write_flash() {
+        return Err(ConsoleError::Sampling);
+    }
+    match command {
+        Command::Help => {
+            let _ = write!(
+                out,
+                "HELP\r\n*IDN?\r\nFACTORY RESET\r\nCONFIG EXPORT\r\nCONFIG IMPORT <hex>\r\n\
+                 THRESHOLD [name mV]\r\nRESTORE [profile]\r\nSTANDBY\r\nINJECT\r\n\
+                 RESET LATCH\r\nSILENCE\r\nRESET CAUSE\r\n\
+                 REBOOT\r\nFAULTS\r\nPERF\r\nLATENCY [CLEAR]\r\nHIL [scenario]\r\n\
+                 AB [preset|OFF|REPORT]\r\nI2C SCAN\r\nUPTIME\r\nHISTOGRAM\r\n\
+                 NEAR MISS\r\nSEQUENCE\r\nSOAK [START|STOP]\r\nLOG [category level]\r\nWATCH\r\n\
+                 EXPORT <CSV|JSON> [seq]\r\nCAPTURE <n>\r\n\
+                 UNLOCK <pin>\r\nLOCK\r\nPIN <new>\r\nPROVISION [field value]\r\n\
+                 DRILL <drill>\r\nMACRO [n steps|n CLEAR]\r\nRUN <n>\r\nTIME\r\n"
+            );
+        }
+        Command::Identify => {
+            let provisioning = provision::get();
+            let _ = write!(
+                out,
+                "PFPU2,Brain detection system,{},{}-{}\r\n\
+                 built {} board {} rev {} config {:08X}\r\nfeatures ",
+                provisioning.serial(),
+                version::VERSION,
+                version::GIT_HASH,
+                version::BUILD_TIME,
+                BOARD_VARIANT,
+                provisioning.revision(),
+                version::config_hash(&backend.config())
+            );
+            let _ = version::write_features(out);
+            let _ = write!(out, "\r\n");
+        }
+        Command::FactoryReset => {
+            let config = Config::factory_reset()?;
+            backend.apply_config(config);
+            uptime::factory_reset().map_err(ConfigError::Storage)?;
+            event_log::operator(Kind::FactoryReset, Source::Console);
+            let _ = write!(out, "OK factory configuration restored\r\n");
+        }
+        Command::ConfigExport => {
+            let config = backend.config();
+            let mut blob = [0u8; Config::MAX_BLOB_SIZE];
+            let _ = write!(out, "CONFIG IMPORT ");
+            for byte in config.to_blob(&mut blob)? {
+                let _ = write!(out, "{:02X}", byte);
+            }
+            let _ = write!(out, "\r\n");
+        }
+        Command::ConfigImport(hex) => {
+            let mut blob = [0u8; Config::MAX_BLOB_SIZE];
+            let config = Config::from_blob(decode_hex(hex, &mut blob)?)?;
+            config.store()?;
+            backend.apply_config(config);
+            event_log::operator(Kind::ConfigChange, Source::Console);
+            log!(Comms, info, "Imported configuration: {}", config);
+            let _ = write!(out, "OK configuration imported\r\n");
+        }
+        Command::Thresholds => {
+            let detection = backend.config().detection;
+            let resolved = detection.resolve(config::adc_scale());
+            for threshold in Threshold::ALL {
+                let _ = write!(
+                    out,
+                    "{} {} mV {} codes\r\n",
+                    threshold.name(),
+                    detection.voltage(threshold).0,
+                    resolved.codes(threshold)
+                );
+            }
+            let unit = if detection.millivolts.is_some() {
+                "millivolts"
+            } else {
+                "codes"
+            };
+            let _ = write!(out, "set in {}\r\n", unit);
+        }
+        Command::Threshold(threshold, voltage) => {
+            let mut config = backend.config();
+            config.detection.set_voltage(threshold, voltage);
+            config.store()?;
+            backend.apply_config(config);
+            event_log::operator(Kind::ConfigChange, Source::Console);
+            let codes = config
+                .detection
+                .resolve(config::adc_scale())
+                .codes(threshold);
+            log!(
+                Comms,
+                info,
+                "{} threshold set to {=i32} mV, {=i16} codes",
+                threshold,
+                voltage.0,
+                codes
+            );
+            let _ = write!(
+                out,
+                "OK {} {} mV {} codes\r\n",
+                threshold.name(),
+                voltage.0,
+                codes
+            );
+        }
+        Command::RestoreProfiles => {
+            let detection = backend.config().detection;
+            let _ = write!(
+                out,
+                "FAST {} samples\r\nSLOW {} samples\r\nprofile {}\r\n",
+                detection.restore_fast_samples,
+                detection.restore_slow_samples,
+                detection.restore_profile.name()
+            );
+        }
+        Command::Restore(profile) => {
+            let mut config = backend.config();
+            config.detection.restore_profile = profile;
+            config.store()?;
+            backend.apply_config(config);
+            event_log::operator(Kind::ConfigChange, Source::Console);
+            log!(Comms, info, "Restore profile set to {}", profile);
+            let _ = write!(
+                out,
+                "OK {} {} samples\r\n",
+                profile.name(),
+                config.detection.restore_samples()
+            );
+        }
+        Command::Standby => {
+            backend
+                .enter_standby()
+                .map_err(|_| ConsoleError::InvalidState)?;
+            event_log::operator(Kind::Disarm, Source::Console);
+            let _ = write!(
+                out,
+                "OK entering standby, press the acknowledge button to wake\r\n"
+            );
+        }
+        Command::Inject => {
+            injection::request();
+            let _ = write!(out, "OK injection test requested\r\n");
+        }
+        Command::ResetLatch => {
+            let _ = write!(
+                out,
+                "Resetting the latched alert releases the interlock. Enter CONFIRM to \
+                 proceed\r\n"
+            );
+        }
+        Command::Drill(drill) => {
+            let _ = match drill {
+                Drill::Contact => write!(
+                    out,
+                    "Simulating contact trips the interlock and sounds the buzzer for {} s. \
+                     Enter CONFIRM to proceed\r\n",
+                    drill::CONTACT_TIME.0 / 1000
+                ),
+                Drill::Disconnect => write!(
+                    out,
+                    "Simulating a probe disconnect stops detection until reboot. Enter \
+                     CONFIRM to proceed\r\n"
+                ),
+                Drill::Watchdog => write!(
+                    out,
+                    "Simulating a watchdog timeout resets the unit. Enter CONFIRM to \
+                     proceed\r\n"
+                ),
+            };
+        }
+        Command::Confirm => return Err(ConsoleError::NothingToConfirm),
+        Command::Silence => {
+            let duration_ms = backend.config().detection.snooze_ms;
+            if !backend.snooze(duration_ms) {
+                return Err(ConsoleError::InvalidState);
+            }
+            event_log::operator(Kind::Silence, Source::Console);
+            let _ = write!(out, "OK buzzer silenced for {} s\r\n", duration_ms / 1000);
+        }
+        // Needs a Console to continue it, or to hold the lock
+        Command::Watch
+        | Command::Export(..)
+        | Command::Capture(_)
+        | Command::Unlock(_)
+        | Command::Lock
+        | Command::Run(_) => return Err(ConsoleError::InvalidState),
+        Command::Macros => {
+            for slot in 0..MACROS {
+                let _ = write!(out, "{} {}\r\n", slot, macros::get(slot).unwrap_or(""));
+            }
+        }
+        Command::Macro(slot, steps) => {
+            macros::set(slot, steps).map_err(ConfigError::Storage)?;
+            let _ = write!(out, "OK macro {} changed\r\n", slot);
+        }
+        Command::Time => {
+            let now_us = scheduler::now_us();
+            let _ = write!(out, "{} us {} ms\r\n", now_us, now_us / 1000);
+        }
+        Command::Pin(pin) => {
+            lock::set_pin(pin).map_err(ConfigError::Storage)?;
+            event_log::operator(Kind::PinChange, Source::Console);
+            let _ = write!(out, "OK PIN changed\r\n");
+        }
+        Command::Provisioning => {
+            let provisioning = provision::get();
+            let _ = write!(
+                out,
+                "SERIAL {}\r\nREV {}\r\nOFFSET {}\r\nGAIN {}\r\nREF {}\r\n",
+                provisioning.serial(),
+                provisioning.revision(),
+                provisioning.adc_offset(),
+                provisioning.adc_gain_ppm(),
+                provisioning.adc_reference_mv()
+            );
+        }
+        Command::Provision(field) => {
+            provision::set(field)?;
+            // Thresholds set in millivolts are converted at the new calibration
+            let config = backend.config();
+            backend.apply_config(config);
+            event_log::operator(Kind::Provision, Source::Console);
+            let _ = write!(out, "OK provisioned\r\n");
+        }
+        Command::ResetCause => {
+            let _ = write!(out, "{}\r\n", reset::last_cause().as_str());
+        }
+        Command::Reboot => reset::reboot(),
+        Command::Faults => {
+            let mut none = true;
+            for (code, stats) in fault::raised() {
+                none = false;
+                let _ = write!(
+                    out,
+                    "{:#06x} count {} first {} ms last {} ms\r\n",
+                    code.value(),
+                    stats.count,
+                    stats.first_ms,
+                    stats.last_ms
+                );
+            }
+            if none {
+                let _ = write!(out, "OK no faults\r\n");
+            }
+        }
+        #[cfg(feature = "perf")]
+        Command::Perf => {
+            for section in Section::ALL {
+                let stats = perf::stats(section);
+                let _ = write!(
+                    out,
+                    "{:?} min {} avg {} max {} cycles ({} runs)\r\n",
+                    section,
+                    if stats.count > 0 { stats.min } else { 0 },
+                    stats.avg(),
+                    stats.max,
+                    stats.count
+                );
+            }
+        }
+        #[cfg(not(feature = "perf"))]
+        Command::Perf => return Err(ConsoleError::NotEnabled),
+        #[cfg(feature = "irq_latency")]
+        Command::Latency => {
+            let histogram = irq::latency_histogram();
+            let mut lower = 0;
+            for (limit, count) in LATENCY_BUCKETS_US.iter().zip(histogram) {
+                let _ = write!(out, "{}-{} us {}\r\n", lower, limit, count);
+                lower = *limit;
+            }
+            let _ = write!(out, ">{} us {}\r\n", lower, histogram[histogram.len() - 1]);
+        }
+        #[cfg(feature = "irq_latency")]
+        Command::LatencyClear => {
+            irq::clear_latency_histogram();
+            let _ = write!(out, "OK latency histogram cleared\r\n");
+        }
+        #[cfg(not(feature = "irq_latency"))]
+        Command::Latency | Command::LatencyClear => return Err(ConsoleError::NotEnabled),
+        #[cfg(feature = "hil")]
+        Command::Hil(Some(name)) => {
+            let scenario = Scenario::from_name(name).ok_or(ConsoleError::UnknownScenario)?;
+            hil::request(scenario);
+            let _ = write!(
+                out,
+                "OK {} requested, starting once armed\r\n",
+                scenario.name()
+            );
+        }
+        #[cfg(feature = "hil")]
+        Command::Hil(None) => match hil::status() {
+            Some((scenario, outcome)) => {
+                let _ = write!(out, "{} {}\r\n", scenario.name(), outcome.as_str());
+            }
+            None => {
+                let _ = write!(out, "OK no scenario requested\r\n");
+            }
+        },
+        #[cfg(not(feature = "hil"))]
+        Command::Hil(_) => return Err(ConsoleError::NotEnabled),
+        #[cfg(feature = "ab_compare")]
+        Command::Ab(Some(name)) => {
+            let preset = if name.eq_ignore_ascii_case("OFF") {
+                None
+            } else {
+                Some(Preset::from_name(name).ok_or(ConsoleError::UnknownPreset)?)
+            };
+            ab_compare::request(preset);
+            match preset {
+                Some(preset) => {
+                    let _ = write!(out, "OK comparing against {}\r\n", preset.name());
+                }
+                None => {
+                    let _ = write!(out, "OK comparison stopped\r\n");
+                }
+            }
+        }
+        #[cfg(feature = "ab_compare")]
+        Command::Ab(None) => {
+            let tally = ab_compare::tally();
+            match tally.preset {
+                Some(preset) => {
+                    let _ = write!(
+                        out,
+                        "{} candidate only {} production only {}\r\n",
+                        preset.name(),
+                        tally.candidate_only,
+                        tally.production_only
+                    );
+                }
+                None => {
+                    let _ = write!(out, "OK no comparison running\r\n");
+                }
+            }
+        }
+        #[cfg(feature = "ab_compare")]
+        Command::AbReport => {
+            let _ = write!(out, "probe,sample,samples,detector\r\n");
+            for divergence in ab_compare::report().iter() {
+                let _ = write!(
+                    out,
+                    "{},{},{},{}\r\n",
+                    divergence.probe,
+                    divergence.since,
+                    divergence.samples,
+                    if divergence.candidate_contact {
+                        "candidate"
+                    } else {
+                        "production"
+                    }
+                );
+            }
+        }
+        #[cfg(not(feature = "ab_compare"))]
+        Command::Ab(_) | Command::AbReport => return Err(ConsoleError::NotEnabled),
+        #[cfg(feature = "i2c_scan")]
+        Command::I2cScan => {
+            let report = i2c_scan::scan().ok_or(ConsoleError::InvalidState)?;
+            report.log();
+            for &(address, name) in I2C_EXPECTED {
+                let status = if report.responded(address) {
+                    "ok"
+                } else {
+                    "missing"
+                };
+                let _ = write!(out, "{:#04x} {} {}\r\n", address, name, status);
+            }
+            for address in report.unexpected() {
+                let _ = write!(out, "{:#04x} unexpected\r\n", address);
+            }
+        }
+        #[cfg(not(feature = "i2c_scan"))]
+        Command::I2cScan => return Err(ConsoleError::NotEnabled),
+        Command::Uptime => {
+            let stats = uptime::stats();
+            let _ = write!(
+                out,
+                "up {} s armed {} s detections {}\r\n\
+                 since factory reset: armed {} s detections {}\r\n",
+                stats.uptime_ms / 1000,
+                stats.armed_ms() / 1000,
+                stats.detections,
+                stats.since_reset.armed_s,
+                stats.since_reset.detections
+            );
+            for state in SystemState::ALL {
+                let ms = stats.time_in(state);
+                if ms > 0 {
+                    let _ = write!(out, "{:?} {} s\r\n", state, ms / 1000);
+                }
+            }
+        }
+        Command::Histogram => {
+            for probe in 0..PROBES {
+                let bins = histogram::bins(probe);
+                let total: u64 = bins.iter().map(|&count| count as u64).sum();
+                let _ = write!(out, "{} {}", probe, total);
+                for count in bins {
+                    let per_mille = if count == 0 {
+                        0
+                    } else {
+                        (count as u64 * 1000 / total).max(1)
+                    };
+                    let _ = write!(out, " {}", per_mille);
+                }
+                let _ = write!(out, "\r\n");
+            }
+        }
+        Command::NearMiss => {
+            for (probe, count) in near_miss::counts().iter().enumerate() {
+                let _ = write!(out, "{} near misses {}\r\n", probe, count);
+            }
+            let _ = write!(out, "time_ms,sample,probe,delta\r\n");
+            for near_miss in near_miss::recent().iter() {
+                let _ = write!(
+                    out,
+                    "{},{},{},{}\r\n",
+                    near_miss.time_ms, near_miss.sample, near_miss.probe, near_miss.delta
+                );
+            }
+        }
+        Command::Sequence => {
+            let now = scheduler::now_ms();
+            for (probe, stage) in state::interlock_stages().iter().enumerate() {
+                let _ = write!(
+                    out,
+                    "{} {} {} ms\r\n",
+                    probe,
+                    stage.name(),
+                    stage.remaining_ms(now)
+                );
+            }
+            let detection = backend.config().detection;
+            let _ = write!(
+                out,
+                "enable delay {} ms cooldown {} ms\r\n",
+                detection.enable_delay_ms, detection.cooldown_ms
+            );
+        }
+        Command::Soak => {
+            let Some(soak) = soak_test::get() else {
+                let _ = write!(out, "OK no soak test started\r\n");
+                return Ok(());
+            };
+            let _ = write!(
+                out,
+                "{} {} s\r\n",
+                if soak.running() { "running" } else { "stopped" },
+                soak.duration_ms(scheduler::now_ms()) / 1000
+            );
+            for probe in 0..PROBES {
+                let stats = soak.stats(probe);
+                let (mean, sd) = (stats.mean_centi(), stats.std_dev_centi());
+                let _ = write!(
+                    out,
+                    "{} samples {} mean {}.{:02} sd {}.{:02} max {} near {}\r\n{} deltas",
+                    probe,
+                    stats.samples(),
+                    mean / 100,
+                    mean % 100,
+                    sd / 100,
+                    sd % 100,
+                    stats.max_delta(),
+                    stats.near_misses(),
+                    probe
+                );
+                // Trailing empty bins are left out, keeping at least the first
+                let bins = stats.deltas();
+                let used = bins.iter().rposition(|&count| count > 0).unwrap_or(0) + 1;
+                for count in &bins[..used] {
+                    let _ = write!(out, " {}", count);
+                }
+                let _ = write!(out, "\r\n");
+            }
+        }
+        Command::SoakStart => {
+            soak_test::start();
+            let _ = write!(out, "OK soak test started\r\n");
+        }
+        Command::SoakStop => {
+            if !soak_test::stop() {
+                return Err(ConsoleError::InvalidState);
+            }
+            let _ = write!(out, "OK soak test stopped\r\n");
+        }
+        Command::Log => {
+            for category in Category::ALL {
+                let level = logging::level(category);
+                let _ = write!(out, "{} {}\r\n", category.name(), level.name());
+            }
+            let _ = write!(
+                out,
+                "RATE {}/s DROPPED {}\r\n",
+                logging::MESSAGES_PER_S,
+                logging::dropped()
+            );
+        }
+        Command::LogLevel(category, level) => {
+            let level = Level::from_name(level).ok_or(ConsoleError::UnknownLogSetting)?;
+            if category.eq_ignore_ascii_case("ALL") {
+                for category in Category::ALL {
+                    logging::set_level(category, level);
+                }
+            } else {
+                let category =
+                    Category::from_name(category).ok_or(ConsoleError::UnknownLogSetting)?;
+                logging::set_level(category, level);
+            }
+            let _ = write!(out, "OK log level set\r\n");
+        }
+    }
+    Ok(())
+}
+
+/// Decode a hex string into `buf`, returning the filled portion.
+fn decode_hex<'b>(hex: &str, buf: &'b mut [u8]) -> Result<&'b [u8], ConsoleError> {
+    if !hex.len().is_multiple_of(2) || hex.len() / 2 > buf.len() {
+        return Err(ConsoleError::InvalidHex);
+    }
+    for (byte, pair) in buf.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = core::str::from_utf8(pair).map_err(|_| ConsoleError::InvalidHex)?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| ConsoleError::InvalidHex)?;
+    }
+    Ok(&buf[..hex.len() / 2])
+}
+
+/// Command waiting for [`Command::Confirm`] on the next line
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+enum Confirmable {
+    /// [`Command::ResetLatch`]
+    ResetLatch,
+    /// [`Command::Drill`]
+    Drill(Drill),
+}
+
+/// Accumulates input into lines and executes them as [`Command`]s
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Console {
+    /// Partial line received so far
+    line: Vec<u8, LINE_SIZE>,
+    /// The current line overflowed, and will be discarded when it ends
+    overflowed: bool,
+    /// Command on the previous line waiting for [`Command::Confirm`]
+    awaiting_confirm: Option<Confirmable>,
+    /// Export still being written, see [`Console::poll_export`]
+    export: Option<Export>,
+    /// Capture still being written, see [`Console::poll_export`]
+    capture: Option<Capture>,
+    /// Live view being streamed, see [`Console::poll_watch`]
+    watch: Option<Watch>,
+    /// Unlocked session for configuration changes
+    lock: Lock,
+    /// Source of the macro being run. Only the steps of [`BUTTON_MACRO`](macros::BUTTON_MACRO),
+    /// which needs the jumper, are run with privileged access.
+    macro_source: Option<Source>,
+    /// The host had the port open when last polled
+    host_open: bool,
+}
+
+impl Console {
+    /// Create an empty console
+    pub const fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            overflowed: false,
+            awaiting_confirm: None,
+            export: None,
+            capture: None,
+            watch: None,
+            lock: Lock::new(),
+            macro_source: None,
+            host_open: false,
+        }
+    }
+
+    /// Follow whether the host has the port open from its DTR line, returning `true` when it has
+    /// just opened it, so the [`banner`](crate::banner) can be written
+    pub fn opened(&mut self, dtr: bool) -> bool {
+        let opened = dtr && !self.host_open;
+        self.host_open = dtr;
+        opened
+    }
+
+    /// Feed received bytes to the console, executing any completed lines and writing their
+    /// responses to `out`. Any byte stops a watch in progress.
+    pub fn push_bytes(
+        &mut self,
+        bytes: &[u8],
+        backend: &mut impl ConsoleBackend,
+        out: &mut impl Write,
+    ) {
+        if !bytes.is_empty() {
+            self.stop_watch();
+        }
+        for byte in bytes {
+            match byte {
+                b'\r' | b'\n' => {
+                    if self.overflowed {
+                        let _ = write!(out, "ERR {}\r\n", ConsoleError::LineTooLong.as_str());
+                    } else if !self.line.is_empty() {
+                        self.run_line(backend, out);
+                    }
+                    self.line.clear();
+                    self.overflowed = false;
+                }
+                _ => {
+                    if self.line.push(*byte).is_err() {
+                        self.overflowed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Continue a [`Command::Export`] or [`Command::Capture`], writing as much as fits in `out`.
+    /// Call whenever the response to the input so far has been sent.
+    pub fn poll_export(&mut self, out: &mut ConsoleOutput) {
+        if let Some(export) = &mut self.export {
+            export.fill(out);
+            if export.done() {
+                self.export = None;
+            }
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.fill(out);
+            if capture.done() {
+                self.capture = None;
+            }
+        }
+    }
+
+    /// Continue a [`Command::Watch`], writing the next line to `out` if it is due. Call whenever
+    /// the response to the input so far has been sent.
+    pub fn poll_watch(&mut self, backend: &mut impl ConsoleBackend, out: &mut ConsoleOutput) {
+        if let Some(watch) = &mut self.watch {
+            watch.fill(out, || backend.snapshot());
+        }
+    }
+
+    /// Run [`BUTTON_MACRO`](macros::BUTTON_MACRO) if the acknowledge button asked for it, writing
+    /// the responses to `out`. Call whenever the response to the input so far has been sent.
+    pub fn poll_macro(&mut self, backend: &mut impl ConsoleBackend, out: &mut ConsoleOutput) {
+        if macros::take_button_request() {
+            if let Err(err) = self.run_macro(macros::BUTTON_MACRO, Source::Button, backend, out) {
+                log!(Comms, warn, "Button macro failed: {}", err);
+                let _ = write!(out, "ERR {}\r\n", err.as_str());
+            }
+        }
+    }
+
+    /// Run the steps of macro `slot`, started by `source`, as lines of their own, echoing each.
+    /// Stops at the first step that fails, and leaves any partial line typed untouched.
+    fn run_macro(
+        &mut self,
+        slot: usize,
+        source: Source,
+        backend: &mut impl ConsoleBackend,
+        out: &mut impl Write,
+    ) -> Result<(), ConsoleError> {
+        if self.macro_source.is_some() {
+            return Err(ConsoleError::InvalidState);
+        }
+        let steps = macros::get(slot).ok_or(ConsoleError::EmptyMacro)?;
+        macros::journal(slot, source);
+        let partial = core::mem::take(&mut self.line);
+        self.macro_source = Some(source);
+        for step in steps.split(macros::SEPARATOR).map(str::trim) {
+            if step.is_empty() {
+                continue;
+            }
+            let _ = write!(out, "> {}\r\n", step);
+            self.line.clear();
+            // Steps are shorter than a line
+            let _ = self.line.extend_from_slice(step.as_bytes());
+            if !self.run_line(backend, out) {
+                break;
+            }
+        }
+        self.macro_source = None;
+        self.line = partial;
+        Ok(())
+    }
+
+    /// End a [`Command::Watch`], if one is running
+    fn stop_watch(&mut self) {
+        if let Some(watch) = self.watch.take() {
+            watch.stop();
+        }
+    }
+
+    /// Parse and execute the buffered line. A [`Command::Confirm`] is only accepted on the line
+    /// after the command it confirms; any other line cancels the confirmation. Any line stops an
+    /// export or capture in progress. Unlocking, privileged commands accepted through the jumper,
+    /// and any refused by the [`Lock`], are journalled. The steps of a macro started on the console
+    /// are authorized like any other line, and those of the button macro by the jumper.
+    /// Returns whether the command succeeded.
+    fn run_line(&mut self, backend: &mut impl ConsoleBackend, out: &mut impl Write) -> bool {
+        let awaiting_confirm = self.awaiting_confirm.take();
+        self.export = None;
+        self.capture = None;
+        let authorized = match self.macro_source {
+            Some(Source::Button) => Some(Source::Button),
+            _ => self.lock.authorized(),
+        };
+        let mut run = None;
+        let access = match authorized {
+            Some(_) => Access::Privileged,
+            None => Access::ReadOnly,
+        };
+        let result = core::str::from_utf8(&self.line)
+            .map_err(|_| ConsoleError::UnknownCommand)
+            .and_then(|line| {
+                let command = Command::parse(line, access).inspect_err(|&err| {
+                    if err == ConsoleError::Locked {
+                        event_log::operator(Kind::Refused, Source::Console);
+                    }
+                })?;
+                log!(Comms, debug, "Console command: {}", command);
+                if command.privileged() {
+                    self.lock.extend();
+                    if authorized == Some(Source::Jumper) {
+                        event_log::operator(Kind::Unlock, Source::Jumper);
+                    }
+                }
+                match command {
+                    // A macro can't confirm its own step
+                    Command::Confirm if self.macro_source.is_some() => {
+                        Err(ConsoleError::InvalidState)
+                    }
+                    Command::Confirm => match awaiting_confirm {
+                        Some(Confirmable::ResetLatch) => {
+                            backend
+                                .reset_latch()
+                                .map_err(|_| ConsoleError::InvalidState)?;
+                            event_log::operator(Kind::Acknowledge, Source::Console);
+                            let _ = write!(out, "OK latched alert reset\r\n");
+                            Ok(())
+                        }
+                        Some(Confirmable::Drill(drill)) => {
+                            if !drill::request(drill, backend.snapshot().state) {
+                                return Err(ConsoleError::InvalidState);
+                            }
+                            let _ = write!(out, "OK drill {} started\r\n", drill.name());
+                            Ok(())
+                        }
+                        None => execute(command, backend, out),
+                    },
+                    Command::ResetLatch => {
+                        self.awaiting_confirm = Some(Confirmable::ResetLatch);
+                        execute(command, backend, out)
+                    }
+                    Command::Drill(drill) => {
+                        self.awaiting_confirm = Some(Confirmable::Drill(drill));
+                        execute(command, backend, out)
+                    }
+                    Command::Watch => {
+                        self.watch = Some(Watch::start());
+                        Ok(())
+                    }
+                    Command::Export(format, from_seq) => {
+                        let config_hash = version::config_hash(&backend.config());
+                        self.export = Some(Export::new(format, config_hash, from_seq));
+                        Ok(())
+                    }
+                    Command::Capture(samples) => {
+                        self.capture = Some(backend.capture(samples));
+                        Ok(())
+                    }
+                    Command::Unlock(pin) => {
+                        if let Err(err) = self.lock.unlock(pin) {
+                            event_log::operator(Kind::Refused, Source::Console);
+                            return Err(err.into());
+                        }
+                        event_log::operator(Kind::Unlock, Source::Console);
+                        let _ = write!(out, "OK unlocked\r\n");
+                        Ok(())
+                    }
+                    Command::Lock => {
+                        self.lock.lock();
+                        let _ = write!(out, "OK locked\r\n");
+                        Ok(())
+                    }
+                    // Run once the line is no longer borrowed
+                    Command::Run(slot) => {
+                        run = Some(slot);
+                        Ok(())
+                    }
+                    _ => execute(command, backend, out),
+                }
+            });
+        let result = match (result, run) {
+            (Ok(()), Some(slot)) => self.run_macro(slot, Source::Console, backend, out),
+            (result, _) => result,
+        };
+        if let Err(err) = &result {
+            log!(Comms, warn, "Console command failed: {}", err);
+            let _ = write!(out, "ERR {}\r\n", err.as_str());
+        }
+        result.is_ok()
+    }
+}
@@ -0,0 +1,62 @@
+//! Event correlation output, pulsed whenever contact is detected.
+//!
+//! The output is driven high as soon as the detector confirms contact on any probe, and low again
+//! when the next averaged sample completes, so each pulse lasts about one averaging window. An
+//! external DAQ or scope recording the analog front-end alongside it sees the instant the firmware
+//! made its decision, for lining up the two during validation campaigns.
+//!
+//! The output is written through the SIO set and clear registers, so it can be driven from the
+//! detection task and the `DMA_IRQ_0` handler without sharing the pin.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use embedded_hal::digital::PinState;
+
+use crate::{
+    config::board::{CorrelationPin, CORRELATION_PIN},
+    hal::{
+        gpio::{FunctionNull, FunctionSio, Pin, PullDown, SioOutput},
+        pac,
+    },
+};
+
+/// Correlation output, driven high for one averaging window on each detection
+pub type CorrelationOutput = Pin<CorrelationPin, FunctionSio<SioOutput>, PullDown>;
+
+/// Take the correlation output, driven low until the first detection
+pub fn init(pin: Pin<CorrelationPin, FunctionNull, PullDown>) -> CorrelationOutput {
+    pin.into_push_pull_output_in_state(PinState::Low)
+}
+
+/// Start a pulse, as contact has just been detected
+pub fn pulse() {
+    // SAFETY: the set register is write-1 atomic, and only ever used for this pin here
+    unsafe {
+        (*pac::SIO::ptr())
+            .gpio_out_set()
+            .write(|w| w.bits(1 << CORRELATION_PIN));
+    }
+}
+
+/// End any pulse, as the next averaged sample has completed
+#[inline(always)]
+pub fn end_pulse() {
+    // SAFETY: the clear register is write-1 atomic, and only ever used for this pin here
+    unsafe {
+        (*pac::SIO::ptr())
+            .gpio_out_clr()
+            .write(|w| w.bits(1 << CORRELATION_PIN));
+    }
+}
@@ -0,0 +1,76 @@
+//! Cross-check between the two ADC channels of the `dual_channel` feature.
+//!
+//! With `dual_channel`, the probe is wired to a second ADC input through an independent divider,
+//! and the ADC alternates between the two inputs. Detection runs on the primary channel, while
+//! [`CrossCheck`] compares its delta with the secondary channel after every transfer. If they
+//! disagree by more than [`MISMATCH_TOLERANCE`] for [`MISMATCH_SAMPLES`] in a row,
+//! [`Error::ChannelMismatch`] is raised, so a single failed divider or ADC input can't silently
+//! hide a contact.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::{warn, Format};
+
+use crate::{
+    error::{Error, Result},
+    sampler::AlignedAverages,
+};
+
+/// Largest difference between the channel deltas still treated as agreement
+pub const MISMATCH_TOLERANCE: u8 = 4;
+/// Consecutive disagreeing samples before [`Error::ChannelMismatch`] is raised (50 ms with 2 ms
+/// averaging). Short disagreements are expected while the two dividers settle after a contact.
+pub const MISMATCH_SAMPLES: u16 = 25;
+
+/// Counts consecutive samples where the two channels disagree
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Format)]
+pub struct CrossCheck {
+    /// Consecutive disagreeing samples
+    mismatched: u16,
+}
+
+impl CrossCheck {
+    /// No disagreeing samples seen
+    pub const fn new() -> Self {
+        Self { mismatched: 0 }
+    }
+
+    /// Compare the averages of the `primary` and `secondary` channels from the latest transfer.
+    ///
+    /// Returns [`Error::ChannelMismatch`] once they have disagreed for [`MISMATCH_SAMPLES`].
+    pub fn check(&mut self, primary: &AlignedAverages, secondary: &AlignedAverages) -> Result<()> {
+        let (primary, secondary) = (primary.get_delta(), secondary.get_delta());
+        if primary.abs_diff(secondary) <= MISMATCH_TOLERANCE {
+            self.mismatched = 0;
+            return Ok(());
+        }
+
+        self.mismatched = self.mismatched.saturating_add(1);
+        if self.mismatched >= MISMATCH_SAMPLES {
+            warn!(
+                "Channel deltas disagree: primary {=u8}, secondary {=u8}",
+                primary, secondary
+            );
+            Err(Error::ChannelMismatch)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Forget any disagreeing samples, when sampling restarts
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
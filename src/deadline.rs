@@ -0,0 +1,175 @@
+//! Soft real-time deadlines for each stage of the detection pipeline.
+//!
+//! Every averaged sample passes through the [`Stage`]s in order: the readings are averaged in
+//! `DMA_IRQ_0`, then the sample is inserted into the [`Buffers`](crate::buffer::Buffers) and
+//! checked for contact. Each stage is timed with the 1 MHz `TIMER` (the Cortex-M0+ has no cycle
+//! counter) by [`check`], which logs a warning whenever a stage runs over its budget. After
+//! [`OVERRUN_LIMIT`] overruns in a row, it returns [`Error::DeadlineMissed`], so timing
+//! regressions latch an error on hardware rather than silently delaying detection.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use defmt::{info, warn, Format};
+
+use crate::{
+    board::TimerBlock,
+    error::{Error, Result},
+    irq::TRANSFER_PERIOD_US,
+};
+
+/// Consecutive overruns of a stage before [`check`] returns an error
+pub const OVERRUN_LIMIT: u8 = 5;
+
+/// Lower 32 bits of the `TIMER` counter, in microseconds. Wraps after about 71 minutes, so only
+/// differences between nearby instants are meaningful.
+///
+/// The `TIMER` peripheral must be running, as it is after
+/// [`Board::init`](crate::board::Board::init).
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Format)]
+pub struct Instant(pub u32);
+
+impl Instant {
+    /// Read the current time, without latching the upper bits of the counter
+    pub fn now() -> Self {
+        // SAFETY: read-only access to a register with no side effects
+        Self(unsafe { (*TimerBlock::ptr()).timerawl().read().bits() })
+    }
+
+    /// Microseconds from `earlier` to `self`
+    pub fn since(self, earlier: Instant) -> u32 {
+        self.0.wrapping_sub(earlier.0)
+    }
+}
+
+/// Value tagged with the time its ADC transfer completed
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Format)]
+pub struct Stamped<T> {
+    /// Tagged value
+    pub value: T,
+    /// When the transfer completed
+    pub completed: Instant,
+}
+
+impl<T> Stamped<T> {
+    /// Tag `value` with the current time
+    pub fn now(value: T) -> Self {
+        Self {
+            value,
+            completed: Instant::now(),
+        }
+    }
+}
+
+/// Stages of the detection pipeline
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Stage {
+    /// Transfer complete to averaged sample, in `DMA_IRQ_0`
+    Average,
+    /// Inserting the sample into the long-term buffer
+    Insert,
+    /// Checking for contact or end of contact, including any state transition
+    Detect,
+    /// Transfer complete to detection finished, including time spent queued
+    Pipeline,
+}
+
+impl Stage {
+    /// All stages
+    pub const ALL: [Stage; 4] = [
+        Stage::Average,
+        Stage::Insert,
+        Stage::Detect,
+        Stage::Pipeline,
+    ];
+
+    /// Longest the stage may take, in microseconds. A sample must be through the pipeline within
+    /// two transfers, so detection never falls further behind.
+    pub const fn budget_us(self) -> u32 {
+        match self {
+            Stage::Average => TRANSFER_PERIOD_US / 2,
+            Stage::Insert => 100,
+            Stage::Detect => 200,
+            Stage::Pipeline => TRANSFER_PERIOD_US * 2,
+        }
+    }
+}
+
+/// Longest run of each stage, in microseconds
+static WORST_CASE_US: [AtomicU32; 4] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+/// Consecutive overruns of each stage
+static OVERRUNS: [AtomicU8; 4] = [
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+];
+
+/// End `stage`, which began at `start`, and return the current time to start the next stage.
+///
+/// Logs a warning if the stage ran over its [budget](Stage::budget_us), and returns
+/// [`Error::DeadlineMissed`] once it has done so [`OVERRUN_LIMIT`] times in a row.
+#[link_section = ".data.ram_func"]
+pub fn check(stage: Stage, start: Instant) -> Result<Instant> {
+    let now = Instant::now();
+    let elapsed = now.since(start);
+
+    // Each stage is only checked from one context, so its slots are never written concurrently
+    let worst = &WORST_CASE_US[stage as usize];
+    if elapsed > worst.load(Ordering::Relaxed) {
+        worst.store(elapsed, Ordering::Relaxed);
+    }
+    let overruns = &OVERRUNS[stage as usize];
+    if elapsed > stage.budget_us() {
+        let count = overruns.load(Ordering::Relaxed).saturating_add(1);
+        overruns.store(count, Ordering::Relaxed);
+        warn!(
+            "{} took {=u32} us, over its {=u32} us budget ({=u8} in a row)",
+            stage,
+            elapsed,
+            stage.budget_us(),
+            count
+        );
+        if count >= OVERRUN_LIMIT {
+            return Err(Error::DeadlineMissed(stage));
+        }
+    } else {
+        overruns.store(0, Ordering::Relaxed);
+    }
+    Ok(now)
+}
+
+/// Longest run of `stage` so far, in microseconds
+pub fn worst_case_us(stage: Stage) -> u32 {
+    WORST_CASE_US[stage as usize].load(Ordering::Relaxed)
+}
+
+/// Log the longest run of every stage
+pub fn log_worst_case() {
+    for stage in Stage::ALL {
+        info!(
+            "{}: worst case {=u32} us of {=u32} us budget",
+            stage,
+            worst_case_us(stage),
+            stage.budget_us()
+        );
+    }
+}
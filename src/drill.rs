@@ -0,0 +1,133 @@
+//! Safety drills: faults simulated from the console, so operators can check the interlock, the
+//! buzzer and their own procedures without touching the blade.
+//!
+//! A [`Drill`] is [requested](request) with `DRILL CONTACT`, `DRILL DISCONNECT` or
+//! `DRILL WATCHDOG`, which must be confirmed on the next line. Each is journalled as
+//! [`Kind::Drill`], so it can't be mistaken for a real fault when the journal is reviewed, and
+//! is otherwise handled exactly like the fault it simulates:
+//!
+//! - [`Drill::Contact`]: [`CONTACT_PROBE`] reports contact for [`CONTACT_TIME`], tripping the
+//!   interlock and sounding the buzzer. The alert then clears or latches as configured. Only
+//!   available while armed. Like a real contact, it counts towards the
+//!   [`uptime`](crate::uptime) detection total.
+//! - [`Drill::Disconnect`]: the next sample raises [`Error::ProbeDisconnected`], stopping
+//!   detection in [`SystemState::Error`] until the unit is rebooted. Only available while sampling.
+//! - [`Drill::Watchdog`]: the detection task misses its next
+//!   [watchdog deadline](liveness::simulate_timeout), so the outputs enter the safe state and the
+//!   chip resets. Only available where the watchdog runs. Events aren't written while sampling, so
+//!   this drill is journalled after the boot that follows it.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use defmt::warn;
+
+pub use crate::protocol::Drill;
+use crate::{
+    buffer::{self, Buffers},
+    detection_core::Phase,
+    error::{Error, Result},
+    liveness,
+    probe::ProbeFault,
+    sampler::SamplerControl,
+    state::{StateMachine, SystemState},
+    units::Millis,
+};
+#[cfg(not(feature = "minimal"))]
+use crate::{event_log, journal::Kind};
+
+/// Probe reported in contact by [`Drill::Contact`]
+pub const CONTACT_PROBE: usize = 0;
+/// How long [`Drill::Contact`] holds contact for
+pub const CONTACT_TIME: Millis = Millis(2_000);
+/// Averaged samples [`Drill::Contact`] holds contact for
+pub const CONTACT_SAMPLES: u64 = buffer::samples_in(CONTACT_TIME);
+
+/// Drill requested from the console, until the detection task runs it
+static PENDING: Mutex<Cell<Option<Drill>>> = Mutex::new(Cell::new(None));
+/// Samples recorded when the running [`Drill::Contact`] ends
+static CONTACT_UNTIL: Mutex<Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+/// Journal and start `drill`, if it is [available](Drill::available) in `current`. A watchdog
+/// timeout is simulated straight away; the others run on the next sample. Returns `false` if the
+/// drill can't run.
+pub fn request(drill: Drill, current: SystemState) -> bool {
+    if !drill.available(current) {
+        return false;
+    }
+    if drill == Drill::Watchdog {
+        if !liveness::simulate_timeout() {
+            return false;
+        }
+    } else {
+        #[cfg(not(feature = "minimal"))]
+        event_log::record(Kind::Drill, drill as u32);
+        critical_section::with(|cs| PENDING.borrow(cs).set(Some(drill)));
+    }
+    warn!("Drill: simulating {}", drill);
+    true
+}
+
+/// Run a requested drill, or continue a simulated contact. Call from the detection task before
+/// each sample is stepped.
+///
+/// Returns whether [`CONTACT_PROBE`] is held in contact by the drill, so its detector must not
+/// be stepped, or [`Error::ProbeDisconnected`] for [`Drill::Disconnect`].
+pub fn poll(
+    buffers: &Buffers,
+    sampler: &mut impl SamplerControl,
+    state: &mut StateMachine,
+) -> Result<bool> {
+    let samples = buffers.samples_recorded();
+    match critical_section::with(|cs| PENDING.borrow(cs).take()) {
+        Some(Drill::Contact) if state.state().armed() => {
+            state.contact(CONTACT_PROBE, sampler, "Drill: simulated contact")?;
+            critical_section::with(|cs| {
+                CONTACT_UNTIL
+                    .borrow(cs)
+                    .set(Some(samples + CONTACT_SAMPLES))
+            });
+        }
+        Some(Drill::Contact) => warn!("Drill abandoned, detection is no longer armed"),
+        Some(Drill::Disconnect) => {
+            return Err(Error::ProbeDisconnected(ProbeFault::Floating));
+        }
+        Some(Drill::Watchdog) | None => {}
+    }
+
+    let until = match critical_section::with(|cs| CONTACT_UNTIL.borrow(cs).get()) {
+        Some(until) => until,
+        None => return Ok(false),
+    };
+    // Ended early, such as by a fault
+    if state.phase(CONTACT_PROBE) != Some(Phase::Alert) {
+        critical_section::with(|cs| CONTACT_UNTIL.borrow(cs).set(None));
+        return Ok(false);
+    }
+    if samples < until {
+        return Ok(true);
+    }
+    critical_section::with(|cs| CONTACT_UNTIL.borrow(cs).set(None));
+    state.contact_ended(
+        CONTACT_PROBE,
+        buffers.latch_alert(),
+        buffers.auto_clear_ms(),
+        buffers.min_trip_ms(),
+        sampler,
+    )?;
+    Ok(false)
+}
@@ -0,0 +1,258 @@
+//! Errors returned by the library. Handlers report errors to the caller instead of panicking, so
+//! the binary decides whether to latch [`SystemState::Error`](crate::state::SystemState::Error),
+//! retry, or reset.
+//!
+//! Each [`Error`] has a stable numeric [`ErrorCode`], which identifies it outside of defmt logs.
+//! The code of the error that latched [`SystemState::Error`] is kept across a reset, and logged on
+//! the next boot with [`take_last`].
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{
+    convert::Infallible,
+    mem::MaybeUninit,
+    ptr::{addr_of, addr_of_mut},
+};
+
+use defmt::Format;
+
+use crate::{
+    bist::Check, config::ConfigError, deadline::Stage, probe::ProbeFault, state::SystemState,
+};
+
+/// Result type for fallible library APIs
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+/// Errors returned by the library
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Error {
+    /// A singleton resource has already been initialized
+    AlreadyInitialized,
+    /// No ADC transfer was in progress, so the latest readings could not be collected
+    NoTransfer,
+    /// A GPIO pin could not be read, written or configured
+    Gpio,
+    /// The crystal oscillator or PLLs could not be started
+    Clocks,
+    /// The USB device could not be built
+    Usb,
+    /// The [`SystemState`] transition is not permitted
+    InvalidTransition {
+        /// Current state
+        from: SystemState,
+        /// Requested state
+        to: SystemState,
+    },
+    /// Configuration could not be loaded or stored
+    Config(ConfigError),
+    /// A pipeline stage repeatedly ran over its [budget](Stage::budget_us)
+    DeadlineMissed(Stage),
+    /// A [built-in self-test](crate::bist) check failed at boot
+    SelfTest(Check),
+    /// The readings show the probe has been disconnected
+    ProbeDisconnected(ProbeFault),
+    /// Detection did not fire within the expected number of samples of a test
+    /// [injection](crate::injection)
+    InjectionFailed,
+    /// The two ADC channels of the `dual_channel` feature disagree (see
+    /// [`crosscheck`](crate::crosscheck))
+    ChannelMismatch,
+    /// The supply failed, as reported by the power-good input (see [`power`](crate::power))
+    PowerFail,
+    /// The core0 stack is nearly exhausted (see [`stack`](crate::stack))
+    StackLow {
+        /// Stack never used, in bytes
+        free_bytes: u32,
+    },
+    /// A periodic proof test did not pass in time (see [`injection`](crate::injection))
+    ProofTestOverdue,
+    /// The averaged signal stayed outside its plausible range, pointing to a front-end fault (see
+    /// [`plausibility`](crate::plausibility))
+    SignalOutOfRange {
+        /// Probe the signal came from
+        probe: u8,
+        /// Last implausible sample
+        sample: u8,
+    },
+    /// The blanking input was held past its window, or asserted too often (see
+    /// [`blanking`](crate::blanking))
+    BlankingOveruse,
+    /// Fewer than two of the `triple_channel` channels are healthy, so they can't be voted on (see
+    /// [`voting`](crate::voting))
+    VoteFailed,
+    /// A status LED or the buzzer could not be driven, so the
+    /// [`Indicators`](crate::state::Indicators) are degraded. Detection and the interlock carry on.
+    IndicatorFailed,
+}
+
+impl Error {
+    /// Stable code identifying this kind of error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::AlreadyInitialized => ErrorCode::AlreadyInitialized,
+            Error::NoTransfer => ErrorCode::NoTransfer,
+            Error::Gpio => ErrorCode::Gpio,
+            Error::Clocks => ErrorCode::Clocks,
+            Error::Usb => ErrorCode::Usb,
+            Error::InvalidTransition { .. } => ErrorCode::InvalidTransition,
+            Error::Config(_) => ErrorCode::Config,
+            Error::DeadlineMissed(_) => ErrorCode::DeadlineMissed,
+            Error::SelfTest(_) => ErrorCode::SelfTest,
+            Error::ProbeDisconnected(_) => ErrorCode::ProbeDisconnected,
+            Error::InjectionFailed => ErrorCode::InjectionFailed,
+            Error::ChannelMismatch => ErrorCode::ChannelMismatch,
+            Error::PowerFail => ErrorCode::PowerFail,
+            Error::StackLow { .. } => ErrorCode::StackLow,
+            Error::ProofTestOverdue => ErrorCode::ProofTestOverdue,
+            Error::SignalOutOfRange { .. } => ErrorCode::SignalOutOfRange,
+            Error::BlankingOveruse => ErrorCode::BlankingOveruse,
+            Error::VoteFailed => ErrorCode::VoteFailed,
+            Error::IndicatorFailed => ErrorCode::IndicatorFailed,
+        }
+    }
+}
+
+/// Stable numeric identity of an [`Error`], for transports that can't carry defmt data.
+///
+/// Codes are never reused or renumbered. New codes may be added, so matches need a wildcard arm.
+#[non_exhaustive]
+#[repr(u16)]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum ErrorCode {
+    /// [`Error::AlreadyInitialized`]
+    AlreadyInitialized = 0x01,
+    /// [`Error::NoTransfer`]
+    NoTransfer = 0x02,
+    /// [`Error::Gpio`]
+    Gpio = 0x03,
+    /// [`Error::Clocks`]
+    Clocks = 0x04,
+    /// [`Error::Usb`]
+    Usb = 0x05,
+    /// [`Error::InvalidTransition`]
+    InvalidTransition = 0x06,
+    /// [`Error::Config`]
+    Config = 0x07,
+    /// [`Error::DeadlineMissed`]
+    DeadlineMissed = 0x08,
+    /// [`Error::SelfTest`]
+    SelfTest = 0x09,
+    /// [`Error::ProbeDisconnected`]
+    ProbeDisconnected = 0x0A,
+    /// [`Error::InjectionFailed`]
+    InjectionFailed = 0x0B,
+    /// [`Error::ChannelMismatch`]
+    ChannelMismatch = 0x0C,
+    /// [`Error::PowerFail`]
+    PowerFail = 0x0D,
+    /// [`Error::StackLow`]
+    StackLow = 0x0E,
+    /// [`Error::ProofTestOverdue`]
+    ProofTestOverdue = 0x0F,
+    /// [`Error::SignalOutOfRange`]
+    SignalOutOfRange = 0x10,
+    /// [`Error::BlankingOveruse`]
+    BlankingOveruse = 0x11,
+    /// [`Error::VoteFailed`]
+    VoteFailed = 0x12,
+    /// [`Error::IndicatorFailed`]
+    IndicatorFailed = 0x13,
+}
+
+impl ErrorCode {
+    /// Every code, in numeric order
+    pub const ALL: [ErrorCode; 19] = [
+        ErrorCode::AlreadyInitialized,
+        ErrorCode::NoTransfer,
+        ErrorCode::Gpio,
+        ErrorCode::Clocks,
+        ErrorCode::Usb,
+        ErrorCode::InvalidTransition,
+        ErrorCode::Config,
+        ErrorCode::DeadlineMissed,
+        ErrorCode::SelfTest,
+        ErrorCode::ProbeDisconnected,
+        ErrorCode::InjectionFailed,
+        ErrorCode::ChannelMismatch,
+        ErrorCode::PowerFail,
+        ErrorCode::StackLow,
+        ErrorCode::ProofTestOverdue,
+        ErrorCode::SignalOutOfRange,
+        ErrorCode::BlankingOveruse,
+        ErrorCode::VoteFailed,
+        ErrorCode::IndicatorFailed,
+    ];
+
+    /// Numeric value of the code
+    pub const fn value(self) -> u16 {
+        self as u16
+    }
+
+    /// Look up a code by its numeric value, returning [`None`] if it is unknown
+    pub fn from_value(value: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|code| code.value() == value)
+    }
+}
+
+/// Marks a valid record in the upper half of [`ERROR_RECORD`] ("ER")
+const RECORD_MAGIC: u32 = 0x4552_0000;
+
+/// Code of the error that latched [`SystemState::Error`] below [`RECORD_MAGIC`]. Not initialized
+/// or cleared by the runtime.
+#[link_section = ".uninit.ERROR_RECORD"]
+static mut ERROR_RECORD: MaybeUninit<u32> = MaybeUninit::uninit();
+
+/// Keep `code` across a reset, replacing any earlier record. Called when
+/// [`SystemState::Error`] is entered.
+pub fn record(code: ErrorCode) {
+    critical_section::with(|_| {
+        // SAFETY: only accessed here and in `take_last`, both in a critical section
+        unsafe {
+            addr_of_mut!(ERROR_RECORD)
+                .cast::<u32>()
+                .write_volatile(RECORD_MAGIC | code.value() as u32);
+        }
+    });
+}
+
+/// Take the code of the error that latched [`SystemState::Error`] before the last reset, if there
+/// was one. Later calls return [`None`] until the next error. A power cycle clears the record.
+pub fn take_last() -> Option<ErrorCode> {
+    critical_section::with(|_| {
+        // SAFETY: only accessed here and in `record`, both in a critical section. Any bit pattern
+        // is a valid `u32`.
+        unsafe {
+            let record = addr_of!(ERROR_RECORD).cast::<u32>().read_volatile();
+            addr_of_mut!(ERROR_RECORD).cast::<u32>().write_volatile(0);
+            if record & 0xFFFF_0000 != RECORD_MAGIC {
+                return None;
+            }
+            ErrorCode::from_value(record as u16)
+        }
+    })
+}
+
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Self {
+        Error::Config(err)
+    }
+}
+
+/// RP2040 GPIO operations cannot fail
+impl From<Infallible> for Error {
+    fn from(err: Infallible) -> Self {
+        match err {}
+    }
+}
@@ -0,0 +1,307 @@
+//! Persistent journal of detections and operator actions, for post-incident review.
+//!
+//! Arming, disarming, acknowledging an alert, silencing the buzzer, calibration and configuration
+//! changes are [recorded](operator) with what caused them (the button, the disable switch or the
+//! console), alongside every [detection](Kind::Detection), [drill](crate::drill), fault and boot.
+//! Records use the compact [`journal`](crate::journal) format, in a ring of [`JOURNAL_SECTORS`]
+//! starting at [`JOURNAL_OFFSET`]. Once the current sector is full, the oldest one is erased and
+//! reused. A sector dumped from flash can be read with the `decode_journal` host tool.
+//!
+//! Events can be recorded from any context, and wait in RAM until they are [flushed](flush). Those
+//! raised by the interrupt handlers arrive through the [`events`](crate::events) queue. Writing
+//! flash stops every interrupt, so like the [`uptime`](crate::uptime) totals, that only happens
+//! while sampling is stopped. Up to [`PENDING_EVENTS`] are kept in the meantime. Any more are
+//! counted, then journalled as a single [`Kind::Dropped`].
+//!
+//! When the supply fails, the power-fail handler journals a [`Kind::PowerFail`] and
+//! [writes the queued events](flush_on_power_fail) at once, even while sampling, as the interlock
+//! is already tripped. Nothing is erased then: the events are only programmed into the erased rest
+//! of the current sector, so a dropout part way through can't lose those already there. Events
+//! that don't fit, those still in the [`events`](crate::events) queue, and any queued on a reset,
+//! are lost.
+//!
+//! Each event is numbered as it is recorded, carrying on from the last one in flash after a boot,
+//! so its [`Record::seq`] shows where events were dropped, or lost as the ring wrapped. A host
+//! that spots a gap in an export can ask for the events from a sequence number again with
+//! `EXPORT <format> <seq>`.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{cell::RefCell, mem};
+
+use critical_section::Mutex;
+use defmt::{debug, warn};
+use heapless::Vec;
+
+use crate::{
+    drill::Drill,
+    journal::{Decoder, Encoder, Kind, Record, Source, MAGIC, MAX_RECORD_BYTES, TAG_ERASED},
+    reset, scheduler,
+    state::SystemState,
+    storage::{self, StorageError, JOURNAL_OFFSET, JOURNAL_SECTORS, PAGE_SIZE, SECTOR_SIZE},
+};
+
+/// Events held in RAM until the next [`flush`]
+pub const PENDING_EVENTS: usize = 32;
+/// Longest encoding of a record at the start of a sector, after a rebase and a sequence number
+const MAX_FIRST_RECORD_BYTES: usize = 2 * (1 + 5) + MAX_RECORD_BYTES;
+
+/// Events waiting to be written
+struct Pending {
+    /// Events in the order they happened
+    records: Vec<Record, PENDING_EVENTS>,
+    /// Events dropped since the last flush, because `records` was full
+    dropped: u32,
+    /// Sequence number of the next event recorded
+    next_seq: u32,
+}
+
+/// Copy of the sector being appended to, as flash can only be rewritten a sector at a time
+struct Writer {
+    /// Index of the current sector in the ring
+    sector: usize,
+    /// Contents of the current sector
+    image: [u8; SECTOR_SIZE],
+    /// Bytes of `image` used so far
+    used: usize,
+    /// Bytes of `image` already in flash, with the rest of the sector erased, or [`None`] if the
+    /// sector must be erased before it is written
+    programmed: Option<usize>,
+    /// Encodes records after the ones in `image`
+    encoder: Encoder,
+}
+
+impl Writer {
+    /// Erase the image and start sector `sector`
+    fn start(&mut self, sector: usize) {
+        self.sector = sector;
+        self.image = [TAG_ERASED; SECTOR_SIZE];
+        self.image[..MAGIC.len()].copy_from_slice(&MAGIC);
+        self.used = MAGIC.len();
+        self.programmed = None;
+        self.encoder = Encoder::new();
+    }
+
+    /// Append `record` to the image, returning `false` if the sector is full
+    fn append(&mut self, record: Record) -> bool {
+        match self.encoder.encode(record, &mut self.image[self.used..]) {
+            Some(len) => {
+                self.used += len;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Erase the sector and write the image to it
+    fn write(&mut self) -> Result<(), StorageError> {
+        storage::write_sector(sector_offset(self.sector), &self.image[..self.used])?;
+        self.programmed = Some(self.used);
+        Ok(())
+    }
+
+    /// Program the bytes appended since the last write into the erased rest of the sector, without
+    /// erasing it. The page they start in is programmed again whole, with the bytes it already
+    /// holds unchanged.
+    fn program(&mut self) -> Result<(), StorageError> {
+        let programmed = self.programmed.ok_or(StorageError::NotErased)?;
+        if programmed == self.used {
+            return Ok(());
+        }
+        let start = programmed / PAGE_SIZE * PAGE_SIZE;
+        let end = self.used.next_multiple_of(PAGE_SIZE);
+        storage::program_pages(
+            sector_offset(self.sector) + start as u32,
+            &self.image[start..end],
+        )?;
+        self.programmed = Some(self.used);
+        Ok(())
+    }
+}
+
+/// Offset of `sector` in the ring
+fn sector_offset(sector: usize) -> u32 {
+    JOURNAL_OFFSET + (sector * SECTOR_SIZE) as u32
+}
+
+/// Events recorded since the last flush
+static PENDING: Mutex<RefCell<Pending>> = Mutex::new(RefCell::new(Pending {
+    records: Vec::new(),
+    dropped: 0,
+    next_seq: 0,
+}));
+
+/// Current sector, only used from the idle task by [`load`] and [`flush`]
+static WRITER: Mutex<RefCell<Writer>> = Mutex::new(RefCell::new(Writer {
+    sector: 0,
+    image: [0; SECTOR_SIZE],
+    used: 0,
+    programmed: None,
+    encoder: Encoder::new(),
+}));
+
+/// Find the sector to append to, and the sequence number to carry on from, then journal the boot.
+/// Call once during startup, after [`reset::decode`].
+///
+/// Sectors fill in order, so the current one is the first with room for another record. A sector
+/// that doesn't hold a journal is empty. If every sector is full, the ring starts again from the
+/// first. Events recorded before this are renumbered to follow those in flash.
+pub fn load() {
+    let next_seq = (0..JOURNAL_SECTORS)
+        .filter_map(|sector| Decoder::new(storage::read(sector_offset(sector), SECTOR_SIZE)))
+        .flatten()
+        .map(|record| record.seq.wrapping_add(1))
+        .max()
+        .unwrap_or(0);
+    critical_section::with(|cs| {
+        let mut pending = PENDING.borrow_ref_mut(cs);
+        for record in &mut pending.records {
+            record.seq = record.seq.wrapping_add(next_seq);
+        }
+        pending.next_seq = pending.next_seq.wrapping_add(next_seq);
+    });
+    let current = (0..JOURNAL_SECTORS).find_map(|sector| {
+        let bytes = storage::read(sector_offset(sector), SECTOR_SIZE);
+        let Some(mut records) = Decoder::new(bytes) else {
+            return Some((sector, 0, bytes.iter().all(|byte| *byte == TAG_ERASED)));
+        };
+        records.by_ref().for_each(drop);
+        let unused = records.remaining();
+        (unused.len() >= MAX_FIRST_RECORD_BYTES && unused.iter().all(|byte| *byte == TAG_ERASED))
+            .then_some((sector, SECTOR_SIZE - unused.len(), true))
+    });
+    let (sector, used, erased) = current.unwrap_or((0, 0, false));
+    critical_section::with(|cs| {
+        let mut writer = WRITER.borrow_ref_mut(cs);
+        writer.start(sector);
+        if used > 0 {
+            writer.image[..used].copy_from_slice(storage::read(sector_offset(sector), used));
+            writer.used = used;
+        }
+        writer.programmed = erased.then_some(used);
+        debug!(
+            "Journal sector {=usize}, {=usize} bytes used",
+            writer.sector, writer.used
+        );
+    });
+    record(Kind::Boot, reset::last_cause() as u32);
+    if reset::last_timeout_simulated() {
+        record(Kind::Drill, Drill::Watchdog as u32);
+    }
+}
+
+/// Queue an event to be journalled at the next [`flush`]
+pub fn record(kind: Kind, value: u32) {
+    record_at(scheduler::now_ms(), kind, value);
+}
+
+/// Queue an event that happened at `at_ms` to be journalled at the next [`flush`], numbering it as
+/// it is queued. A dropped event still uses up its sequence number.
+pub fn record_at(at_ms: u32, kind: Kind, value: u32) {
+    critical_section::with(|cs| {
+        let mut pending = PENDING.borrow_ref_mut(cs);
+        let record = Record {
+            seq: pending.next_seq,
+            at_ms,
+            kind: kind as u8,
+            value,
+        };
+        pending.next_seq = pending.next_seq.wrapping_add(1);
+        if pending.records.push(record).is_err() {
+            pending.dropped = pending.dropped.saturating_add(1);
+        }
+    });
+}
+
+/// Queue an operator action, caused by `source`
+pub fn operator(kind: Kind, source: Source) {
+    record(kind, source as u32);
+}
+
+/// Contents of journal sector `idx`, counting from the oldest, so the last of the
+/// [`JOURNAL_SECTORS`] is the one being appended to. Events still queued in RAM aren't included.
+pub fn sector(idx: usize) -> &'static [u8] {
+    let current = critical_section::with(|cs| WRITER.borrow_ref(cs).sector);
+    storage::read(
+        sector_offset((current + 1 + idx) % JOURNAL_SECTORS),
+        SECTOR_SIZE,
+    )
+}
+
+/// Write the queued events to flash, if `current` doesn't sample. Call periodically from the
+/// lowest-priority task, and before entering standby.
+pub fn flush(current: SystemState) {
+    if current.sampling() {
+        return;
+    }
+    write_pending(true);
+}
+
+/// Journal a [`Kind::PowerFail`], then write the queued events to flash straight away, whatever
+/// the state. Call from the power-fail handler, once the interlock is tripped.
+///
+/// Nothing is erased, as that takes tens of milliseconds the supply may not hold up for. The
+/// events are programmed a page at a time into the erased rest of the current sector, and any that
+/// don't fit are lost.
+pub fn flush_on_power_fail() {
+    record(Kind::PowerFail, 0);
+    write_pending(false);
+}
+
+/// Write the queued events to flash, after those in the current sector. Does nothing before
+/// [`load`], which finds that sector. Unless `erase` is set, only the erased rest of the current
+/// sector is programmed, and the events that don't fit there are discarded.
+fn write_pending(erase: bool) {
+    critical_section::with(|cs| {
+        let mut writer = WRITER.borrow_ref_mut(cs);
+        if writer.used == 0 {
+            return Ok(());
+        }
+        let mut pending = PENDING.borrow_ref_mut(cs);
+        let dropped = mem::take(&mut pending.dropped);
+        let dropped = (dropped > 0).then(|| {
+            let seq = pending.next_seq;
+            pending.next_seq = seq.wrapping_add(1);
+            Record {
+                seq,
+                at_ms: scheduler::now_ms(),
+                kind: Kind::Dropped as u8,
+                value: dropped,
+            }
+        });
+        let records = mem::take(&mut pending.records);
+        if records.is_empty() && dropped.is_none() {
+            return Ok(());
+        }
+        for record in records.into_iter().chain(dropped) {
+            if !writer.append(record) {
+                if !erase {
+                    break;
+                }
+                writer.write()?;
+                let next = (writer.sector + 1) % JOURNAL_SECTORS;
+                writer.start(next);
+                // Always fits in an empty sector
+                writer.append(record);
+            }
+        }
+        if erase {
+            writer.write()
+        } else {
+            writer.program()
+        }
+    })
+    .unwrap_or_else(|err| warn!("Unable to write the event journal: {}", err));
+}
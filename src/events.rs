@@ -0,0 +1,115 @@
+//! System events raised by the interrupt handlers, and handled later from the main loop.
+//!
+//! Detection, the [`StateMachine`](crate::state::StateMachine) and the button and switch
+//! handlers [push] a [`SystemEvent`] as it happens, from any context and on either core, without
+//! waiting on flash or a log transport. The main loop of each binary [dispatches](dispatch) them
+//! after updating the [`Indicators`](crate::state::Indicators), which display the state the
+//! events led to. Each event is journalled in the [`event_log`] with the time it was pushed, and
+//! logged.
+//!
+//! The queue holds [`EVENT_QUEUE_SIZE`] events. Any pushed while it is full are counted, then
+//! journalled as a single [`Kind::Dropped`] at the next dispatch.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use defmt::Format;
+use heapless::mpmc::MpMcQueue;
+
+#[cfg(not(feature = "minimal"))]
+use crate::event_log;
+use crate::{
+    error::ErrorCode,
+    journal::{Kind, Source},
+    log, scheduler,
+};
+
+/// Events held until the next [`dispatch`]. Must be a power of two.
+pub const EVENT_QUEUE_SIZE: usize = 16;
+
+/// Events waiting to be dispatched, with the [`scheduler::now_ms`] they were pushed at
+static QUEUE: MpMcQueue<(u32, SystemEvent), EVENT_QUEUE_SIZE> = MpMcQueue::new();
+/// Events pushed while the queue was full, since the last dispatch
+static DROPPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Something that happened in an interrupt handler
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Format)]
+pub enum SystemEvent {
+    /// Contact was detected on `probe`, with the averaged sample `value`
+    Detection {
+        /// Probe in contact
+        probe: u8,
+        /// Averaged sample that crossed the threshold
+        value: u8,
+    },
+    /// An error stopped detection, or put the [`Indicators`](crate::state::Indicators) in degraded
+    /// mode
+    Fault(ErrorCode),
+    /// An operator action, or one the firmware took in their place, caused by a [`Source`]
+    Operator(Kind, Source),
+    /// The external [`trigger`](crate::trigger) input marked the sample stream, at the low 32 bits
+    /// of this [`SampleCounter`](crate::buffer::SampleCounter)
+    Marker(u32),
+}
+
+impl SystemEvent {
+    /// Kind and value of the event's journal record
+    #[cfg(not(feature = "minimal"))]
+    fn record(self) -> (Kind, u32) {
+        match self {
+            SystemEvent::Detection { probe, value } => {
+                (Kind::Detection, (probe as u32) << 8 | value as u32)
+            }
+            SystemEvent::Fault(code) => (Kind::Fault, code.value() as u32),
+            SystemEvent::Operator(kind, source) => (kind, source as u32),
+            SystemEvent::Marker(sample) => (Kind::Marker, sample),
+        }
+    }
+}
+
+/// Queue `event` to be handled at the next [`dispatch`]. Can be called from any context.
+pub fn push(event: SystemEvent) {
+    if QUEUE.enqueue((scheduler::now_ms(), event)).is_err() {
+        critical_section::with(|cs| {
+            let dropped = DROPPED.borrow(cs);
+            dropped.set(dropped.get().saturating_add(1));
+        });
+    }
+}
+
+/// Journal and log every queued event, in the order they were pushed. Call from the main loop.
+pub fn dispatch() {
+    while let Some((at_ms, event)) = QUEUE.dequeue() {
+        log!(Detection, debug, "Event at {=u32} ms: {}", at_ms, event);
+        #[cfg(not(feature = "minimal"))]
+        {
+            let (kind, value) = event.record();
+            event_log::record_at(at_ms, kind, value);
+        }
+    }
+    let dropped = critical_section::with(|cs| DROPPED.borrow(cs).replace(0));
+    if dropped > 0 {
+        log!(
+            Detection,
+            warn,
+            "{=u32} events dropped, queue full",
+            dropped
+        );
+        #[cfg(not(feature = "minimal"))]
+        event_log::record(Kind::Dropped, dropped);
+    }
+}
@@ -0,0 +1,300 @@
+//! Streams the [`event_log`] over the console as CSV or JSON, so incident data can be archived
+//! with a plain serial terminal.
+//!
+//! `EXPORT CSV` and `EXPORT JSON` start an [`Export`], which the
+//! [`Console`](crate::console::Console) continues each time the USB device is polled, as the whole
+//! journal doesn't fit in one response. A header identifies the unit by its [`provision`]ed serial
+//! number and hardware revision, the firmware and the [hash](version::config_hash) of the active
+//! configuration (see [`version`]), and the [time](scheduler::now_us) the export started, as
+//! comment lines in CSV. Events are then listed from the oldest, one per line, numbering each boot
+//! so that the times since boot can be told apart:
+//!
+//! ```text
+//! # unit PFPU2-0007 rev B time_us 81234567
+//! # firmware 0.1.0 3f2a91c04d7e built 2024-03-28T17:02:11Z board proto-v2 config 5D0C1E77
+//! # features triple_status,persist_panic
+//! boot,time_ms,event,source,probe,value,seq
+//! 1,12,boot,,,5,40
+//! 1,2041,calibration,system,,,41
+//! 1,73150,detection,,0,212,42
+//! CRC32 8A431392
+//! ```
+//!
+//! Events before the first journalled boot are numbered 0. The last line is the CRC-32 (as used
+//! by [`storage::crc32`]) of every byte before it, so a truncated or garbled capture can be
+//! spotted. Events still queued in RAM are not included, see [`event_log::sector`].
+//!
+//! Each event ends with its [sequence number](Record::seq), so a gap shows events that were
+//! dropped or lost as the journal wrapped. `EXPORT CSV <seq>` or `EXPORT JSON <seq>` only lists
+//! the events from sequence number `seq` on, so a host can ask for those again after losing part
+//! of an export.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt::Write;
+
+use heapless::String;
+
+pub use crate::protocol::ExportFormat;
+use crate::{
+    config::board::BOARD_VARIANT,
+    console::ConsoleOutput,
+    event_log,
+    journal::{Decoder, Kind, Record, Source},
+    provision, scheduler,
+    storage::{self, JOURNAL_SECTORS},
+    version,
+};
+
+/// Longest line written by an [`Export`], enough to list every feature in the header
+const LINE_SIZE: usize = 384;
+
+/// A single line of an [`Export`]
+type Line = String<LINE_SIZE>;
+
+/// Part of the export written next
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+enum Stage {
+    /// Firmware and configuration, at the start of the JSON object
+    Header,
+    /// Enabled features, then the CSV header row or the start of the JSON array of events
+    Features,
+    /// One line per event
+    Events,
+    /// End of the JSON object
+    Footer,
+    /// CRC of everything before it
+    Crc,
+    /// Nothing left to write
+    Done,
+}
+
+/// Progress through an export of the [`event_log`]
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Export {
+    /// Text format
+    format: ExportFormat,
+    /// [`version::config_hash`] of the active configuration
+    config_hash: u32,
+    /// Part written next
+    stage: Stage,
+    /// Next sector to decode, counting from the oldest
+    sector: usize,
+    /// Events left in the sector being decoded
+    records: Option<Decoder<'static>>,
+    /// Boots seen so far
+    boot: u32,
+    /// Whether an event has been written, so JSON needs a separator before the next
+    separate: bool,
+    /// Sequence number of the first event written, skipping those before it
+    from_seq: u32,
+    /// CRC-32 of the lines written so far, before the final inversion
+    crc: u32,
+}
+
+impl Export {
+    /// Start exporting in `format`, from firmware running a configuration with `config_hash`,
+    /// listing the events from sequence number `from_seq` on
+    pub fn new(format: ExportFormat, config_hash: u32, from_seq: u32) -> Self {
+        Self {
+            format,
+            config_hash,
+            stage: Stage::Header,
+            sector: 0,
+            records: None,
+            boot: 0,
+            separate: false,
+            from_seq,
+            crc: u32::MAX,
+        }
+    }
+
+    /// Whether the whole export has been written
+    pub fn done(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    /// Write as many whole lines to `out` as fit
+    pub fn fill(&mut self, out: &mut ConsoleOutput) {
+        loop {
+            // Advance a copy, kept only if its line fits
+            let mut next = self.clone();
+            let Some(line) = next.line() else {
+                return;
+            };
+            if out.push_str(&line).is_err() {
+                return;
+            }
+            *self = next;
+        }
+    }
+
+    /// Produce the next line, or [`None`] once done
+    fn line(&mut self) -> Option<Line> {
+        let mut line = Line::new();
+        match (self.stage, self.format) {
+            (Stage::Header, ExportFormat::Csv) => {
+                let provisioning = provision::get();
+                let _ = write!(
+                    line,
+                    "# unit {} rev {} time_us {}\r\n\
+                     # firmware {} {} built {} board {} config {:08X}\r\n",
+                    provisioning.serial(),
+                    provisioning.revision(),
+                    scheduler::now_us(),
+                    version::VERSION,
+                    version::GIT_HASH,
+                    version::BUILD_TIME,
+                    BOARD_VARIANT,
+                    self.config_hash
+                );
+                self.stage = Stage::Features;
+            }
+            (Stage::Header, ExportFormat::Json) => {
+                let provisioning = provision::get();
+                let _ = write!(
+                    line,
+                    "{{\"serial\":\"{}\",\"rev\":\"{}\",\"time_us\":{},\"firmware\":\"{}\",\
+                     \"git\":\"{}\",\"built\":\"{}\",\"board\":\"{}\",\"config\":\"{:08X}\",\r\n",
+                    provisioning.serial(),
+                    provisioning.revision(),
+                    scheduler::now_us(),
+                    version::VERSION,
+                    version::GIT_HASH,
+                    version::BUILD_TIME,
+                    BOARD_VARIANT,
+                    self.config_hash
+                );
+                self.stage = Stage::Features;
+            }
+            (Stage::Features, ExportFormat::Csv) => {
+                let _ = write!(line, "# features ");
+                let _ = version::write_features(&mut line);
+                let _ = write!(line, "\r\nboot,time_ms,event,source,probe,value,seq\r\n");
+                self.stage = Stage::Events;
+            }
+            (Stage::Features, ExportFormat::Json) => {
+                let _ = write!(line, "\"features\":[");
+                for (idx, feature) in version::FEATURES.iter().enumerate() {
+                    let separator = if idx > 0 { "," } else { "" };
+                    let _ = write!(line, "{}\"{}\"", separator, feature);
+                }
+                let _ = write!(line, "],\r\n\"events\":[");
+                self.stage = Stage::Events;
+            }
+            (Stage::Events, format) => match self.next_record() {
+                Some(record) => self.write_record(&mut line, format, record),
+                None => self.stage = Stage::Footer,
+            },
+            (Stage::Footer, ExportFormat::Csv) => self.stage = Stage::Crc,
+            (Stage::Footer, ExportFormat::Json) => {
+                let _ = write!(line, "\r\n]}}\r\n");
+                self.stage = Stage::Crc;
+            }
+            (Stage::Crc, _) => {
+                let _ = write!(line, "CRC32 {:08X}\r\n", !self.crc);
+                self.stage = Stage::Done;
+                return Some(line);
+            }
+            (Stage::Done, _) => return None,
+        }
+        self.crc = storage::crc32_update(self.crc, line.as_bytes());
+        Some(line)
+    }
+
+    /// Next event in the journal, moving on to the next sector as each runs out
+    fn next_record(&mut self) -> Option<Record> {
+        loop {
+            if let Some(record) = self.records.as_mut().and_then(Iterator::next) {
+                return Some(record);
+            } else if self.sector == JOURNAL_SECTORS {
+                return None;
+            }
+            self.records = Decoder::new(event_log::sector(self.sector));
+            self.sector += 1;
+        }
+    }
+
+    /// Write `record` to `line` as a CSV row or JSON object, unless it comes before `from_seq`
+    fn write_record(&mut self, line: &mut Line, format: ExportFormat, record: Record) {
+        let kind = Kind::from_raw(record.kind);
+        if kind == Some(Kind::Boot) {
+            self.boot += 1;
+        }
+        // Boots are still counted, so skipped events keep their numbering
+        if record.seq < self.from_seq {
+            return;
+        }
+        let value = record.value;
+        let (source, probe, value) = match kind {
+            Some(Kind::Detection) => (None, Some(value >> 8), Some(value & 0xFF)),
+            Some(kind) if kind.operator() => match Source::from_raw(value) {
+                Some(source) => (Some(source), None, None),
+                None => (None, None, Some(value)),
+            },
+            _ => (None, None, Some(value)),
+        };
+
+        match format {
+            ExportFormat::Csv => {
+                let _ = write!(line, "{},{},", self.boot, record.at_ms);
+                match kind {
+                    Some(kind) => {
+                        let _ = write!(line, "{}", kind.name());
+                    }
+                    None => {
+                        let _ = write!(line, "{}", record.kind);
+                    }
+                }
+                let _ = write!(line, ",{},", source.map_or("", Source::name));
+                if let Some(probe) = probe {
+                    let _ = write!(line, "{}", probe);
+                }
+                let _ = write!(line, ",");
+                if let Some(value) = value {
+                    let _ = write!(line, "{}", value);
+                }
+                let _ = write!(line, ",{}\r\n", record.seq);
+            }
+            ExportFormat::Json => {
+                let separator = if self.separate { "," } else { "" };
+                let _ = write!(
+                    line,
+                    "{}\r\n  {{\"seq\":{},\"boot\":{},\"time_ms\":{},\"event\":",
+                    separator, record.seq, self.boot, record.at_ms
+                );
+                match kind {
+                    Some(kind) => {
+                        let _ = write!(line, "\"{}\"", kind.name());
+                    }
+                    None => {
+                        let _ = write!(line, "{}", record.kind);
+                    }
+                }
+                if let Some(source) = source {
+                    let _ = write!(line, ",\"source\":\"{}\"", source.name());
+                }
+                if let Some(probe) = probe {
+                    let _ = write!(line, ",\"probe\":{}", probe);
+                }
+                if let Some(value) = value {
+                    let _ = write!(line, ",\"value\":{}", value);
+                }
+                let _ = write!(line, "}}");
+                self.separate = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Fault manager, keeping statistics of every [`ErrorCode`] raised since boot.
+//!
+//! Errors are [raised](raise) by [`StateMachine::fail`](crate::state::StateMachine::fail), and by
+//! the [`Indicators`](crate::state::Indicators) when an output fails. Only the first occurrence of
+//! each code is logged. Repeats of an identical code are counted instead, with the time of the
+//! first and last occurrence, so a flapping sensor can't flood the log and drown out everything
+//! else. The table is printed by the `FAULTS` console command.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use defmt::Format;
+
+use crate::{error::ErrorCode, scheduler};
+
+/// Number of entries in the fault table, one per [`ErrorCode`]
+const FAULT_CODES: usize = ErrorCode::ALL.len();
+
+/// Occurrences of a single [`ErrorCode`]
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Format)]
+pub struct FaultStats {
+    /// Times the code was raised
+    pub count: u32,
+    /// Milliseconds since boot at the first occurrence
+    pub first_ms: u32,
+    /// Milliseconds since boot at the latest occurrence
+    pub last_ms: u32,
+}
+
+/// Statistics for each code, indexed as [`ErrorCode::ALL`]
+static TABLE: Mutex<RefCell<[FaultStats; FAULT_CODES]>> = Mutex::new(RefCell::new(
+    [FaultStats {
+        count: 0,
+        first_ms: 0,
+        last_ms: 0,
+    }; FAULT_CODES],
+));
+
+/// Count an occurrence of `code`.
+///
+/// Returns `true` if this is the first occurrence since boot, and should be logged.
+pub fn raise(code: ErrorCode) -> bool {
+    let now = scheduler::now_ms();
+    critical_section::with(|cs| {
+        let mut table = TABLE.borrow_ref_mut(cs);
+        let Some(stats) = index(code).map(|idx| &mut table[idx]) else {
+            return true;
+        };
+        if stats.count == 0 {
+            stats.first_ms = now;
+        }
+        stats.count = stats.count.saturating_add(1);
+        stats.last_ms = now;
+        stats.count == 1
+    })
+}
+
+/// Statistics for `code`, with a count of 0 if it has never been raised
+pub fn stats(code: ErrorCode) -> FaultStats {
+    index(code).map_or(FaultStats::default(), |idx| {
+        critical_section::with(|cs| TABLE.borrow_ref(cs)[idx])
+    })
+}
+
+/// Every code raised since boot, with its statistics, in numeric order
+pub fn raised() -> impl Iterator<Item = (ErrorCode, FaultStats)> {
+    let table = critical_section::with(|cs| *TABLE.borrow_ref(cs));
+    ErrorCode::ALL
+        .into_iter()
+        .zip(table)
+        .filter(|(_, stats)| stats.count > 0)
+}
+
+/// Position of `code` in [`ErrorCode::ALL`]
+fn index(code: ErrorCode) -> Option<usize> {
+    ErrorCode::ALL.iter().position(|other| *other == code)
+}
@@ -0,0 +1,267 @@
+//! Hardware-in-the-loop bench test (feature `hil`), verifying an assembled unit end to end
+//! without a saw or a test subject.
+//!
+//! On the production bench, the probe input is replaced by a loopback fixture: the signal
+//! generator output is fed back to the ADC input through a shunt controlled by the `hil_dac` pin.
+//! The pin is a PWM DAC, filtered by an RC network on the fixture (e.g. 1 kΩ and 100 nF), so its
+//! level sets how far the detection signal is pulled down, as a contact would. The [`Loopback`]
+//! plays a [`Scenario`] on the DAC, one level per averaged sample, and checks that detection
+//! reacts as expected.
+//!
+//! A scenario is requested with the `HIL <scenario>` console command, and starts the next time the
+//! system is armed. Its [`Outcome`] is logged, and printed by `HIL` on its own. A failed scenario
+//! is reported to the operator rather than raised as an [`Error`](crate::error::Error), as it
+//! tests the unit rather than protecting anyone.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use defmt::{info, warn, Format};
+use embedded_hal::pwm::SetDutyCycle;
+
+use crate::{
+    buffer::Buffers,
+    config::board::{HilPwmChannel, HilPwmSlice},
+    error::Result,
+    hal::pwm::{self, FreeRunning, Slice},
+    state::SystemState,
+};
+
+/// PWM counter top for the DAC, giving 256 levels (94 kHz with a 24 MHz system clock)
+pub const DAC_TOP: u16 = 255;
+/// Samples before and after the contact in each [`Scenario`], letting the signal settle
+const LEAD_SAMPLES: u64 = 50;
+
+/// PWM channel driving the loopback DAC
+pub type HilPwm = pwm::Channel<Slice<HilPwmSlice, FreeRunning>, HilPwmChannel>;
+
+/// Scenario waiting to start
+static REQUESTED: Mutex<Cell<Option<Scenario>>> = Mutex::new(Cell::new(None));
+/// Most recent scenario, and how it went
+static STATUS: Mutex<Cell<Option<(Scenario, Outcome)>>> = Mutex::new(Cell::new(None));
+
+/// Synthetic waveforms played by the [`Loopback`]. Levels are DAC counts, where 0 leaves the
+/// signal untouched and 255 pulls it down as far as the fixture allows.
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Scenario {
+    /// Firm contact for 200 ms. Must be detected.
+    Step,
+    /// Intermittent contact, toggling every 8 ms for 200 ms. Must be detected.
+    Bounce,
+    /// A single-sample glitch. Must be rejected by the confirmation check.
+    Blip,
+    /// Full scale over 4 s, slower than any contact. Must not be detected.
+    Drift,
+}
+
+impl Scenario {
+    /// Every scenario
+    pub const ALL: [Scenario; 4] = [
+        Scenario::Step,
+        Scenario::Bounce,
+        Scenario::Blip,
+        Scenario::Drift,
+    ];
+
+    /// Console name of the scenario
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scenario::Step => "STEP",
+            Scenario::Bounce => "BOUNCE",
+            Scenario::Blip => "BLIP",
+            Scenario::Drift => "DRIFT",
+        }
+    }
+
+    /// Look up a scenario by its case-insensitive [name](Scenario::name)
+    pub fn from_name(name: &str) -> Option<Self> {
+        Scenario::ALL
+            .into_iter()
+            .find(|scenario| scenario.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Whether detection must enter [`SystemState::Alert`] during the scenario
+    pub fn expects_contact(&self) -> bool {
+        matches!(self, Scenario::Step | Scenario::Bounce)
+    }
+
+    /// Length of the scenario in samples, long enough for any alert to clear
+    pub fn length(&self) -> u64 {
+        match self {
+            Scenario::Step | Scenario::Bounce => 2 * LEAD_SAMPLES + 400,
+            Scenario::Blip => 2 * LEAD_SAMPLES + 1,
+            Scenario::Drift => 2 * LEAD_SAMPLES + 2000,
+        }
+    }
+
+    /// DAC level `sample` samples into the scenario
+    pub fn level(&self, sample: u64) -> u8 {
+        let Some(sample) = sample
+            .checked_sub(LEAD_SAMPLES)
+            .filter(|sample| *sample < self.length() - 2 * LEAD_SAMPLES)
+        else {
+            return 0;
+        };
+        match self {
+            Scenario::Step if sample < 100 => u8::MAX,
+            Scenario::Bounce if sample < 100 && sample / 4 % 2 == 0 => u8::MAX,
+            Scenario::Step | Scenario::Bounce => 0,
+            Scenario::Blip => u8::MAX,
+            Scenario::Drift => (sample * u8::MAX as u64 / 2000) as u8,
+        }
+    }
+}
+
+/// Result of a [`Scenario`]
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Outcome {
+    /// Requested, waiting for the system to arm
+    Pending,
+    /// Still playing
+    Running,
+    /// Detection reacted as expected
+    Pass,
+    /// Detection missed an expected contact, or raised an unexpected one
+    Fail,
+    /// The system left detection before the scenario ended
+    Abandoned,
+}
+
+impl Outcome {
+    /// Console description of the outcome
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Pending => "waiting for the system to arm",
+            Outcome::Running => "running",
+            Outcome::Pass => "PASS",
+            Outcome::Fail => "FAIL",
+            Outcome::Abandoned => "abandoned",
+        }
+    }
+}
+
+/// Request `scenario`, which starts the next time the system is armed.
+pub fn request(scenario: Scenario) {
+    critical_section::with(|cs| REQUESTED.borrow(cs).set(Some(scenario)));
+}
+
+/// Most recent scenario and its outcome, if any has been requested
+pub fn status() -> Option<(Scenario, Outcome)> {
+    critical_section::with(|cs| {
+        REQUESTED
+            .borrow(cs)
+            .get()
+            .map(|scenario| (scenario, Outcome::Pending))
+            .or(STATUS.borrow(cs).get())
+    })
+}
+
+/// Record the `outcome` of `scenario`
+fn set_status(scenario: Scenario, outcome: Outcome) {
+    critical_section::with(|cs| STATUS.borrow(cs).set(Some((scenario, outcome))));
+}
+
+/// A [`Scenario`] being played
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+struct Run {
+    /// Scenario being played
+    scenario: Scenario,
+    /// Samples recorded when it started
+    started: u64,
+    /// Whether detection has entered [`SystemState::Alert`] since it started
+    contact: bool,
+}
+
+/// Plays [`Scenario`]s on the loopback DAC
+pub struct Loopback {
+    /// DAC output
+    output: HilPwm,
+    /// Scenario being played, if any
+    running: Option<Run>,
+}
+
+impl Loopback {
+    /// Take the DAC channel, whose slice must already be running with a top of [`DAC_TOP`], and
+    /// set it to 0.
+    pub fn new(mut output: HilPwm) -> Result<Self> {
+        output.set_duty_cycle(0)?;
+        Ok(Self {
+            output,
+            running: None,
+        })
+    }
+
+    /// Start a requested scenario, or advance the running one and check on detection. Call from
+    /// the detection task after each batch of samples, with the current state.
+    ///
+    /// A scenario is abandoned if the system leaves [`SystemState::Armed`],
+    /// [`SystemState::Warning`], [`SystemState::Alert`] or [`SystemState::Latched`].
+    pub fn poll(&mut self, current: SystemState, buffers: &Buffers) -> Result<()> {
+        let samples = buffers.samples_recorded();
+        match &mut self.running {
+            None if current == SystemState::Armed => {
+                if let Some(scenario) = critical_section::with(|cs| REQUESTED.borrow(cs).take()) {
+                    info!("Starting loopback scenario {}", scenario);
+                    set_status(scenario, Outcome::Running);
+                    self.running = Some(Run {
+                        scenario,
+                        started: samples,
+                        contact: false,
+                    });
+                }
+            }
+            None => {}
+            Some(run) => {
+                run.contact |= matches!(current, SystemState::Alert | SystemState::Latched);
+                if !matches!(
+                    current,
+                    SystemState::Armed
+                        | SystemState::Warning
+                        | SystemState::Alert
+                        | SystemState::Latched
+                ) {
+                    info!(
+                        "Loopback scenario {} abandoned in {}",
+                        run.scenario, current
+                    );
+                    set_status(run.scenario, Outcome::Abandoned);
+                    self.running = None;
+                } else if samples.wrapping_sub(run.started) >= run.scenario.length() {
+                    if run.contact == run.scenario.expects_contact() {
+                        info!("Loopback scenario {} passed", run.scenario);
+                        set_status(run.scenario, Outcome::Pass);
+                    } else {
+                        warn!(
+                            "Loopback scenario {} failed: contact expected {}, detected {}",
+                            run.scenario,
+                            run.scenario.expects_contact(),
+                            run.contact
+                        );
+                        set_status(run.scenario, Outcome::Fail);
+                    }
+                    self.running = None;
+                }
+            }
+        }
+
+        let level = self.running.map_or(0, |run| {
+            run.scenario.level(samples.wrapping_sub(run.started))
+        });
+        self.output.set_duty_cycle(level as u16)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Histogram of averaged sample values over the last hour, for checking the health of the analog
+//! front-end.
+//!
+//! Each probe's samples are counted into [`BINS`] bins of [`BIN_WIDTH`] counts. A healthy probe
+//! stays within a few bins around its bias point, so counts creeping towards either end show the
+//! signal drifting towards a rail, such as from a failing bias network, before it is far enough
+//! to cause a misdetection.
+//!
+//! The counts are kept in [`SLICES`] slices of [`SLICE_MS`], with the oldest slice cleared as each
+//! new one starts, so the histogram covers between 50 and 60 minutes. It is printed by the
+//! `HISTOGRAM` console command.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+use crate::{
+    buffer::{ProbeSamples, PROBES},
+    scheduler,
+};
+
+/// Number of bins covering the range of a sample
+pub const BINS: usize = 32;
+/// Range of sample values counted in each bin
+pub const BIN_WIDTH: usize = 256 / BINS;
+/// Number of slices the hour is split into
+pub const SLICES: usize = 6;
+/// Time covered by each slice, 10 minutes
+pub const SLICE_MS: u32 = 600_000;
+
+/// Samples counted in each bin, the first counting samples from 0 to `BIN_WIDTH - 1`
+pub type Bins = [u32; BINS];
+
+/// Counts for each probe, and when the current slice started
+static HISTOGRAM: Mutex<RefCell<Histogram>> = Mutex::new(RefCell::new(Histogram::new()));
+
+/// Slices of counts for each probe
+struct Histogram {
+    /// Counts of each slice, for each probe
+    slices: [[Bins; SLICES]; PROBES],
+    /// Index of the slice being counted into
+    current: usize,
+    /// [`scheduler::now_ms`] at which the current slice started
+    started_ms: u32,
+}
+
+impl Histogram {
+    /// Nothing counted, with the first slice starting at boot
+    const fn new() -> Self {
+        Self {
+            slices: [[[0; BINS]; SLICES]; PROBES],
+            current: 0,
+            started_ms: 0,
+        }
+    }
+
+    /// Move on to a new slice for each [`SLICE_MS`] elapsed by `now_ms`, clearing the oldest. If
+    /// sampling was stopped for longer than the whole hour, every slice is cleared.
+    fn advance(&mut self, now_ms: u32) {
+        let elapsed = now_ms.wrapping_sub(self.started_ms);
+        if elapsed < SLICE_MS {
+            return;
+        }
+        let slices = elapsed / SLICE_MS;
+        for _ in 0..(slices as usize).min(SLICES) {
+            self.current = (self.current + 1) % SLICES;
+            for probe in self.slices.iter_mut() {
+                probe[self.current] = [0; BINS];
+            }
+        }
+        self.started_ms = self.started_ms.wrapping_add(slices * SLICE_MS);
+    }
+}
+
+/// Count a new sample from each probe
+pub fn record(samples: ProbeSamples) {
+    let now = scheduler::now_ms();
+    critical_section::with(|cs| {
+        let mut histogram = HISTOGRAM.borrow_ref_mut(cs);
+        histogram.advance(now);
+        let current = histogram.current;
+        for (probe, sample) in histogram.slices.iter_mut().zip(samples) {
+            let bin = &mut probe[current][sample as usize / BIN_WIDTH];
+            *bin = bin.saturating_add(1);
+        }
+    });
+}
+
+/// Samples from `probe` counted in each bin over the last hour
+pub fn bins(probe: usize) -> Bins {
+    let now = scheduler::now_ms();
+    critical_section::with(|cs| {
+        let mut histogram = HISTOGRAM.borrow_ref_mut(cs);
+        histogram.advance(now);
+        let mut bins = [0u32; BINS];
+        for slice in histogram.slices[probe].iter() {
+            for (total, count) in bins.iter_mut().zip(slice) {
+                *total = total.saturating_add(*count);
+            }
+        }
+        bins
+    })
+}
@@ -0,0 +1,133 @@
+//! I2C bus scan, checking that the peripherals listed in `board.toml` respond, with the
+//! `i2c_scan` feature.
+//!
+//! [`init`] takes the bus and scans it at boot, and [`scan`] repeats the scan on demand for the
+//! `I2C SCAN` console command. Each address from [`FIRST_ADDRESS`] to [`LAST_ADDRESS`] is probed
+//! with a 1-byte read, which every peripheral acknowledges without side effects. The resulting
+//! [`ScanReport`] is logged as a warning for each peripheral in [`I2C_EXPECTED`] that doesn't
+//! respond, and for each unexpected address that does, so a missing part, a solder bridge or a
+//! wrong address strap on a new board shows up at first power-on.
+//!
+//! A scan takes a few tens of milliseconds at [`BUS_FREQ_KHZ`], blocking the task that runs it,
+//! but not interrupts.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use defmt::{info, warn, Format};
+use embedded_hal::i2c::I2c;
+
+use crate::{
+    config::board::{I2cBlock, I2cSclPin, I2cSdaPin, I2C_EXPECTED},
+    hal::{
+        gpio::{FunctionI2C, Pin, PullUp},
+        i2c::I2C,
+    },
+};
+
+/// Lowest address probed, as 0x00-0x07 are reserved
+pub const FIRST_ADDRESS: u8 = 0x08;
+/// Highest address probed, as 0x78-0x7F are reserved
+pub const LAST_ADDRESS: u8 = 0x77;
+/// Bus clock, the standard-mode rate every peripheral supports
+pub const BUS_FREQ_KHZ: u32 = 100;
+
+/// I2C controller on the pins assigned in `board.toml`
+pub type I2cBus = I2C<
+    I2cBlock,
+    (
+        Pin<I2cSdaPin, FunctionI2C, PullUp>,
+        Pin<I2cSclPin, FunctionI2C, PullUp>,
+    ),
+>;
+
+/// Bus taken by [`init`]
+static BUS: Mutex<RefCell<Option<I2cBus>>> = Mutex::new(RefCell::new(None));
+
+/// Addresses that responded to a scan
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Format)]
+pub struct ScanReport {
+    /// Bit `n` is set if address `n` acknowledged
+    present: u128,
+}
+
+impl ScanReport {
+    /// Whether `address` acknowledged
+    pub fn responded(&self, address: u8) -> bool {
+        self.present & 1 << address != 0
+    }
+
+    /// Address and name of each expected peripheral that didn't respond
+    pub fn missing(&self) -> impl Iterator<Item = (u8, &'static str)> + '_ {
+        I2C_EXPECTED
+            .iter()
+            .copied()
+            .filter(|(address, _)| !self.responded(*address))
+    }
+
+    /// Each address that responded without being expected
+    pub fn unexpected(&self) -> impl Iterator<Item = u8> + '_ {
+        (FIRST_ADDRESS..=LAST_ADDRESS).filter(|address| {
+            self.responded(*address)
+                && !I2C_EXPECTED.iter().any(|(expected, _)| expected == address)
+        })
+    }
+
+    /// Log a warning for each missing or unexpected peripheral
+    pub fn log(&self) {
+        let mut healthy = true;
+        for (address, name) in self.missing() {
+            healthy = false;
+            warn!(
+                "I2C peripheral missing: {=str} at {=u8:#04x}",
+                name, address
+            );
+        }
+        for address in self.unexpected() {
+            healthy = false;
+            warn!("I2C peripheral unexpected at {=u8:#04x}", address);
+        }
+        if healthy {
+            info!(
+                "I2C bus healthy: all {=usize} peripherals responded",
+                I2C_EXPECTED.len()
+            );
+        }
+    }
+}
+
+/// Take the bus, then [`scan`] it and log the report. Call once during startup.
+pub fn init(bus: I2cBus) -> ScanReport {
+    critical_section::with(|cs| BUS.borrow_ref_mut(cs).replace(bus));
+    let report = scan().unwrap_or_default();
+    report.log();
+    report
+}
+
+/// Probe every address on the bus, or return [`None`] if [`init`] hasn't taken it yet, or another
+/// scan is running. Interrupts stay enabled while scanning, so sampling continues.
+pub fn scan() -> Option<ScanReport> {
+    let mut bus = critical_section::with(|cs| BUS.borrow_ref_mut(cs).take())?;
+    let mut report = ScanReport::default();
+    for address in FIRST_ADDRESS..=LAST_ADDRESS {
+        if bus.read(address, &mut [0]).is_ok() {
+            report.present |= 1 << address;
+        }
+    }
+    critical_section::with(|cs| BUS.borrow_ref_mut(cs).replace(bus));
+    Some(report)
+}
@@ -0,0 +1,167 @@
+//! Test-signal injection, proving the whole path from the analog front end to the interlock.
+//!
+//! The [`Injector`] drives a spare GPIO, which steps the front end through a resistor as a contact
+//! would. Detection must then enter [`SystemState::Alert`] within [`DETECT_WITHIN_SAMPLES`], or
+//! [`Error::InjectionFailed`] is raised. A test starts the next time the system is armed after it
+//! is [requested](request) with the `INJECT` console command.
+//!
+//! Tests are also run as periodic proof tests, every
+//! [`DetectionConfig::proof_test_hours`] of time spent in [`SystemState::Armed`]. If one can't
+//! pass within [`PROOF_TEST_GRACE_SAMPLES`] of falling due, because each attempt is abandoned,
+//! [`Error::ProofTestOverdue`] is raised.
+//!
+//! The injected contact is handled like any other: the interlock trips and the buzzer sounds until
+//! contact ends.
+//!
+//! [`DetectionConfig::proof_test_hours`]: crate::config::DetectionConfig::proof_test_hours
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::info;
+use embedded_hal::digital::{OutputPin, PinState};
+
+#[cfg(feature = "hil")]
+use crate::hil::Loopback;
+use crate::{
+    buffer::Buffers,
+    config::board::TestInjectPin,
+    error::{Error, Result},
+    hal::gpio::{FunctionNull, FunctionSio, Pin, PullDown, SioOutput},
+    irq::TRANSFER_PERIOD_US,
+    state::SystemState,
+};
+
+/// Samples within which detection must fire after the step is injected (20 ms with 2 ms
+/// averaging)
+pub const DETECT_WITHIN_SAMPLES: u64 = 10;
+/// Averaged samples recorded per hour
+pub const SAMPLES_PER_HOUR: u64 = 3_600_000_000 / TRANSFER_PERIOD_US as u64;
+/// Armed samples after a proof test falls due before it is overdue (15 minutes)
+pub const PROOF_TEST_GRACE_SAMPLES: u64 = SAMPLES_PER_HOUR / 4;
+
+/// Set when a test has been requested, until it starts
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Injection output, driven high while a test is running
+pub type InjectOutput = Pin<TestInjectPin, FunctionSio<SioOutput>, PullDown>;
+
+/// Request an injection test, which starts the next time the system is armed.
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Runs injection tests on the detection path
+pub struct Injector {
+    /// Injection output
+    output: InjectOutput,
+    /// Samples recorded when the running test started
+    started: Option<u64>,
+    /// Samples recorded as of the previous poll
+    last_poll: u64,
+    /// Samples spent armed since the last test passed, or since boot
+    armed_samples: u64,
+    /// Plays bench test scenarios, if the loopback DAC is fitted
+    #[cfg(feature = "hil")]
+    loopback: Option<Loopback>,
+}
+
+impl Injector {
+    /// Take the injection pin, driving it low.
+    pub fn new(pin: Pin<TestInjectPin, FunctionNull, PullDown>) -> Self {
+        Self {
+            output: pin.into_push_pull_output_in_state(PinState::Low),
+            started: None,
+            last_poll: 0,
+            armed_samples: 0,
+            #[cfg(feature = "hil")]
+            loopback: None,
+        }
+    }
+
+    /// Also play [`hil`](crate::hil) scenarios on `loopback`, polled with the injection tests
+    #[cfg(feature = "hil")]
+    pub fn with_loopback(self, loopback: Loopback) -> Self {
+        Self {
+            loopback: Some(loopback),
+            ..self
+        }
+    }
+
+    /// Request a proof test if one is due, then start a requested test, or check on the running
+    /// one. Call from the detection task after each batch of samples, with the current state.
+    ///
+    /// A test is abandoned if the system leaves [`SystemState::Armed`] for anything but
+    /// [`SystemState::Warning`] or [`SystemState::Alert`]. Returns [`Error::InjectionFailed`] if
+    /// detection hasn't fired within [`DETECT_WITHIN_SAMPLES`], or [`Error::ProofTestOverdue`] if
+    /// a proof test has been due for [`PROOF_TEST_GRACE_SAMPLES`].
+    pub fn poll(&mut self, current: SystemState, buffers: &Buffers) -> Result<()> {
+        #[cfg(feature = "hil")]
+        if let Some(loopback) = &mut self.loopback {
+            loopback.poll(current, buffers)?;
+        }
+        let samples = buffers.samples_recorded();
+        if current.armed() {
+            self.armed_samples += samples.wrapping_sub(self.last_poll);
+        }
+        self.last_poll = samples;
+        let interval = buffers.proof_test_hours() as u64 * SAMPLES_PER_HOUR;
+        if interval != 0 && self.armed_samples >= interval {
+            if self.armed_samples >= interval + PROOF_TEST_GRACE_SAMPLES {
+                return Err(Error::ProofTestOverdue);
+            } else if self.started.is_none() && !REQUESTED.load(Ordering::Relaxed) {
+                info!("Proof test due");
+                request();
+            }
+        }
+
+        match self.started {
+            // Only load and store are available on the M0+, but a request racing with this is
+            // harmless
+            None if current == SystemState::Armed && REQUESTED.load(Ordering::Relaxed) => {
+                REQUESTED.store(false, Ordering::Relaxed);
+                info!("Injecting test step");
+                self.output.set_high()?;
+                self.started = Some(samples);
+            }
+            Some(started) if current == SystemState::Alert => {
+                self.finish()?;
+                self.armed_samples = 0;
+                info!(
+                    "Injection test passed, detected within {=u64} samples",
+                    samples.wrapping_sub(started)
+                );
+            }
+            Some(_) if !current.armed() => {
+                self.finish()?;
+                info!("Injection test abandoned in {}", current);
+            }
+            Some(started) if samples.wrapping_sub(started) > DETECT_WITHIN_SAMPLES => {
+                self.finish()?;
+                return Err(Error::InjectionFailed);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Stop injecting
+    fn finish(&mut self) -> Result<()> {
+        self.started = None;
+        self.output.set_low()?;
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-//! Interrupt and exception handlers, plus static [`Mutex`].
+//! Interrupt and exception handler logic, shared by the tasks bound in the binary.
 
 // Copyright 2024 Cameron Rodriguez
 //
@@ -14,312 +14,439 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::{cell::RefCell, cmp::Ordering};
-
-use cortex_m_rt::exception;
-use critical_section::Mutex;
-#[allow(unused_imports)]
-use defmt::trace;
-use defmt::{debug, Format};
 use embedded_hal::digital::InputPin;
-use rp2040_hal::{
-    adc::DmaReadTarget,
-    dma::{single_buffer, single_buffer::Transfer, Channel, CH0},
-    gpio::{bank0::Gpio9, FunctionSio, Pin, PullDown, SioInput},
-    pac::interrupt,
-    pwm,
-    pwm::{FreeRunning, Pwm3, Slice},
-};
+use heapless::spsc::{Consumer, Producer, Queue};
+#[cfg(not(feature = "minimal"))]
+use usb_device::{device::UsbDevice, UsbError};
+#[cfg(not(feature = "minimal"))]
+use usbd_serial::SerialPort;
 
-#[cfg(feature = "rgba_status")]
-use crate::components::Rgba;
-#[cfg(feature = "triple_status")]
-use crate::components::Triple;
+#[cfg(not(feature = "minimal"))]
+use crate::console::{Console, ConsoleBackend, ConsoleOutput};
+#[cfg(not(feature = "minimal"))]
+use crate::hal::usb::UsbBus;
+#[cfg(not(feature = "minimal"))]
+use crate::{banner, drill, lock, macros, near_miss, soak_test};
 use crate::{
-    buffer::{Buffers, DetectionMsg},
-    components::{StatusLed, StatusLedBase, StatusLedStates},
+    blanking,
+    buffer::{Buffers, DetectionMsg, TransferSamples, PROBES},
+    config::board::{AckButtonPin, DisableSwitchPin},
+    correlation,
+    deadline::{self, Instant, Stage, Stamped},
+    detection_core::{Event, Phase},
+    error::{Error, Result},
+    events::{self, SystemEvent},
+    hal::gpio::{FunctionSio, Pin, PullDown, SioInput},
+    journal::{Kind, Source},
+    log,
+    perf::{self, Section},
+    postmortem::{self, TraceEvent},
+    power::{self, STANDBY_HOLD_MS},
+    presence::{self, PRESENCE_REQUIRED},
+    sampler::{Sampler, SamplerControl},
+    scheduler,
+    sequence::Timing,
+    state::{StateMachine, SystemState, LATCH_RESET_HOLD_MS, SNOOZE_PRESS_MAX_MS},
+    trigger,
 };
 
-/// Wrapper for [DMA `Transfer`](Transfer)
-pub type ReadingsDma = Transfer<Channel<CH0>, DmaReadTarget<u8>, &'static mut [u8; 4000]>;
-/// Wrapper for [`DISABLE_SWITCH`]
-pub type DisableSwitch = Pin<Gpio9, FunctionSio<SioInput>, PullDown>;
-/// Wrapper for [`SIGNAL_GEN`]
-pub type SignalPwm = pwm::Channel<Slice<Pwm3, FreeRunning>, pwm::A>;
-/// Wrapper for [`SIGNAL_CONF`]
-pub type SignalGenConfig = (Channel<CH0>, DmaReadTarget<u8>, &'static mut [u8; 4000]);
-
-/// Status LEDs for access in interrupts. Implementation for feature `rgba_status`.
-#[cfg(feature = "rgba_status")]
-pub static STATUS_LEDS: Mutex<RefCell<Option<&'static mut StatusLedBase<Rgba>>>> =
-    Mutex::new(RefCell::new(None));
-/// Status LEDs for access in interrupts. There is a nearly identical implementation for feature
-/// `rgba_status`, which does not appear here.
-#[cfg(any(doc, feature = "triple_status"))]
-pub static STATUS_LEDS: Mutex<RefCell<Option<&'static mut StatusLedBase<Triple>>>> =
-    Mutex::new(RefCell::new(None));
-
-///  access in interrupts
-pub static READINGS_FIFO: Mutex<RefCell<Option<ReadingsDma>>> = Mutex::new(RefCell::new(None));
-
-/// access when disabling system/ in error state
-pub static SIGNAL_GEN: Mutex<RefCell<Option<SignalPwm>>> = Mutex::new(RefCell::new(None));
+/// Disable switch input, polled by SysTick
+pub type DisableSwitch = Pin<DisableSwitchPin, FunctionSio<SioInput>, PullDown>;
+/// Acknowledge button input, read by [`AckButton`]
+pub type AckButtonInput = Pin<AckButtonPin, FunctionSio<SioInput>, PullDown>;
+/// USB device serving the [`Console`]
+#[cfg(not(feature = "minimal"))]
+pub type ConsoleUsbDevice = UsbDevice<'static, UsbBus>;
+/// USB serial port serving the [`Console`]
+#[cfg(not(feature = "minimal"))]
+pub type ConsoleSerial = SerialPort<'static, UsbBus>;
 
-/// Stores signal config when detection is paused
-pub static SIGNAL_CONF: Mutex<RefCell<Option<SignalGenConfig>>> = Mutex::new(RefCell::new(None));
+/// Number of slots in the [`SampleQueue`]. It holds one less sample than this, or 30 ms of
+/// readings.
+pub const SAMPLE_QUEUE_SIZE: usize = 16;
+/// Averaged sample from each probe, or each channel with `triple_channel`, or the error raised
+/// while collecting them, tagged with the time its transfer completed
+pub type QueuedSample = Stamped<Result<TransferSamples>>;
+/// Averaged samples waiting for detection
+pub type SampleQueue = Queue<QueuedSample, SAMPLE_QUEUE_SIZE>;
+/// Pushes to the [`SampleQueue`] from `DMA_IRQ_0`
+pub type SampleProducer = Producer<'static, QueuedSample, SAMPLE_QUEUE_SIZE>;
+/// Pops from the [`SampleQueue`] for detection
+pub type SampleConsumer = Consumer<'static, QueuedSample, SAMPLE_QUEUE_SIZE>;
 
-/// Global buffers for analyzing readings
-pub static BUFFERS: Mutex<RefCell<Option<&'static mut Buffers>>> = Mutex::new(RefCell::new(None));
-
-/// Global disable switch
-pub static DISABLE_SWITCH: Mutex<RefCell<Option<DisableSwitch>>> = Mutex::new(RefCell::new(None));
-
-/// Calculates proper averages aligned with signal timing
-#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
-pub struct AlignedAverages {
-    /// The average voltage from the "higher" 2000 measurements
-    avg_high: i32,
-    /// The average voltage from the "lower" 2000 measurements
-    avg_low: i32,
+/// Handler for `DMA_IRQ_0`: reads the ADC values, calculates averages and checks for contact.
+pub fn readings_complete(
+    sampler: &mut Sampler,
+    buffers: &mut Buffers,
+    state: &mut StateMachine,
+) -> Result<()> {
+    let completed = Instant::now();
+    let sample_avg = sampler.complete_transfer();
+    process_queued(
+        Stamped {
+            value: sample_avg,
+            completed,
+        },
+        sampler,
+        buffers,
+        state,
+    )
 }
 
-impl AlignedAverages {
-    /// Takes the partial sums of the samples to calculate the high and low averages for contact
-    /// detection.
-    ///
-    /// Sorting requires an allocator, so this implementation identifies the highest partial sums.
-    /// Although this implementation technically allows for non-adjacent partial sums to be matched,
-    /// in effect this has little impact as those scenarios result in low overall deltas.
-    ///
-    /// This would be a good section to rewrite :)
-    pub fn align_signal_timing(partial_sums: &[i32; 4]) -> Self {
-        let mut avg_high_idx = [4usize; 2];
-        let mut avg_high = 0i32;
+/// Handler for `DMA_IRQ_0`: reads the ADC values, calculates averages and queues them for
+/// [`process_queued`], so detection can run outside the interrupt.
+///
+/// If the queue is full, the sample is dropped rather than waiting for detection to catch up.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+pub fn queue_readings(sampler: &mut Sampler, producer: &mut SampleProducer) {
+    let completed = Instant::now();
+    let sample_avg = sampler.complete_transfer();
+    if producer
+        .enqueue(Stamped {
+            value: sample_avg,
+            completed,
+        })
+        .is_err()
+    {
+        log!(Sampling, warn, "Sample queue full, dropping {}", sample_avg);
+    }
+}
 
-        let match_sum = partial_sums
-            .iter()
-            .enumerate()
-            .max_by(|a, b| a.1.cmp(b.1))
-            .unwrap();
-        avg_high_idx[0] = match_sum.0;
-        avg_high += match_sum.1;
+/// Runs detection on a sample popped from the [`SampleQueue`], returning any error raised while
+/// it was collected. The time from the end of the transfer is checked against the
+/// [`Stage::Pipeline`] deadline.
+pub fn process_queued(
+    sample: QueuedSample,
+    sampler: &mut impl SamplerControl,
+    buffers: &mut Buffers,
+    state: &mut StateMachine,
+) -> Result<()> {
+    process_sample(sample.value?, sampler, buffers, state)?;
+    deadline::check(Stage::Pipeline, sample.completed)?;
+    Ok(())
+}
 
-        let match_sum = partial_sums
-            .iter()
-            .enumerate()
-            .max_by(|a, b| match a.1.cmp(b.1) {
-                Ordering::Less | Ordering::Equal => {
-                    if avg_high_idx.contains(&b.0) {
-                        Ordering::Greater
-                    } else {
-                        Ordering::Less
-                    }
-                }
-                Ordering::Greater => {
-                    if avg_high_idx.contains(&a.0) {
-                        Ordering::Less
-                    } else {
-                        Ordering::Greater
-                    }
-                }
-            })
-            .unwrap();
-        avg_high_idx[1] = match_sum.0;
-        avg_high += match_sum.1;
-        avg_high /= 2000;
-        #[cfg(feature = "trace_indiv_samples")]
-        Self::trace_high_index(&avg_high_idx);
+/// Runs [`process_queued`] on each of `samples` in one pass, so the caller takes its locks once
+/// per wakeup rather than once per sample. Errors are passed to [`StateMachine::fail`] as they are
+/// raised, so each sample sees the same state as if they were processed one at a time.
+///
+/// Callers should bound `samples` to those already queued, so samples arriving during the pass
+/// can't hold the locks indefinitely.
+pub fn process_batch(
+    samples: impl Iterator<Item = QueuedSample>,
+    sampler: &mut impl SamplerControl,
+    buffers: &mut Buffers,
+    state: &mut StateMachine,
+) {
+    for sample in samples {
+        process_queued(sample, sampler, buffers, state)
+            .unwrap_or_else(|err| state.fail(sampler, err));
+    }
+}
 
-        let avg_low = partial_sums
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, sum)| {
-                if !avg_high_idx.contains(&idx) {
-                    Some(sum)
-                } else {
-                    None
+/// Records an averaged sample from each probe, and checks each for contact or end of contact.
+/// Each step is checked against its [`deadline`]. Samples outside the plausible range are
+/// discarded, see [`plausibility`](crate::plausibility), as are samples taken while armed with the
+/// [`blanking`] input asserted. A requested [`drill`] runs before the probes are stepped.
+///
+/// With `triple_channel`, failed channels are faulted first, and the probe is recorded from the
+/// first healthy channel, see [`Buffers::qualify`].
+pub fn process_sample(
+    samples: TransferSamples,
+    sampler: &mut impl SamplerControl,
+    buffers: &mut Buffers,
+    state: &mut StateMachine,
+) -> Result<()> {
+    let start = Instant::now();
+    #[cfg(feature = "triple_channel")]
+    let (channels, samples) = (samples, buffers.qualify(samples)?);
+    if !buffers.check_plausible(samples)? {
+        log!(Detection, debug, "Implausible sample discarded");
+        return Ok(());
+    }
+    // Checked in every state, so windows are timed even while they can't blank anything
+    let blanked = buffers.check_blanking(blanking::asserted(), scheduler::now_ms())?;
+    if blanked && matches!(state.state(), SystemState::Armed | SystemState::Warning) {
+        return Ok(());
+    }
+    perf::measure(Section::Insert, || {
+        buffers.insert(samples);
+        #[cfg(feature = "triple_channel")]
+        buffers.insert_channels(channels);
+    });
+    if trigger::take_edge() {
+        events::push(SystemEvent::Marker(buffers.samples_recorded() as u32));
+    }
+    let inserted = deadline::check(Stage::Insert, start)?;
+    // A probe held in contact by a drill isn't stepped until the drill ends
+    #[cfg(not(feature = "minimal"))]
+    let drilled = drill::poll(buffers, sampler, state)?.then_some(drill::CONTACT_PROBE);
+    #[cfg(feature = "minimal")]
+    let drilled: Option<usize> = None;
+    // Taken before any probe is stepped, so a transition caused by one probe doesn't change the
+    // phase of the others until the next sample
+    let phases: [Option<Phase>; PROBES] = core::array::from_fn(|probe| state.phase(probe));
+    #[cfg(not(feature = "minimal"))]
+    soak_test::record(buffers, &phases);
+    for (probe, phase) in phases.into_iter().enumerate() {
+        if drilled == Some(probe) {
+            continue;
+        }
+        let event = phase.and_then(|phase| buffers.step(probe, phase));
+        #[cfg(not(feature = "minimal"))]
+        if matches!(phase, Some(Phase::Armed | Phase::Warning))
+            && event != Some(Event::Contact)
+            && buffers.near_miss(probe)
+        {
+            near_miss::record(buffers, probe);
+        }
+        match event {
+            Some(Event::Settled) if state.settled(buffers.arming_delay_ms()) => {
+                state.transition(SystemState::Armed, sampler, "Signal settled")?;
+                events::push(SystemEvent::Operator(Kind::Calibration, Source::System));
+            }
+            Some(Event::Contact) => {
+                correlation::pulse();
+                if let Some((sample, value)) = buffers.last_detection(probe) {
+                    postmortem::record(TraceEvent::Detection {
+                        sample: sample.get_counter() as u32,
+                        value,
+                        probe: probe as u8,
+                    });
+                    events::push(SystemEvent::Detection {
+                        probe: probe as u8,
+                        value,
+                    });
                 }
-            })
-            .sum::<i32>()
-            / 2000;
-
-        Self { avg_low, avg_high }
+                state.contact(probe, sampler, DetectionMsg::create(buffers, probe))?;
+            }
+            Some(Event::ContactEnded) => {
+                state.contact_ended(
+                    probe,
+                    buffers.latch_alert(),
+                    buffers.auto_clear_ms(),
+                    buffers.min_trip_ms(),
+                    sampler,
+                )?;
+            }
+            Some(Event::Warning) => state.warning(probe, sampler)?,
+            Some(Event::WarningEnded) => state.warning_ended(probe, sampler)?,
+            // Still waiting out the arming delay
+            Some(Event::Settled) | None => {}
+        }
     }
+    #[cfg(feature = "ab_compare")]
+    buffers.compare(core::array::from_fn(|probe| state.phase(probe)));
+    deadline::check(Stage::Detect, inserted)?;
+    Ok(())
+}
 
-    /// Records the two highest measurements from the first four of a 2 ms sample.
-    #[cfg(any(doc, feature = "trace_indiv_samples"))]
-    pub fn trace_high_index(avg_high_idx: &[usize; 2]) {
-        trace!("high indices (mod 4): {}", avg_high_idx);
+/// Handler for SysTick, used for checking the [`DisableSwitch`]. Releasing the switch doesn't
+/// rearm detection while the operator is [absent](StateMachine::operator_absent). The switch is
+/// ignored in any state that can't be disabled, such as [`SystemState::Error`].
+pub fn check_disable_switch(
+    switch: &mut DisableSwitch,
+    sampler: &mut impl SamplerControl,
+    state: &mut StateMachine,
+) -> Result<()> {
+    if switch.is_high()? {
+        let current = state.state();
+        if current != SystemState::Disabled && current.can_transition(SystemState::Disabled) {
+            state.transition(SystemState::Disabled, sampler, "System disabled by switch.")?;
+            events::push(SystemEvent::Operator(Kind::Disarm, Source::Switch));
+        }
+        Ok(())
+    } else if state.state() == SystemState::Disabled && !state.operator_absent() {
+        state.transition(SystemState::Armed, sampler, "System enabled by switch.")?;
+        events::push(SystemEvent::Operator(Kind::Arm, Source::Switch));
+        Ok(())
+    } else {
+        Ok(())
     }
+}
 
-    /// Calculates the average range of the sample interval
-    fn get_delta(&self) -> u8 {
-        u8::try_from(self.avg_high - self.avg_low).map_or(255, |avg| avg)
+/// Handler for SysTick: returns [`Error::PowerFail`] once [`power::on_power_fail`] has run, so
+/// the binary latches [`SystemState::Error`]. The interlock is already tripped by then.
+pub fn check_power_good(state: &StateMachine) -> Result<()> {
+    if power::power_failed() && state.state() != SystemState::Error {
+        Err(Error::PowerFail)
+    } else {
+        Ok(())
     }
 }
 
-/// ISR for reading ADC values and calculating averages
-#[interrupt]
-fn DMA_IRQ_0() {
-    let mut readings_isr: Option<ReadingsDma> = None;
-    if readings_isr.is_none() {
-        debug!("critical_section: DMA take readings");
-        critical_section::with(|cs| readings_isr = READINGS_FIFO.take(cs));
-    }
+/// Handler for SysTick, polled with the acknowledge button: releases interlocks whose
+/// [minimum trip](StateMachine::release_stretched) has passed.
+pub fn check_stretch(state: &mut StateMachine) -> Result<()> {
+    state.release_stretched()
+}
 
-    if let Some(adc_dma_transfer) = readings_isr {
-        let (dma_ch, dma_from, avg_buffer) = adc_dma_transfer.wait();
+/// Handler for SysTick, polled with the acknowledge button: releases interlocks whose
+/// [release sequence](StateMachine::sequence_interlocks) has finished with `timing`.
+pub fn check_sequence(state: &mut StateMachine, timing: Timing) -> Result<()> {
+    state.sequence_interlocks(timing)
+}
 
-        // Align averages with incoming signals
-        let mut partial_sums = [0i32; 4]; // 1000 samples each
-        for (idx, partial) in partial_sums.iter_mut().enumerate() {
-            *partial = avg_buffer
-                .iter()
-                .skip(idx)
-                .step_by(4)
-                .map(|i| *i as i32)
-                .sum::<i32>();
+/// Handler for SysTick, polled with the acknowledge button: disables detection while the operator
+/// is away from the saw, if the board requires their [`presence`]. Does nothing otherwise.
+pub fn check_presence(sampler: &mut impl SamplerControl, state: &mut StateMachine) -> Result<()> {
+    if !PRESENCE_REQUIRED {
+        return Ok(());
+    }
+    if !presence::present() {
+        if state.state().armed() {
+            state.presence_lost(sampler)?;
+            events::push(SystemEvent::Operator(Kind::Disarm, Source::Presence));
         }
-        let avgs = AlignedAverages::align_signal_timing(&partial_sums);
+    } else if state.operator_absent() {
+        state.presence_restored(sampler)?;
+        events::push(SystemEvent::Operator(Kind::Arm, Source::Presence));
+    }
+    Ok(())
+}
 
-        #[cfg(feature = "trace_indiv_samples")]
-        trace_indiv_samples(avg_buffer, &avgs);
+/// Acknowledge button, polled by SysTick to measure how long it has been held
+pub struct AckButton {
+    /// Button input, high while pressed
+    input: AckButtonInput,
+    /// How long the button had been held as of the previous poll
+    prev_held_ms: u32,
+    /// How long the button has been held, or 0 if released
+    held_ms: u32,
+}
 
-        // Determine if enough low sample events have occurred
-        let sample_avg = avgs.get_delta();
-        let mut contact_detected = false;
-        let mut reset_detected = false;
-        critical_section::with(|cs| {
-            debug!("critical_section: dma update and check longterm buffers");
-            let buffers = BUFFERS.take(cs).expect(Buffers::NO_BUFFER_PANIC_MSG);
-            buffers.insert(sample_avg);
+impl AckButton {
+    /// Track presses on `input`, which should already be pulled down.
+    pub fn new(input: AckButtonInput) -> Self {
+        Self {
+            input,
+            prev_held_ms: 0,
+            held_ms: 0,
+        }
+    }
 
-            debug!("critical_section: match status for correct buffer logic");
-            let status_leds = STATUS_LEDS.borrow_ref(cs);
-            if status_leds.is_some() {
-                match status_leds.as_ref().unwrap().state {
-                    StatusLedStates::Normal => {
-                        if buffers.detect_contact() {
-                            contact_detected = true
-                        }
-                    }
-                    StatusLedStates::Alert => {
-                        if buffers.detect_end_contact() {
-                            reset_detected = true
-                        }
-                    }
-                    StatusLedStates::Error | StatusLedStates::Disabled => {}
-                }
-            }
+    /// Sample the button, `elapsed_ms` after the previous poll.
+    pub fn poll(&mut self, elapsed_ms: u32) -> Result<()> {
+        self.prev_held_ms = self.held_ms;
+        self.held_ms = if self.input.is_high()? {
+            self.held_ms.saturating_add(elapsed_ms)
+        } else {
+            0
+        };
+        Ok(())
+    }
 
-            BUFFERS.replace(cs, Some(buffers));
-            debug!("exit buffer critical section");
-        });
-        if contact_detected {
-            critical_section::with(|cs| {
-                let buffers = BUFFERS.take(cs).unwrap();
-                #[cfg(feature = "rgba_status")]
-                StatusLedBase::<Rgba>::set_alert(cs, Some(DetectionMsg::create(buffers)));
-                #[cfg(feature = "triple_status")]
-                StatusLedBase::<Triple>::set_alert(cs, Some(DetectionMsg::create(buffers)));
-                BUFFERS.replace(cs, Some(buffers));
-            });
-        } else if reset_detected {
-            critical_section::with(|cs| {
-                #[cfg(feature = "rgba_status")]
-                StatusLedBase::<Rgba>::set_normal(cs, None);
-                #[cfg(feature = "triple_status")]
-                StatusLedBase::<Triple>::set_normal(cs, None);
-            })
-        }
+    /// Whether the button has just been held for `hold_ms`. Only true for one poll per press.
+    pub fn held_for(&self, hold_ms: u32) -> bool {
+        self.prev_held_ms < hold_ms && self.held_ms >= hold_ms
+    }
 
-        let new_dma_transfer = single_buffer::Config::new(dma_ch, dma_from, avg_buffer);
-        debug!("critical_section: start new DMA transfer");
-        critical_section::with(|cs| READINGS_FIFO.replace(cs, Some(new_dma_transfer.start())));
-    } else {
-        // Report error if FIFO is not active
-        critical_section::with(|cs| {
-            debug!("critical_section: dma set_error for no active FIFO");
-            #[cfg(feature = "rgba_status")]
-            StatusLedBase::<Rgba>::set_error(
-                cs,
-                Some("No ADC transfer in progress! Unable to collect latest readings"),
-            );
-            #[cfg(feature = "triple_status")]
-            StatusLedBase::<Triple>::set_error(
-                cs,
-                Some("No ADC transfer in progress! Unable to collect latest readings"),
-            );
-        });
+    /// Whether the button has just been released, after being held for less than `max_ms`
+    pub fn pressed_briefly(&self, max_ms: u32) -> bool {
+        self.held_ms == 0 && self.prev_held_ms > 0 && self.prev_held_ms < max_ms
     }
 }
 
-/// Records the following information about a 2 ms sample (note all measurements are 8 bits on a
-/// <span style="white-space:nowrap;">3.3 V</span> signal):
-/// - Maximum voltage recorded
-/// - Minimum voltage recorded
-/// - Average voltage from higher half
-/// - Average voltage from lower half
-/// - The first 20 measurements
-/// - All unique measurements seen
-///
-/// Example of a trace:
-///
-/// ```shell
-/// [TRACE] interrupt.rs:59    => max: Some(255) // min: Some(0) // avg1: 127 // avg2: 127
-/// -> all_unique samples: [Some(0), Some(1), Some(2), Some(3), None, None, None, None, None, None, None, None, None, None, None, None, Some(16), Some(17), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(95), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(140), Some(141), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(231), Some(232), Some(233), Some(234), Some(235), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(253), Some(254), Some(255)]
-/// ```
-#[cfg(any(doc, feature = "trace_indiv_samples"))]
-pub fn trace_indiv_samples(avg_buffer: &[u8; 4000], avgs: &AlignedAverages) {
-    let unique_samples = avg_buffer.iter().fold([None; 256], |mut acc, s| {
-        acc[*s as usize] = Some(s);
-        acc
-    });
-    trace!(
-                "max: {} // min: {} // avg_high: {} // avg_low: {} // 20 samples: {}\n-> all_unique samples: {}",
-                avg_buffer.iter().max(),
-                avg_buffer.iter().min(),
-                avgs.avg_high,
-                avgs.avg_low,
-                avg_buffer.get(0..20).unwrap(),
-                unique_samples
-            );
+/// Handler for SysTick: polls the [`AckButton`], `elapsed_ms` after the previous poll. Holding it
+/// for [`STANDBY_HOLD_MS`] enters [`SystemState::Standby`], unless contact or an error is being
+/// shown. Holding it for [`LATCH_RESET_HOLD_MS`] resets a [`SystemState::Latched`] alert, as
+/// does its [auto-clear](StateMachine::auto_clear_due) time passing. A press shorter than
+/// [`SNOOZE_PRESS_MAX_MS`] [snoozes](StateMachine::snooze) the buzzer for `snooze_ms`, or, while
+/// it is silent and the configuration jumper is fitted, runs the button's stored
+/// [macro](crate::macros).
+pub fn check_ack_button(
+    button: &mut AckButton,
+    elapsed_ms: u32,
+    snooze_ms: u32,
+    sampler: &mut impl SamplerControl,
+    state: &mut StateMachine,
+) -> Result<()> {
+    button.poll(elapsed_ms)?;
+    if button.held_for(STANDBY_HOLD_MS) && state.state().can_transition(SystemState::Standby) {
+        state.transition(SystemState::Standby, sampler, "Standby requested by button")?;
+        events::push(SystemEvent::Operator(Kind::Disarm, Source::Button));
+    } else if button.held_for(LATCH_RESET_HOLD_MS) && state.state() == SystemState::Latched {
+        state.reset_latch(sampler, "Latched alert reset by button")?;
+        events::push(SystemEvent::Operator(Kind::Acknowledge, Source::Button));
+    } else if button.pressed_briefly(SNOOZE_PRESS_MAX_MS) && state.snooze(snooze_ms) {
+        events::push(SystemEvent::Operator(Kind::Silence, Source::Button));
+    } else if state.auto_clear_due() {
+        state.reset_latch(sampler, "Latched alert cleared automatically")?;
+        events::push(SystemEvent::Operator(Kind::Acknowledge, Source::System));
+    }
+    #[cfg(not(feature = "minimal"))]
+    if button.pressed_briefly(SNOOZE_PRESS_MAX_MS)
+        && !state.state().audible()
+        && lock::jumper_fitted()
+    {
+        macros::request_button();
+    }
+    Ok(())
 }
 
-/// ISR for SysTick, used for checking [`DisableSwitch`]
-///
-/// Lazily takes ownership of [`DISABLE_SWITCH`] as it will not be used again in the main runtime
-/// again.
-#[exception]
-fn SysTick() {
-    static mut DISABLE_SWITCH_ISR: Option<DisableSwitch> = None;
+/// Handler for `USBCTRL_IRQ`: services the USB device and runs any commands received by the
+/// [`Console`]. Writes the [`banner`] first whenever the host opens the port.
+#[cfg(not(feature = "minimal"))]
+pub fn poll_console(
+    usb_dev: &mut ConsoleUsbDevice,
+    serial: &mut ConsoleSerial,
+    console: &mut Console,
+    backend: &mut impl ConsoleBackend,
+) {
+    let mut response = ConsoleOutput::new();
+    let polled = usb_dev.poll(&mut [serial]);
+    if console.opened(serial.dtr()) {
+        // Too long for one response, so it is written as it is formatted
+        let _ = banner::write(&backend.config(), &mut SerialWriter { usb_dev, serial });
+    }
+    if polled {
+        let mut rx_buf = [0u8; 64];
+        if let Ok(count) = serial.read(&mut rx_buf) {
+            console.push_bytes(&rx_buf[..count], backend, &mut response);
+        }
+    }
+    // Each poll after the host reads the previous response continues an export, and polls pended
+    // by watch::wake continue a watch
+    console.poll_export(&mut response);
+    console.poll_watch(backend, &mut response);
+    console.poll_macro(backend, &mut response);
+    if !response.is_empty() {
+        write_serial(usb_dev, serial, response.as_bytes());
+    }
+}
 
-    if DISABLE_SWITCH_ISR.is_none() {
-        critical_section::with(|cs| {
-            *DISABLE_SWITCH_ISR = DISABLE_SWITCH.take(cs);
-        })
+/// Writes formatted text straight to the console with [`write_serial`]
+#[cfg(not(feature = "minimal"))]
+struct SerialWriter<'a> {
+    /// USB device serving the console
+    usb_dev: &'a mut ConsoleUsbDevice,
+    /// USB serial port of the console
+    serial: &'a mut ConsoleSerial,
+}
+
+#[cfg(not(feature = "minimal"))]
+impl core::fmt::Write for SerialWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_serial(self.usb_dev, self.serial, s.as_bytes());
+        Ok(())
     }
+}
 
-    if let Some(switch) = DISABLE_SWITCH_ISR {
-        if switch
-            .is_high()
-            .expect("Unable to check disable switch state")
-        {
-            critical_section::with(|cs| {
-                debug!("critical_section: system disabled by switch");
-                #[cfg(feature = "rgba_status")]
-                StatusLedBase::<Rgba>::set_disabled(cs, Some("System disabled by switch."));
-                #[cfg(feature = "triple_status")]
-                StatusLedBase::<Triple>::set_disabled(cs, Some("System disabled by switch."));
-            });
-        } else if switch.is_low().unwrap() {
-            critical_section::with(|cs| {
-                debug!("critical_section: system disabled by switch");
-                #[cfg(feature = "rgba_status")]
-                StatusLedBase::<Rgba>::set_normal(cs, Some("System disabled by switch."));
-                #[cfg(feature = "triple_status")]
-                StatusLedBase::<Triple>::set_normal(cs, Some("System disabled by switch."));
-            });
+/// Write all of `data` to the console, polling the device while the serial buffer is full.
+///
+/// Gives up after a few attempts, so a disconnected host can't stall the interrupt.
+#[cfg(not(feature = "minimal"))]
+pub fn write_serial(usb_dev: &mut ConsoleUsbDevice, serial: &mut ConsoleSerial, mut data: &[u8]) {
+    let mut attempts = 0;
+    while !data.is_empty() && attempts < 100 {
+        match serial.write(data) {
+            Ok(written) => data = &data[written..],
+            Err(UsbError::WouldBlock) => {
+                usb_dev.poll(&mut [serial]);
+                attempts += 1;
+            }
+            Err(_) => break,
         }
     }
 }
@@ -0,0 +1,301 @@
+//! Interrupt priorities and latency budgets.
+//!
+//! Every interrupt used by the binaries is listed in [`Isr`], with its priority and the longest it
+//! may run. Acquisition (`DMA_IRQ_0`) is only preempted by the power-good edge (`IO_IRQ_BANK0`),
+//! whose handler just trips the interlock, so the ADC FIFO never overflows while another handler
+//! runs. Timers sit in the middle, and the USB console and inter-core messages are lowest. The RTIC
+//! binaries must use the same priorities in their `#[task]` attributes, which they check with
+//! `const` assertions. Other executors call [`set_priorities`].
+//!
+//! With the `irq_timing` feature, [`timed`] measures each handler with the 1 MHz `TIMER` (the
+//! Cortex-M0+ has no cycle counter) and logs any run over budget.
+//!
+//! With the `irq_latency` feature, [`record_dma_latency`] also builds a histogram of the delay
+//! from each transfer completing to `DMA_IRQ_0` being handled, printed by the `LATENCY` console
+//! command. The `TIMER` has no input capture, so the completion time is recovered from the DMA
+//! instead: the chained channel starts the moment the other completes, so the readings it has
+//! already taken give the time elapsed, to within one ADC reading. This shows how long USB, flash
+//! and locking in lower priority handlers hold off acquisition.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(any(feature = "irq_timing", feature = "irq_latency"))]
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::peripheral::NVIC;
+use defmt::Format;
+#[cfg(feature = "irq_timing")]
+use defmt::{info, warn};
+
+#[cfg(feature = "irq_timing")]
+use crate::deadline::Instant;
+#[cfg(feature = "irq_latency")]
+use crate::hal::pac;
+use crate::{
+    board::{SIGNAL_GEN_FREQ_HZ, SIO_FIFO_IRQ, TIMER_ALARM_IRQ},
+    buffer::{ADC_CHANNELS, DMA_BUFFER_SIZE},
+    config::board::MAINS_HZ,
+    hal::pac::{Interrupt, NVIC_PRIO_BITS},
+};
+
+/// Fastest conversion rate of the ADC, shared between all of its inputs
+pub const ADC_MAX_RATE_HZ: u32 = 500_000;
+/// ADC readings per period of the detection signal, across every channel: 4 with `lock_in`, one at
+/// each quarter period, 2 on each channel with `dual_channel` or `dual_probe`, otherwise 2.
+///
+/// Two readings on each of the three `triple_channel` inputs would need 600 ksamples/s, over
+/// [`ADC_MAX_RATE_HZ`], so those inputs share 2 readings per period instead. Each is read every 1.5
+/// periods, which still alternates between the high and low halves of the signal.
+pub const READINGS_PER_PERIOD: u32 = if cfg!(feature = "lock_in") {
+    4
+} else if cfg!(feature = "triple_channel") {
+    2
+} else {
+    2 * ADC_CHANNELS as u32
+};
+/// ADC sample rate across every channel, [`READINGS_PER_PERIOD`] times the signal generator
+/// frequency
+pub const ADC_SAMPLE_RATE_HZ: u32 = READINGS_PER_PERIOD * SIGNAL_GEN_FREQ_HZ;
+/// Time between `DMA_IRQ_0` interrupts, while one transfer fills the averaging buffer
+pub const TRANSFER_PERIOD_US: u32 =
+    (DMA_BUFFER_SIZE as u64 * 1_000_000 / ADC_SAMPLE_RATE_HZ as u64) as u32;
+
+const _: () = assert!(ADC_SAMPLE_RATE_HZ <= ADC_MAX_RATE_HZ);
+// A transfer aligned to the mains spans whole cycles of it, so hum cancels out of the average
+const _: () = assert!(
+    MAINS_HZ == 0
+        || (DMA_BUFFER_SIZE as u64 * MAINS_HZ as u64).is_multiple_of(ADC_SAMPLE_RATE_HZ as u64)
+);
+
+/// Interrupt handlers used by the binaries
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Isr {
+    /// Power-good input falling, see [`power::on_power_fail`](crate::power::on_power_fail)
+    IoIrqBank0,
+    /// Completes ADC transfers and averages the readings
+    DmaIrq0,
+    /// Watchdog and disable switch polling (RTIC binary)
+    SysTick,
+    /// Timer queue (Embassy binary)
+    TimerIrq0,
+    /// USB console
+    UsbctrlIrq,
+    /// Messages from core1 (`dual_core` binary)
+    SioIrqProc0,
+}
+
+impl Isr {
+    /// All handlers
+    pub const ALL: [Isr; 6] = [
+        Isr::IoIrqBank0,
+        Isr::DmaIrq0,
+        Isr::SysTick,
+        Isr::TimerIrq0,
+        Isr::UsbctrlIrq,
+        Isr::SioIrqProc0,
+    ];
+
+    /// Logical priority, as used by RTIC. Higher numbers preempt lower ones.
+    pub const fn priority(self) -> u8 {
+        match self {
+            Isr::IoIrqBank0 => 4,
+            Isr::DmaIrq0 => 3,
+            Isr::SysTick | Isr::TimerIrq0 => 2,
+            Isr::UsbctrlIrq | Isr::SioIrqProc0 => 1,
+        }
+    }
+
+    /// Longest the handler may run, in microseconds. `DMA_IRQ_0` must finish well before the next
+    /// transfer completes. Saving the configuration from the console erases flash, and is expected
+    /// to exceed its budget, as is the power-fail handler while it flushes the journal.
+    pub const fn budget_us(self) -> u32 {
+        match self {
+            Isr::IoIrqBank0 => 20,
+            Isr::DmaIrq0 => TRANSFER_PERIOD_US * 3 / 4,
+            Isr::SysTick | Isr::TimerIrq0 => 100,
+            Isr::UsbctrlIrq | Isr::SioIrqProc0 => 1_000,
+        }
+    }
+
+    /// NVIC interrupt, or [`None`] for system exceptions
+    const fn interrupt(self) -> Option<Interrupt> {
+        match self {
+            Isr::IoIrqBank0 => Some(Interrupt::IO_IRQ_BANK0),
+            Isr::DmaIrq0 => Some(Interrupt::DMA_IRQ_0),
+            Isr::SysTick => None,
+            Isr::TimerIrq0 => Some(TIMER_ALARM_IRQ),
+            Isr::UsbctrlIrq => Some(Interrupt::USBCTRL_IRQ),
+            Isr::SioIrqProc0 => Some(SIO_FIFO_IRQ),
+        }
+    }
+}
+
+// Only a power failure preempts acquisition, which has to keep up with the ADC
+const _: () = assert!(Isr::DmaIrq0.budget_us() < TRANSFER_PERIOD_US);
+const _: () = assert!(Isr::IoIrqBank0.priority() > Isr::DmaIrq0.priority());
+const _: () = assert!(Isr::DmaIrq0.priority() > Isr::SysTick.priority());
+const _: () = assert!(Isr::SysTick.priority() > Isr::UsbctrlIrq.priority());
+const _: () = assert!(Isr::IoIrqBank0.priority() <= 1 << NVIC_PRIO_BITS);
+
+/// Converts a logical priority to the value written to the NVIC, where lower values preempt
+pub const fn hw_priority(priority: u8) -> u8 {
+    ((1 << NVIC_PRIO_BITS) - priority) << (8 - NVIC_PRIO_BITS)
+}
+
+/// Assign the priority of every [`Isr`] in the NVIC, for executors that don't do this themselves.
+/// Must be called before the interrupts are unmasked.
+pub fn set_priorities(nvic: &mut NVIC) {
+    for isr in Isr::ALL {
+        if let Some(interrupt) = isr.interrupt() {
+            // SAFETY: called before the interrupts are unmasked, so no priority-based critical
+            // sections are active
+            unsafe { nvic.set_priority(interrupt, hw_priority(isr.priority())) };
+        }
+    }
+}
+
+/// Longest run of each handler, in microseconds
+#[cfg(feature = "irq_timing")]
+static WORST_CASE_US: [AtomicU32; 6] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+/// Run the body of handler `isr`. With the `irq_timing` feature, also records its duration and
+/// warns if it exceeds the budget. The `TIMER` peripheral must be running, as it is after
+/// [`Board::init`](crate::board::Board::init).
+#[inline(always)]
+pub fn timed<R>(isr: Isr, f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "irq_timing")]
+    {
+        let start = Instant::now();
+        let ret = f();
+        let elapsed = Instant::now().since(start);
+
+        // Each slot is only written by its own handler, which can't preempt itself
+        let worst = &WORST_CASE_US[isr as usize];
+        if elapsed > worst.load(Ordering::Relaxed) {
+            worst.store(elapsed, Ordering::Relaxed);
+        }
+        if elapsed > isr.budget_us() {
+            warn!(
+                "{} ran for {=u32} us, over its {=u32} us budget",
+                isr,
+                elapsed,
+                isr.budget_us()
+            );
+        }
+        ret
+    }
+    #[cfg(not(feature = "irq_timing"))]
+    {
+        let _ = isr;
+        f()
+    }
+}
+
+/// Longest run of `isr` so far, in microseconds
+#[cfg(feature = "irq_timing")]
+pub fn worst_case_us(isr: Isr) -> u32 {
+    WORST_CASE_US[isr as usize].load(Ordering::Relaxed)
+}
+
+/// Log the longest run of every handler that has run
+#[cfg(feature = "irq_timing")]
+pub fn log_worst_case() {
+    for isr in Isr::ALL.into_iter().filter(|isr| worst_case_us(*isr) > 0) {
+        info!(
+            "{}: worst case {=u32} us of {=u32} us budget",
+            isr,
+            worst_case_us(isr),
+            isr.budget_us()
+        );
+    }
+}
+
+/// Upper bounds of the `DMA_IRQ_0` latency histogram buckets, in microseconds. A final bucket
+/// counts every longer delay, including any where the next transfer also completed first.
+pub const LATENCY_BUCKETS_US: [u32; 8] = [10, 20, 50, 100, 200, 500, 1_000, 2_000];
+/// Number of buckets in the latency histogram, including the final one
+pub const LATENCY_BINS: usize = LATENCY_BUCKETS_US.len() + 1;
+
+/// Time taken by one ADC reading, the resolution of the latency histogram
+#[cfg(feature = "irq_latency")]
+const READING_PERIOD_NS: u32 = 1_000_000_000 / ADC_SAMPLE_RATE_HZ;
+
+/// Count of `DMA_IRQ_0` entries in each latency bucket
+#[cfg(feature = "irq_latency")]
+static LATENCY_HISTOGRAM: [AtomicU32; LATENCY_BINS] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+/// Record how long ago the last ADC transfer completed. Call on entry to `DMA_IRQ_0`, before the
+/// completed channel is queued again.
+#[cfg(feature = "irq_latency")]
+#[inline(always)]
+pub fn record_dma_latency() {
+    // SAFETY: read-only access to the status of the acquisition channels, 0 and 1
+    let dma = unsafe { &*pac::DMA::ptr() };
+    let running = (0..2)
+        .map(|ch| dma.ch(ch))
+        .find(|ch| ch.ch_ctrl_trig().read().busy().bit_is_set());
+    let bucket = match running {
+        Some(ch) => {
+            let taken = DMA_BUFFER_SIZE as u32 - ch.ch_trans_count().read().bits();
+            let latency_us = taken * READING_PERIOD_NS / 1_000;
+            LATENCY_BUCKETS_US
+                .iter()
+                .position(|limit| latency_us < *limit)
+                .unwrap_or(LATENCY_BUCKETS_US.len())
+        }
+        // Both transfers have completed, so acquisition stalled
+        None => LATENCY_BUCKETS_US.len(),
+    };
+    // Only written by `DMA_IRQ_0`, which can't preempt itself, and cleared with it masked
+    let count = &LATENCY_HISTOGRAM[bucket];
+    count.store(
+        count.load(Ordering::Relaxed).wrapping_add(1),
+        Ordering::Relaxed,
+    );
+}
+
+/// Number of `DMA_IRQ_0` entries in each bucket of [`LATENCY_BUCKETS_US`], then the number later
+/// than all of them
+#[cfg(feature = "irq_latency")]
+pub fn latency_histogram() -> [u32; LATENCY_BINS] {
+    core::array::from_fn(|bucket| LATENCY_HISTOGRAM[bucket].load(Ordering::Relaxed))
+}
+
+/// Empty the latency histogram, to start a new measurement
+#[cfg(feature = "irq_latency")]
+pub fn clear_latency_histogram() {
+    critical_section::with(|_| {
+        for count in &LATENCY_HISTOGRAM {
+            count.store(0, Ordering::Relaxed);
+        }
+    });
+}
@@ -1,7 +1,13 @@
-//! This [RP2040](rp2040_hal) project provides contact detection with a
+//! This [RP2040 and RP2350](hal) project provides contact detection with a
 //! highly-conductivity/highly-capacitive surface (such as brain tissue) for an autopsy saw. For
 //! more information, check out [the repo](https://github.com/cam-rod/aps490_pfpu2_mini).
 //!
+//! The hardware-independent logic lives in the `pfpu2-core` crate of the workspace, and each of its
+//! modules is re-exported here under the same name, such as [`detection_core`] and [`journal`].
+//! Drivers that depend only on the chip, such as the flash driver behind [`storage`], live in the
+//! `pfpu2-rp2040` crate. This crate holds the board, the tasks that drive the hardware, and the
+//! binaries built on both.
+//!
 //! ## Crate features
 //!
 //! - `triple_status`: Enables the use of 3 LEDs to provide system status. This is the main user
@@ -11,148 +17,185 @@
 //! - `trace_avg_samples`: Logs the average voltage difference measured, 250 samples at a time. See
 //!   [`buffer::Buffers::trace_avg_samples`].
 //! - `trace_indiv_samples` Logs information on every sample recorded. Very noisy! See
-//!   [`interrupt::AlignedAverages::trace_high_index`] and [`interrupt::trace_indiv_samples`]
+//!   [`sampler::AlignedAverages::trace_high_index`] and [`sampler::trace_indiv_samples`]
+//! - `persist_panic`: Enabled by default. Installs the [`panic`] handler, which applies the
+//!   [safe state](safe_state) and keeps the panic message across a watchdog reset, so it can be
+//!   logged on the next boot. Disable it to use `panic-probe` instead, which halts for the
+//!   debugger.
 //! - `disable_switch`: Starts the SysTick timer to check the disable switch status. Never tested
-//!   this feature, and I'm pretty sure my implementation will cause the system to panic due to poor
-//!   synchronization. This functionality should be redesigned before enabling the feature.
+//!   on hardware.
+//! - `embassy`: Builds the `embassy` binary, which runs the same [`interrupt`] handlers as async
+//!   tasks on the [Embassy](https://embassy.dev) executor instead of RTIC. Flash it with
+//!   `cargo run --bin embassy --features embassy`.
+//! - `dual_core`: Builds the `dual_core` binary, where core0 only handles acquisition and core1
+//!   runs detection, the status LEDs and the USB console. Nothing is written to flash, so the
+//!   journal and usage totals aren't kept across resets. See [`multicore`]. Not yet available on
+//!   the RP2350.
+//! - `rp2350`: Builds for the RP2350 with [rp235x-hal](https://docs.rs/rp235x-hal), selected by
+//!   `board-pico2`. Build with `--target thumbv8m.main-none-eabihf`. The chip differences are
+//!   resolved in [`board`] and [`clocks`], and the rest of the crate uses the HAL through [`hal`].
+//! - `dual_channel`: Alternates the ADC between the probe input and a second input wired through
+//!   an independent divider, and raises an error if the two disagree. A channel that is clearly
+//!   faulty on its own, noisy or pinned at a rail, is disqualified, and detection carries on with
+//!   the other. See [`crosscheck`] and [`health`].
+//! - `dual_probe`: Supervises the probes of two stations, on the first and second ADC inputs. Each
+//!   probe has its own detector, and contact on one only trips that station's interlock and
+//!   lights its probe LED. The status LEDs and buzzer show the combined state. See
+//!   [`buffer::PROBES`].
+//! - `triple_channel`: Samples the probe on three ADC inputs, each through an independent
+//!   divider and with its own detector, and only detects contact when two of them agree. A channel
+//!   whose readings show it has failed, or whose [health](health) score runs out, stops counting,
+//!   and voting falls back to either of the other two. The inputs share the ADC rate of a single
+//!   channel, so each sample takes three times as long. See [`voting`].
+//! - `lock_in`: Samples at four times the signal frequency, and stores the magnitude of the
+//!   readings demodulated against in-phase and quadrature references instead of the difference
+//!   between the high and low halves, rejecting interference uncorrelated with the signal. The
+//!   magnitude doesn't depend on the phase of the ADC against the signal generator. A square
+//!   signal reads up to about 1.4 times higher than without it, so the thresholds may need
+//!   adjusting. Can't be combined with `dual_channel`, `dual_probe` or `triple_channel`, as the ADC
+//!   can't sample more than one input that fast. See [`sampler::AlignedAverages::demodulate`].
+//! - `replay`: Feeds a recorded waveform to detection in place of the probe readings, so detection
+//!   changes can be checked against real contact recordings without a rig. See [`replay`].
+//! - `irq_timing`: Measures every interrupt handler, and logs any that run over their budget in
+//!   [`irq`].
+//! - `irq_latency`: Builds a histogram of the delay from each ADC transfer completing to its
+//!   interrupt being handled, printed by the `LATENCY` console command. See [`irq`].
+//! - `perf`: Counts the cycles spent averaging, inserting and detecting on each sample, printed by
+//!   the `PERF` console command. See [`perf`].
+//! - `unrolled_avg`: Unrolls the block averaging loop, handling four periods of the signal per
+//!   iteration. Compare the two with `perf` before enabling it.
+//! - `hil`: Plays synthetic contact scenarios on a PWM DAC looped back into the ADC input by the
+//!   production bench fixture, to verify each assembled unit. See [`hil`].
+//! - `i2c_scan`: Probes every address on the I2C bus at boot and on the `I2C SCAN` console
+//!   command, warning about expected peripherals that don't respond and about unexpected ones that
+//!   do, to catch assembly errors on new boards. See [`i2c_scan`].
+//! - `mfg_test`: Holding the acknowledge button at power-up enters a manufacturing test instead
+//!   of detection, which toggles every output, sweeps the buzzer and echoes every input on the USB
+//!   console, so assembled boards can be verified by a fixture. See [`mfg_test`].
+//! - `mock`: Provides the [`MockIndicator`](indicator::MockIndicator), which records the LED
+//!   patterns it is shown for checking the indication logic without hardware. See [`indicator`].
+//! - `minimal`: Leaves out the USB console and everything only it uses, as well as the flash
+//!   journal, usage totals, PIN lock and provisioning, so the safety-critical build (sampling,
+//!   detection, status LEDs and interlock, with their self-tests and fault handling) is a small
+//!   artifact that can be reviewed on its own. Only the configuration is read from flash, and the
+//!   ADC uses its nominal calibration. The main binary still builds, but only with the LED, board,
+//!   channel, probe, `disable_switch`, `persist_panic` and `trace_*` features.
 //!
 //! <div class="warning">Features <code>triple_status</code> and <code>rgba_status</code> are
-//! mutually exclusive.</div>
+//! mutually exclusive, as are <code>dual_channel</code>, <code>dual_probe</code> and
+//! <code>triple_channel</code>, which all use the second ADC input.</div>
+//!
+//! ## Configuration
+//!
+//! Clock, buffer sizes and default thresholds are set in `board.toml`, and the pin map of each
+//! board variant in `boards/`. The build script turns both into [`config::board`]. Use
+//! [`board_pin!`] to take the assigned pins. The variant is selected with one of these features,
+//! defaulting to `board-proto-v2`:
+//!
+//! - `board-proto-v1`: First prototype, with only the status LEDs, disable switch, signal
+//!   generator and ADC input fitted
+//! - `board-proto-v2`: Second prototype, adding the interlock, buzzer, buttons, power-good input
+//!   and self-test circuits
+//! - `board-pico-bare`: A Pico without a carrier board, showing the normal state on the on-board
+//!   LED
+//! - `board-pico2`: A Pico 2 without a carrier board, wired as `board-pico-bare`. Enables `rp2350`.
+//!
+//! Detection thresholds are stored as a [`Config`](config::Config) in the last 64 KiB of flash,
+//! falling back to compile-time defaults if none has been saved. The Pico enumerates as a USB
+//! serial [`console`], which can restore the defaults (`FACTORY RESET`) or clone a known-good
+//! configuration to other units (`CONFIG EXPORT` on one, then paste the output into the other).
+//! The serial number, hardware revision and ADC calibration of each unit are kept apart from it,
+//! and written once on the bench with the jumper fitted (see [`provision`]).
+//!
+//! The RTIC binary starts the hardware watchdog, and only feeds it while both acquisition and
+//! detection keep checking in. See [`liveness`].
+//!
+//! At boot, the [built-in self-test](bist) checks the LEDs, ADC, DMA, stored configuration and
+//! watchdog scratch registers. If any check fails, the system stays in the error state.
 //!
 //! ## Demo
 //!
-//! The following is a simplified (including [`Rgba`](components::Rgba)-only lights) implementation of the [binary crate](https://github.com/cam-rod/aps490_pfpu2_mini/blob/main/src/main.rs)
+//! The firmware runs on [RTIC 2](https://rtic.rs). [`Board::init`](board::Board::init) sets up
+//! the hardware, whose handles are owned by the app and passed to the handlers in [`interrupt`],
+//! so the library doesn't depend on the executor. The following is a simplified (no console or
+//! watchdog) implementation of the [binary crate](https://github.com/cam-rod/aps490_pfpu2_mini/blob/main/src/main.rs)
 //! used on our proof-of-concept.
 //!
 //! ```no_run
 //! #![no_std]
 //! #![no_main]
 //!
-//! use aps490_pfpu2_mini::{
-//!     buffer::{create_avg_buffer, Buffers},
-//!     components::{LedControl, Rgba, StatusLed, StatusLedBase},
-//!     interrupt::{DISABLE_SWITCH, READINGS_FIFO, SIGNAL_GEN, STATUS_LEDS},
-//! };
-//! use cortex_m::peripheral::syst::SystClkSource;
-//! use defmt::{debug, warn};
 //! #[allow(unused_imports)]
 //! use defmt_rtt as _;
-//! use embedded_hal::pwm::SetDutyCycle;
-//! #[allow(unused_imports)]
-//! use panic_probe as _;
-//! use rp2040_hal::{
-//!     adc::{Adc, AdcPin},
-//!     clocks::init_clocks_and_plls,
-//!     dma::{single_buffer, DMAExt, SingleChannel},
-//!     entry,
-//!     gpio::Pins,
-//!     pac,
-//!     prelude::*,
-//!     pwm::Slices,
-//!     Sio, Watchdog,
-//! };
 //!
 //! #[link_section = ".boot2"]
 //! #[used]
 //! pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
-//! pub const XOSC_FREQ_HZ: u32 = 12_000_000;
-//! const SYS_CLOCK_FREQ: u32 = 24_000_000;
-//! pub static SIGNAL_GEN_FREQ_HZ: f32 = 100_000.0;
-//!
-//! #[entry]
-//! fn main() -> ! {
-//!     let mut pac = pac::Peripherals::take().unwrap();
-//!     let core = pac::CorePeripherals::take().unwrap();
-//!     let mut watchdog = Watchdog::new(pac.WATCHDOG);
-//!     let sio = Sio::new(pac.SIO);
-//!
-//!     let mut clocks = init_clocks_and_plls(
-//!         XOSC_FREQ_HZ,
-//!         pac.XOSC,
-//!         pac.CLOCKS,
-//!         pac.PLL_SYS,
-//!         pac.PLL_USB,
-//!         &mut pac.RESETS,
-//!         &mut watchdog,
-//!     )
-//!     .ok()
-//!     .unwrap();
-//!     let mut sysclk_rescale = clocks.system_clock.freq().to_Hz() as f32 / SYS_CLOCK_FREQ as f32;
-//!
-//!     // Setup status LEDs
-//!     let pins = Pins::new(
-//!         pac.IO_BANK0,
-//!         pac.PADS_BANK0,
-//!         sio.gpio_bank0,
-//!         &mut pac.RESETS,
-//!     );
-//!     critical_section::with(|cs| {
-//!         STATUS_LEDS.replace(cs, Rgba::init(pins.gpio6, pins.gpio7, pins.gpio8));
-//!     });
-//!
-//!     // Initialize and start signal generator
-//!     let mut pwm_slices = Slices::new(pac.PWM, &mut pac.RESETS);
-//!     pwm_slices.pwm3.set_top(
-//!         ((clocks.system_clock.freq().to_Hz() as f32 / (SIGNAL_GEN_FREQ_HZ * sysclk_rescale))
-//!             - 1.0) as u16,
-//!     );
-//!     pwm_slices.pwm3.enable();
-//!     let mut signal_gen = pwm_slices.pwm3.channel_a;
-//!     signal_gen.output_to(pins.gpio22);
-//!     signal_gen.set_duty_cycle_percent(50).unwrap();
-//!     critical_section::with(|cs| SIGNAL_GEN.replace(cs, Some(signal_gen)));
-//!
-//!     // Setup ADC pins, DMA, buffers
-//!     let mut adc = Adc::new(pac.ADC, &mut pac.RESETS);
-//!     let mut adc_pin0 = AdcPin::new(pins.gpio26.into_floating_input()).unwrap();
-//!     let mut dma = pac.DMA.split(&mut pac.RESETS);
-//!     Buffers::init();
-//!
-//!     // Setup first transfer
-//!     let avg_buffer = create_avg_buffer().unwrap();
-//!     let mut readings_fifo = adc
-//!         .build_fifo()
-//!         .set_channel(&mut adc_pin0)
-//!         .clock_divider(
-//!             ((clocks.system_clock.freq().to_Hz() as f32
-//!                 / (2.0 * (SIGNAL_GEN_FREQ_HZ * sysclk_rescale)))
-//!                 - 1.0) as u16,
-//!             0,
+//!
+//! #[rtic::app(device = aps490_pfpu2_mini::hal::pac, peripherals = true)]
+//! mod app {
+//!     use aps490_pfpu2_mini::{
+//!         board::Board,
+//!         buffer::Buffers,
+//!         interrupt,
+//!         sampler::Sampler,
+//!         state::{StateMachine, SystemIndicators},
+//!     };
+//!     use rtic::mutex_prelude::*;
+//!
+//!     #[shared]
+//!     struct Shared {
+//!         sampler: Sampler,
+//!         buffers: &'static mut Buffers,
+//!         state: StateMachine,
+//!     }
+//!
+//!     #[local]
+//!     struct Local {
+//!         indicators: SystemIndicators,
+//!     }
+//!
+//!     #[init]
+//!     fn init(cx: init::Context) -> (Shared, Local) {
+//!         // Starts the signal generator and ADC transfers, and loads the configuration
+//!         let Board {
+//!             mut sampler,
+//!             buffers,
+//!             mut state,
+//!             indicators,
+//!             self_test,
+//!             ..
+//!         } = Board::init(cx.device).unwrap();
+//!         // Stays in the error state if the built-in self-test failed
+//!         state.begin(&mut sampler, self_test);
+//!         (
+//!             Shared {
+//!                 sampler,
+//!                 buffers,
+//!                 state,
+//!             },
+//!             Local { indicators },
 //!         )
-//!         .shift_8bit()
-//!         .enable_dma()
-//!         .start_paused();
-//!     dma.ch0.enable_irq0();
-//!     let adc_dma_transfer =
-//!         single_buffer::Config::new(dma.ch0, readings_fifo.dma_read_target(), avg_buffer);
-//!     debug!("critical_section: transfer readings FIFO to mutex");
-//!     critical_section::with(|cs| READINGS_FIFO.replace(cs, Some(adc_dma_transfer.start())));
-//!     readings_fifo.resume();
-//!
-//!     // Configure and enable SysTick for disable switch
-//!     let disable_switch = pins.gpio9.into_pull_down_input();
-//!     disable_switch.set_schmitt_enabled(true); // Debouncing
-//!     debug!("critical_section: init disable switch");
-//!     critical_section::with(|cs| DISABLE_SWITCH.replace(cs, Some(disable_switch)));
-//!
-//!     let mut syst = core.SYST;
-//!     syst.set_clock_source(SystClkSource::Core); // 1 us per tick
-//!     syst.set_reload(20_000);
-//!     syst.clear_current();
-//!     #[cfg(feature = "disable_switch")]
-//!     syst.enable_interrupt();
-//!
-//!     // Begin normal system operation
-//!     critical_section::with(|cs| {
-//!         #[cfg(feature = "rgba_status")]
-//!         StatusLedBase::<Rgba>::set_normal(cs, Some("System initialization complete"));
-//!         #[cfg(feature = "triple_status")]
-//!         StatusLedBase::<Triple>::set_normal(cs, Some("System initialization complete"));
-//!     });
-//!     unsafe { pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0) }
-//!     loop {
-//!         // All functionality in interrupts
-//!         cortex_m::asm::wfi();
+//!     }
+//!
+//!     // Logging and LEDs are updated at the lowest priority
+//!     #[idle(shared = [state], local = [indicators])]
+//!     fn idle(mut cx: idle::Context) -> ! {
+//!         loop {
+//!             let current = cx.shared.state.lock(|state| state.state());
+//!             cx.local.indicators.update(current);
+//!             cortex_m::asm::wfi();
+//!         }
+//!     }
+//!
+//!     #[task(binds = DMA_IRQ_0, shared = [sampler, buffers, state])]
+//!     fn dma_irq_0(cx: dma_irq_0::Context) {
+//!         (cx.shared.sampler, cx.shared.buffers, cx.shared.state).lock(
+//!             |sampler, buffers, state| {
+//!                 interrupt::readings_complete(sampler, buffers, state)
+//!                     .unwrap_or_else(|err| state.fail(sampler, err))
+//!             },
+//!         );
 //!     }
 //! }
 //! ```
@@ -176,9 +219,131 @@
 #![warn(missing_docs)]
 #![cfg_attr(docsrs, feature(doc_cfg), feature(doc_auto_cfg), feature(doc_cfg_hide))]
 
+// Before the modules, so `board_pin!` is in scope in all of them
+include!(concat!(env!("OUT_DIR"), "/board_pin_generated.rs"));
+
+#[cfg(feature = "ab_compare")]
+pub mod ab_compare;
+pub mod banner;
+pub mod bist;
+pub mod blanking;
+pub mod board;
+pub mod budget;
 pub mod buffer;
+#[cfg(not(feature = "minimal"))]
+pub mod capture;
+pub mod clocks;
 pub mod components;
+pub mod config;
+#[cfg(not(feature = "minimal"))]
+pub mod console;
+pub mod correlation;
+pub mod crosscheck;
+pub mod deadline;
+#[cfg(not(feature = "minimal"))]
+pub mod drill;
+pub mod error;
+#[cfg(not(feature = "minimal"))]
+pub mod event_log;
+pub mod events;
+#[cfg(not(feature = "minimal"))]
+pub mod export;
+pub mod fault;
+#[cfg(feature = "hil")]
+pub mod hil;
+#[cfg(not(feature = "minimal"))]
+pub mod histogram;
+#[cfg(feature = "i2c_scan")]
+pub mod i2c_scan;
+pub mod injection;
 pub mod interrupt;
+pub mod irq;
+pub mod liveness;
+#[cfg(not(feature = "minimal"))]
+pub mod lock;
+pub mod logging;
+#[cfg(not(feature = "minimal"))]
+pub mod macros;
+#[cfg(feature = "mfg_test")]
+pub mod mfg_test;
+#[cfg(feature = "dual_core")]
+pub mod multicore;
+#[cfg(not(feature = "minimal"))]
+pub mod near_miss;
+pub mod panic;
+pub mod perf;
+pub mod plausibility;
+pub mod postmortem;
+pub mod power;
+pub mod presence;
+pub mod probe;
+#[cfg(not(feature = "minimal"))]
+pub mod provision;
+#[cfg(feature = "replay")]
+pub mod replay;
+pub mod reset;
+pub mod safe_state;
+pub mod sampler;
+pub mod scheduler;
+#[cfg(not(feature = "minimal"))]
+pub mod soak_test;
+pub mod stack;
+pub mod state;
+pub mod storage;
+pub mod trigger;
+#[cfg(not(feature = "minimal"))]
+pub mod uptime;
+pub mod version;
+#[cfg(not(feature = "minimal"))]
+pub mod watch;
+
+pub use pfpu2_core::{
+    auto_zero, detection_core, health, indicator, journal, protocol, ring, sequence, soak,
+    system_state, units, voting,
+};
+/// HAL of the chip the firmware is built for
+#[cfg(not(feature = "rp2350"))]
+pub use rp2040_hal as hal;
+/// HAL of the chip the firmware is built for
+#[cfg(feature = "rp2350")]
+pub use rp235x_hal as hal;
 
 #[cfg(all(feature = "triple_status", feature = "rgba_status"))]
 compile_error!("Features `triple_status` and `rgba_status` cannot be enabled at the same time in crate aps490_pfpu2_mini");
+#[cfg(all(feature = "dual_channel", feature = "dual_probe"))]
+compile_error!("Features `dual_channel` and `dual_probe` cannot be enabled at the same time in crate aps490_pfpu2_mini");
+#[cfg(all(
+    feature = "triple_channel",
+    any(feature = "dual_channel", feature = "dual_probe")
+))]
+compile_error!("Feature `triple_channel` cannot be combined with `dual_channel` or `dual_probe` in crate aps490_pfpu2_mini");
+#[cfg(all(
+    feature = "lock_in",
+    any(
+        feature = "dual_channel",
+        feature = "dual_probe",
+        feature = "triple_channel"
+    )
+))]
+compile_error!("Feature `lock_in` cannot be combined with `dual_channel`, `dual_probe` or `triple_channel` in crate aps490_pfpu2_mini");
+#[cfg(all(
+    feature = "minimal",
+    any(
+        feature = "embassy",
+        feature = "dual_core",
+        feature = "hil",
+        feature = "mfg_test",
+        feature = "replay",
+        feature = "perf",
+        feature = "irq_latency",
+        feature = "irq_timing",
+        feature = "ab_compare",
+        feature = "i2c_scan",
+        feature = "lock_in",
+        feature = "mock",
+        feature = "unrolled_avg"
+    )
+))]
+compile_error!("Feature `minimal` leaves out the console, journal, usage totals, PIN lock and provisioning, so can only be combined with the LED, board, channel, probe, `disable_switch`, `persist_panic` and `trace_*` features in crate aps490_pfpu2_mini");
+#[cfg(all(feature = "rp2350", feature = "dual_core"))]
+compile_error!("Feature `dual_core` is not yet supported with `rp2350` in crate aps490_pfpu2_mini");
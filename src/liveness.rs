@@ -0,0 +1,183 @@
+//! Task liveness checks, which gate feeding the hardware watchdog.
+//!
+//! Each [`Task`] calls [`check_in`] whenever it runs. [`feed_if_alive`] is called periodically,
+//! and only feeds the watchdog if every task has checked in since the last call (or is
+//! [suspended](suspend)). Otherwise it applies the [`SAFE_STATE`] and stops feeding for good, so a
+//! wedged DMA chain or a stuck detection loop produces a clean reset. The tasks that missed their
+//! deadline are kept in a watchdog scratch register, and logged on the next boot with
+//! [`take_last_timeout`].
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{error, Format, Formatter};
+
+use crate::{
+    hal::{fugit::MicrosDurationU32, pac, Watchdog},
+    safe_state::SAFE_STATE,
+};
+
+/// Watchdog timeout. Must be longer than the interval between calls to [`feed_if_alive`].
+pub const WATCHDOG_TIMEOUT: MicrosDurationU32 = MicrosDurationU32::millis(500);
+/// Deadline for every task to check in, and the interval between calls to [`feed_if_alive`]
+pub const CHECK_IN_DEADLINE: MicrosDurationU32 = MicrosDurationU32::millis(100);
+/// Marks a valid record in the upper half of the scratch register
+const SCRATCH_MAGIC: u32 = 0x5744_0000;
+/// Set in [`MissedTasks`] when the timeout was [simulated](simulate_timeout)
+const SIMULATED_BIT: u8 = 0x80;
+
+/// Tasks monitored by the watchdog
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Task {
+    /// `DMA_IRQ_0`, completing ADC transfers. Suspended while the sampler is paused.
+    Acquisition,
+    /// Loop running detection on the collected samples
+    Detection,
+}
+
+impl Task {
+    /// All monitored tasks
+    const ALL: [Task; 2] = [Task::Acquisition, Task::Detection];
+
+    /// Bit representing this task in [`MissedTasks`]
+    const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Set by each task on check in, cleared when the watchdog is fed
+static CHECKED_IN: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+/// Tasks not expected to check in
+static SUSPENDED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+/// Set once a deadline has been missed, after which the watchdog is never fed
+static STARVED: AtomicBool = AtomicBool::new(false);
+/// Set once the watchdog has been [started](start)
+static STARTED: AtomicBool = AtomicBool::new(false);
+/// Set by [`simulate_timeout`], so [`Task::Detection`] misses the next deadline
+static SIMULATED: AtomicBool = AtomicBool::new(false);
+
+/// Set of tasks that missed their deadline
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct MissedTasks(u8);
+
+impl MissedTasks {
+    /// Whether `task` missed its deadline
+    pub fn contains(&self, task: Task) -> bool {
+        self.0 & task.bit() != 0
+    }
+
+    /// Whether the timeout was [simulated](simulate_timeout) for a drill
+    pub fn simulated(&self) -> bool {
+        self.0 & SIMULATED_BIT != 0
+    }
+}
+
+impl Format for MissedTasks {
+    fn format(&self, fmt: Formatter) {
+        for task in Task::ALL.into_iter().filter(|task| self.contains(*task)) {
+            defmt::write!(fmt, "{} ", task);
+        }
+        if self.simulated() {
+            defmt::write!(fmt, "(simulated)");
+        }
+    }
+}
+
+/// Record that `task` is alive
+pub fn check_in(task: Task) {
+    CHECKED_IN[task as usize].store(true, Ordering::Release);
+}
+
+/// Stop expecting `task` to check in, such as while it is paused
+pub fn suspend(task: Task) {
+    SUSPENDED[task as usize].store(true, Ordering::Release);
+}
+
+/// Expect `task` to check in again. It counts as checked in until the next deadline.
+pub fn resume(task: Task) {
+    check_in(task);
+    SUSPENDED[task as usize].store(false, Ordering::Release);
+}
+
+/// Start the watchdog with [`WATCHDOG_TIMEOUT`]. It is paused while a debugger halts the core.
+pub fn start(watchdog: &mut Watchdog) {
+    watchdog.pause_on_debug(true);
+    watchdog.start(WATCHDOG_TIMEOUT);
+    STARTED.store(true, Ordering::Release);
+}
+
+/// Treat [`Task::Detection`] as having missed the next deadline, for a [drill](crate::drill).
+/// The timeout is handled as a real one, resetting the chip, but is marked
+/// [simulated](MissedTasks::simulated). Returns `false` if the watchdog hasn't been started, so
+/// the drill can't run.
+pub fn simulate_timeout() -> bool {
+    let started = STARTED.load(Ordering::Acquire);
+    if started {
+        SIMULATED.store(true, Ordering::Release);
+    }
+    started
+}
+
+/// Feed the watchdog if every task has checked in since the last call, and clear the check ins.
+/// Must be called every [`CHECK_IN_DEADLINE`].
+///
+/// Returns `false` if a task missed its deadline. The outputs are put in the [`SAFE_STATE`], and
+/// the watchdog will not be fed again, resetting the chip after [`WATCHDOG_TIMEOUT`].
+pub fn feed_if_alive(watchdog: &mut Watchdog) -> bool {
+    if STARVED.load(Ordering::Acquire) {
+        return false;
+    }
+
+    let mut missed = MissedTasks::default();
+    for task in Task::ALL {
+        if !SUSPENDED[task as usize].load(Ordering::Acquire)
+            && !CHECKED_IN[task as usize].load(Ordering::Acquire)
+        {
+            missed.0 |= task.bit();
+        }
+    }
+    if SIMULATED.load(Ordering::Acquire) {
+        missed.0 |= Task::Detection.bit() | SIMULATED_BIT;
+    }
+    if missed != MissedTasks::default() {
+        STARVED.store(true, Ordering::Release);
+        SAFE_STATE.apply();
+        error!("Missed watchdog deadline: {}. Resetting", missed);
+        // SAFETY: scratch0 is not used by the HAL or the bootrom
+        unsafe {
+            (*pac::WATCHDOG::ptr())
+                .scratch0()
+                .write(|w| w.bits(SCRATCH_MAGIC | missed.0 as u32));
+        }
+        return false;
+    }
+
+    for checked_in in &CHECKED_IN {
+        checked_in.store(false, Ordering::Release);
+    }
+    watchdog.feed();
+    true
+}
+
+/// Take the tasks that caused the last watchdog reset, if any. Later calls return [`None`] until
+/// the next missed deadline.
+pub fn take_last_timeout() -> Option<MissedTasks> {
+    // SAFETY: scratch0 is only used by this module
+    let scratch0 = unsafe { (*pac::WATCHDOG::ptr()).scratch0() };
+    let record = scratch0.read().bits();
+    scratch0.write(|w| unsafe { w.bits(0) });
+    (record & 0xFFFF_0000 == SCRATCH_MAGIC).then_some(MissedTasks(record as u8))
+}
@@ -0,0 +1,231 @@
+//! Configuration lock, so thresholds and other safety-relevant settings can only be changed by
+//! someone holding the PIN, or with physical access to the configuration jumper.
+//!
+//! The PIN is 4 to 8 digits, set with the `PIN` console command and persisted in its own flash
+//! sector at [`LOCK_OFFSET`]. Until one is set, only the jumper unlocks. `UNLOCK <pin>` opens a
+//! [`Lock`] session on the console, which ends with `LOCK`, or once [`SESSION_MS`] pass without a
+//! privileged command. While the jumper is fitted, privileged commands are accepted without a
+//! PIN. Read-only queries never need either.
+//!
+//! Each wrong PIN is tallied in the page after the PIN record, by programming one more byte of it
+//! to zero, so the count survives a reset without erasing the sector each time. After
+//! [`MAX_ATTEMPTS`] wrong PINs in a row, `UNLOCK` is refused until the jumper is fitted, so the
+//! PIN can't be found by trying every one over the console, with or without power cycling. A
+//! correct PIN, entered with the jumper fitted once locked out, or a new PIN clears the tally.
+//!
+//! The [`Console`](crate::console::Console) journals every unlock, wrong PIN and refused command.
+//! The PIN is stored as entered: anyone able to read out the flash can also reach the jumper.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use defmt::{info, warn};
+
+pub use crate::protocol::{ConfigPin, LockError, MAX_DIGITS, MIN_DIGITS};
+use crate::{
+    config::board::{ConfigJumperPin, CONFIG_JUMPER_PIN},
+    hal::{
+        gpio::{FunctionNull, FunctionSio, Pin, PullDown, PullUp, SioInput},
+        pac,
+    },
+    journal::Source,
+    scheduler,
+    storage::{self, StorageError, LOCK_OFFSET, PAGE_SIZE},
+};
+
+/// Marks a valid PIN record in flash ("LOCK")
+const MAGIC: u32 = 0x4B43_4F4C;
+/// Size of the PIN record: magic, number of digits, value and CRC
+const RECORD_SIZE: usize = 16;
+/// Offset of the page tallying wrong PINs, one zeroed byte each, after the PIN record
+const TALLY_OFFSET: u32 = LOCK_OFFSET + PAGE_SIZE as u32;
+/// How long an unlocked session lasts after the unlock or the latest privileged command, 10
+/// minutes
+pub const SESSION_MS: u32 = 600_000;
+/// Wrong PINs in a row before unlocking is refused until the jumper is fitted
+pub const MAX_ATTEMPTS: u8 = 5;
+
+// The tally must fit in its page
+const _: () = assert!(MAX_ATTEMPTS as usize <= PAGE_SIZE);
+
+/// PIN loaded from flash, or [`None`] if none has been set
+static PIN: Mutex<Cell<Option<ConfigPin>>> = Mutex::new(Cell::new(None));
+/// Wrong PINs entered in a row, as tallied in flash. Kept here as well, so a tally that couldn't
+/// be programmed still counts until the next reset.
+static FAILURES: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// Configuration jumper input, pulled low while the jumper is fitted
+pub type JumperInput = Pin<ConfigJumperPin, FunctionSio<SioInput>, PullUp>;
+
+/// Decode a record written by [`encode`], or [`None`] if it is missing or corrupt
+fn decode(record: &[u8]) -> Option<ConfigPin> {
+    let word = |idx: usize| u32::from_le_bytes(record[idx..idx + 4].try_into().unwrap());
+    if word(0) != MAGIC || storage::crc32(&record[..12]) != word(12) {
+        return None;
+    }
+    ConfigPin::from_parts(word(4) as u8, word(8))
+}
+
+/// Encode `pin` as a flash record
+fn encode(pin: &ConfigPin) -> [u8; RECORD_SIZE] {
+    let mut record = [0; RECORD_SIZE];
+    record[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    record[4..8].copy_from_slice(&(pin.digits() as u32).to_le_bytes());
+    record[8..12].copy_from_slice(&pin.value().to_le_bytes());
+    let crc = storage::crc32(&record[..12]);
+    record[12..].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// Load the PIN persisted by [`set_pin`], and the wrong PINs tallied since. Call once during
+/// startup.
+pub fn load() {
+    let pin = decode(storage::read(LOCK_OFFSET, RECORD_SIZE));
+    let failures = storage::read(TALLY_OFFSET, MAX_ATTEMPTS as usize)
+        .iter()
+        .take_while(|byte| **byte == 0)
+        .count() as u8;
+    if pin.is_none() {
+        warn!("No configuration PIN set, only the jumper unlocks privileged commands");
+    } else if failures >= MAX_ATTEMPTS {
+        warn!("Too many wrong configuration PINs, only the jumper unlocks privileged commands");
+    }
+    critical_section::with(|cs| {
+        PIN.borrow(cs).set(pin);
+        FAILURES.borrow(cs).set(failures);
+    });
+}
+
+/// Persist and use `pin` from now on, clearing the tally of wrong PINs. Interrupts are disabled
+/// while flash is written.
+pub fn set_pin(pin: ConfigPin) -> Result<(), StorageError> {
+    storage::write_sector(LOCK_OFFSET, &encode(&pin))?;
+    critical_section::with(|cs| {
+        PIN.borrow(cs).set(Some(pin));
+        FAILURES.borrow(cs).set(0);
+    });
+    info!("Configuration PIN changed");
+    Ok(())
+}
+
+/// Count another wrong PIN, and tally it in flash by zeroing one more byte of the tally page.
+/// Only programs the page, so it never erases the sector. Returns the wrong PINs in a row.
+fn count_failure() -> u8 {
+    let failures = critical_section::with(|cs| {
+        let failures = FAILURES.borrow(cs);
+        failures.set(failures.get().saturating_add(1));
+        failures.get()
+    });
+    if failures <= MAX_ATTEMPTS {
+        let mut page = [0xFF; PAGE_SIZE];
+        page[..failures as usize].fill(0);
+        if let Err(err) = storage::program_pages(TALLY_OFFSET, &page) {
+            warn!("Unable to tally the wrong PIN in flash: {}", err);
+        }
+    }
+    failures
+}
+
+/// Clear the tally of wrong PINs after a correct one. The tally can't be unprogrammed, so the
+/// sector is erased and `pin` written again. If that fails, the tally is only cleared until the
+/// next reset.
+fn clear_failures(pin: ConfigPin) {
+    if let Err(err) = storage::write_sector(LOCK_OFFSET, &encode(&pin)) {
+        warn!("Unable to clear the wrong PIN tally in flash: {}", err);
+    }
+    critical_section::with(|cs| FAILURES.borrow(cs).set(0));
+}
+
+/// Take the configuration jumper input, pulled up so the fitted jumper reads low
+pub fn monitor_jumper(pin: Pin<ConfigJumperPin, FunctionNull, PullDown>) -> JumperInput {
+    let input = pin.into_pull_up_input();
+    input.set_schmitt_enabled(true);
+    input
+}
+
+/// Whether the configuration jumper is fitted
+pub fn jumper_fitted() -> bool {
+    // SAFETY: read-only access to the SIO GPIO input register
+    let sio = unsafe { &*pac::SIO::ptr() };
+    sio.gpio_in().read().bits() & 1 << CONFIG_JUMPER_PIN == 0
+}
+
+/// Unlocked session of one console. Wrong PINs are counted across every console.
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct Lock {
+    /// [`scheduler::now_ms`] of the unlock or the latest privileged command, while a session is
+    /// open
+    session_ms: Option<u32>,
+}
+
+impl Lock {
+    /// Start locked
+    pub const fn new() -> Self {
+        Self { session_ms: None }
+    }
+
+    /// What allows privileged commands now: an open session, or failing that the jumper. Returns
+    /// [`None`] if they must be refused.
+    pub fn authorized(&mut self) -> Option<Source> {
+        let now = scheduler::now_ms();
+        if self
+            .session_ms
+            .is_some_and(|since| now.wrapping_sub(since) >= SESSION_MS)
+        {
+            self.session_ms = None;
+        }
+        if self.session_ms.is_some() {
+            Some(Source::Console)
+        } else {
+            jumper_fitted().then_some(Source::Jumper)
+        }
+    }
+
+    /// Extend an open session by [`SESSION_MS`] from now, after a privileged command
+    pub fn extend(&mut self) {
+        if self.session_ms.is_some() {
+            self.session_ms = Some(scheduler::now_ms());
+        }
+    }
+
+    /// Open a session if `attempt` matches the PIN. After [`MAX_ATTEMPTS`] wrong PINs in a row,
+    /// only a PIN entered with the jumper fitted is checked, which then clears the tally.
+    pub fn unlock(&mut self, attempt: ConfigPin) -> Result<(), LockError> {
+        let (pin, failures) =
+            critical_section::with(|cs| (PIN.borrow(cs).get(), FAILURES.borrow(cs).get()));
+        let pin = pin.ok_or(LockError::NoPin)?;
+        if failures >= MAX_ATTEMPTS && !jumper_fitted() {
+            return Err(LockError::LockedOut);
+        }
+        if attempt != pin {
+            let failures = count_failure();
+            warn!("Wrong configuration PIN, attempt {=u8}", failures);
+            return Err(LockError::WrongPin);
+        }
+        if failures > 0 {
+            clear_failures(pin);
+        }
+        self.session_ms = Some(scheduler::now_ms());
+        info!("Configuration unlocked");
+        Ok(())
+    }
+
+    /// End any open session
+    pub fn lock(&mut self) {
+        self.session_ms = None;
+    }
+}
@@ -0,0 +1,275 @@
+//! Runtime log filtering by [`Category`], on top of defmt's build-time filter.
+//!
+//! Full debug output over RTT perturbs the timing of the detection path, while the build-time
+//! minimum drops the detail needed for field reports. Each category has its own minimum
+//! [`Level`], set with the `LOG` console command, so the detail can be turned up for one part of
+//! the system at a time. Messages logged with [`log!`](crate::log!) are dropped before they reach
+//! defmt if their category's level is higher. The `DEFMT_LOG` filter still applies first, so a
+//! level can't bring back a message compiled out of the build.
+//!
+//! Every category starts at [`Level::Trace`], letting through everything the build includes.
+//!
+//! Debug and trace messages are also rate limited across every category, so per-sample detail
+//! can't flood RTT: once [`BURST_MESSAGES`] have been logged in quick succession, only
+//! [`MESSAGES_PER_S`] are let through, and the rest are dropped before they reach defmt. An RTT
+//! channel the host reads slowly, or has stopped reading in blocking mode, then can't stall the
+//! detection path on chatty output. Messages at [`Level::Info`] and above, such as state changes
+//! and detections, are never dropped, and the first one after any drops is preceded by the number
+//! of messages dropped. Levels compiled out by `DEFMT_LOG` don't count against the limit.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{
+    cell::Cell,
+    mem,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use critical_section::Mutex;
+use defmt::Format;
+
+use crate::scheduler;
+
+include!(concat!(env!("OUT_DIR"), "/log_level_generated.rs"));
+
+/// Debug and trace messages logged in quick succession before the rate limit applies
+pub const BURST_MESSAGES: u32 = 64;
+/// Debug and trace messages logged per second once the burst is used up
+pub const MESSAGES_PER_S: u32 = 100;
+/// Milliseconds between each message allowed by the rate limit
+const MESSAGE_INTERVAL_MS: u32 = 1000 / MESSAGES_PER_S;
+
+/// Part of the system a message comes from
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Category {
+    /// Signal generation, ADC transfers and averaging
+    Sampling,
+    /// Sample buffers and the contact state machine
+    Detection,
+    /// Status LEDs and buzzer
+    Leds,
+    /// USB console
+    Comms,
+}
+
+impl Category {
+    /// Every category, in declaration order
+    pub const ALL: [Category; 4] = [
+        Category::Sampling,
+        Category::Detection,
+        Category::Leds,
+        Category::Comms,
+    ];
+
+    /// Name used by the `LOG` console command
+    pub const fn name(self) -> &'static str {
+        match self {
+            Category::Sampling => "SAMPLING",
+            Category::Detection => "DETECTION",
+            Category::Leds => "LEDS",
+            Category::Comms => "COMMS",
+        }
+    }
+
+    /// Category called `name`, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|category| category.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Severity of a message, from least to most severe
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Level {
+    /// Very detailed, per-sample information
+    Trace,
+    /// Detail useful while debugging
+    Debug,
+    /// Normal operation
+    Info,
+    /// Something unexpected, but handled
+    Warn,
+    /// Something failed
+    Error,
+}
+
+impl Level {
+    /// Every level, in declaration order, so each is at the index of its discriminant
+    pub const ALL: [Level; 5] = [
+        Level::Trace,
+        Level::Debug,
+        Level::Info,
+        Level::Warn,
+        Level::Error,
+    ];
+
+    /// Name used by the `LOG` console command
+    pub const fn name(self) -> &'static str {
+        match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+
+    /// Level called `name`, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Minimum [`Level`] of each [`Category`], indexed by its discriminant
+static LEVELS: [AtomicU8; 4] = [
+    AtomicU8::new(Level::Trace as u8),
+    AtomicU8::new(Level::Trace as u8),
+    AtomicU8::new(Level::Trace as u8),
+    AtomicU8::new(Level::Trace as u8),
+];
+
+/// Token bucket limiting messages below [`Level::Info`]
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+struct RateLimit {
+    /// Messages that can be logged before the limit applies
+    tokens: u32,
+    /// [`scheduler::now_ms`] the tokens were last refilled up to
+    refilled_ms: u32,
+    /// Messages dropped since the last one logged at [`Level::Info`] or above
+    dropped: u32,
+    /// Messages dropped since boot
+    dropped_total: u32,
+}
+
+impl RateLimit {
+    /// Full bucket, with nothing dropped
+    const fn new() -> Self {
+        Self {
+            tokens: BURST_MESSAGES,
+            refilled_ms: 0,
+            dropped: 0,
+            dropped_total: 0,
+        }
+    }
+
+    /// Refill the tokens earned up to `now_ms`, then take one if any are left
+    fn take(&mut self, now_ms: u32) -> bool {
+        let earned = now_ms.wrapping_sub(self.refilled_ms) / MESSAGE_INTERVAL_MS;
+        if self.tokens + earned >= BURST_MESSAGES {
+            self.tokens = BURST_MESSAGES;
+            self.refilled_ms = now_ms;
+        } else {
+            self.tokens += earned;
+            self.refilled_ms = self.refilled_ms.wrapping_add(earned * MESSAGE_INTERVAL_MS);
+        }
+        if self.tokens == 0 {
+            self.dropped = self.dropped.saturating_add(1);
+            self.dropped_total = self.dropped_total.saturating_add(1);
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+/// Rate limit shared by every category
+static RATE_LIMIT: Mutex<Cell<RateLimit>> = Mutex::new(Cell::new(RateLimit::new()));
+
+/// Minimum level logged for `category`
+pub fn level(category: Category) -> Level {
+    Level::ALL[LEVELS[category as usize].load(Ordering::Relaxed) as usize]
+}
+
+/// Log only messages at `level` or above for `category`
+pub fn set_level(category: Category, level: Level) {
+    LEVELS[category as usize].store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether a message at `level` in `category` is logged. Called by [`log!`](crate::log!).
+#[inline(always)]
+pub fn enabled(category: Category, level: Level) -> bool {
+    level as u8 >= LEVELS[category as usize].load(Ordering::Relaxed)
+}
+
+/// Whether a message at `level` fits in the rate limit, taking its share if so. Called by
+/// [`log!`](crate::log!) once the message is [enabled].
+///
+/// Messages at [`Level::Info`] or above are always admitted, after logging how many messages were
+/// dropped since the last one. So are those below [`BUILD_LEVEL`], as defmt drops them anyway.
+pub fn admit(level: Level) -> bool {
+    if level < BUILD_LEVEL {
+        return true;
+    }
+    let (admitted, dropped) = critical_section::with(|cs| {
+        let cell = RATE_LIMIT.borrow(cs);
+        let mut limit = cell.get();
+        let result = if level >= Level::Info {
+            (true, mem::take(&mut limit.dropped))
+        } else {
+            (limit.take(scheduler::now_ms()), 0)
+        };
+        cell.set(limit);
+        result
+    });
+    if dropped > 0 {
+        defmt::warn!("{=u32} log messages dropped by the rate limit", dropped);
+    }
+    admitted
+}
+
+/// Messages dropped by the rate limit since boot
+pub fn dropped() -> u32 {
+    critical_section::with(|cs| RATE_LIMIT.borrow(cs).get().dropped_total)
+}
+
+/// Log a defmt message in a [`Category`], if its runtime [`Level`] and the rate limit allow it.
+/// Takes the category, the level as the name of the defmt macro, then the macro's arguments:
+///
+/// ```no_run
+/// use aps490_pfpu2_mini::log;
+///
+/// log!(Sampling, debug, "Queueing DMA buffer");
+/// log!(Detection, warn, "{=u8} samples dropped", 3);
+/// ```
+#[macro_export]
+macro_rules! log {
+    (@emit $category:ident, $level:ident, $macro:ident, $($arg:tt)+) => {
+        if $crate::logging::enabled(
+            $crate::logging::Category::$category,
+            $crate::logging::Level::$level,
+        ) && $crate::logging::admit($crate::logging::Level::$level)
+        {
+            defmt::$macro!($($arg)+)
+        }
+    };
+    ($category:ident, trace, $($arg:tt)+) => {
+        $crate::log!(@emit $category, Trace, trace, $($arg)+)
+    };
+    ($category:ident, debug, $($arg:tt)+) => {
+        $crate::log!(@emit $category, Debug, debug, $($arg)+)
+    };
+    ($category:ident, info, $($arg:tt)+) => {
+        $crate::log!(@emit $category, Info, info, $($arg)+)
+    };
+    ($category:ident, warn, $($arg:tt)+) => {
+        $crate::log!(@emit $category, Warn, warn, $($arg)+)
+    };
+    ($category:ident, error, $($arg:tt)+) => {
+        $crate::log!(@emit $category, Error, error, $($arg)+)
+    };
+}
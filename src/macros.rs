@@ -0,0 +1,114 @@
+//! Stored command macros, so routine maintenance is one step for someone who doesn't know the
+//! console.
+//!
+//! A macro is a list of console commands separated by `;`, such as `INJECT;FAULTS;EXPORT CSV`.
+//! Each of the [`MACROS`] slots holds up to [`MACRO_LEN`] characters, persisted in their own flash
+//! sector at [`MACRO_OFFSET`], which neither `FACTORY RESET` nor `CONFIG IMPORT` touch. They are
+//! stored with `MACRO <n> <steps>` and emptied with `MACRO <n> CLEAR`, both privileged.
+//!
+//! `RUN <n>` runs a macro on the console. So does pressing the acknowledge button briefly while the
+//! configuration jumper is fitted and the buzzer is silent, for slot [`BUTTON_MACRO`], with the
+//! responses sent to the console if a host has it open. Either way, the
+//! [`Console`](crate::console::Console) runs the steps in turn as if they were typed, stopping at
+//! the first that fails. The steps of a macro started with `RUN` need the same access as if they
+//! were typed, so a console that isn't unlocked can only run queries. Only the button macro runs
+//! with privileged access, as it needs the jumper. A macro can't `CONFIRM` its own steps, and each
+//! run is journalled as a [`Kind::Macro`]. A streaming command such as `EXPORT` only completes as
+//! the last step, and a macro can't run another.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::Cell;
+
+use cortex_m::peripheral::NVIC;
+use critical_section::Mutex;
+use defmt::info;
+
+pub use crate::protocol::{MACROS, MACRO_LEN};
+use crate::{
+    event_log,
+    hal::pac::Interrupt,
+    journal::{Kind, Source},
+    storage::{self, StorageError, MACRO_OFFSET},
+};
+
+/// Marks a valid macro record in flash ("MACR")
+const MAGIC: u32 = 0x5243_414D;
+/// Size of the macro record: magic, each slot padded with zeros, and CRC
+const RECORD_SIZE: usize = 4 + MACROS * MACRO_LEN + 4;
+/// Separates the steps of a macro
+pub const SEPARATOR: char = ';';
+/// Slot run by the acknowledge button while the configuration jumper is fitted
+pub const BUTTON_MACRO: usize = 0;
+
+/// Set by [`request_button`], until the console runs [`BUTTON_MACRO`]
+static BUTTON_REQUESTED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Stored macro record, or [`None`] if it is missing or corrupt
+fn record() -> Option<&'static [u8]> {
+    let record = storage::read(MACRO_OFFSET, RECORD_SIZE);
+    let (payload, crc) = record.split_at(RECORD_SIZE - 4);
+    (payload[..4] == MAGIC.to_le_bytes() && crc == storage::crc32(payload).to_le_bytes())
+        .then_some(record)
+}
+
+/// Steps of macro `slot`, or [`None`] if it is empty or out of range
+pub fn get(slot: usize) -> Option<&'static str> {
+    if slot >= MACROS {
+        return None;
+    }
+    let bytes = &record()?[4 + slot * MACRO_LEN..][..MACRO_LEN];
+    let len = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(MACRO_LEN);
+    core::str::from_utf8(&bytes[..len])
+        .ok()
+        .filter(|steps| !steps.is_empty())
+}
+
+/// Persist `steps` in `slot`, or empty it if [`None`]. Interrupts are disabled while flash is
+/// written.
+pub fn set(slot: usize, steps: Option<&str>) -> Result<(), StorageError> {
+    let mut record = [0; RECORD_SIZE];
+    for other in 0..MACROS {
+        let steps = if other == slot { steps } else { get(other) };
+        if let Some(steps) = steps {
+            record[4 + other * MACRO_LEN..][..steps.len()].copy_from_slice(steps.as_bytes());
+        }
+    }
+    record[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    let crc = storage::crc32(&record[..RECORD_SIZE - 4]);
+    record[RECORD_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+    storage::write_sector(MACRO_OFFSET, &record)?;
+    info!("Macro {=usize} changed", slot);
+    Ok(())
+}
+
+/// Journal a run of macro `slot` started by `source`
+pub fn journal(slot: usize, source: Source) {
+    event_log::record(Kind::Macro, (slot as u32) << 8 | source as u32);
+}
+
+/// Ask the console to run [`BUTTON_MACRO`], pending `USBCTRL_IRQ` so it does so straight away
+pub fn request_button() {
+    critical_section::with(|cs| BUTTON_REQUESTED.borrow(cs).set(true));
+    NVIC::pend(Interrupt::USBCTRL_IRQ);
+}
+
+/// Whether [`BUTTON_MACRO`] was requested since the previous call
+pub fn take_button_request() -> bool {
+    critical_section::with(|cs| BUTTON_REQUESTED.borrow(cs).replace(false))
+}
@@ -19,167 +19,471 @@
 #![warn(missing_docs)]
 #![cfg_attr(docsrs, feature(doc_cfg), feature(doc_auto_cfg), feature(doc_cfg_hide))]
 
-#[cfg(feature = "rgba_status")]
-use aps490_pfpu2_mini::components::Rgba;
-#[cfg(feature = "triple_status")]
-use aps490_pfpu2_mini::components::Triple;
-use aps490_pfpu2_mini::{
-    buffer::{create_avg_buffer, Buffers},
-    components::{LedControl, StatusLed, StatusLedBase},
-    interrupt::{DISABLE_SWITCH, READINGS_FIFO, SIGNAL_GEN, STATUS_LEDS},
-};
-use cortex_m::peripheral::syst::SystClkSource;
-use defmt::{debug, info, warn};
+#[cfg(feature = "rp2350")]
+use aps490_pfpu2_mini::hal::block::ImageDef;
 #[allow(unused_imports)]
 use defmt_rtt as _;
-use embedded_hal::pwm::SetDutyCycle;
+#[cfg(not(feature = "persist_panic"))]
 #[allow(unused_imports)]
 use panic_probe as _;
-use rp2040_hal::{
-    adc::{Adc, AdcPin},
-    clocks::init_clocks_and_plls,
-    dma::{single_buffer, DMAExt, SingleChannel},
-    entry,
-    fugit::RateExtU32,
-    gpio::Pins,
-    pac,
-    prelude::*,
-    pwm::Slices,
-    Sio, Watchdog,
-};
 
 /// Second-stage bootloader, from [rp2040-boot2](https://docs.rs/rp2040-boot2)
+#[cfg(not(feature = "rp2350"))]
 #[link_section = ".boot2"]
 #[used]
 pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
-/// External high-speed crystal on the pico board is 12Mhz
-pub const XOSC_FREQ_HZ: u32 = 12_000_000;
-/// Attempt to run system clock at 24 MHz
-const SYS_CLOCK_FREQ: u32 = 24_000_000;
-/// Frequency of detection signal is 100 kHz
-pub static SIGNAL_GEN_FREQ_HZ: f32 = 100_000.0;
-
-/// Main operation loop
-#[entry]
-fn main() -> ! {
-    #[cfg(all(feature = "triple_status", feature = "rgba_status"))]
-    compile_error!("Features `triple_status` and `rgba_status` cannot be enabled at the same time in crate aps490_pfpu2_mini");
-
-    info!("Detection system startup");
-    let mut pac = pac::Peripherals::take().unwrap();
-    let core = pac::CorePeripherals::take().unwrap();
-    let mut watchdog = Watchdog::new(pac.WATCHDOG);
-    let sio = Sio::new(pac.SIO);
-
-    // Rescale other calculations based on system clock
-    let mut sysclk_rescale = 1f32;
-    let mut clocks = init_clocks_and_plls(
-        XOSC_FREQ_HZ,
-        pac.XOSC,
-        pac.CLOCKS,
-        pac.PLL_SYS,
-        pac.PLL_USB,
-        &mut pac.RESETS,
-        &mut watchdog,
-    )
-    .ok()
-    .unwrap();
-    // Attempt to switch system to 24 MHz for efficiency
-    clocks
-        .system_clock
-        .configure_clock(&clocks.reference_clock, SYS_CLOCK_FREQ.Hz())
-        .unwrap_or_else(|err| {
-            warn!(
-                "Unable to downscale clock speed: {}\nClocks will continue to run at {=u32}",
-                err,
-                clocks.system_clock.freq().to_Hz()
-            );
-            sysclk_rescale = clocks.system_clock.freq().to_Hz() as f32 / SYS_CLOCK_FREQ as f32;
-        });
-    let pins = Pins::new(
-        pac.IO_BANK0,
-        pac.PADS_BANK0,
-        sio.gpio_bank0,
-        &mut pac.RESETS,
-    );
-
-    // Setup status LEDs
-    debug!("critical_section: init status LEDs");
-    critical_section::with(|cs| {
-        #[cfg(feature = "rgba_status")]
-        STATUS_LEDS.replace(cs, Rgba::init(pins.gpio6, pins.gpio7, pins.gpio8));
-        #[cfg(feature = "triple_status")]
-        STATUS_LEDS.replace(cs, Triple::init(pins.gpio6, pins.gpio7, pins.gpio8));
-    });
-
-    // Initialize and start signal generator
-    let mut pwm_slices = Slices::new(pac.PWM, &mut pac.RESETS);
-    pwm_slices
-        .pwm3
-        // Ex. 24 MHz clock generates 100 kHz signal ->  240 clk cycles per PWM cycle (`top`)
-        // with 50% duty cycle
-        .set_top(
-            ((clocks.system_clock.freq().to_Hz() as f32 / (SIGNAL_GEN_FREQ_HZ * sysclk_rescale))
-                - 1.0) as u16,
-        );
-    pwm_slices.pwm3.enable();
-    let mut signal_gen = pwm_slices.pwm3.channel_a;
-    signal_gen.output_to(pins.gpio22);
-    signal_gen.set_duty_cycle_percent(50).unwrap();
-    debug!("critical_section: transfer PWM control to mutex");
-    critical_section::with(|cs| SIGNAL_GEN.replace(cs, Some(signal_gen)));
-
-    // Setup ADC pins, DMA, buffers
-    let mut adc = Adc::new(pac.ADC, &mut pac.RESETS);
-    let mut adc_pin0 = AdcPin::new(pins.gpio26.into_floating_input()).unwrap();
-    let mut dma = pac.DMA.split(&mut pac.RESETS);
-    Buffers::init();
-
-    // Setup first transfer
-    let avg_buffer = create_avg_buffer().unwrap();
-    let mut readings_fifo = adc
-        .build_fifo()
-        .set_channel(&mut adc_pin0)
-        // Ex. 24 MHz clock at 200 ksamples/s (2x SIGNAL_FREQ_KHZ) -> sample every 120 clk cycles
-        .clock_divider(
-            ((clocks.system_clock.freq().to_Hz() as f32
-                / (2.0 * (SIGNAL_GEN_FREQ_HZ * sysclk_rescale)))
-                - 1.0) as u16,
-            0,
+/// Image definition, which the RP2350 boot ROM looks for to run the firmware as a secure Arm
+/// executable
+#[cfg(feature = "rp2350")]
+#[link_section = ".start_block"]
+#[used]
+pub static IMAGE_DEF: ImageDef = ImageDef::secure_exe();
+
+/// RTIC application. Tasks run at the following priorities:
+///
+/// 4. `IO_IRQ_BANK0`: power failure, tripping the interlock
+/// 3. `DMA_IRQ_0`: averaging
+/// 2. SysTick: watchdog, disable switch and acknowledge button
+/// 1. `USBCTRL_IRQ`: USB console, left out with `minimal`
+/// 0. idle: contact detection, on samples queued by `DMA_IRQ_0`, then status LEDs, logging and
+///    standby
+#[rtic::app(device = aps490_pfpu2_mini::hal::pac, peripherals = true)]
+mod app {
+    use core::iter;
+
+    use aps490_pfpu2_mini::{
+        board::Board,
+        buffer::Buffers,
+        config::Config,
+        deadline, events,
+        hal::Watchdog,
+        injection::Injector,
+        interrupt::{self, AckButton, DisableSwitch, SampleConsumer, SampleProducer, SampleQueue},
+        irq::{self, Isr},
+        liveness::{self, Task},
+        power::{self, DutyCycle},
+        reset,
+        sampler::{Sampler, SamplerControl},
+        scheduler::{self, Periodic, Scheduler, HEARTBEAT_PERIOD_MS, STATS_PERIOD_MS},
+        stack,
+        state::{StateMachine, SystemIndicators, SystemState},
+    };
+    #[cfg(not(feature = "minimal"))]
+    use aps490_pfpu2_mini::{
+        capture::Capture,
+        console::{Console, ConsoleBackend},
+        error::Result,
+        event_log,
+        interrupt::{ConsoleSerial, ConsoleUsbDevice},
+        journal::Kind,
+        uptime,
+        watch::{self, Snapshot},
+    };
+    use cortex_m::peripheral::syst::SystClkSource;
+    use defmt::info;
+    #[cfg(not(feature = "minimal"))]
+    use rtic::mutex_prelude::*;
+    use rtic::Mutex;
+
+    // RTIC asserts every resource type is `Send`, even for a resource that is left out
+    /// Stands in for the USB device left out of the `minimal` build
+    #[cfg(feature = "minimal")]
+    type ConsoleUsbDevice = ();
+    /// Stands in for the serial port left out of the `minimal` build
+    #[cfg(feature = "minimal")]
+    type ConsoleSerial = ();
+    /// Stands in for the console left out of the `minimal` build
+    #[cfg(feature = "minimal")]
+    type Console = ();
+
+    /// Jobs run by SysTick
+    #[derive(Copy, Clone)]
+    enum TickJob {
+        /// Feed the watchdog if every task has checked in
+        FeedWatchdog,
+        /// Check the disable switch
+        #[cfg(feature = "disable_switch")]
+        PollSwitch,
+        /// Check for the acknowledge button being held and the operator's presence, latch any
+        /// power failure, and wake the console while a `WATCH` is running
+        PollButton,
+    }
+    /// Number of [`TickJob`]s
+    const TICK_JOBS: usize = 2 + cfg!(feature = "disable_switch") as usize;
+
+    /// Jobs run by idle, between detection passes
+    #[derive(Copy, Clone)]
+    enum IdleJob {
+        /// Log the system state
+        Heartbeat,
+        /// Log timing statistics and stack headroom
+        Stats,
+    }
+
+    // Task priorities must match the table in `irq`
+    const _: () = assert!(Isr::IoIrqBank0.priority() == 4);
+    const _: () = assert!(Isr::DmaIrq0.priority() == 3);
+    const _: () = assert!(Isr::SysTick.priority() == 2);
+    #[cfg(not(feature = "minimal"))]
+    const _: () = assert!(Isr::UsbctrlIrq.priority() == 1);
+
+    /// Resources shared between tasks
+    #[shared]
+    struct Shared {
+        /// Signal generator and ADC transfer
+        sampler: Sampler,
+        /// Buffers for analyzing readings
+        buffers: &'static mut Buffers,
+        /// System state and interlock
+        state: StateMachine,
+        /// Active configuration, loaded from flash at startup
+        config: Config,
+    }
+
+    /// Resources owned by a single task
+    #[local]
+    struct Local {
+        /// Hardware watchdog, fed by SysTick
+        watchdog: Watchdog,
+        /// Jobs run by SysTick
+        tick_jobs: Scheduler<TickJob, TICK_JOBS>,
+        /// Jobs run by idle
+        idle_jobs: Scheduler<IdleJob, 2>,
+        /// Disable switch, polled by SysTick
+        disable_switch: DisableSwitch,
+        /// Acknowledge button, polled by SysTick
+        ack_button: AckButton,
+        /// USB device serving the console
+        #[cfg(not(feature = "minimal"))]
+        usb_dev: ConsoleUsbDevice,
+        /// USB serial port serving the console
+        #[cfg(not(feature = "minimal"))]
+        usb_serial: ConsoleSerial,
+        /// Command console state
+        #[cfg(not(feature = "minimal"))]
+        console: Console,
+        /// Queues samples from `DMA_IRQ_0`
+        sample_producer: SampleProducer,
+        /// Pops samples for detection in idle
+        sample_consumer: SampleConsumer,
+        /// Status LEDs and buzzer, updated in idle
+        indicators: SystemIndicators,
+        /// Test-signal injection, checked by idle
+        injector: Injector,
+    }
+
+    /// System initialization
+    #[init(local = [sample_queue: SampleQueue = SampleQueue::new()])]
+    fn init(cx: init::Context) -> (Shared, Local) {
+        info!("Detection system startup");
+        reset::decode();
+        let Board {
+            mut sampler,
+            buffers,
+            mut state,
+            indicators,
+            config,
+            self_test,
+            disable_switch,
+            ack_button,
+            injector,
+            #[cfg(not(feature = "minimal"))]
+            usb_dev,
+            #[cfg(not(feature = "minimal"))]
+            usb_serial,
+            system_clock_freq,
+            mut watchdog,
+            ..
+        } = Board::init(cx.device).unwrap();
+
+        // Configure and enable SysTick every 1 ms, for the watchdog and disable switch. It also
+        // wakes idle to run its jobs.
+        let mut syst = cx.core.SYST;
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(system_clock_freq.to_kHz() - 1);
+        syst.clear_current();
+        syst.enable_interrupt();
+        let tick_jobs = Scheduler::new([
+            Periodic::new(
+                TickJob::FeedWatchdog,
+                liveness::CHECK_IN_DEADLINE.to_millis(),
+            ),
+            #[cfg(feature = "disable_switch")]
+            Periodic::new(TickJob::PollSwitch, scheduler::SWITCH_POLL_PERIOD_MS),
+            Periodic::new(TickJob::PollButton, scheduler::SWITCH_POLL_PERIOD_MS),
+        ]);
+        let idle_jobs = Scheduler::new([
+            Periodic::new(IdleJob::Heartbeat, HEARTBEAT_PERIOD_MS),
+            Periodic::new(IdleJob::Stats, STATS_PERIOD_MS),
+        ]);
+
+        // Begin normal system operation once tasks are unmasked
+        let (sample_producer, sample_consumer) = cx.local.sample_queue.split();
+        state.begin(&mut sampler, self_test);
+        liveness::start(&mut watchdog);
+        (
+            Shared {
+                sampler,
+                buffers,
+                state,
+                config,
+            },
+            Local {
+                watchdog,
+                tick_jobs,
+                idle_jobs,
+                disable_switch,
+                ack_button,
+                #[cfg(not(feature = "minimal"))]
+                usb_dev,
+                #[cfg(not(feature = "minimal"))]
+                usb_serial,
+                #[cfg(not(feature = "minimal"))]
+                console: Console::new(),
+                sample_producer,
+                sample_consumer,
+                indicators,
+                injector,
+            },
         )
-        .shift_8bit()
-        .enable_dma()
-        .start_paused();
-    dma.ch0.enable_irq0();
-    let adc_dma_transfer =
-        single_buffer::Config::new(dma.ch0, readings_fifo.dma_read_target(), avg_buffer);
-    debug!("critical_section: transfer readings FIFO to mutex");
-    critical_section::with(|cs| READINGS_FIFO.replace(cs, Some(adc_dma_transfer.start())));
-    readings_fifo.resume();
-
-    // Configure and enable SysTick for disable switch
-    let disable_switch = pins.gpio9.into_pull_down_input();
-    disable_switch.set_schmitt_enabled(true); // Debouncing
-    debug!("critical_section: init disable switch");
-    critical_section::with(|cs| DISABLE_SWITCH.replace(cs, Some(disable_switch)));
-
-    let mut syst = core.SYST;
-    syst.set_clock_source(SystClkSource::Core); // 1 us per tick
-    syst.set_reload(20_000);
-    syst.clear_current();
-    #[cfg(feature = "disable_switch")]
-    syst.enable_interrupt();
-
-    // Begin normal system operation
-    critical_section::with(|cs| {
-        #[cfg(feature = "rgba_status")]
-        StatusLedBase::<Rgba>::set_normal(cs, Some("System initialization complete"));
-        #[cfg(feature = "triple_status")]
-        StatusLedBase::<Triple>::set_normal(cs, Some("System initialization complete"));
-    });
-    unsafe { pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0) }
-    loop {
-        // All functionality in interrupts
-        cortex_m::asm::wfi();
+    }
+
+    /// Runs contact detection on the queued samples in one batch and checks any test injection,
+    /// then logs any state changes, updates the indicators and runs any [`IdleJob`]s, sleeping
+    /// until the next interrupt. Time spent asleep is reported with the other statistics. Checks in
+    /// with the watchdog on every pass, which happens at least once per SysTick.
+    ///
+    /// In [`SystemState::Standby`], the chip is left [dormant](power::dormant) until woken, then
+    /// recalibrates.
+    ///
+    /// The sampler is only locked if the system state changes, so `DMA_IRQ_0` is never masked
+    /// while detection runs. The indicators are updated without holding any lock.
+    #[idle(
+        shared = [sampler, buffers, state],
+        local = [sample_consumer, indicators, idle_jobs, injector]
+    )]
+    fn idle(cx: idle::Context) -> ! {
+        let consumer = cx.local.sample_consumer;
+        let indicators = cx.local.indicators;
+        let jobs = cx.local.idle_jobs;
+        let injector = cx.local.injector;
+        let mut sampler = LockedSampler(cx.shared.sampler);
+        let mut buffers = cx.shared.buffers;
+        let mut state = cx.shared.state;
+        let mut duty_cycle = DutyCycle::new();
+        loop {
+            liveness::check_in(Task::Detection);
+            // Only the samples already queued, so the locks are released between batches
+            let pending = consumer.len();
+            if pending > 0 {
+                (&mut buffers, &mut state).lock(|buffers, state| {
+                    let samples = iter::from_fn(|| consumer.dequeue()).take(pending);
+                    interrupt::process_batch(samples, &mut sampler, buffers, state)
+                });
+            }
+            let current = (&mut buffers, &mut state).lock(|buffers, state| {
+                injector
+                    .poll(state.state(), buffers)
+                    .unwrap_or_else(|err| state.fail(&mut sampler, err));
+                state.state()
+            });
+            indicators.update(current);
+            events::dispatch();
+            if current == SystemState::Standby {
+                // The LEDs are already off, so only the usage totals and journal need saving before
+                // sleeping
+                #[cfg(not(feature = "minimal"))]
+                {
+                    uptime::report(current);
+                    event_log::flush(current);
+                }
+                let woken_by = power::dormant(power::WAKE_PINS);
+                #[cfg(not(feature = "minimal"))]
+                event_log::operator(Kind::Wake, power::wake_source(woken_by));
+                #[cfg(feature = "minimal")]
+                let _ = woken_by;
+                state.lock(|state| {
+                    state
+                        .transition(SystemState::Calibrating, &mut sampler, "Woke from standby")
+                        .unwrap_or_else(|err| state.fail(&mut sampler, err))
+                });
+                continue;
+            }
+            for job in jobs.due(scheduler::now_ms()) {
+                match job {
+                    IdleJob::Heartbeat => info!("Heartbeat: {}", current),
+                    IdleJob::Stats => {
+                        duty_cycle.log();
+                        #[cfg(not(feature = "minimal"))]
+                        {
+                            uptime::report(current);
+                            event_log::flush(current);
+                        }
+                        deadline::log_worst_case();
+                        #[cfg(feature = "irq_timing")]
+                        irq::log_worst_case();
+                        #[cfg(feature = "perf")]
+                        aps490_pfpu2_mini::perf::log_stats();
+                        stack::check().unwrap_or_else(|err| {
+                            state.lock(|state| state.fail(&mut sampler, err));
+                        });
+                    }
+                }
+            }
+            // Interrupts stay pending while masked, so a sample queued after the check still wakes
+            // the core
+            cortex_m::interrupt::free(|_| {
+                if !consumer.ready() {
+                    duty_cycle.sleep();
+                }
+            });
+        }
+    }
+
+    /// Trips the interlock as soon as the supply starts to fail
+    #[task(binds = IO_IRQ_BANK0, priority = 4)]
+    fn io_irq_bank0(_: io_irq_bank0::Context) {
+        irq::timed(Isr::IoIrqBank0, power::on_power_fail);
+    }
+
+    /// Reads ADC values, calculates averages and queues them for detection
+    #[task(binds = DMA_IRQ_0, priority = 3, shared = [sampler], local = [sample_producer])]
+    fn dma_irq_0(mut cx: dma_irq_0::Context) {
+        let producer = cx.local.sample_producer;
+        irq::timed(Isr::DmaIrq0, || {
+            cx.shared
+                .sampler
+                .lock(|sampler| interrupt::queue_readings(sampler, producer))
+        });
+    }
+
+    /// Runs the [`TickJob`]s: feeds the watchdog if every task has checked in, and checks the
+    /// disable switch and acknowledge button
+    #[task(
+        binds = SysTick,
+        priority = 2,
+        shared = [sampler, buffers, state],
+        local = [watchdog, tick_jobs, disable_switch, ack_button]
+    )]
+    fn sys_tick(mut cx: sys_tick::Context) {
+        irq::timed(Isr::SysTick, || {
+            for job in cx.local.tick_jobs.due(scheduler::now_ms()) {
+                match job {
+                    TickJob::FeedWatchdog => {
+                        liveness::feed_if_alive(cx.local.watchdog);
+                    }
+                    #[cfg(feature = "disable_switch")]
+                    TickJob::PollSwitch => {
+                        let switch = &mut *cx.local.disable_switch;
+                        (&mut cx.shared.sampler, &mut cx.shared.state).lock(|sampler, state| {
+                            interrupt::check_disable_switch(switch, sampler, state)
+                                .unwrap_or_else(|err| state.fail(sampler, err))
+                        });
+                    }
+                    TickJob::PollButton => {
+                        let button = &mut *cx.local.ack_button;
+                        let (snooze_ms, timing) = cx
+                            .shared
+                            .buffers
+                            .lock(|buffers| (buffers.snooze_ms(), buffers.sequence_timing()));
+                        (&mut cx.shared.sampler, &mut cx.shared.state).lock(|sampler, state| {
+                            interrupt::check_power_good(state)
+                                .and_then(|()| interrupt::check_stretch(state))
+                                .and_then(|()| interrupt::check_sequence(state, timing))
+                                .and_then(|()| interrupt::check_presence(sampler, state))
+                                .and_then(|()| {
+                                    interrupt::check_ack_button(
+                                        button,
+                                        scheduler::SWITCH_POLL_PERIOD_MS,
+                                        snooze_ms,
+                                        sampler,
+                                        state,
+                                    )
+                                })
+                                .unwrap_or_else(|err| state.fail(sampler, err))
+                        });
+                        #[cfg(not(feature = "minimal"))]
+                        watch::wake();
+                    }
+                }
+            }
+        });
+    }
+
+    /// Serves the USB console
+    #[cfg(not(feature = "minimal"))]
+    #[task(
+        binds = USBCTRL_IRQ,
+        priority = 1,
+        shared = [sampler, buffers, state, config],
+        local = [usb_dev, usb_serial, console]
+    )]
+    fn usbctrl_irq(mut cx: usbctrl_irq::Context) {
+        irq::timed(Isr::UsbctrlIrq, || {
+            interrupt::poll_console(
+                cx.local.usb_dev,
+                cx.local.usb_serial,
+                cx.local.console,
+                &mut cx.shared,
+            )
+        });
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    impl ConsoleBackend for usbctrl_irq::SharedResources<'_> {
+        fn config(&mut self) -> Config {
+            self.config.lock(|config| *config)
+        }
+
+        fn apply_config(&mut self, config: Config) {
+            self.config.lock(|active| *active = config);
+            self.buffers
+                .lock(|buffers| buffers.set_detection_config(config.detection));
+        }
+
+        fn enter_standby(&mut self) -> Result<()> {
+            (&mut self.sampler, &mut self.state).lock(|sampler, state| {
+                state.transition(
+                    SystemState::Standby,
+                    sampler,
+                    "Standby requested by console",
+                )
+            })
+        }
+
+        fn reset_latch(&mut self) -> Result<()> {
+            (&mut self.sampler, &mut self.state)
+                .lock(|sampler, state| state.reset_latch(sampler, "Latched alert reset by console"))
+        }
+
+        fn snooze(&mut self, duration_ms: u32) -> bool {
+            self.state.lock(|state| state.snooze(duration_ms))
+        }
+
+        fn snapshot(&mut self) -> Snapshot {
+            (&mut self.buffers, &mut self.state)
+                .lock(|buffers, state| Snapshot::take(buffers, state))
+        }
+
+        fn capture(&mut self, samples: usize) -> Capture {
+            self.buffers.lock(|buffers| Capture::take(buffers, samples))
+        }
+
+        fn can_write_flash(&mut self) -> bool {
+            !self.state.lock(|state| state.state().sampling())
+        }
+    }
+
+    /// Shared [`Sampler`], locked only when it needs to be paused or resumed
+    struct LockedSampler<M>(M);
+
+    impl<M: Mutex<T = Sampler>> SamplerControl for LockedSampler<M> {
+        fn pause(&mut self) {
+            self.0.lock(|sampler| sampler.pause());
+        }
+
+        fn resume(&mut self) {
+            self.0.lock(|sampler| sampler.resume());
+        }
     }
 }
@@ -0,0 +1,306 @@
+//! Manufacturing test mode, with the `mfg_test` feature, so an assembled board can be verified by
+//! a fixture without the full sensing rig.
+//!
+//! [`Board::init`](crate::board::Board::init) checks whether the acknowledge button is held at
+//! power-up ([`requested`]). If it is, [`run`] takes over once the USB console is set up, instead
+//! of returning, so neither detection nor the watchdog ever start. Each cycle of the test:
+//!
+//! 1. Toggles each configured output in turn for [`STEP_MS`]: the status LEDs, the interlocks, the
+//!    buzzer and the test injection output. Every output pad is read back while one is toggled, so
+//!    a pin stuck at either level, or shorted to another output, fails.
+//! 2. Sweeps the buzzer from [`SWEEP_START_HZ`] to [`SWEEP_END_HZ`], for a microphone on the
+//!    fixture.
+//! 3. Echoes the level of every input, then each change, until any byte is received on the
+//!    console, which starts the next cycle.
+//!
+//! Results are printed on the USB console, one line per check, and logged:
+//!
+//! ```text
+//! MFG TEST proto-v2
+//! OUT status_led_0 GPIO6 PASS
+//! OUT interlock GPIO10 FAIL read 00000540 expected 00000440
+//! OUTPUTS FAIL 1
+//! BUZZER 500-4000 Hz
+//! IN ack_button GPIO12 1
+//! ```
+//!
+//! The first cycle starts once the host opens the port, or after [`WAIT_HOST_MS`]. Between steps,
+//! the outputs are held in the [safe state](crate::safe_state) with every LED off, but each
+//! interlock is released while it is tested: only enter this mode on the fixture, with no saw
+//! connected. Reset the board to leave it.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt::{self, Write};
+
+use defmt::{info, warn};
+use embedded_hal::digital::PinState;
+use heapless::String;
+
+use crate::{
+    board::Timer,
+    buffer::PROBES,
+    components::{LedControl, StatusLeds},
+    config::board::{
+        ACK_BUTTON_PIN, BLANKING_PIN, BOARD_VARIANT, BUZZER_PIN, CONFIG_JUMPER_PIN,
+        CORRELATION_PIN, DISABLE_SWITCH_PIN, INTERLOCK_PINS, INTERLOCK_TRIPPED_LEVEL,
+        POWER_GOOD_PIN, PRESENCE_PIN, PROBE_LED_PINS, STATUS_LED_PINS, TEST_INJECT_PIN,
+        TRIGGER_PIN,
+    },
+    hal::pac,
+    interrupt::{self, ConsoleSerial, ConsoleUsbDevice},
+};
+
+/// How long the acknowledge button must be held at power-up to enter the test
+pub const ENTRY_HOLD_MS: u32 = 100;
+/// Longest wait for the host to open the console before the first cycle starts
+pub const WAIT_HOST_MS: u32 = 5_000;
+/// How long each output is toggled for, long enough for the fixture to measure it
+pub const STEP_MS: u32 = 500;
+/// First buzzer frequency of the sweep
+pub const SWEEP_START_HZ: u32 = 500;
+/// Last buzzer frequency of the sweep
+pub const SWEEP_END_HZ: u32 = 4_000;
+/// Increase in buzzer frequency between each tone of the sweep
+pub const SWEEP_STEP_HZ: u32 = 250;
+/// How long each tone of the sweep lasts
+pub const SWEEP_STEP_MS: u32 = 100;
+/// Interval between samples of the inputs while echoing them
+pub const ECHO_POLL_MS: u32 = 10;
+
+/// Longest line printed, without the line ending
+const LINE_SIZE: usize = 96;
+
+/// Names of the status LEDs, as in the board's pin map
+const STATUS_LED_NAMES: [&str; 3] = ["status_led_0", "status_led_1", "status_led_2"];
+/// Names of each station's interlock, as in the board's pin map
+const INTERLOCK_NAMES: [&str; 2] = ["interlock", "interlock_b"];
+/// Names of each station's probe LED, as in the board's pin map
+const PROBE_LED_NAMES: [&str; 2] = ["probe_led", "probe_led_b"];
+/// Name and GPIO number of each input, echoed in the last step
+const INPUTS: [(&str, u8); 7] = [
+    ("disable_switch", DISABLE_SWITCH_PIN),
+    ("ack_button", ACK_BUTTON_PIN),
+    ("power_good", POWER_GOOD_PIN),
+    ("config_jumper", CONFIG_JUMPER_PIN),
+    ("presence", PRESENCE_PIN),
+    ("blanking", BLANKING_PIN),
+    ("trigger", TRIGGER_PIN),
+];
+
+/// Name and GPIO number of each configured output, in the order they are tested
+fn outputs() -> impl Iterator<Item = (&'static str, u8)> {
+    STATUS_LED_NAMES
+        .into_iter()
+        .zip(STATUS_LED_PINS)
+        .chain(INTERLOCK_NAMES.into_iter().zip(INTERLOCK_PINS).take(PROBES))
+        .chain(PROBE_LED_NAMES.into_iter().zip(PROBE_LED_PINS).take(PROBES))
+        .chain([
+            ("buzzer", BUZZER_PIN),
+            ("test_inject", TEST_INJECT_PIN),
+            ("correlation", CORRELATION_PIN),
+        ])
+}
+
+/// Mask of every configured output
+fn output_mask() -> u32 {
+    outputs().fold(0, |mask, (_, pin)| mask | 1 << pin)
+}
+
+/// Outputs driven high between steps: the interlocks tripped, and the LEDs off. Open-drain
+/// interlocks are driven like the other outputs, at the level of their pull-up when high.
+fn idle_levels() -> u32 {
+    let mut high = 0;
+    if matches!(INTERLOCK_TRIPPED_LEVEL, PinState::High) {
+        high |= INTERLOCK_PINS[..PROBES]
+            .iter()
+            .fold(0, |mask, pin| mask | 1 << pin);
+    }
+    if matches!(StatusLeds::ERROR_LED.1, PinState::Low) {
+        high |= STATUS_LED_PINS.iter().fold(0, |mask, pin| mask | 1 << pin);
+    }
+    high
+}
+
+/// Level of every GPIO pad
+fn levels() -> u32 {
+    // SAFETY: read-only access to the SIO GPIO input register
+    let sio = unsafe { &*pac::SIO::ptr() };
+    sio.gpio_in().read().bits()
+}
+
+/// Drive the outputs in `mask` to `high`
+fn drive(mask: u32, high: u32) {
+    // SAFETY: the set and clear registers are write-1 atomic, and every output is already enabled
+    // by its owner in `Board::init`, which never runs them once the test has started
+    unsafe {
+        let sio = &*pac::SIO::ptr();
+        sio.gpio_out_set().write(|w| w.bits(mask & high));
+        sio.gpio_out_clr().write(|w| w.bits(mask & !high));
+    }
+}
+
+/// Whether the acknowledge button is held for [`ENTRY_HOLD_MS`]. Returns as soon as it isn't, so a
+/// normal boot isn't delayed.
+pub fn requested(timer: &Timer) -> bool {
+    let start = timer.get_counter_low();
+    while timer.get_counter_low().wrapping_sub(start) < ENTRY_HOLD_MS * 1000 {
+        if levels() & 1 << ACK_BUTTON_PIN == 0 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Run the test cycle until reset, reporting on the console served by `usb_dev` and `serial`
+pub fn run(usb_dev: ConsoleUsbDevice, serial: ConsoleSerial, timer: Timer) -> ! {
+    warn!("Manufacturing test mode, detection is not running");
+    let mut fixture = Fixture {
+        usb_dev,
+        serial,
+        timer,
+        restart: false,
+    };
+    drive(output_mask(), idle_levels());
+    fixture.wait_for_host();
+    loop {
+        fixture.restart = false;
+        fixture.print(format_args!("MFG TEST {}", BOARD_VARIANT));
+        fixture.test_outputs();
+        fixture.sweep_buzzer();
+        fixture.echo_inputs();
+    }
+}
+
+/// Console and timer used by the test
+struct Fixture {
+    /// USB device serving the console
+    usb_dev: ConsoleUsbDevice,
+    /// USB serial port serving the console
+    serial: ConsoleSerial,
+    /// Microsecond timer
+    timer: Timer,
+    /// Whether a byte was received, to start the next cycle
+    restart: bool,
+}
+
+impl Fixture {
+    /// Service the USB device, noting any byte received
+    fn poll(&mut self) {
+        if self.usb_dev.poll(&mut [&mut self.serial]) {
+            let mut rx_buf = [0u8; 64];
+            if self.serial.read(&mut rx_buf).is_ok_and(|count| count > 0) {
+                self.restart = true;
+            }
+        }
+    }
+
+    /// Keep servicing the USB device for `duration_ms`
+    fn wait_ms(&mut self, duration_ms: u32) {
+        let start = self.timer.get_counter_low();
+        while self.timer.get_counter_low().wrapping_sub(start) < duration_ms * 1000 {
+            self.poll();
+        }
+    }
+
+    /// Wait until the host opens the console, or [`WAIT_HOST_MS`] pass
+    fn wait_for_host(&mut self) {
+        let start = self.timer.get_counter_low();
+        while !self.serial.dtr()
+            && self.timer.get_counter_low().wrapping_sub(start) < WAIT_HOST_MS * 1000
+        {
+            self.poll();
+        }
+    }
+
+    /// Log a line, and print it on the console
+    fn print(&mut self, args: fmt::Arguments) {
+        let mut line = String::<LINE_SIZE>::new();
+        let _ = line.write_fmt(args);
+        info!("{=str}", line.as_str());
+        interrupt::write_serial(&mut self.usb_dev, &mut self.serial, line.as_bytes());
+        interrupt::write_serial(&mut self.usb_dev, &mut self.serial, b"\r\n");
+    }
+
+    /// Toggle each output in turn, checking every output pad reads back as driven
+    fn test_outputs(&mut self) {
+        let mask = output_mask();
+        let idle = idle_levels();
+        let mut failures = 0;
+        for (name, pin) in outputs() {
+            let expected = idle ^ 1 << pin;
+            drive(mask, expected);
+            self.wait_ms(STEP_MS);
+            let read = levels() & mask;
+            drive(mask, idle);
+            if read == expected {
+                self.print(format_args!("OUT {} GPIO{} PASS", name, pin));
+            } else {
+                failures += 1;
+                self.print(format_args!(
+                    "OUT {} GPIO{} FAIL read {:08X} expected {:08X}",
+                    name, pin, read, expected
+                ));
+            }
+        }
+        if failures == 0 {
+            self.print(format_args!("OUTPUTS PASS"));
+        } else {
+            self.print(format_args!("OUTPUTS FAIL {}", failures));
+        }
+    }
+
+    /// Play each tone of the sweep on the buzzer, then silence it
+    fn sweep_buzzer(&mut self) {
+        self.print(format_args!(
+            "BUZZER {}-{} Hz",
+            SWEEP_START_HZ, SWEEP_END_HZ
+        ));
+        let buzzer = 1 << BUZZER_PIN;
+        for freq_hz in (SWEEP_START_HZ..=SWEEP_END_HZ).step_by(SWEEP_STEP_HZ as usize) {
+            let half_period_us = 500_000 / freq_hz;
+            let start = self.timer.get_counter_low();
+            let mut toggled = start;
+            let mut high = 0;
+            while self.timer.get_counter_low().wrapping_sub(start) < SWEEP_STEP_MS * 1000 {
+                if self.timer.get_counter_low().wrapping_sub(toggled) >= half_period_us {
+                    toggled = toggled.wrapping_add(half_period_us);
+                    high ^= buzzer;
+                    drive(buzzer, high);
+                }
+                self.poll();
+            }
+        }
+        drive(buzzer, 0);
+    }
+
+    /// Print the level of every input, then each change, until a byte is received
+    fn echo_inputs(&mut self) {
+        self.print(format_args!("INPUTS send any byte to repeat the test"));
+        // Every input differs from the inverse, so the first sample prints them all
+        let mut previous = !levels();
+        while !self.restart {
+            let current = levels();
+            for (name, pin) in INPUTS {
+                if (previous ^ current) & 1 << pin != 0 {
+                    let level = current >> pin & 1;
+                    self.print(format_args!("IN {} GPIO{} {}", name, pin, level));
+                }
+            }
+            previous = current;
+            self.wait_ms(ECHO_POLL_MS);
+        }
+    }
+}
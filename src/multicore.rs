@@ -0,0 +1,456 @@
+//! Runs detection, status LEDs and the USB console on core1, so core0 only handles acquisition.
+//!
+//! Core0 completes each ADC transfer and sends the averaged sample to core1 over the SIO FIFO.
+//! Core1 records samples and checks for contact, and sends [`ToCore0`] commands back when the
+//! system state requires sampling to be paused or resumed. Nothing on core1 can delay the
+//! `DMA_IRQ_0` handler.
+//!
+//! Core0 keeps executing from flash, including the power-fail handler, so core1 never writes it.
+//! The console refuses every command that would (see [`Command::writes_flash`]). Journalled events
+//! and the usage totals stay in RAM, and are lost on reset (see [`event_log`] and [`uptime`]).
+//! Change the stored configuration with the single-core binary.
+//!
+//! [`Command::writes_flash`]: crate::console::Command::writes_flash
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::{info, warn, Format};
+
+#[cfg(feature = "disable_switch")]
+use crate::interrupt::DisableSwitch;
+use crate::{
+    buffer::{Buffers, TransferSamples, ADC_CHANNELS, PROBES},
+    capture::Capture,
+    config::Config,
+    console::{Console, ConsoleBackend},
+    deadline::{self, Stage, Stamped},
+    error::{Error, Result},
+    event_log, events,
+    hal::sio::SioFifo,
+    injection::Injector,
+    interrupt::{self, AckButton, ConsoleSerial, ConsoleUsbDevice},
+    journal::Kind,
+    power,
+    probe::ProbeFault,
+    sampler::{Sampler, SamplerControl},
+    scheduler::{self, Periodic, Scheduler, HEARTBEAT_PERIOD_MS, STATS_PERIOD_MS},
+    stack,
+    state::{StateMachine, SystemIndicators, SystemState},
+    uptime,
+    watch::Snapshot,
+};
+
+/// Size of the core1 stack, in words
+pub const CORE1_STACK_WORDS: usize = 4096;
+
+/// Messages sent from core0 to core1
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum ToCore1 {
+    /// Averaged sample from each probe, or each channel with `triple_channel`, from the latest
+    /// transfer
+    Sample(TransferSamples),
+    /// No ADC transfer was in progress
+    TransferMissing,
+    /// Averaging repeatedly ran over its [deadline](crate::deadline)
+    AverageLate,
+    /// The readings show the probe has been disconnected
+    ProbeDisconnected(ProbeFault),
+    /// The two ADC channels disagree
+    ChannelMismatch,
+}
+
+// The samples of every probe, or every channel with `triple_channel`, fit below the flag bit of an
+// encoded sample
+const _: () = assert!(PROBES < 4 && ADC_CHANNELS < 4);
+
+impl ToCore1 {
+    /// Flag set in the encoded value of a [`ToCore1::Sample`], with the sample of each probe in
+    /// the bytes below it, from the lowest. With `triple_channel`, the bytes hold the sample of each
+    /// channel, and the low bits of the top byte mark the channels that have failed.
+    const SAMPLE: u32 = 1 << 31;
+    /// Encoded value of [`ToCore1::TransferMissing`]
+    const TRANSFER_MISSING: u32 = 0x100;
+    /// Encoded value of [`ToCore1::AverageLate`]
+    const AVERAGE_LATE: u32 = 0x101;
+    /// Encoded value of [`ToCore1::ProbeDisconnected`] with [`ProbeFault::Railed`]
+    const PROBE_RAILED: u32 = 0x102;
+    /// Encoded value of [`ToCore1::ProbeDisconnected`] with [`ProbeFault::Floating`]
+    const PROBE_FLOATING: u32 = 0x103;
+    /// Encoded value of [`ToCore1::ChannelMismatch`]
+    const CHANNEL_MISMATCH: u32 = 0x104;
+
+    /// Encode as a FIFO word
+    pub fn encode(self) -> u32 {
+        match self {
+            ToCore1::Sample(samples) => Self::SAMPLE | encode_samples(samples),
+            ToCore1::TransferMissing => Self::TRANSFER_MISSING,
+            ToCore1::AverageLate => Self::AVERAGE_LATE,
+            ToCore1::ProbeDisconnected(ProbeFault::Railed) => Self::PROBE_RAILED,
+            ToCore1::ProbeDisconnected(ProbeFault::Floating) => Self::PROBE_FLOATING,
+            ToCore1::ChannelMismatch => Self::CHANNEL_MISMATCH,
+        }
+    }
+
+    /// Decode a FIFO word, returning [`None`] if it is not a valid message
+    pub fn decode(word: u32) -> Option<Self> {
+        match word {
+            word if word & Self::SAMPLE != 0 => {
+                decode_samples(word & !Self::SAMPLE).map(ToCore1::Sample)
+            }
+            Self::TRANSFER_MISSING => Some(ToCore1::TransferMissing),
+            Self::AVERAGE_LATE => Some(ToCore1::AverageLate),
+            Self::PROBE_RAILED => Some(ToCore1::ProbeDisconnected(ProbeFault::Railed)),
+            Self::PROBE_FLOATING => Some(ToCore1::ProbeDisconnected(ProbeFault::Floating)),
+            Self::CHANNEL_MISMATCH => Some(ToCore1::ChannelMismatch),
+            _ => None,
+        }
+    }
+}
+
+/// Pack the sample of each probe into the bytes of a word, from the lowest
+#[cfg(not(feature = "triple_channel"))]
+fn encode_samples(samples: TransferSamples) -> u32 {
+    let mut bytes = [0; 4];
+    bytes[..PROBES].copy_from_slice(&samples);
+    u32::from_le_bytes(bytes)
+}
+
+/// Pack the sample of each channel into the bytes of a word, from the lowest, marking failed
+/// channels in the top byte
+#[cfg(feature = "triple_channel")]
+fn encode_samples(samples: TransferSamples) -> u32 {
+    let mut bytes = [0; 4];
+    for (channel, sample) in samples.into_iter().enumerate() {
+        match sample {
+            Some(sample) => bytes[channel] = sample,
+            None => bytes[3] |= 1 << channel,
+        }
+    }
+    u32::from_le_bytes(bytes)
+}
+
+/// Unpack the samples packed by [`encode_samples`], returning [`None`] if any unused byte is set
+#[cfg(not(feature = "triple_channel"))]
+fn decode_samples(word: u32) -> Option<TransferSamples> {
+    let bytes = word.to_le_bytes();
+    bytes[PROBES..]
+        .iter()
+        .all(|&byte| byte == 0)
+        .then(|| core::array::from_fn(|probe| bytes[probe]))
+}
+
+/// Unpack the samples packed by [`encode_samples`], returning [`None`] if any unused bit of the
+/// top byte is set
+#[cfg(feature = "triple_channel")]
+fn decode_samples(word: u32) -> Option<TransferSamples> {
+    let bytes = word.to_le_bytes();
+    let failed = bytes[3];
+    (failed >> ADC_CHANNELS == 0).then(|| {
+        core::array::from_fn(|channel| (failed & 1 << channel == 0).then_some(bytes[channel]))
+    })
+}
+
+/// Messages sent from core1 to core0
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum ToCore0 {
+    /// Call [`Sampler::pause`]
+    Pause,
+    /// Call [`Sampler::resume`]
+    Resume,
+}
+
+impl ToCore0 {
+    /// Encode as a FIFO word
+    pub fn encode(self) -> u32 {
+        match self {
+            ToCore0::Pause => 0,
+            ToCore0::Resume => 1,
+        }
+    }
+
+    /// Decode a FIFO word, returning [`None`] if it is not a valid message
+    pub fn decode(word: u32) -> Option<Self> {
+        match word {
+            0 => Some(ToCore0::Pause),
+            1 => Some(ToCore0::Resume),
+            _ => None,
+        }
+    }
+}
+
+/// Handler for `DMA_IRQ_0` on core0: completes the transfer and sends the sample to core1.
+///
+/// If the FIFO is full, the sample is dropped rather than waiting for core1.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+pub fn send_readings(sampler: &mut Sampler, fifo: &mut SioFifo) {
+    let msg = match sampler.complete_transfer() {
+        Ok(samples) => ToCore1::Sample(samples),
+        Err(Error::DeadlineMissed(_)) => ToCore1::AverageLate,
+        Err(Error::ProbeDisconnected(fault)) => ToCore1::ProbeDisconnected(fault),
+        Err(Error::ChannelMismatch) => ToCore1::ChannelMismatch,
+        Err(_) => ToCore1::TransferMissing,
+    };
+    if fifo.is_write_ready() {
+        fifo.write(msg.encode());
+    } else {
+        warn!("Core1 FIFO full, dropping {}", msg);
+    }
+}
+
+/// Handler for `SIO_IRQ_PROC0` on core0: runs commands received from core1.
+pub fn receive_commands(sampler: &mut Sampler, fifo: &mut SioFifo) {
+    while let Some(word) = fifo.read() {
+        match ToCore0::decode(word) {
+            Some(ToCore0::Pause) => sampler.pause(),
+            Some(ToCore0::Resume) => sampler.resume(),
+            None => warn!("Invalid message from core1: {=u32:#x}", word),
+        }
+    }
+}
+
+/// Proxy for the [`Sampler`] owned by core0, which sends it [`ToCore0`] commands
+pub struct RemoteSampler {
+    /// Core1 end of the SIO FIFO
+    fifo: SioFifo,
+}
+
+impl RemoteSampler {
+    /// Take the core1 end of the SIO FIFO
+    pub fn new(fifo: SioFifo) -> Self {
+        Self { fifo }
+    }
+
+    /// Receive the next message from core0, if any
+    pub fn receive(&mut self) -> Option<ToCore1> {
+        let word = self.fifo.read()?;
+        let msg = ToCore1::decode(word);
+        if msg.is_none() {
+            warn!("Invalid message from core0: {=u32:#x}", word);
+        }
+        msg
+    }
+}
+
+impl SamplerControl for RemoteSampler {
+    fn pause(&mut self) {
+        self.fifo.write_blocking(ToCore0::Pause.encode());
+    }
+
+    fn resume(&mut self) {
+        self.fifo.write_blocking(ToCore0::Resume.encode());
+    }
+}
+
+/// Resources moved to core1
+pub struct Core1 {
+    /// Buffers for analyzing readings
+    pub buffers: &'static mut Buffers,
+    /// System state and interlock
+    pub state: StateMachine,
+    /// Status LEDs and buzzer
+    pub indicators: SystemIndicators,
+    /// Active configuration
+    pub config: Config,
+    /// USB device serving the console
+    pub usb_dev: ConsoleUsbDevice,
+    /// USB serial port serving the console
+    pub usb_serial: ConsoleSerial,
+    /// Disable switch input
+    #[cfg(feature = "disable_switch")]
+    pub disable_switch: DisableSwitch,
+    /// Acknowledge button input
+    pub ack_button: AckButton,
+    /// Test-signal injection output
+    pub injector: Injector,
+}
+
+/// Jobs run by the core1 loop, between samples
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Format)]
+enum Core1Job {
+    /// Check the disable switch
+    #[cfg(feature = "disable_switch")]
+    PollSwitch,
+    /// Check for the acknowledge button being held and the operator's presence, and latch any
+    /// power failure
+    PollButton,
+    /// Log the system state
+    Heartbeat,
+    /// Log timing statistics, and the core0 stack headroom
+    Stats,
+}
+/// Number of [`Core1Job`]s
+const CORE1_JOBS: usize = 3 + cfg!(feature = "disable_switch") as usize;
+
+impl Core1 {
+    /// Core1 main loop. Processes samples from core0, and updates the indicators, polls the USB
+    /// console and runs any [`Core1Job`]s between them. In [`SystemState::Standby`], leaves the
+    /// chip [dormant](power::dormant) until woken.
+    pub fn run(self, mut remote: RemoteSampler) -> ! {
+        let Core1 {
+            buffers,
+            mut state,
+            mut indicators,
+            mut config,
+            mut usb_dev,
+            mut usb_serial,
+            #[cfg(feature = "disable_switch")]
+            mut disable_switch,
+            mut ack_button,
+            mut injector,
+        } = self;
+        #[cfg(feature = "perf")]
+        crate::perf::start_counter();
+        let mut console = Console::new();
+        let mut jobs: Scheduler<Core1Job, CORE1_JOBS> = Scheduler::new([
+            #[cfg(feature = "disable_switch")]
+            Periodic::new(Core1Job::PollSwitch, scheduler::SWITCH_POLL_PERIOD_MS),
+            Periodic::new(Core1Job::PollButton, scheduler::SWITCH_POLL_PERIOD_MS),
+            Periodic::new(Core1Job::Heartbeat, HEARTBEAT_PERIOD_MS),
+            Periodic::new(Core1Job::Stats, STATS_PERIOD_MS),
+        ]);
+
+        loop {
+            while let Some(msg) = remote.receive() {
+                // Time spent in the FIFO isn't counted towards the pipeline deadline
+                let sample = Stamped::now(match msg {
+                    ToCore1::Sample(samples) => Ok(samples),
+                    ToCore1::TransferMissing => Err(Error::NoTransfer),
+                    ToCore1::AverageLate => Err(Error::DeadlineMissed(Stage::Average)),
+                    ToCore1::ProbeDisconnected(fault) => Err(Error::ProbeDisconnected(fault)),
+                    ToCore1::ChannelMismatch => Err(Error::ChannelMismatch),
+                });
+                interrupt::process_queued(sample, &mut remote, buffers, &mut state)
+                    .unwrap_or_else(|err| state.fail(&mut remote, err));
+            }
+            injector
+                .poll(state.state(), buffers)
+                .unwrap_or_else(|err| state.fail(&mut remote, err));
+
+            for job in jobs.due(scheduler::now_ms()) {
+                match job {
+                    #[cfg(feature = "disable_switch")]
+                    Core1Job::PollSwitch => interrupt::check_disable_switch(
+                        &mut disable_switch,
+                        &mut remote,
+                        &mut state,
+                    )
+                    .unwrap_or_else(|err| state.fail(&mut remote, err)),
+                    Core1Job::PollButton => interrupt::check_power_good(&state)
+                        .and_then(|()| interrupt::check_stretch(&mut state))
+                        .and_then(|()| {
+                            interrupt::check_sequence(
+                                &mut state,
+                                config.detection.sequence_timing(),
+                            )
+                        })
+                        .and_then(|()| interrupt::check_presence(&mut remote, &mut state))
+                        .and_then(|()| {
+                            interrupt::check_ack_button(
+                                &mut ack_button,
+                                scheduler::SWITCH_POLL_PERIOD_MS,
+                                config.detection.snooze_ms,
+                                &mut remote,
+                                &mut state,
+                            )
+                        })
+                        .unwrap_or_else(|err| state.fail(&mut remote, err)),
+                    Core1Job::Heartbeat => info!("Heartbeat: {}", state.state()),
+                    Core1Job::Stats => {
+                        deadline::log_worst_case();
+                        uptime::log_stats();
+                        #[cfg(feature = "perf")]
+                        crate::perf::log_stats();
+                        stack::check().unwrap_or_else(|err| state.fail(&mut remote, err));
+                    }
+                }
+            }
+
+            indicators.update(state.state());
+            events::dispatch();
+            if state.state() == SystemState::Standby {
+                let woken_by = power::dormant(power::WAKE_PINS);
+                event_log::operator(Kind::Wake, power::wake_source(woken_by));
+                state
+                    .transition(SystemState::Calibrating, &mut remote, "Woke from standby")
+                    .unwrap_or_else(|err| state.fail(&mut remote, err));
+            }
+            interrupt::poll_console(
+                &mut usb_dev,
+                &mut usb_serial,
+                &mut console,
+                &mut Core1Backend {
+                    config: &mut config,
+                    buffers,
+                    state: &mut state,
+                    sampler: &mut remote,
+                },
+            );
+        }
+    }
+}
+
+/// [`ConsoleBackend`] for the resources owned by core1
+struct Core1Backend<'a> {
+    /// Active configuration
+    config: &'a mut Config,
+    /// Buffers for analyzing readings
+    buffers: &'a mut Buffers,
+    /// System state and interlock
+    state: &'a mut StateMachine,
+    /// Sampler on core0
+    sampler: &'a mut RemoteSampler,
+}
+
+impl ConsoleBackend for Core1Backend<'_> {
+    fn config(&mut self) -> Config {
+        *self.config
+    }
+
+    fn apply_config(&mut self, config: Config) {
+        *self.config = config;
+        self.buffers.set_detection_config(config.detection);
+    }
+
+    fn enter_standby(&mut self) -> Result<()> {
+        self.state.transition(
+            SystemState::Standby,
+            self.sampler,
+            "Standby requested by console",
+        )
+    }
+
+    fn reset_latch(&mut self) -> Result<()> {
+        self.state
+            .reset_latch(self.sampler, "Latched alert reset by console")
+    }
+
+    fn snooze(&mut self, duration_ms: u32) -> bool {
+        self.state.snooze(duration_ms)
+    }
+
+    fn snapshot(&mut self) -> Snapshot {
+        Snapshot::take(self.buffers, self.state)
+    }
+
+    fn capture(&mut self, samples: usize) -> Capture {
+        Capture::take(self.buffers, samples)
+    }
+
+    /// Never, as core0 keeps executing from flash
+    fn can_write_flash(&mut self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,101 @@
+//! Near misses: changes that came close to the trigger threshold without detecting contact.
+//!
+//! While armed, each sample that changed by at least
+//! [`DetectionConfig::near_miss_percent`](crate::config::DetectionConfig::near_miss_percent) of
+//! the trigger threshold, but didn't confirm contact, is counted for its probe, and kept with its
+//! time in a ring of the most recent [`RECENT_SIZE`]. A healthy probe rarely comes near the
+//! threshold, so near misses trending upwards show one degrading before it causes a false or
+//! missed detection. Both are printed by the `NEAR MISS` console command.
+//!
+//! Near misses are kept apart from the [journal](crate::event_log), so a noisy probe can't fill
+//! it, and are lost on reboot.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use defmt::Format;
+use heapless::Deque;
+
+use crate::{
+    buffer::{Buffers, PROBES},
+    log, scheduler,
+};
+
+/// Near misses kept for [`recent`], enough to fit one console response
+pub const RECENT_SIZE: usize = 12;
+
+/// Near misses counted for each probe, and the most recent of them
+static NEAR_MISSES: Mutex<RefCell<NearMisses>> = Mutex::new(RefCell::new(NearMisses {
+    counts: [0; PROBES],
+    recent: Deque::new(),
+}));
+
+/// Counts and ring of near misses
+struct NearMisses {
+    /// Near misses since boot, for each probe
+    counts: [u32; PROBES],
+    /// Most recent near misses of any probe, oldest first
+    recent: Deque<NearMiss, RECENT_SIZE>,
+}
+
+/// A change close to the trigger threshold that didn't detect contact
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub struct NearMiss {
+    /// [`scheduler::now_ms`] at which it was recorded
+    pub time_ms: u32,
+    /// [`SampleCounter`](crate::buffer::SampleCounter) of the sample that changed
+    pub sample: u64,
+    /// Probe that changed
+    pub probe: u8,
+    /// Change from the sample before, in codes
+    pub delta: i16,
+}
+
+/// Record the most recent sample of `probe` as a near miss
+pub fn record(buffers: &Buffers, probe: usize) {
+    let near_miss = NearMiss {
+        time_ms: scheduler::now_ms(),
+        sample: buffers.samples_recorded(),
+        probe: probe as u8,
+        delta: buffers.delta(probe),
+    };
+    critical_section::with(|cs| {
+        let mut near_misses = NEAR_MISSES.borrow_ref_mut(cs);
+        near_misses.counts[probe] = near_misses.counts[probe].saturating_add(1);
+        if near_misses.recent.is_full() {
+            near_misses.recent.pop_front();
+        }
+        let _ = near_misses.recent.push_back(near_miss);
+    });
+    log!(
+        Detection,
+        debug,
+        "Near miss on probe {=usize}, changed by {=i16}",
+        probe,
+        near_miss.delta
+    );
+}
+
+/// Near misses since boot, for each probe
+pub fn counts() -> [u32; PROBES] {
+    critical_section::with(|cs| NEAR_MISSES.borrow_ref(cs).counts)
+}
+
+/// The last [`RECENT_SIZE`] near misses of any probe, oldest first
+pub fn recent() -> Deque<NearMiss, RECENT_SIZE> {
+    critical_section::with(|cs| NEAR_MISSES.borrow_ref(cs).recent.clone())
+}
@@ -0,0 +1,144 @@
+//! Panic handler (feature `persist_panic`), which keeps the panic message across a reset.
+//!
+//! On panic, the handler applies the [`SAFE_STATE`](crate::safe_state::SAFE_STATE), records the
+//! message and return address in a `.uninit` RAM section that is not cleared at startup, then
+//! resets the chip with the watchdog. The binary logs the record on the next boot with
+//! [`take_last`], so panics in the field aren't silent.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{
+    mem::MaybeUninit,
+    ptr::{addr_of, addr_of_mut},
+};
+
+use defmt::{Format, Formatter};
+
+/// Longest panic message kept, in bytes. Longer messages are truncated.
+pub const MESSAGE_SIZE: usize = 120;
+/// Marks a valid [`PanicRecord`] ("PNIC" in little-endian)
+const MAGIC: u32 = 0x4349_4E50;
+
+/// Panic message and location, kept across a reset
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct PanicRecord {
+    /// [`MAGIC`] if the record is valid
+    magic: u32,
+    /// Return address of the panic handler, the closest available to the program counter at the
+    /// panic
+    pub pc: u32,
+    /// Length of `message`
+    len: u32,
+    /// Panic message, including the location
+    message: [u8; MESSAGE_SIZE],
+}
+
+impl PanicRecord {
+    /// Panic message, including the location
+    pub fn message(&self) -> &str {
+        let len = (self.len as usize).min(MESSAGE_SIZE);
+        match core::str::from_utf8(&self.message[..len]) {
+            Ok(message) => message,
+            // Truncation may split a character
+            Err(err) => core::str::from_utf8(&self.message[..err.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl Format for PanicRecord {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(fmt, "{=str} (pc {=u32:#010x})", self.message(), self.pc)
+    }
+}
+
+/// Record written by the panic handler. Not initialized or cleared by the runtime.
+#[link_section = ".uninit.PANIC_RECORD"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+/// Take the record of a panic from before the last reset, if there was one. Later calls return
+/// [`None`] until the next panic.
+pub fn take_last() -> Option<PanicRecord> {
+    critical_section::with(|_| {
+        // SAFETY: only accessed here and in the panic handler, both with interrupts disabled. The
+        // magic is read first, and the rest of the record only if it was written by the handler.
+        unsafe {
+            let record = (*addr_of_mut!(PANIC_RECORD)).as_mut_ptr();
+            if addr_of!((*record).magic).read_volatile() != MAGIC {
+                return None;
+            }
+            addr_of_mut!((*record).magic).write_volatile(0);
+            Some(record.read())
+        }
+    })
+}
+
+/// Writes into a [`PanicRecord`] message, truncating at [`MESSAGE_SIZE`]
+#[cfg(feature = "persist_panic")]
+struct MessageWriter<'a> {
+    /// Message buffer
+    buf: &'a mut [u8; MESSAGE_SIZE],
+    /// Bytes written so far
+    len: usize,
+}
+
+#[cfg(feature = "persist_panic")]
+impl core::fmt::Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let count = s.len().min(MESSAGE_SIZE - self.len);
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Applies the safe state, records the panic and resets with the watchdog.
+#[cfg(feature = "persist_panic")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    use crate::hal::pac;
+
+    use crate::safe_state::SAFE_STATE;
+
+    cortex_m::interrupt::disable();
+    // Read before any call overwrites it
+    let pc = cortex_m::register::lr::read();
+    // Trip the interlock before formatting anything, which is slow and could panic again
+    SAFE_STATE.apply();
+    defmt::error!("{}", defmt::Display2Format(info));
+
+    // SAFETY: nothing else runs once interrupts are disabled, and the peripherals are only
+    // written, so their owners can't be left in an inconsistent state that matters before reset
+    unsafe {
+        let record = (*addr_of_mut!(PANIC_RECORD)).as_mut_ptr();
+        let mut writer = MessageWriter {
+            buf: &mut *addr_of_mut!((*record).message),
+            len: 0,
+        };
+        let _ = write!(writer, "{}", info);
+        addr_of_mut!((*record).len).write_volatile(writer.len as u32);
+        addr_of_mut!((*record).pc).write_volatile(pc);
+        addr_of_mut!((*record).magic).write_volatile(MAGIC);
+
+        (*pac::WATCHDOG::ptr())
+            .ctrl()
+            .write(|w| w.trigger().set_bit());
+    }
+    loop {
+        cortex_m::asm::nop();
+    }
+}
@@ -0,0 +1,167 @@
+//! Cycle-count instrumentation of the detection hot path (feature `perf`).
+//!
+//! [`measure`] counts the core clock cycles spent in each [`Section`], keeping the minimum,
+//! average and maximum, which the `PERF` console command prints. The Cortex-M0+ has no DWT cycle
+//! counter, so cycles are read from SysTick, which counts down at the core clock. A section must be
+//! shorter than one SysTick period: 1 ms with the RTIC binary's tick, or 2^24 cycles where
+//! [`start_counter`] leaves it free-running.
+//!
+//! Without the feature, [`measure`] just runs its closure.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "perf")]
+use core::cell::RefCell;
+
+#[cfg(feature = "perf")]
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+#[cfg(feature = "perf")]
+use critical_section::Mutex;
+#[cfg(feature = "perf")]
+use defmt::info;
+use defmt::Format;
+
+/// Measured sections of the detection path
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum Section {
+    /// Block averaging of a transfer, in
+    /// [`Sampler::complete_transfer`](crate::sampler::Sampler::complete_transfer)
+    Average,
+    /// [`Buffers::insert`](crate::buffer::Buffers::insert)
+    Insert,
+    /// Contact detection on the latest sample, in [`Buffers::step`](crate::buffer::Buffers::step)
+    Detect,
+}
+
+impl Section {
+    /// Every section
+    pub const ALL: [Section; 3] = [Section::Average, Section::Insert, Section::Detect];
+}
+
+/// Cycle counts of a [`Section`]
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Format)]
+pub struct CycleStats {
+    /// Fewest cycles in a single run
+    pub min: u32,
+    /// Most cycles in a single run
+    pub max: u32,
+    /// Cycles across every run
+    pub total: u64,
+    /// Number of runs
+    pub count: u32,
+}
+
+impl CycleStats {
+    /// No runs yet
+    pub const fn new() -> Self {
+        Self {
+            min: u32::MAX,
+            max: 0,
+            total: 0,
+            count: 0,
+        }
+    }
+
+    /// Average cycles per run, or 0 if there have been none
+    pub fn avg(&self) -> u32 {
+        match self.count {
+            0 => 0,
+            count => (self.total / count as u64) as u32,
+        }
+    }
+
+    /// Record a run of `cycles`
+    #[cfg(feature = "perf")]
+    fn record(&mut self, cycles: u32) {
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.total = self.total.wrapping_add(cycles as u64);
+        self.count = self.count.wrapping_add(1);
+    }
+}
+
+impl Default for CycleStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Statistics for each [`Section`]
+#[cfg(feature = "perf")]
+static STATS: Mutex<RefCell<[CycleStats; Section::ALL.len()]>> =
+    Mutex::new(RefCell::new([CycleStats::new(); Section::ALL.len()]));
+
+/// Leave SysTick counting down from its maximum at the core clock, without an interrupt, unless it
+/// is already running. Call on each core that runs a [`Section`], before it is measured.
+#[cfg(feature = "perf")]
+pub fn start_counter() {
+    // SAFETY: SysTick is only reconfigured if it is not running. The RTIC binary sets its own
+    // reload afterwards, which `measure` reads back
+    let mut syst = unsafe { cortex_m::Peripherals::steal() }.SYST;
+    if !syst.is_counter_enabled() {
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(0x00FF_FFFF);
+        syst.clear_current();
+        syst.enable_counter();
+    }
+}
+
+/// Run `f` as `section`. With the `perf` feature, also records the cycles it took.
+#[inline(always)]
+pub fn measure<R>(section: Section, f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "perf")]
+    {
+        let start = SYST::get_current();
+        let ret = f();
+        let end = SYST::get_current();
+        // The counter counts down, reloading after it reaches 0
+        let cycles = if start >= end {
+            start - end
+        } else {
+            start + SYST::get_reload() + 1 - end
+        };
+        critical_section::with(|cs| STATS.borrow_ref_mut(cs)[section as usize].record(cycles));
+        ret
+    }
+    #[cfg(not(feature = "perf"))]
+    {
+        let _ = section;
+        f()
+    }
+}
+
+/// Cycle counts of `section` so far
+#[cfg(feature = "perf")]
+pub fn stats(section: Section) -> CycleStats {
+    critical_section::with(|cs| STATS.borrow_ref(cs)[section as usize])
+}
+
+/// Log the cycle counts of every section that has run
+#[cfg(feature = "perf")]
+pub fn log_stats() {
+    for section in Section::ALL {
+        let stats = stats(section);
+        if stats.count > 0 {
+            info!(
+                "{}: min {=u32} / avg {=u32} / max {=u32} cycles over {=u32} runs",
+                section,
+                stats.min,
+                stats.avg(),
+                stats.max,
+                stats.count
+            );
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Plausibility limits on the averaged signal.
+//!
+//! A front-end fault, such as a shorted divider or a saturated op-amp, can push the averaged
+//! signal somewhere no probe could put it while still changing enough to look like contact. Each
+//! sample is checked by a [`PlausibilityMonitor`] against the range configured by
+//! [`DetectionConfig::plausible_min`] and [`DetectionConfig::plausible_max`]. Samples outside it
+//! are kept from the detector, and once more than
+//! [`DetectionConfig::implausible_samples`] have been in a row, [`Error::SignalOutOfRange`] is
+//! raised.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::{warn, Format};
+
+use crate::{
+    buffer::{ProbeSamples, PROBES},
+    config::DetectionConfig,
+    error::{Error, Result},
+};
+
+/// Counts consecutive implausible samples from each probe
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub struct PlausibilityMonitor {
+    /// Consecutive samples outside the plausible range, for each probe
+    outside: [u16; PROBES],
+}
+
+impl PlausibilityMonitor {
+    /// No implausible samples seen
+    pub const fn new() -> Self {
+        Self {
+            outside: [0; PROBES],
+        }
+    }
+
+    /// Check the latest sample from each probe against the range in `config`.
+    ///
+    /// Returns whether every sample is plausible and can be passed on to the detector, or
+    /// [`Error::SignalOutOfRange`] once a probe has been outside the range for more than
+    /// [`DetectionConfig::implausible_samples`].
+    pub fn check(&mut self, samples: ProbeSamples, config: &DetectionConfig) -> Result<bool> {
+        let range = config.plausible_min..=config.plausible_max;
+        let mut plausible = true;
+        for (probe, (outside, sample)) in self.outside.iter_mut().zip(samples).enumerate() {
+            if range.contains(&sample) {
+                *outside = 0;
+                continue;
+            }
+
+            plausible = false;
+            *outside = outside.saturating_add(1);
+            if *outside > config.implausible_samples {
+                warn!(
+                    "Probe {=usize} signal {=u8} outside plausible range {=u8}..={=u8}",
+                    probe, sample, config.plausible_min, config.plausible_max
+                );
+                return Err(Error::SignalOutOfRange {
+                    probe: probe as u8,
+                    sample,
+                });
+            }
+        }
+        Ok(plausible)
+    }
+}
@@ -0,0 +1,198 @@
+//! Post-mortem trace of recent events, kept in RAM across a reset.
+//!
+//! State changes, the first occurrence of each error and every detected contact are
+//! [recorded](record) with a timestamp in a ring of the last [`TRACE_EVENTS`], in a `.uninit` RAM
+//! section that is not cleared at startup. [`reset::decode`](crate::reset::decode) dumps the ring
+//! from before the reset to the log on the next boot, then starts a new one. This covers the
+//! common case where RTT wasn't attached when the interesting thing happened: attach, reset the
+//! chip with the watchdog (`REBOOT`), and read what led up to it.
+//!
+//! A power cycle loses the ring, and events recorded before [`dump_last`] runs are dropped.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{
+    mem::{size_of, MaybeUninit},
+    ptr::{addr_of, addr_of_mut},
+};
+
+use defmt::{info, Format};
+
+use crate::{error::ErrorCode, scheduler, state::SystemState};
+
+/// Number of events kept in the ring
+pub const TRACE_EVENTS: usize = 64;
+/// Marks a valid [`TraceRing`] ("TRCE" in little-endian)
+const MAGIC: u32 = 0x4543_5254;
+
+/// An event worth knowing about after a reset
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Format)]
+pub enum TraceEvent {
+    /// The [`SystemState`] changed
+    StateChange {
+        /// Previous state
+        from: SystemState,
+        /// New state
+        to: SystemState,
+    },
+    /// First occurrence of an error since boot
+    Error(ErrorCode),
+    /// Contact was detected
+    Detection {
+        /// Lower 32 bits of the counter of the sample on which contact was detected
+        sample: u32,
+        /// Averaged sample that was detected
+        value: u8,
+        /// Probe the contact was detected on, always 0 without `dual_probe`
+        probe: u8,
+    },
+}
+
+/// [`TraceEvent`] with its timestamp, stored as plain integers so any bit pattern left in RAM
+/// can be read back safely
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct RawEntry {
+    /// Milliseconds since boot
+    at_ms: u32,
+    /// Which [`TraceEvent`] variant this is
+    kind: u8,
+    /// First small field of the event
+    a: u8,
+    /// Second small field of the event
+    b: u16,
+    /// Wide field of the event
+    c: u32,
+}
+
+/// [`RawEntry::kind`] of a [`TraceEvent::StateChange`]
+const KIND_STATE_CHANGE: u8 = 1;
+/// [`RawEntry::kind`] of a [`TraceEvent::Error`]
+const KIND_ERROR: u8 = 2;
+/// [`RawEntry::kind`] of a [`TraceEvent::Detection`]
+const KIND_DETECTION: u8 = 3;
+
+impl RawEntry {
+    /// Pack `event`, which happened `at_ms` milliseconds since boot
+    fn pack(event: TraceEvent, at_ms: u32) -> Self {
+        let (kind, a, b, c) = match event {
+            TraceEvent::StateChange { from, to } => (KIND_STATE_CHANGE, from as u8, to as u16, 0),
+            TraceEvent::Error(code) => (KIND_ERROR, 0, code.value(), 0),
+            TraceEvent::Detection {
+                sample,
+                value,
+                probe,
+            } => (KIND_DETECTION, value, probe as u16, sample),
+        };
+        Self {
+            at_ms,
+            kind,
+            a,
+            b,
+            c,
+        }
+    }
+
+    /// Unpack the event and its timestamp, or [`None`] if the entry is corrupt
+    fn unpack(&self) -> Option<(u32, TraceEvent)> {
+        let state = |value: u16| SystemState::ALL.get(value as usize).copied();
+        let event = match self.kind {
+            KIND_STATE_CHANGE => TraceEvent::StateChange {
+                from: state(self.a as u16)?,
+                to: state(self.b)?,
+            },
+            KIND_ERROR => TraceEvent::Error(ErrorCode::from_value(self.b)?),
+            KIND_DETECTION => TraceEvent::Detection {
+                sample: self.c,
+                value: self.a,
+                probe: u8::try_from(self.b).ok()?,
+            },
+            _ => return None,
+        };
+        Some((self.at_ms, event))
+    }
+}
+
+/// Ring of the most recent events
+#[repr(C)]
+struct TraceRing {
+    /// [`MAGIC`] once the ring has been started on this boot or an earlier one
+    magic: u32,
+    /// Total events recorded, of which the last [`TRACE_EVENTS`] are kept
+    recorded: u32,
+    /// Events, oldest overwritten first
+    entries: [RawEntry; TRACE_EVENTS],
+}
+
+/// RAM taken by the ring, counted in the [`budget`](crate::budget)
+pub const RING_BYTES: usize = size_of::<TraceRing>();
+
+/// Ring written by [`record`]. Not initialized or cleared by the runtime.
+#[link_section = ".uninit.TRACE_RING"]
+static mut TRACE_RING: MaybeUninit<TraceRing> = MaybeUninit::uninit();
+
+/// Add `event` to the ring, overwriting the oldest once it is full. Dropped if the ring hasn't
+/// been started by [`dump_last`] yet.
+pub fn record(event: TraceEvent) {
+    let entry = RawEntry::pack(event, scheduler::now_ms());
+    critical_section::with(|_| {
+        // SAFETY: only accessed here and in `dump_last`, both in a critical section. Every field
+        // is an integer, so any bit pattern is valid.
+        unsafe {
+            let ring = (*addr_of_mut!(TRACE_RING)).as_mut_ptr();
+            if addr_of!((*ring).magic).read_volatile() != MAGIC {
+                return;
+            }
+            let recorded = addr_of!((*ring).recorded).read_volatile();
+            addr_of_mut!((*ring).entries[recorded as usize % TRACE_EVENTS]).write_volatile(entry);
+            addr_of_mut!((*ring).recorded).write_volatile(recorded.wrapping_add(1));
+        }
+    });
+}
+
+/// Log the events recorded before the last reset, oldest first, then start a new ring. Call once
+/// at boot, before anything is recorded.
+pub fn dump_last() {
+    // Copied out so nothing is logged in the critical section
+    let (valid, recorded, entries) = critical_section::with(|_| {
+        // SAFETY: only accessed here and in `record`, both in a critical section. Every field is
+        // an integer, so any bit pattern is valid.
+        unsafe {
+            let ring = (*addr_of_mut!(TRACE_RING)).as_mut_ptr();
+            let valid = addr_of!((*ring).magic).read_volatile() == MAGIC;
+            let recorded = addr_of!((*ring).recorded).read_volatile();
+            let entries = addr_of!((*ring).entries).read_volatile();
+            addr_of_mut!((*ring).recorded).write_volatile(0);
+            addr_of_mut!((*ring).magic).write_volatile(MAGIC);
+            (valid, recorded, entries)
+        }
+    });
+    if !valid || recorded == 0 {
+        return;
+    }
+
+    let kept = (recorded as usize).min(TRACE_EVENTS);
+    info!(
+        "Trace before reset: last {=usize} of {=u32} events",
+        kept, recorded
+    );
+    let oldest = recorded as usize - kept;
+    for idx in oldest..oldest + kept {
+        match entries[idx % TRACE_EVENTS].unpack() {
+            Some((at_ms, event)) => info!("  {=u32} ms: {}", at_ms, event),
+            None => info!("  (corrupt entry)"),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! Power saving, for the battery-backed portable variant.
+//!
+//! [`gate_unused_clocks`] stops the clocks to peripherals the firmware never uses, and the idle
+//! loops sleep with `wfi` between interrupts. [`DutyCycle`] tracks how long the core spends
+//! asleep, which is logged with the other timing statistics.
+//!
+//! In [`SystemState::Standby`](crate::state::SystemState::Standby), entered by holding the
+//! acknowledge button for [`STANDBY_HOLD_MS`] or with the `STANDBY` console command, the chip is
+//! put into [`dormant`] mode until one of the [`WAKE_PINS`] sees a rising edge. The pin that woke
+//! it is journalled as a [`Kind::Wake`](crate::journal::Kind::Wake), see [`wake_source`].
+//!
+//! ## Brown-out
+//!
+//! The VSYS supervisor pulls the power-good input low when the supply is about to drop out. Its
+//! falling edge fires `IO_IRQ_BANK0`, the highest priority interrupt, where [`on_power_fail`]
+//! applies the [`SAFE_STATE`], tripping the interlock, and records
+//! [`ErrorCode::PowerFail`] across the coming reset. It then writes the queued events to the
+//! [journal](crate::event_log::flush_on_power_fail), except in the dual-core build, where core1
+//! executes from flash, and the `minimal` build, which has no journal. The interlock stays tripped
+//! from then on, and [`check_power_good`](crate::interrupt::check_power_good) latches
+//! [`SystemState::Error`](crate::state::SystemState::Error) from the next poll. If the supply
+//! still drops below [`BOD_VSEL`], the brown-out detector [configured](configure_brownout) at
+//! boot holds the chip in reset. The interlock output is undriven while in reset, so the saw
+//! controller must treat a floating interlock as tripped.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::info;
+
+use crate::{
+    config::board::{PowerGoodPin, ACK_BUTTON_PIN, I2C_BLOCK, POWER_GOOD_PIN},
+    deadline::Instant,
+    error::{self, ErrorCode},
+    hal::{
+        gpio::{FunctionNull, FunctionSio, Interrupt, Pin, PullDown, PullUp, SioInput},
+        pac,
+    },
+    journal::Source,
+    safe_state::SAFE_STATE,
+};
+
+/// How long the acknowledge button must be held to enter standby
+pub const STANDBY_HOLD_MS: u32 = 2_000;
+/// Inputs that wake the chip from [`dormant`] on a rising edge: the acknowledge button, and the
+/// disable switch if enabled
+pub const WAKE_PINS: &[u8] = &[
+    ACK_BUTTON_PIN,
+    #[cfg(feature = "disable_switch")]
+    crate::config::board::DISABLE_SWITCH_PIN,
+];
+/// Whether the `i2c_scan` feature uses an I2C block
+const I2C_SCAN: bool = cfg!(feature = "i2c_scan");
+/// Value written to `XOSC.DORMANT` to stop the crystal oscillator ("coma")
+const XOSC_DORMANT: u32 = 0x636f_6d61;
+/// Brown-out detector threshold on the core supply, 0.989 V. The default of 0.860 V (0.946 V on
+/// the RP2350) leaves the chip running well below the 1.10 V regulator output before it resets.
+pub const BOD_VSEL: u8 = 0b1100;
+/// Password in the upper half of every write to a `POWMAN` register
+#[cfg(feature = "rp2350")]
+const POWMAN_PASSWORD: u32 = 0x5AFE_0000;
+
+/// Set by [`on_power_fail`], after which the interlock is never released
+static POWER_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Power-good input from the VSYS supervisor, low when the supply is about to drop out
+pub type PowerGoodInput = Pin<PowerGoodPin, FunctionSio<SioInput>, PullUp>;
+
+/// Stop the clocks to the PIO, I2C, SPI, UART and RTC blocks, which the firmware never uses. With
+/// `i2c_scan`, the I2C block in [`I2cBlock`](crate::config::board::I2cBlock) keeps its clock.
+///
+/// `WAKE_EN` also applies while the core sleeps in `wfi`, so the clocks stay gated throughout.
+#[cfg(not(feature = "rp2350"))]
+pub fn gate_unused_clocks() {
+    // SAFETY: only clears enables for peripherals that are never taken out of reset, leaving the
+    // I2C block used by `i2c_scan` as it is
+    let clocks = unsafe { &*pac::CLOCKS::ptr() };
+    clocks.wake_en0().modify(|_, w| {
+        w.clk_sys_pio0()
+            .clear_bit()
+            .clk_sys_pio1()
+            .clear_bit()
+            .clk_sys_i2c0()
+            .bit(I2C_SCAN && I2C_BLOCK == 0)
+            .clk_sys_i2c1()
+            .bit(I2C_SCAN && I2C_BLOCK == 1)
+            .clk_peri_spi0()
+            .clear_bit()
+            .clk_sys_spi0()
+            .clear_bit()
+            .clk_peri_spi1()
+            .clear_bit()
+            .clk_sys_spi1()
+            .clear_bit()
+            .clk_rtc_rtc()
+            .clear_bit()
+            .clk_sys_rtc()
+            .clear_bit()
+    });
+    clocks.wake_en1().modify(|_, w| {
+        w.clk_peri_uart0()
+            .clear_bit()
+            .clk_sys_uart0()
+            .clear_bit()
+            .clk_peri_uart1()
+            .clear_bit()
+            .clk_sys_uart1()
+            .clear_bit()
+    });
+}
+
+/// Stop the clocks to the PIO, I2C, SPI, UART and HSTX blocks, which the firmware never uses. With
+/// `i2c_scan`, the I2C block in [`I2cBlock`](crate::config::board::I2cBlock) keeps its clock. The
+/// RP2350 has no RTC.
+///
+/// `WAKE_EN` also applies while the core sleeps in `wfi`, so the clocks stay gated throughout.
+#[cfg(feature = "rp2350")]
+pub fn gate_unused_clocks() {
+    // SAFETY: only clears enables for peripherals that are never taken out of reset, leaving the
+    // I2C block used by `i2c_scan` as it is
+    let clocks = unsafe { &*pac::CLOCKS::ptr() };
+    clocks.wake_en0().modify(|_, w| {
+        w.clk_sys_pio0()
+            .clear_bit()
+            .clk_sys_pio1()
+            .clear_bit()
+            .clk_sys_pio2()
+            .clear_bit()
+            .clk_sys_i2c0()
+            .bit(I2C_SCAN && I2C_BLOCK == 0)
+            .clk_sys_i2c1()
+            .bit(I2C_SCAN && I2C_BLOCK == 1)
+            .clk_sys_hstx()
+            .clear_bit()
+            .clk_hstx()
+            .clear_bit()
+    });
+    clocks.wake_en1().modify(|_, w| {
+        w.clk_peri_spi0()
+            .clear_bit()
+            .clk_sys_spi0()
+            .clear_bit()
+            .clk_peri_spi1()
+            .clear_bit()
+            .clk_sys_spi1()
+            .clear_bit()
+            .clk_peri_uart0()
+            .clear_bit()
+            .clk_sys_uart0()
+            .clear_bit()
+            .clk_peri_uart1()
+            .clear_bit()
+            .clk_sys_uart1()
+            .clear_bit()
+    });
+}
+
+/// Stop every clock until one of `wake_pins` sees a rising edge, then restore the clocks and
+/// return the pin that woke the chip. [`None`] if no edge was latched on any of them, which
+/// should only happen if the chip was woken some other way.
+///
+/// `clk_ref` and `clk_sys` are moved onto the crystal, which is then stopped, halting both cores,
+/// the timer and the watchdog. The PLLs relock once the crystal restarts. `clk_usb` also stops, so
+/// the host sees the console disconnect. Sampling must already be paused.
+pub fn dormant(wake_pins: &[u8]) -> Option<u8> {
+    // SAFETY: the clock muxes are only switched between sources that stay running, and are
+    // restored before returning. Interrupts are masked throughout.
+    let (clocks, xosc, io, pll_sys, pll_usb) = unsafe {
+        (
+            &*pac::CLOCKS::ptr(),
+            &*pac::XOSC::ptr(),
+            &*pac::IO_BANK0::ptr(),
+            &*pac::PLL_SYS::ptr(),
+            &*pac::PLL_USB::ptr(),
+        )
+    };
+    let woken_by = cortex_m::interrupt::free(|_| {
+        let ref_ctrl = clocks.clk_ref_ctrl().read().bits();
+        let sys_ctrl = clocks.clk_sys_ctrl().read().bits();
+        clocks.clk_ref_ctrl().modify(|_, w| w.src().xosc_clksrc());
+        while clocks.clk_ref_selected().read().bits() != 1 << 2 {}
+        clocks.clk_sys_ctrl().modify(|_, w| w.src().clk_ref());
+        while clocks.clk_sys_selected().read().bits() != 1 {}
+
+        for &pin in wake_pins {
+            let (reg, mask) = rising_edge(pin);
+            // Clear any edge latched before the chip went dormant
+            io.intr(reg).write(|w| unsafe { w.bits(mask) });
+            io.dormant_wake_inte(reg)
+                .modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        }
+        // SAFETY: the documented value for entering dormant mode
+        xosc.dormant().write(|w| unsafe { w.bits(XOSC_DORMANT) });
+        while xosc.status().read().stable().bit_is_clear() {}
+        // Read before the edges are cleared below
+        let woken_by = wake_pins.iter().copied().find(|&pin| {
+            let (reg, mask) = rising_edge(pin);
+            io.intr(reg).read().bits() & mask != 0
+        });
+        for &pin in wake_pins {
+            let (reg, mask) = rising_edge(pin);
+            io.dormant_wake_inte(reg)
+                .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+            io.intr(reg).write(|w| unsafe { w.bits(mask) });
+        }
+
+        while pll_sys.cs().read().lock().bit_is_clear() {}
+        while pll_usb.cs().read().lock().bit_is_clear() {}
+        // SAFETY: restores the values read above
+        clocks.clk_ref_ctrl().write(|w| unsafe { w.bits(ref_ctrl) });
+        while clocks.clk_ref_selected().read().bits() != 1 << (ref_ctrl & 0b11) {}
+        clocks.clk_sys_ctrl().write(|w| unsafe { w.bits(sys_ctrl) });
+        while clocks.clk_sys_selected().read().bits() != 1 << (sys_ctrl & 0b1) {}
+        woken_by
+    });
+    info!("Woke from dormant, GPIO {}", woken_by);
+    woken_by
+}
+
+/// Journal [`Source`] of the wake pin returned by [`dormant`], [`Source::System`] if it is
+/// unknown
+pub fn wake_source(pin: Option<u8>) -> Source {
+    match pin {
+        Some(ACK_BUTTON_PIN) => Source::Button,
+        #[cfg(feature = "disable_switch")]
+        Some(crate::config::board::DISABLE_SWITCH_PIN) => Source::Switch,
+        _ => Source::System,
+    }
+}
+
+/// Raise the brown-out detector threshold to [`BOD_VSEL`].
+#[cfg(not(feature = "rp2350"))]
+pub fn configure_brownout() {
+    // SAFETY: the BOD register is only written here, at boot
+    let vreg = unsafe { &*pac::VREG_AND_CHIP_RESET::ptr() };
+    vreg.bod()
+        .write(|w| unsafe { w.vsel().bits(BOD_VSEL) }.en().set_bit());
+}
+
+/// Raise the brown-out detector threshold to [`BOD_VSEL`], in `POWMAN` on the RP2350.
+#[cfg(feature = "rp2350")]
+pub fn configure_brownout() {
+    // SAFETY: the BOD register is only written here, at boot. `VSEL` is bits 4-8 and `EN` bit 0.
+    let powman = unsafe { &*pac::POWMAN::ptr() };
+    powman
+        .bod()
+        .write(|w| unsafe { w.bits(POWMAN_PASSWORD | (BOD_VSEL as u32) << 4 | 1) });
+}
+
+/// Take the power-good input, and unmask the interrupt on its falling edge. A supply that is
+/// already failing counts as a power failure.
+pub fn monitor_power_good(pin: Pin<PowerGoodPin, FunctionNull, PullDown>) -> PowerGoodInput {
+    let mut input = pin.into_pull_up_input();
+    input.set_schmitt_enabled(true);
+    input.clear_interrupt(Interrupt::EdgeLow);
+    input.set_interrupt_enabled(Interrupt::EdgeLow, true);
+    // SAFETY: read-only access to the SIO GPIO input register
+    let sio = unsafe { &*pac::SIO::ptr() };
+    if sio.gpio_in().read().bits() & 1 << POWER_GOOD_PIN == 0 {
+        on_power_fail();
+    }
+    input
+}
+
+/// Handler for `IO_IRQ_BANK0`: applies the [`SAFE_STATE`], acknowledges the power-good edge,
+/// records [`ErrorCode::PowerFail`] and flushes the [`event_log`](crate::event_log) on the first
+/// failure.
+///
+/// The safe state is written through the SIO registers, so the interlock trips without waiting
+/// for any lock on the [`StateMachine`](crate::state::StateMachine).
+pub fn on_power_fail() {
+    SAFE_STATE.apply();
+    let (reg, mask) = falling_edge(POWER_GOOD_PIN);
+    // SAFETY: the interrupt clear register is write-1 atomic, so other owners of the IO_BANK0
+    // block are unaffected
+    unsafe {
+        (*pac::IO_BANK0::ptr()).intr(reg).write(|w| w.bits(mask));
+    }
+    if !POWER_FAILED.load(Ordering::Relaxed) {
+        POWER_FAILED.store(true, Ordering::Relaxed);
+        error::record(ErrorCode::PowerFail);
+        #[cfg(not(any(feature = "dual_core", feature = "minimal")))]
+        crate::event_log::flush_on_power_fail();
+    }
+}
+
+/// Whether the supply has failed since boot
+pub fn power_failed() -> bool {
+    POWER_FAILED.load(Ordering::Relaxed)
+}
+
+/// Register index and bit mask of the falling edge event for GPIO `pin`, in `IO_BANK0.INTR`
+fn falling_edge(pin: u8) -> (usize, u32) {
+    let pin = pin as usize;
+    (pin / 8, 1 << (4 * (pin % 8) + 2))
+}
+
+/// Register index and bit mask of the rising edge event for GPIO `pin`, in `IO_BANK0.INTR` and
+/// `IO_BANK0.DORMANT_WAKE_INTE`
+fn rising_edge(pin: u8) -> (usize, u32) {
+    let pin = pin as usize;
+    (pin / 8, 1 << (4 * (pin % 8) + 3))
+}
+
+/// Tracks the share of time the core spends asleep in `wfi`, over a reporting window.
+pub struct DutyCycle {
+    /// Start of the current reporting window.
+    window_start: Instant,
+    /// Time spent asleep since `window_start`.
+    asleep_us: u32,
+}
+
+impl DutyCycle {
+    /// Start tracking from now.
+    pub fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            asleep_us: 0,
+        }
+    }
+
+    /// Sleep with `wfi` until the next interrupt, recording the time spent asleep.
+    ///
+    /// Call this with interrupts masked, so the woken interrupt is only serviced after the sleep
+    /// has been recorded.
+    pub fn sleep(&mut self) {
+        let start = Instant::now();
+        cortex_m::asm::wfi();
+        self.asleep_us = self.asleep_us.saturating_add(Instant::now().since(start));
+    }
+
+    /// Log the busy/sleep split since the last report, and start a new window.
+    pub fn log(&mut self) {
+        let window_us = Instant::now().since(self.window_start).max(1);
+        let asleep = (self.asleep_us.min(window_us) as u64 * 100 / window_us as u64) as u8;
+        info!(
+            "CPU duty cycle: {=u8}% busy, {=u8}% asleep over {=u32} ms",
+            100 - asleep,
+            asleep,
+            window_us / 1000
+        );
+        *self = Self::new();
+    }
+}
+
+impl Default for DutyCycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
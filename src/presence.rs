@@ -0,0 +1,52 @@
+//! Optional operator-presence input, for sites whose rules don't allow the saw to run unattended.
+//!
+//! A switch or capacitive pad drives the input high while the operator is at the saw. If the board
+//! variant sets `presence.required` (see [`PRESENCE_REQUIRED`]), the operator leaving while
+//! detection is armed moves the system to [`SystemState::Disabled`] with the interlock tripped,
+//! rather than released as when disabled by the switch. Detection rearms once they return. See
+//! [`check_presence`](crate::interrupt::check_presence), which is polled with the acknowledge
+//! button.
+//!
+//! Otherwise, the input is configured but never read, so boards without it fitted are unaffected.
+//!
+//! [`SystemState::Disabled`]: crate::state::SystemState::Disabled
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub use crate::config::board::PRESENCE_REQUIRED;
+use crate::{
+    config::board::{PresencePin, PRESENCE_PIN},
+    hal::{
+        gpio::{FunctionNull, FunctionSio, Pin, PullDown, SioInput},
+        pac,
+    },
+};
+
+/// Operator-presence input, pulled down so an unconnected input reads as absent
+pub type PresenceInput = Pin<PresencePin, FunctionSio<SioInput>, PullDown>;
+
+/// Take the operator-presence input, pulled down with its Schmitt trigger enabled
+pub fn monitor(pin: Pin<PresencePin, FunctionNull, PullDown>) -> PresenceInput {
+    let input = pin.into_pull_down_input();
+    input.set_schmitt_enabled(true);
+    input
+}
+
+/// Whether the operator is present
+pub fn present() -> bool {
+    // SAFETY: read-only access to the SIO GPIO input register
+    let sio = unsafe { &*pac::SIO::ptr() };
+    sio.gpio_in().read().bits() & 1 << PRESENCE_PIN != 0
+}
@@ -0,0 +1,99 @@
+//! Probe-disconnect detection.
+//!
+//! A disconnected probe leaves the device blind while every sample still looks valid. Each
+//! transfer's [`AlignedAverages`] are checked by a [`ProbeMonitor`], which raises
+//! [`Error::ProbeDisconnected`] if the signal sits at a rail, or shows no trace of the signal
+//! generator as a floating input does, for [`DISCONNECT_SAMPLES`] in a row.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::Format;
+
+use crate::{
+    error::{Error, Result},
+    sampler::AlignedAverages,
+};
+
+/// Readings within this many counts of 0 or 255 are at a rail
+pub const RAIL_MARGIN: i32 = 5;
+/// Largest high/low difference still treated as a floating input, with no signal reaching the
+/// ADC
+pub const FLOATING_DELTA: u8 = 1;
+/// Consecutive suspect samples before the probe is reported as disconnected (0.5 s with 2 ms
+/// averaging)
+pub const DISCONNECT_SAMPLES: u16 = 250;
+
+/// How a disconnected probe was recognized
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum ProbeFault {
+    /// Both halves of the signal are pinned to the same rail
+    Railed,
+    /// The signal generator can't be seen in the readings
+    Floating,
+}
+
+/// Counts consecutive samples matching each [`ProbeFault`]
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Format)]
+pub struct ProbeMonitor {
+    /// Consecutive samples at a rail
+    railed: u16,
+    /// Consecutive samples with no signal
+    floating: u16,
+}
+
+impl ProbeMonitor {
+    /// No suspect samples seen
+    pub const fn new() -> Self {
+        Self {
+            railed: 0,
+            floating: 0,
+        }
+    }
+
+    /// Check the averages from the latest transfer.
+    ///
+    /// Returns [`Error::ProbeDisconnected`] once either pattern has lasted for
+    /// [`DISCONNECT_SAMPLES`].
+    pub fn check(&mut self, avgs: &AlignedAverages) -> Result<()> {
+        // The high half is never below the low half, so either test puts both at the same rail
+        let (high, low) = (avgs.high(), avgs.low());
+        let railed = high <= RAIL_MARGIN || low >= 255 - RAIL_MARGIN;
+        self.railed = if railed {
+            self.railed.saturating_add(1)
+        } else {
+            0
+        };
+        // A railed signal also shows no delta, so it is only counted as railed
+        let floating = !railed && avgs.get_delta() <= FLOATING_DELTA;
+        self.floating = if floating {
+            self.floating.saturating_add(1)
+        } else {
+            0
+        };
+
+        if self.railed >= DISCONNECT_SAMPLES {
+            Err(Error::ProbeDisconnected(ProbeFault::Railed))
+        } else if self.floating >= DISCONNECT_SAMPLES {
+            Err(Error::ProbeDisconnected(ProbeFault::Floating))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Forget any suspect samples, when sampling restarts
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
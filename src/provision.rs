@@ -0,0 +1,226 @@
+//! Per-unit provisioning: the serial number, hardware revision and ADC calibration constants
+//! written on the bench, so units in the lab fleet can be told apart.
+//!
+//! The [`Provisioning`] record lives in its own flash sector at [`PROVISION_OFFSET`], which
+//! neither `FACTORY RESET` nor `CONFIG IMPORT` touch. It is written one [`Field`] at a time by the
+//! `PROVISION` console command, and only while the configuration jumper is fitted: a PIN session
+//! isn't enough, as the record describes the hardware rather than how it is used. The serial
+//! number and revision are printed by `*IDN?`, and at the start of every
+//! [`export`](crate::export) of the event log. Until a unit is provisioned, both read as `-`.
+//!
+//! The ADC offset and gain correct the spread of the RP2040 ADC between units, so the thresholds
+//! can be set for the nominal front end rather than the worst unit. The
+//! [`Sampler`](crate::sampler::Sampler) applies them to every block average (see
+//! [`AlignedAverages::calibrate`](crate::sampler::AlignedAverages::calibrate)), taking effect as
+//! soon as they are set. Set `OFFSET 0` and `GAIN 1000000` before measuring them, so the readings
+//! are uncorrected. Until then, they make no correction.
+//!
+//! The ADC reference is the voltage spanned by the full scale of a corrected sample, used to
+//! convert thresholds set in millivolts to codes (see
+//! [`DetectionConfig::millivolts`](crate::config::DetectionConfig::millivolts)). Until it is set,
+//! the nominal [`ADC_REFERENCE_MV`] is used.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use defmt::{info, warn, Format, Formatter};
+
+pub use crate::protocol::{
+    Field, ProvisionError, MAX_ADC_GAIN_PPM, MAX_ADC_OFFSET, MAX_ADC_REFERENCE_MV,
+    MIN_ADC_GAIN_PPM, MIN_ADC_REFERENCE_MV, REVISION_LEN, SERIAL_LEN,
+};
+use crate::{
+    lock,
+    storage::{self, PROVISION_OFFSET},
+    units::ADC_REFERENCE_MV,
+};
+
+/// Marks a valid provisioning record in flash ("PROV")
+const MAGIC: u32 = 0x564F_5250;
+/// Size of the provisioning record: magic, serial number, revision, offset, reference, gain and
+/// CRC
+const RECORD_SIZE: usize = 40;
+/// ADC gain making no correction, in parts per million
+pub const UNITY_GAIN_PPM: u32 = 1_000_000;
+
+/// Provisioning loaded from flash, or [`Provisioning::NONE`] if the unit hasn't been provisioned
+static PROVISIONING: Mutex<Cell<Provisioning>> = Mutex::new(Cell::new(Provisioning::NONE));
+
+/// Identity and calibration of one unit
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Provisioning {
+    /// Serial number, padded with zeros
+    serial: [u8; SERIAL_LEN],
+    /// Hardware revision, padded with zeros
+    revision: [u8; REVISION_LEN],
+    /// Added to the ADC readings, in codes
+    adc_offset: i16,
+    /// Voltage spanned by the full scale of a corrected sample, in millivolts, or 0 if not set
+    adc_reference_mv: u16,
+    /// Multiplies the ADC readings, in parts per million
+    adc_gain_ppm: u32,
+}
+
+impl Provisioning {
+    /// An unprovisioned unit, with no correction
+    pub const NONE: Self = Self {
+        serial: [0; SERIAL_LEN],
+        revision: [0; REVISION_LEN],
+        adc_offset: 0,
+        adc_reference_mv: 0,
+        adc_gain_ppm: UNITY_GAIN_PPM,
+    };
+
+    /// Whether a serial number has been set
+    pub fn provisioned(&self) -> bool {
+        self.serial[0] != 0
+    }
+
+    /// Serial number, or `-` if not set
+    pub fn serial(&self) -> &str {
+        text(&self.serial)
+    }
+
+    /// Hardware revision, or `-` if not set
+    pub fn revision(&self) -> &str {
+        text(&self.revision)
+    }
+
+    /// ADC offset correction, in codes
+    pub fn adc_offset(&self) -> i16 {
+        self.adc_offset
+    }
+
+    /// ADC gain correction, in parts per million
+    pub fn adc_gain_ppm(&self) -> u32 {
+        self.adc_gain_ppm
+    }
+
+    /// ADC reference in millivolts, or [`ADC_REFERENCE_MV`] if not set
+    pub fn adc_reference_mv(&self) -> u32 {
+        match self.adc_reference_mv {
+            0 => ADC_REFERENCE_MV,
+            reference_mv => reference_mv as u32,
+        }
+    }
+
+    /// Decode a record written by [`Provisioning::encode`], or [`None`] if it is missing or
+    /// corrupt
+    fn decode(record: &[u8]) -> Option<Self> {
+        let word = |idx: usize| u32::from_le_bytes(record[idx..idx + 4].try_into().unwrap());
+        (word(0) == MAGIC && storage::crc32(&record[..36]) == word(36)).then(|| Self {
+            serial: record[4..20].try_into().unwrap(),
+            revision: record[20..28].try_into().unwrap(),
+            adc_offset: i16::from_le_bytes([record[28], record[29]]),
+            // Zero in records written before it was added
+            adc_reference_mv: u16::from_le_bytes([record[30], record[31]]),
+            adc_gain_ppm: word(32),
+        })
+    }
+
+    /// Encode as a flash record
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0; RECORD_SIZE];
+        record[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4..20].copy_from_slice(&self.serial);
+        record[20..28].copy_from_slice(&self.revision);
+        record[28..30].copy_from_slice(&self.adc_offset.to_le_bytes());
+        record[30..32].copy_from_slice(&self.adc_reference_mv.to_le_bytes());
+        record[32..36].copy_from_slice(&self.adc_gain_ppm.to_le_bytes());
+        let crc = storage::crc32(&record[..36]);
+        record[36..].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+}
+
+impl Default for Provisioning {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+impl Format for Provisioning {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(
+            fmt,
+            "serial {=str} revision {=str} ADC offset {=i16} gain {=u32} ppm reference {=u32} mV",
+            self.serial(),
+            self.revision(),
+            self.adc_offset,
+            self.adc_gain_ppm,
+            self.adc_reference_mv()
+        );
+    }
+}
+
+/// Text padded with zeros in `bytes`, or `-` if empty
+fn text(bytes: &[u8]) -> &str {
+    let len = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(bytes.len());
+    match core::str::from_utf8(&bytes[..len]) {
+        Ok(text) if !text.is_empty() => text,
+        _ => "-",
+    }
+}
+
+/// Copy `text` into `bytes`, padded with zeros
+fn set_text(bytes: &mut [u8], text: &str) {
+    bytes.fill(0);
+    bytes[..text.len()].copy_from_slice(text.as_bytes());
+}
+
+/// Load the provisioning persisted by [`set`]. Call once during startup.
+pub fn load() {
+    let provisioning = Provisioning::decode(storage::read(PROVISION_OFFSET, RECORD_SIZE));
+    match provisioning {
+        Some(provisioning) => info!("Provisioning: {}", provisioning),
+        None => warn!("Unit not provisioned, no serial number or ADC calibration"),
+    }
+    critical_section::with(|cs| {
+        PROVISIONING
+            .borrow(cs)
+            .set(provisioning.unwrap_or_default())
+    });
+}
+
+/// Provisioning of this unit
+pub fn get() -> Provisioning {
+    critical_section::with(|cs| PROVISIONING.borrow(cs).get())
+}
+
+/// Persist and use `field` from now on, if the configuration jumper is fitted. Interrupts are
+/// disabled while flash is written.
+pub fn set(field: Field) -> Result<Provisioning, ProvisionError> {
+    if !lock::jumper_fitted() {
+        return Err(ProvisionError::JumperRequired);
+    }
+    let mut provisioning = get();
+    match field {
+        Field::Serial(serial) => set_text(&mut provisioning.serial, serial),
+        Field::Revision(revision) => set_text(&mut provisioning.revision, revision),
+        Field::AdcOffset(offset) => provisioning.adc_offset = offset,
+        Field::AdcGain(gain_ppm) => provisioning.adc_gain_ppm = gain_ppm,
+        Field::AdcReference(reference_mv) => provisioning.adc_reference_mv = reference_mv,
+    }
+    storage::write_sector(PROVISION_OFFSET, &provisioning.encode())
+        .map_err(ProvisionError::Storage)?;
+    critical_section::with(|cs| PROVISIONING.borrow(cs).set(provisioning));
+    info!("Provisioned {}", field);
+    Ok(provisioning)
+}
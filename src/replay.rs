@@ -0,0 +1,80 @@
+//! Replay of recorded waveforms (feature `replay`), to validate detection changes against real
+//! contact recordings without a rig.
+//!
+//! The [`Replayer`] stands in for the probe: [`Sampler::complete_transfer`] returns the next
+//! recorded sample instead of the averaged ADC readings, so the [`Buffers`], detection and the
+//! state machine all see the recording. The ADC transfers keep running, so timing, deadlines and
+//! the watchdog behave as they would on the rig. The probe checks are skipped, as no probe needs to
+//! be connected.
+//!
+//! The [`WAVEFORM`] is embedded at build time from the file named by the `REPLAY_WAVEFORM`
+//! environment variable, relative to the crate root (default `logs/voltdiv.log`). It is either a
+//! raw file of averaged samples, one byte each, or a defmt log recorded with the
+//! `trace_avg_samples` feature, whose logged blocks of samples are replayed in order. The waveform
+//! repeats once it ends.
+//!
+//! ```text
+//! REPLAY_WAVEFORM=logs/all_up_knife_debug.log cargo run --features replay
+//! ```
+//!
+//! [`Sampler::complete_transfer`]: crate::sampler::Sampler::complete_transfer
+//! [`Buffers`]: crate::buffer::Buffers
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use defmt::info;
+
+/// Recorded averaged samples, embedded by `build.rs`. Never empty.
+pub static WAVEFORM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/replay_waveform.bin"));
+
+/// Feeds the [`WAVEFORM`] in place of the probe readings
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub struct Replayer {
+    /// Index of the next sample in the [`WAVEFORM`]
+    position: usize,
+    /// Complete passes through the [`WAVEFORM`]
+    passes: u32,
+}
+
+impl Replayer {
+    /// Start from the beginning of the [`WAVEFORM`]
+    pub const fn new() -> Self {
+        Self {
+            position: 0,
+            passes: 0,
+        }
+    }
+
+    /// Take the next recorded sample, restarting the [`WAVEFORM`] once it ends
+    pub fn next_sample(&mut self) -> u8 {
+        if self.position >= WAVEFORM.len() {
+            self.position = 0;
+            self.passes = self.passes.wrapping_add(1);
+            info!(
+                "Replay pass {=u32} complete ({=usize} samples), restarting",
+                self.passes,
+                WAVEFORM.len()
+            );
+        }
+        let sample = WAVEFORM[self.position];
+        self.position += 1;
+        sample
+    }
+
+    /// Complete passes through the [`WAVEFORM`]
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
+}
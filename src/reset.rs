@@ -0,0 +1,145 @@
+//! Reset cause reporting.
+//!
+//! [`decode`] runs once at boot. It reads the chip and watchdog reset reasons, and takes the
+//! records kept across the reset by [`panic`], [`liveness`] and [`error`], logging each of them
+//! with the overall [`ResetCause`]. The cause is kept for the `RESET CAUSE` console command.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use defmt::{error, info, Format};
+
+use crate::{board::ChipReset, error, hal::pac, liveness, panic, postmortem};
+
+/// Cause of the last reset, from [`decode`]. Unknown until then.
+static LAST_CAUSE: AtomicU8 = AtomicU8::new(ResetCause::Unknown as u8);
+/// Whether the last reset was a watchdog timeout simulated for a [drill](crate::drill)
+static LAST_SIMULATED: AtomicBool = AtomicBool::new(false);
+
+/// Why the chip last reset, in order of precedence
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub enum ResetCause {
+    /// The [panic handler](crate::panic) reset the chip
+    Panic,
+    /// A task missed its [watchdog deadline](crate::liveness), or the watchdog wasn't fed
+    Watchdog,
+    /// The watchdog was triggered on purpose, such as by the `REBOOT` console command
+    Commanded,
+    /// A debugger reset the chip
+    Debugger,
+    /// The `RUN` pin was pulled low
+    RunPin,
+    /// Power was applied, or the supply browned out
+    PowerOn,
+    /// No reason was recorded
+    Unknown,
+}
+
+impl ResetCause {
+    /// Every cause, indexed by its discriminant
+    const ALL: [ResetCause; 7] = [
+        ResetCause::Panic,
+        ResetCause::Watchdog,
+        ResetCause::Commanded,
+        ResetCause::Debugger,
+        ResetCause::RunPin,
+        ResetCause::PowerOn,
+        ResetCause::Unknown,
+    ];
+
+    /// Short description printed to the console
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResetCause::Panic => "panic",
+            ResetCause::Watchdog => "watchdog timeout",
+            ResetCause::Commanded => "commanded",
+            ResetCause::Debugger => "debugger",
+            ResetCause::RunPin => "RUN pin",
+            ResetCause::PowerOn => "power-on or brown-out",
+            ResetCause::Unknown => "unknown",
+        }
+    }
+}
+
+/// Work out why the chip reset, logging the cause and any records kept across the reset. Call
+/// once at boot, before [`Board::init`](crate::board::Board::init).
+pub fn decode() -> ResetCause {
+    let chip = ChipReset::read();
+    // SAFETY: read-only access to the reset reason register
+    let reason = unsafe { (*pac::WATCHDOG::ptr()).reason().read() };
+
+    let panicked = panic::take_last();
+    if let Some(record) = panicked {
+        error!("Reset after panic: {}", record);
+    }
+    let missed = liveness::take_last_timeout();
+    if let Some(missed) = missed {
+        error!("Reset by watchdog, missed deadline: {}", missed);
+    }
+    if let Some(code) = error::take_last() {
+        error!("Reset while in error state: {}", code);
+    }
+
+    let cause = if panicked.is_some() {
+        ResetCause::Panic
+    } else if missed.is_some() || reason.timer().bit_is_set() {
+        ResetCause::Watchdog
+    } else if reason.force().bit_is_set() {
+        ResetCause::Commanded
+    } else if chip.debugger {
+        ResetCause::Debugger
+    } else if chip.run_pin {
+        ResetCause::RunPin
+    } else if chip.power_on {
+        ResetCause::PowerOn
+    } else {
+        ResetCause::Unknown
+    };
+    info!("Reset cause: {}", cause);
+    LAST_CAUSE.store(cause as u8, Ordering::Relaxed);
+    LAST_SIMULATED.store(
+        missed.is_some_and(|missed| missed.simulated()),
+        Ordering::Relaxed,
+    );
+    postmortem::dump_last();
+    cause
+}
+
+/// Cause of the last reset, as found by [`decode`]
+pub fn last_cause() -> ResetCause {
+    ResetCause::ALL[LAST_CAUSE.load(Ordering::Relaxed) as usize]
+}
+
+/// Whether the last reset was a [`ResetCause::Watchdog`] simulated for a [drill](crate::drill),
+/// as found by [`decode`]
+pub fn last_timeout_simulated() -> bool {
+    LAST_SIMULATED.load(Ordering::Relaxed)
+}
+
+/// Reset the chip with the watchdog, which is reported as [`ResetCause::Commanded`] on the next
+/// boot.
+pub fn reboot() -> ! {
+    info!("Rebooting");
+    // SAFETY: the chip resets immediately, so no other owner of the watchdog runs again
+    unsafe {
+        (*pac::WATCHDOG::ptr())
+            .ctrl()
+            .write(|w| w.trigger().set_bit());
+    }
+    loop {
+        cortex_m::asm::nop();
+    }
+}
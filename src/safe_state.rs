@@ -0,0 +1,123 @@
+//! The safe state of the outputs, entered on every fault.
+//!
+//! [`SAFE_STATE`] is the single description of what a fault does to the outputs: the interlock
+//! tripped, the buzzer silent, and only the error LED lit. It matches the display of
+//! [`SystemState::Error`], so nothing changes when the error is later shown by the
+//! [`Indicators`](crate::state::Indicators).
+//!
+//! [`SafeState::apply`] writes the outputs through the SIO set and clear registers, without the
+//! pins' owners, so every fault path applies it the same way:
+//! - the [panic handler](crate::panic), before resetting
+//! - [`liveness::feed_if_alive`](crate::liveness::feed_if_alive), once a deadline is missed and
+//!   the watchdog is about to reset the chip
+//! - [`power::on_power_fail`](crate::power::on_power_fail)
+//! - [`StateMachine::fail`](crate::state::StateMachine::fail), on every error transition
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use embedded_hal::digital::PinState;
+
+use crate::{
+    buffer::PROBES,
+    components::{LedControl, StatusLedStates, StatusLeds},
+    config::board::{
+        BUZZER_PIN, INTERLOCK_OPEN_DRAIN, INTERLOCK_PINS, INTERLOCK_TRIPPED_LEVEL, STATUS_LED_PINS,
+    },
+    hal::pac,
+    state::SystemState,
+};
+
+/// Outputs applied on every fault
+pub const SAFE_STATE: SafeState = SafeState::of(SystemState::Error);
+
+/// Levels of the safety-relevant outputs
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct SafeState {
+    /// Whether the interlocks stop the saws
+    pub interlock_tripped: bool,
+    /// Whether the buzzer sounds
+    pub buzzer_on: bool,
+    /// Whether the error LED is lit. The other status LEDs are always off.
+    pub error_led: bool,
+}
+
+impl SafeState {
+    /// The outputs displayed in `state`
+    pub const fn of(state: SystemState) -> Self {
+        Self {
+            interlock_tripped: state.interlock_tripped(),
+            buzzer_on: state.buzzer_on(),
+            error_led: matches!(state.led(), StatusLedStates::Error),
+        }
+    }
+
+    /// Masks of the GPIOs driven high, driven low, and released to their pull-up, in that order.
+    /// Only open-drain interlocks are ever released.
+    const fn masks(&self) -> (u32, u32, u32) {
+        let (mut high, mut low, mut released) = (0, 0, 0);
+        let mut interlock = 0;
+        let mut probe = 0;
+        while probe < PROBES {
+            interlock |= 1 << INTERLOCK_PINS[probe];
+            probe += 1;
+        }
+        let tripped_high = matches!(INTERLOCK_TRIPPED_LEVEL, PinState::High);
+        if self.interlock_tripped != tripped_high {
+            low |= interlock;
+        } else if INTERLOCK_OPEN_DRAIN {
+            released |= interlock;
+        } else {
+            high |= interlock;
+        }
+        let buzzer = 1 << BUZZER_PIN;
+        if self.buzzer_on {
+            high |= buzzer;
+        } else {
+            low |= buzzer;
+        }
+
+        let (error_idx, error_level) = StatusLeds::ERROR_LED;
+        let active_high = matches!(error_level, PinState::High);
+        let mut idx = 0;
+        while idx < STATUS_LED_PINS.len() {
+            let led = 1 << STATUS_LED_PINS[idx];
+            if (self.error_led && idx == error_idx) == active_high {
+                high |= led;
+            } else {
+                low |= led;
+            }
+            idx += 1;
+        }
+        (high, low, released)
+    }
+
+    /// Drive the outputs to this state, from any context.
+    ///
+    /// Each SIO register write is atomic, so pins owned elsewhere are unaffected. The outputs are
+    /// also enabled, in case a fault comes before their owners have set them up, except for
+    /// open-drain interlocks released to their pull-up.
+    pub fn apply(&self) {
+        let (high, low, released) = self.masks();
+        // SAFETY: the set, clear and output enable registers are write-1 atomic. The owners of
+        // these pins only drive them to the same levels on a fault.
+        unsafe {
+            let sio = &*pac::SIO::ptr();
+            sio.gpio_oe_clr().write(|w| w.bits(released));
+            sio.gpio_out_set().write(|w| w.bits(high));
+            sio.gpio_out_clr().write(|w| w.bits(low));
+            sio.gpio_oe_set().write(|w| w.bits(high | low));
+        }
+    }
+}
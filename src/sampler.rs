@@ -0,0 +1,612 @@
+//! Signal generation and ADC sampling over DMA.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cmp::Ordering;
+use core::ops::Deref;
+
+use cortex_m::prelude::_embedded_hal_PwmPin;
+use defmt::Format;
+
+#[cfg(feature = "dual_channel")]
+use crate::crosscheck::CrossCheck;
+#[cfg(any(feature = "dual_channel", feature = "triple_channel"))]
+use crate::health::HealthMonitor;
+#[cfg(not(feature = "minimal"))]
+use crate::provision::UNITY_GAIN_PPM;
+#[cfg(feature = "replay")]
+use crate::replay::Replayer;
+use crate::{
+    buffer::{TransferSamples, ADC_CHANNELS, AVG_BUFFER_SIZE, DMA_BUFFER_SIZE},
+    config::board::{SignalPwmChannel, SignalPwmSlice},
+    correlation,
+    deadline::{self, Instant, Stage},
+    error::{Error, Result},
+    hal::{
+        adc::DmaReadTarget,
+        dma::{
+            double_buffer::{self, Transfer, WriteNext},
+            Channel, EndlessWriteTarget, SingleChannel, WriteTarget, CH0, CH1,
+        },
+        pwm,
+        pwm::{FreeRunning, Slice},
+    },
+    liveness::{self, Task},
+    log,
+    perf::{self, Section},
+    probe::ProbeMonitor,
+};
+
+/// Buffer filled by each ADC transfer
+pub struct ReadingsBuffer(&'static mut [u8; DMA_BUFFER_SIZE]);
+/// Chained pair of DMA channels, each triggering the other once its transfer completes
+pub type ReadingsChannels = (Channel<CH0>, Channel<CH1>);
+/// Wrapper for [DMA `Transfer`](Transfer) into one buffer, with the other queued to follow
+pub type ReadingsDma = Transfer<
+    Channel<CH0>,
+    Channel<CH1>,
+    DmaReadTarget<u8>,
+    ReadingsBuffer,
+    WriteNext<ReadingsBuffer>,
+>;
+/// PWM channel generating the detection signal
+pub type SignalPwm = pwm::Channel<Slice<SignalPwmSlice, FreeRunning>, SignalPwmChannel>;
+/// Parts of a [`ReadingsDma`] stored while detection is paused
+pub type SignalGenConfig = (ReadingsChannels, DmaReadTarget<u8>, [ReadingsBuffer; 2]);
+
+impl ReadingsBuffer {
+    /// Wrap one of the buffers from [`create_avg_buffers`](crate::buffer::create_avg_buffers)
+    pub fn new(buffer: &'static mut [u8; DMA_BUFFER_SIZE]) -> Self {
+        Self(buffer)
+    }
+}
+
+impl Deref for ReadingsBuffer {
+    type Target = [u8; DMA_BUFFER_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+// SAFETY: the buffer is borrowed for 'static, so it outlives any transfer, and its address and
+// length never change
+unsafe impl WriteTarget for ReadingsBuffer {
+    type TransmittedWord = u8;
+
+    fn tx_treq() -> Option<u8> {
+        None
+    }
+
+    fn tx_address_count(&mut self) -> (u32, u32) {
+        (self.0.as_mut_ptr() as u32, DMA_BUFFER_SIZE as u32)
+    }
+
+    fn tx_increment(&self) -> bool {
+        true
+    }
+}
+
+// The same range is returned every time, as for a peripheral. The HAL only releases the channels
+// of a chained transfer from the ADC once its last buffer is filled with this marker.
+impl EndlessWriteTarget for ReadingsBuffer {}
+
+/// Owns the signal generator and the ADC transfer, so both can be paused and resumed together.
+pub struct Sampler {
+    /// Detection signal output
+    signal_gen: SignalPwm,
+    /// Transfer in progress, while detection is running
+    readings: Option<ReadingsDma>,
+    /// Transfer config, while detection is paused
+    paused: Option<SignalGenConfig>,
+    /// Checks each transfer for a disconnected probe
+    probe: ProbeMonitor,
+    /// Checks each transfer for a disconnected probe on the secondary channel
+    #[cfg(any(
+        feature = "dual_channel",
+        feature = "dual_probe",
+        feature = "triple_channel"
+    ))]
+    probe_b: ProbeMonitor,
+    /// Checks each transfer for a disconnected probe on the third channel
+    #[cfg(feature = "triple_channel")]
+    probe_c: ProbeMonitor,
+    /// Compares the primary and secondary channels
+    #[cfg(feature = "dual_channel")]
+    crosscheck: CrossCheck,
+    /// Scores the health of each channel, disqualifying a clearly faulty one
+    #[cfg(any(feature = "dual_channel", feature = "triple_channel"))]
+    health: HealthMonitor<ADC_CHANNELS>,
+    /// Supplies recorded samples in place of the probe readings
+    #[cfg(feature = "replay")]
+    replayer: Replayer,
+}
+
+impl Sampler {
+    /// Take ownership of a running signal generator and chained ADC transfer.
+    pub fn new(signal_gen: SignalPwm, readings: ReadingsDma) -> Self {
+        Self {
+            signal_gen,
+            readings: Some(readings),
+            paused: None,
+            probe: ProbeMonitor::new(),
+            #[cfg(any(
+                feature = "dual_channel",
+                feature = "dual_probe",
+                feature = "triple_channel"
+            ))]
+            probe_b: ProbeMonitor::new(),
+            #[cfg(feature = "triple_channel")]
+            probe_c: ProbeMonitor::new(),
+            #[cfg(feature = "dual_channel")]
+            crosscheck: CrossCheck::new(),
+            #[cfg(any(feature = "dual_channel", feature = "triple_channel"))]
+            health: HealthMonitor::new(),
+            #[cfg(feature = "replay")]
+            replayer: Replayer::new(),
+        }
+    }
+
+    /// Take the buffer filled by the completed transfer, calculate the averaged sample in place,
+    /// and queue the buffer to be filled again.
+    ///
+    /// The DMA channels are chained, so the next transfer started into the other buffer as soon
+    /// as this one completed, without waiting for the interrupt. Acquisition only stops if the
+    /// buffer isn't queued again before that transfer completes too.
+    ///
+    /// Returns [`Error::NoTransfer`] if no transfer is in progress, [`Error::DeadlineMissed`] if
+    /// averaging has repeatedly run over budget (see [`deadline`]), or
+    /// [`Error::ProbeDisconnected`] if the readings show the probe has been disconnected (see
+    /// [`probe`](crate::probe)). With `dual_channel`, both channels are checked, and
+    /// [`Error::ChannelMismatch`] is returned if they disagree (see [`crosscheck`]). The sample is
+    /// taken from the primary channel, unless it has been disqualified (see
+    /// [`health`](crate::health)), when only the secondary channel is checked and sampled. With
+    /// `dual_probe`, both probes are checked, and a sample is returned from each. With
+    /// `triple_channel`, a sample is returned from each channel, or [`None`] from a channel whose
+    /// readings show it has failed or that has been disqualified, leaving it to the
+    /// [vote](crate::voting) rather than raising an error. The averages are corrected for the ADC
+    /// offset and gain of the unit before they are checked (see [`AlignedAverages::calibrate`]),
+    /// except in the `minimal` build, which isn't provisioned.
+    ///
+    /// With `replay`, the next recorded sample is returned for every probe instead, and the probes
+    /// are not checked (see [`replay`](crate::replay)).
+    ///
+    /// Runs from RAM along with the averaging, so flash cache misses don't add jitter (see
+    /// `memory.x`).
+    #[inline(never)]
+    #[link_section = ".data.ram_func"]
+    pub fn complete_transfer(&mut self) -> Result<TransferSamples> {
+        #[cfg(feature = "irq_latency")]
+        crate::irq::record_dma_latency();
+        let start = Instant::now();
+        // A correlation pulse lasts until the window after the detection completes
+        correlation::end_pulse();
+        let mut readings = self.readings.take().ok_or(Error::NoTransfer)?;
+        // Acknowledge the interrupt, otherwise it will fire again immediately
+        readings.check_irq0();
+        // Returns straight away, as the transfer has completed
+        let (filled, running) = readings.wait();
+        liveness::check_in(Task::Acquisition);
+
+        #[cfg_attr(feature = "replay", allow(unused_variables))]
+        let avgs = perf::measure(Section::Average, || AlignedAverages::from_readings(&filled));
+        #[cfg(feature = "trace_indiv_samples")]
+        trace_indiv_samples(&filled, &avgs[0]);
+        log!(Sampling, debug, "Queueing DMA buffer");
+        self.readings = Some(running.write_next(filled));
+        deadline::check(Stage::Average, start)?;
+        #[cfg(not(feature = "replay"))]
+        let samples = {
+            #[cfg(not(feature = "minimal"))]
+            let provisioning = crate::provision::get();
+            #[cfg(not(feature = "minimal"))]
+            let avgs = avgs
+                .map(|avgs| avgs.calibrate(provisioning.adc_offset(), provisioning.adc_gain_ppm()));
+            #[cfg(not(any(feature = "dual_channel", feature = "triple_channel")))]
+            self.probe.check(&avgs[0])?;
+            #[cfg(feature = "dual_channel")]
+            let primary = match self.check_health(&avgs) {
+                // Nothing is left to cross-check against, so detection carries on with the other
+                Some(0) => {
+                    self.probe_b.check(&avgs[1])?;
+                    1
+                }
+                Some(_) => {
+                    self.probe.check(&avgs[0])?;
+                    0
+                }
+                None => {
+                    self.probe.check(&avgs[0])?;
+                    self.probe_b.check(&avgs[1])?;
+                    self.crosscheck.check(&avgs[0], &avgs[1])?;
+                    0
+                }
+            };
+            #[cfg(feature = "dual_probe")]
+            self.probe_b.check(&avgs[1])?;
+            #[cfg(not(any(
+                feature = "dual_channel",
+                feature = "dual_probe",
+                feature = "triple_channel"
+            )))]
+            let samples = [avgs[0].get_delta()];
+            #[cfg(feature = "dual_channel")]
+            let samples = [avgs[primary].get_delta()];
+            #[cfg(feature = "dual_probe")]
+            let samples = [avgs[0].get_delta(), avgs[1].get_delta()];
+            #[cfg(feature = "triple_channel")]
+            let samples = {
+                let disqualified = self.check_health(&avgs);
+                let healthy = [
+                    self.probe.check(&avgs[0]).is_ok(),
+                    self.probe_b.check(&avgs[1]).is_ok(),
+                    self.probe_c.check(&avgs[2]).is_ok(),
+                ];
+                core::array::from_fn(|channel| {
+                    (healthy[channel] && disqualified != Some(channel))
+                        .then(|| avgs[channel].get_delta())
+                })
+            };
+            samples
+        };
+        #[cfg(all(feature = "replay", not(feature = "triple_channel")))]
+        let samples = [self.replayer.next_sample(); crate::buffer::PROBES];
+        #[cfg(all(feature = "replay", feature = "triple_channel"))]
+        let samples = [Some(self.replayer.next_sample()); ADC_CHANNELS];
+        Ok(samples)
+    }
+
+    /// Score the health of each channel on the averages of the latest transfer, see
+    /// [`HealthMonitor`], and log an error if a channel is disqualified. Returns the disqualified
+    /// channel, if any.
+    #[cfg(any(feature = "dual_channel", feature = "triple_channel"))]
+    fn check_health(&mut self, avgs: &[AlignedAverages; ADC_CHANNELS]) -> Option<usize> {
+        let samples = core::array::from_fn(|channel| avgs[channel].get_delta());
+        if let Some(channel) = self.health.record(samples) {
+            let symptoms = self.health.last_window()[channel];
+            log!(
+                Sampling,
+                error,
+                "Channel {=usize} disqualified: noise {=u32}, {=u32} samples railed, {=u32} \
+                 disagreeing in the last window",
+                channel,
+                symptoms.noise,
+                symptoms.railed,
+                symptoms.disagreed
+            );
+        }
+        self.health.disqualified()
+    }
+
+    /// Pause signal generation, readings, and interrupts when disabled or error raised
+    pub fn pause(&mut self) {
+        log!(Sampling, debug, "Disabling signal generation");
+        self.signal_gen.disable();
+
+        log!(Sampling, debug, "Disabling FIFO readings/interrupts");
+        if let Some(readings) = self.readings.take() {
+            // The queued buffer has to be filled too before the channels are free
+            let (first, running) = readings.wait();
+            let (ch_a, ch_b, from, second) = running.wait();
+            self.paused = Some(((ch_a, ch_b), from, [first, second]));
+        }
+        liveness::suspend(Task::Acquisition);
+    }
+
+    /// Resume components with normal operation
+    pub fn resume(&mut self) {
+        log!(Sampling, debug, "Restoring signal generation");
+        self.signal_gen.enable();
+
+        log!(Sampling, debug, "Restoring ADC readings and interrupts");
+        if let Some(((mut ch_a, mut ch_b), from, [first, second])) = self.paused.take() {
+            ch_a.enable_irq0();
+            ch_b.enable_irq0();
+            let new_transfer = double_buffer::Config::new((ch_a, ch_b), from, first);
+            self.readings = Some(new_transfer.start().write_next(second));
+            self.probe.reset();
+            #[cfg(any(
+                feature = "dual_channel",
+                feature = "dual_probe",
+                feature = "triple_channel"
+            ))]
+            self.probe_b.reset();
+            #[cfg(feature = "triple_channel")]
+            self.probe_c.reset();
+            #[cfg(feature = "dual_channel")]
+            self.crosscheck.reset();
+        } else if self.readings.is_none() {
+            log!(Sampling, warn, "Failed to restore FIFO config");
+        }
+        liveness::resume(Task::Acquisition);
+    }
+}
+
+/// Pauses and resumes sampling. Implemented by [`Sampler`], and by proxies for a [`Sampler`] owned
+/// by another core.
+pub trait SamplerControl {
+    /// Pause sampling, see [`Sampler::pause`]
+    fn pause(&mut self);
+    /// Resume sampling, see [`Sampler::resume`]
+    fn resume(&mut self);
+}
+
+impl SamplerControl for Sampler {
+    fn pause(&mut self) {
+        Sampler::pause(self);
+    }
+
+    fn resume(&mut self) {
+        Sampler::resume(self);
+    }
+}
+
+/// Calculates proper averages aligned with signal timing
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub struct AlignedAverages {
+    /// The average voltage from the "higher" half of the measurements
+    avg_high: i32,
+    /// The average voltage from the "lower" half of the measurements
+    avg_low: i32,
+}
+
+impl AlignedAverages {
+    /// Takes the partial sums of the samples to calculate the high and low averages for contact
+    /// detection.
+    ///
+    /// Sorting requires an allocator, so this implementation identifies the highest partial sums.
+    /// Although this implementation technically allows for non-adjacent partial sums to be matched,
+    /// in effect this has little impact as those scenarios result in low overall deltas.
+    ///
+    /// This would be a good section to rewrite :)
+    #[link_section = ".data.ram_func"]
+    pub fn align_signal_timing(partial_sums: &[i32; 4]) -> Self {
+        let mut avg_high_idx = [4usize; 2];
+        let mut avg_high = 0i32;
+
+        let match_sum = partial_sums
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.cmp(b.1))
+            .unwrap();
+        avg_high_idx[0] = match_sum.0;
+        avg_high += match_sum.1;
+
+        let match_sum = partial_sums
+            .iter()
+            .enumerate()
+            .max_by(|a, b| match a.1.cmp(b.1) {
+                Ordering::Less | Ordering::Equal => {
+                    if avg_high_idx.contains(&b.0) {
+                        Ordering::Greater
+                    } else {
+                        Ordering::Less
+                    }
+                }
+                Ordering::Greater => {
+                    if avg_high_idx.contains(&a.0) {
+                        Ordering::Less
+                    } else {
+                        Ordering::Greater
+                    }
+                }
+            })
+            .unwrap();
+        avg_high_idx[1] = match_sum.0;
+        avg_high += match_sum.1;
+        avg_high /= (AVG_BUFFER_SIZE / 2) as i32;
+        #[cfg(feature = "trace_indiv_samples")]
+        Self::trace_high_index(&avg_high_idx);
+
+        let avg_low = partial_sums
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, sum)| {
+                if !avg_high_idx.contains(&idx) {
+                    Some(sum)
+                } else {
+                    None
+                }
+            })
+            .sum::<i32>()
+            / (AVG_BUFFER_SIZE / 2) as i32;
+
+        Self { avg_low, avg_high }
+    }
+
+    /// Records the two highest measurements from the first four of a 2 ms sample.
+    #[cfg(any(doc, feature = "trace_indiv_samples"))]
+    pub fn trace_high_index(avg_high_idx: &[usize; 2]) {
+        log!(Sampling, trace, "high indices (mod 4): {}", avg_high_idx);
+    }
+
+    /// Sums every fourth reading from each of the [`ADC_CHANNELS`] in a single pass over the
+    /// buffer, and aligns each channel's partial sums with the signal. Readings from the channels
+    /// are interleaved, starting with channel 0.
+    ///
+    /// The buffer is read a word at a time, adding the even and odd bytes of each word into two
+    /// pairs of 16-bit lanes, which are spread into the partial sums before they can overflow.
+    ///
+    /// # Panics
+    ///
+    /// If `avg_buffer` isn't word-aligned, as the buffers from
+    /// [`create_avg_buffers`](crate::buffer::create_avg_buffers) are.
+    #[link_section = ".data.ram_func"]
+    // There is only one channel without `dual_channel`, `dual_probe` or `triple_channel`
+    #[allow(clippy::modulo_one)]
+    pub fn from_readings(avg_buffer: &[u8; DMA_BUFFER_SIZE]) -> [Self; ADC_CHANNELS] {
+        // SAFETY: every bit pattern is a valid u32
+        let (head, words, tail) = unsafe { avg_buffer.align_to::<u32>() };
+        // Guaranteed by `create_avg_buffers`, otherwise readings would be skipped
+        assert!(head.is_empty() && tail.is_empty());
+
+        let mut partial_sums = [[0i32; 4]; ADC_CHANNELS]; // AVG_BUFFER_SIZE / 4 samples each
+        for block in words.chunks(LANE_PERIODS * ADC_CHANNELS) {
+            let mut lanes = [[0u32; 2]; ADC_CHANNELS];
+            sum_lanes(block, &mut lanes);
+            // Little-endian, so byte 0 of each word is the earliest reading
+            for (word, [even, odd]) in lanes.into_iter().enumerate() {
+                let bytes = [even & 0xFFFF, odd & 0xFFFF, even >> 16, odd >> 16];
+                for (byte, sum) in bytes.into_iter().enumerate() {
+                    let pos = word * 4 + byte;
+                    partial_sums[pos % ADC_CHANNELS][pos / ADC_CHANNELS] += sum as i32;
+                }
+            }
+        }
+        partial_sums.map(|sums| {
+            if cfg!(feature = "lock_in") {
+                Self::demodulate(&sums)
+            } else {
+                Self::align_signal_timing(&sums)
+            }
+        })
+    }
+
+    /// Demodulates the partial sums of readings taken at each quarter period of the signal, with
+    /// the `lock_in` feature.
+    ///
+    /// Multiplying by the in-phase and quadrature references reduces to the differences between
+    /// opposite quarters, and their magnitude is the peak-to-peak amplitude of a sinusoidal
+    /// signal, whatever its phase against the ADC. Interference uncorrelated with the signal
+    /// averages out of both. Each block holds a whole number of periods, so the reference phase
+    /// carries over from one block to the next. The high and low averages are placed either side
+    /// of the mean, so the [`ProbeMonitor`] sees the same levels as without it.
+    #[link_section = ".data.ram_func"]
+    pub fn demodulate(partial_sums: &[i32; 4]) -> Self {
+        let [deg_0, deg_90, deg_180, deg_270] = partial_sums.map(|sum| sum as i64);
+        let (in_phase, quadrature) = (deg_0 - deg_180, deg_90 - deg_270);
+        let magnitude = (in_phase * in_phase + quadrature * quadrature)
+            .unsigned_abs()
+            .isqrt()
+            / (AVG_BUFFER_SIZE / 4) as u64;
+        let mean = partial_sums.iter().sum::<i32>() / AVG_BUFFER_SIZE as i32;
+        let avg_low = mean - magnitude as i32 / 2;
+        Self {
+            avg_low,
+            avg_high: avg_low + magnitude as i32,
+        }
+    }
+
+    /// Correct both averages for the ADC offset and gain measured when the unit was
+    /// [provisioned](crate::provision), as `average * gain_ppm / 1_000_000 + offset`. The gain
+    /// scales the difference between them, while the offset only moves the levels seen by the
+    /// [`ProbeMonitor`].
+    #[cfg(not(feature = "minimal"))]
+    pub fn calibrate(&self, offset: i16, gain_ppm: u32) -> Self {
+        if offset == 0 && gain_ppm == UNITY_GAIN_PPM {
+            return *self;
+        }
+        // Averages are at most 8 bits, so the product fits
+        let correct = |avg: i32| avg * gain_ppm as i32 / UNITY_GAIN_PPM as i32 + offset as i32;
+        Self {
+            avg_high: correct(self.avg_high),
+            avg_low: correct(self.avg_low),
+        }
+    }
+
+    /// Average of the higher half of the readings
+    pub fn high(&self) -> i32 {
+        self.avg_high
+    }
+
+    /// Average of the lower half of the readings
+    pub fn low(&self) -> i32 {
+        self.avg_low
+    }
+
+    /// Calculates the average range of the sample interval
+    pub fn get_delta(&self) -> u8 {
+        u8::try_from(self.avg_high - self.avg_low).map_or(255, |avg| avg)
+    }
+}
+
+/// Periods of the signal that can be summed in 16-bit lanes without overflowing
+const LANE_PERIODS: usize = (u16::MAX / u8::MAX as u16) as usize;
+/// Periods of the signal summed per iteration with `unrolled_avg`
+#[cfg(feature = "unrolled_avg")]
+const UNROLL_PERIODS: usize = 4;
+
+/// Adds the even and odd bytes of each word in `block` into `lanes`, indexed by the word's
+/// position in a period of the signal (four readings from each channel).
+#[cfg(not(feature = "unrolled_avg"))]
+#[inline(always)]
+fn sum_lanes(block: &[u32], lanes: &mut [[u32; 2]; ADC_CHANNELS]) {
+    for period in block.chunks_exact(ADC_CHANNELS) {
+        for (lane, word) in lanes.iter_mut().zip(period) {
+            lane[0] += word & 0x00FF_00FF;
+            lane[1] += (word >> 8) & 0x00FF_00FF;
+        }
+    }
+}
+
+/// Adds the even and odd bytes of each word in `block` into `lanes`, indexed by the word's
+/// position in a period of the signal (four readings from each channel).
+///
+/// Handles [`UNROLL_PERIODS`] periods per iteration, which the compiler fully unrolls as their
+/// length is fixed, then any left over one at a time.
+#[cfg(feature = "unrolled_avg")]
+#[inline(always)]
+fn sum_lanes(block: &[u32], lanes: &mut [[u32; 2]; ADC_CHANNELS]) {
+    let mut groups = block.chunks_exact(UNROLL_PERIODS * ADC_CHANNELS);
+    for group in &mut groups {
+        let group: &[u32; UNROLL_PERIODS * ADC_CHANNELS] = group.try_into().unwrap();
+        for period in group.chunks_exact(ADC_CHANNELS) {
+            for (lane, word) in lanes.iter_mut().zip(period) {
+                lane[0] += word & 0x00FF_00FF;
+                lane[1] += (word >> 8) & 0x00FF_00FF;
+            }
+        }
+    }
+    for period in groups.remainder().chunks_exact(ADC_CHANNELS) {
+        for (lane, word) in lanes.iter_mut().zip(period) {
+            lane[0] += word & 0x00FF_00FF;
+            lane[1] += (word >> 8) & 0x00FF_00FF;
+        }
+    }
+}
+
+/// Records the following information about a 2 ms sample (note all measurements are 8 bits on a
+/// <span style="white-space:nowrap;">3.3 V</span> signal):
+/// - Maximum voltage recorded
+/// - Minimum voltage recorded
+/// - Average voltage from higher half
+/// - Average voltage from lower half
+/// - The first 20 measurements
+/// - All unique measurements seen
+///
+/// Example of a trace:
+///
+/// ```shell
+/// [TRACE] sampler.rs:59    => max: Some(255) // min: Some(0) // avg1: 127 // avg2: 127
+/// -> all_unique samples: [Some(0), Some(1), Some(2), Some(3), None, None, None, None, None, None, None, None, None, None, None, None, Some(16), Some(17), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(95), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(140), Some(141), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(231), Some(232), Some(233), Some(234), Some(235), None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, Some(253), Some(254), Some(255)]
+/// ```
+#[cfg(any(doc, feature = "trace_indiv_samples"))]
+pub fn trace_indiv_samples(avg_buffer: &[u8; DMA_BUFFER_SIZE], avgs: &AlignedAverages) {
+    let unique_samples = avg_buffer.iter().fold([None; 256], |mut acc, s| {
+        acc[*s as usize] = Some(s);
+        acc
+    });
+    log!(
+        Sampling,
+        trace,
+        "max: {} // min: {} // avg_high: {} // avg_low: {} // 20 samples: {}\n-> all_unique samples: {}",
+        avg_buffer.iter().max(),
+        avg_buffer.iter().min(),
+        avgs.avg_high,
+        avgs.avg_low,
+        avg_buffer.get(0..20).unwrap(),
+        unique_samples
+    );
+}
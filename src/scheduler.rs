@@ -0,0 +1,123 @@
+//! Tick-based cooperative scheduler for periodic jobs.
+//!
+//! Each loop or timer interrupt owns a [`Scheduler`] listing its jobs and their periods, and
+//! matches on the jobs returned by [`Scheduler::due`]. New periodic features add a job, rather than
+//! another hand-rolled counter. Jobs run to completion in the caller's context, so they should be
+//! short.
+//!
+//! Every time in the firmware comes from the 64-bit `TIMER` ([`TimerBlock`], `TIMER0` on the
+//! RP2350), counting microseconds from boot: the defmt timestamp of each log message is
+//! [`now_us`], and the times in the [journal](crate::event_log), exports and console responses are
+//! [`now_ms`]. A host can read the clock with the `TIME` console command, to line its own timeline
+//! up with the unit's.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::board::{self, TimerBlock};
+
+/// Interval between checks of the disable switch
+pub const SWITCH_POLL_PERIOD_MS: u32 = 20;
+/// Interval between heartbeat log messages
+pub const HEARTBEAT_PERIOD_MS: u32 = 10_000;
+/// Interval between timing statistics log messages
+pub const STATS_PERIOD_MS: u32 = 60_000;
+
+defmt::timestamp!("{=u64:us}", now_us());
+
+/// Milliseconds since the `TIMER` started, wrapping after about 49 days.
+///
+/// The `TIMER` peripheral must be running, as it is after
+/// [`Board::init`](crate::board::Board::init).
+pub fn now_ms() -> u32 {
+    uptime_ms() as u32
+}
+
+/// Milliseconds since the `TIMER` started, without wrapping. See [`now_ms`].
+pub fn uptime_ms() -> u64 {
+    now_us() / 1000
+}
+
+/// Microseconds since the `TIMER` started, without wrapping, or 0 while it is still held in
+/// reset, before [`Board::init`](crate::board::Board::init)
+pub fn now_us() -> u64 {
+    if !board::timer_running() {
+        return 0;
+    }
+    // SAFETY: read-only access to registers with no side effects
+    let timer = unsafe { &*TimerBlock::ptr() };
+    // The raw registers aren't latched, so retry if the low word wrapped between reads
+    loop {
+        let high = timer.timerawh().read().bits();
+        let low = timer.timerawl().read().bits();
+        if timer.timerawh().read().bits() == high {
+            return ((high as u64) << 32) | low as u64;
+        }
+    }
+}
+
+/// Job run every `period` ticks
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Periodic<J> {
+    /// Job to run
+    job: J,
+    /// Ticks between runs
+    period: u32,
+    /// Tick of the next run
+    next_due: u32,
+}
+
+impl<J> Periodic<J> {
+    /// Run `job` every `period` ticks, starting one period after tick 0
+    pub const fn new(job: J, period: u32) -> Self {
+        Self {
+            job,
+            period,
+            next_due: period,
+        }
+    }
+}
+
+/// Fixed set of periodic jobs
+pub struct Scheduler<J, const N: usize> {
+    /// Jobs, in the order they run when due together
+    jobs: [Periodic<J>; N],
+}
+
+impl<J: Copy, const N: usize> Scheduler<J, N> {
+    /// Create a scheduler for `jobs`
+    pub const fn new(jobs: [Periodic<J>; N]) -> Self {
+        Self { jobs }
+    }
+
+    /// Jobs due at tick `now`, which is usually [`now_ms`]. Ticks may wrap.
+    ///
+    /// Each returned job is scheduled again one period later. If a job has fallen more than a
+    /// period behind, the missed runs are skipped rather than run back to back.
+    pub fn due(&mut self, now: u32) -> impl Iterator<Item = J> + '_ {
+        self.jobs.iter_mut().filter_map(move |entry| {
+            // Wrapping comparison, valid while jobs are checked at least every 2^31 ticks
+            let late = now.wrapping_sub(entry.next_due) as i32;
+            if late < 0 {
+                return None;
+            }
+            entry.next_due = if late as u32 >= entry.period {
+                now.wrapping_add(entry.period)
+            } else {
+                entry.next_due.wrapping_add(entry.period)
+            };
+            Some(entry.job)
+        })
+    }
+}
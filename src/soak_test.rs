@@ -0,0 +1,124 @@
+//! Long-duration soak test, run from the console to characterize the noise floor before changing
+//! the detection thresholds.
+//!
+//! `SOAK START` clears and starts the statistics, and `SOAK STOP` freezes them. In between, every
+//! sample of a probe that is armed is recorded in its [`SoakStats`], from the same samples and
+//! deltas the detector checks, while detection carries on as usual. `SOAK` prints a summary at
+//! any time: the duration, then for each probe the mean and standard deviation of its samples,
+//! the largest delta, the [near misses](crate::near_miss), and the distribution of its deltas.
+//!
+//! The statistics are kept in RAM only, so a reboot ends the soak test.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+use crate::{
+    buffer::{Buffers, PROBES},
+    detection_core::Phase,
+    log, scheduler,
+    soak::SoakStats,
+};
+
+/// Soak test in progress or stopped, if one was started since boot
+static SOAK: Mutex<RefCell<Option<Soak>>> = Mutex::new(RefCell::new(None));
+
+/// Statistics of a soak test, and its duration
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Soak {
+    /// [`scheduler::now_ms`] at which the test started
+    started_ms: u32,
+    /// [`scheduler::now_ms`] at which the test stopped, if it has
+    stopped_ms: Option<u32>,
+    /// Statistics of each probe
+    stats: [SoakStats; PROBES],
+}
+
+impl Soak {
+    /// Whether samples are still being recorded
+    pub fn running(&self) -> bool {
+        self.stopped_ms.is_none()
+    }
+
+    /// Milliseconds recorded for, up to `now_ms` if still running
+    pub fn duration_ms(&self, now_ms: u32) -> u32 {
+        self.stopped_ms
+            .unwrap_or(now_ms)
+            .wrapping_sub(self.started_ms)
+    }
+
+    /// Statistics of `probe`
+    pub fn stats(&self, probe: usize) -> &SoakStats {
+        &self.stats[probe]
+    }
+}
+
+/// Start a new soak test, discarding the statistics of any previous one
+pub fn start() {
+    let started_ms = scheduler::now_ms();
+    critical_section::with(|cs| {
+        SOAK.borrow(cs).replace(Some(Soak {
+            started_ms,
+            stopped_ms: None,
+            stats: [SoakStats::new(); PROBES],
+        }));
+    });
+    log!(Detection, info, "Soak test started");
+}
+
+/// Stop the soak test in progress, keeping its statistics. Returns `false` if none is running.
+pub fn stop() -> bool {
+    let now = scheduler::now_ms();
+    let stopped = critical_section::with(|cs| {
+        let mut soak = SOAK.borrow_ref_mut(cs);
+        match soak.as_mut() {
+            Some(soak) if soak.running() => {
+                soak.stopped_ms = Some(now);
+                true
+            }
+            _ => false,
+        }
+    });
+    if stopped {
+        log!(Detection, info, "Soak test stopped");
+    }
+    stopped
+}
+
+/// Record the latest sample of each probe in `phases` that is armed, if a soak test is running
+pub fn record(buffers: &Buffers, phases: &[Option<Phase>; PROBES]) {
+    critical_section::with(|cs| {
+        let mut soak = SOAK.borrow_ref_mut(cs);
+        let Some(soak) = soak.as_mut().filter(|soak| soak.running()) else {
+            return;
+        };
+        for (probe, phase) in phases.iter().enumerate() {
+            if matches!(phase, Some(Phase::Armed | Phase::Warning)) {
+                soak.stats[probe].record(
+                    buffers.latest(probe),
+                    buffers.delta(probe),
+                    buffers.near_miss(probe),
+                );
+            }
+        }
+    });
+}
+
+/// Copy of the current soak test, if one was started since boot
+pub fn get() -> Option<Soak> {
+    critical_section::with(|cs| *SOAK.borrow_ref(cs))
+}
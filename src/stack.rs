@@ -0,0 +1,91 @@
+//! Stack usage monitoring for the core0 main stack, which every interrupt handler runs on.
+//!
+//! The binaries link with `flip-link`, which places the stack at the bottom of RAM below the
+//! statics, so an overflow faults instead of silently corrupting them. [`paint`] fills the unused
+//! stack with [`PAINT`] at boot, and [`check`] scans for the deepest word that has been
+//! overwritten since. The remaining headroom is logged with the other statistics, and
+//! [`Error::StackLow`] is raised once less than [`MIN_FREE_BYTES`] remain, well before a fault.
+//!
+//! The core1 stack of the `dual_core` binary is a static, so it is not covered.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::ptr::addr_of;
+
+use defmt::info;
+
+use crate::error::{Error, Result};
+
+/// Pattern painted over the unused stack
+pub const PAINT: u32 = 0xC0FF_EE00;
+/// Least free stack before [`Error::StackLow`] is raised
+pub const MIN_FREE_BYTES: u32 = 2048;
+/// Lowest address of the stack. `flip-link` places it at the start of RAM.
+const STACK_BOTTOM: u32 = 0x2000_0000;
+/// Words left unpainted below the stack pointer, for the frame of [`paint`] itself
+const PAINT_MARGIN_WORDS: u32 = 32;
+
+extern "C" {
+    /// Initial stack pointer, set by `cortex-m-rt` and moved below the statics by `flip-link`
+    static _stack_start: u32;
+}
+
+/// Initial stack pointer, the top of the stack
+fn stack_top() -> u32 {
+    // Only the address of the linker symbol is taken, which is safe
+    addr_of!(_stack_start) as u32
+}
+
+/// Size of the stack, in bytes
+pub fn size() -> u32 {
+    stack_top() - STACK_BOTTOM
+}
+
+/// Paint the unused stack below the current stack pointer with [`PAINT`]. Call once, as early as
+/// possible at boot.
+pub fn paint() {
+    let bottom = STACK_BOTTOM as *mut u32;
+    let words = (cortex_m::register::msp::read() - STACK_BOTTOM) / 4 - PAINT_MARGIN_WORDS;
+    for idx in 0..words as usize {
+        // SAFETY: the words are below the stack pointer and margin, so unused, and in RAM
+        unsafe { bottom.add(idx).write_volatile(PAINT) };
+    }
+}
+
+/// Free stack at the deepest point reached since [`paint`], in bytes.
+///
+/// Scans up from the bottom of the stack, so it takes a few milliseconds and should only run at a
+/// low priority.
+pub fn free_bytes() -> u32 {
+    let bottom = STACK_BOTTOM as *const u32;
+    let words = size() / 4;
+    // SAFETY: the whole range is within the stack, and only read
+    let painted = (0..words as usize)
+        .take_while(|idx| unsafe { bottom.add(*idx).read_volatile() } == PAINT)
+        .count();
+    painted as u32 * 4
+}
+
+/// Log the stack headroom, and return [`Error::StackLow`] if less than [`MIN_FREE_BYTES`]
+/// remain.
+pub fn check() -> Result<()> {
+    let free = free_bytes();
+    info!("Stack: {=u32} of {=u32} bytes never used", free, size());
+    if free < MIN_FREE_BYTES {
+        Err(Error::StackLow { free_bytes: free })
+    } else {
+        Ok(())
+    }
+}
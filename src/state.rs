@@ -0,0 +1,924 @@
+//! System state machine. Every change of [`SystemState`] goes through
+//! [`StateMachine::transition`], which updates the status LEDs, interlock outputs, buzzer and
+//! sampler together, and logs the reason.
+//!
+//! With `dual_probe`, the [`SystemState`] covers both probes: it is
+//! [`Alert`](SystemState::Alert) while either is in contact, and only leaves it once contact has
+//! ended on both. Each probe has its own interlock, so while one station is in contact, the other
+//! keeps running (see [`StateMachine::contact`]). Each probe also has its own LED, lit while it is
+//! in contact or its alert is latched, so the operator can see which station tripped.
+//!
+//! A smaller signal change, above [`DetectionConfig::warning_delta`] but below the contact
+//! threshold, enters [`SystemState::Warning`] instead. It shows the same LED pattern as an alert
+//! with a short beep every [`WARNING_BEEP_PERIOD_MS`], but the interlock stays released.
+//!
+//! The operator can [snooze](StateMachine::snooze) the buzzer for a while, without changing the
+//! LEDs or interlocks.
+//!
+//! Once the signal settles, detection waits [`DetectionConfig::arming_delay_ms`] to arm, with the
+//! interlocks tripped while the yellow LED blinks a countdown (see [`StateMachine::settled`]).
+//!
+//! Each interlock stays tripped for at least [`DetectionConfig::min_trip_ms`] from the detection,
+//! even if contact ends sooner, so a slow PLC input card sees every contact the firmware saw (see
+//! [`StateMachine::release_stretched`]).
+//!
+//! Releasing an interlock is [sequenced](crate::sequence): it stays tripped for
+//! [`DetectionConfig::cooldown_ms`] after an alert, then [`DetectionConfig::enable_delay_ms`]
+//! before every release, so the gated tool is never re-enabled instantly (see
+//! [`StateMachine::sequence_interlocks`]). Tripping always takes effect at once.
+//!
+//! If the operator leaves while detection is armed, and the board requires their
+//! [presence](crate::presence), the system enters [`SystemState::Disabled`] with the interlock
+//! tripped until they return (see [`StateMachine::presence_lost`]).
+//!
+//! [`DetectionConfig::warning_delta`]: crate::config::DetectionConfig::warning_delta
+//! [`DetectionConfig::arming_delay_ms`]: crate::config::DetectionConfig::arming_delay_ms
+//! [`DetectionConfig::min_trip_ms`]: crate::config::DetectionConfig::min_trip_ms
+//! [`DetectionConfig::cooldown_ms`]: crate::config::DetectionConfig::cooldown_ms
+//! [`DetectionConfig::enable_delay_ms`]: crate::config::DetectionConfig::enable_delay_ms
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::Cell;
+
+use cortex_m::singleton;
+use critical_section::Mutex;
+use defmt::{error, Format, Formatter};
+use embedded_hal::digital::{OutputPin, PinState};
+use heapless::spsc::{Consumer, Producer, Queue};
+
+pub use crate::detection_core::SETTLE_SAMPLES;
+pub use crate::system_state::SystemState;
+#[cfg(not(feature = "minimal"))]
+use crate::uptime;
+use crate::{
+    bist::SelfTest,
+    buffer::{DetectionMsg, PROBES},
+    components::{LedControl, StatusLedStates, StatusLeds},
+    config::{
+        board::{BuzzerPin, INTERLOCK_OPEN_DRAIN, INTERLOCK_TRIPPED_LEVEL},
+        DetectionConfig,
+    },
+    detection_core::Phase,
+    error::{self, Error, Result},
+    events::{self, SystemEvent},
+    fault,
+    hal::{
+        gpio::{
+            DynPinId, FunctionNull, FunctionSio, OutputEnableOverride, Pin, PinId, PullDown,
+            PullNone, SioOutput, ValidFunction,
+        },
+        pac,
+    },
+    log,
+    postmortem::{self, TraceEvent},
+    power,
+    safe_state::SAFE_STATE,
+    sampler::SamplerControl,
+    scheduler,
+    sequence::{Sequencer, Stage, Timing},
+};
+
+/// How long the acknowledge button must be held to reset a [`SystemState::Latched`] alert
+pub const LATCH_RESET_HOLD_MS: u32 = 3_000;
+/// Message displayed if system enters [`SystemState::Error`]
+const RESET_MSG: &str = "\nSystem must be power cycled to restore normal operation.";
+/// Message displayed if system enters [`SystemState::Disabled`]
+const DISABLE_MSG: &str = "\nToggle the disable switch to resume normal operation.";
+/// Message displayed if system enters [`SystemState::Latched`]
+const LATCHED_MSG: &str =
+    "\nHold the acknowledge button or run RESET LATCH on the console to resume normal operation.";
+/// Interval between the beeps sounded in [`SystemState::Warning`]
+pub const WARNING_BEEP_PERIOD_MS: u32 = 1_000;
+/// Length of each beep sounded in [`SystemState::Warning`]
+pub const WARNING_BEEP_MS: u32 = 40;
+/// Longest press of the acknowledge button that snoozes the buzzer
+pub const SNOOZE_PRESS_MAX_MS: u32 = 1_000;
+/// Period the yellow LED blinks at while counting down to arming
+pub const ARMING_BLINK_PERIOD_MS: u32 = 1_000;
+/// Period the yellow LED blinks at for the last [`ARMING_FINAL_MS`] of the countdown
+pub const ARMING_FINAL_BLINK_PERIOD_MS: u32 = 250;
+/// Final part of the countdown to arming, blinked faster so the operator knows it is about to end
+pub const ARMING_FINAL_MS: u32 = 3_000;
+/// State changes waiting for [`Indicators::update`]
+pub const STATE_CHANGE_QUEUE_SIZE: usize = 8;
+
+/// Interlock output, at [`INTERLOCK_TRIPPED_LEVEL`] while the saw must stop, and open-drain if
+/// [`INTERLOCK_OPEN_DRAIN`] is set. Type-erased, so the interlocks of every probe can be kept in one
+/// array. The internal pull is off, as an open-drain interlock has an external pull-up.
+pub type Interlock = Pin<DynPinId, FunctionSio<SioOutput>, PullNone>;
+/// Status LED of one probe, driven high while the probe is in contact or its alert is latched.
+/// Type-erased, so the LEDs of every probe can be kept in one array.
+pub type ProbeLed = Pin<DynPinId, FunctionSio<SioOutput>, PullDown>;
+/// Buzzer output, driven high while sounding
+pub type Buzzer = Pin<BuzzerPin, FunctionSio<SioOutput>, PullDown>;
+/// Indicators driving the LEDs selected by the `*_status` feature
+pub type SystemIndicators = Indicators<StatusLeds>;
+/// State changes passed from the [`StateMachine`] to the [`Indicators`]
+pub type StateChangeQueue = Queue<StateChange, STATE_CHANGE_QUEUE_SIZE>;
+
+/// [`scheduler::now_ms`] at which the buzzer unmutes, while snoozed. Shared with the
+/// [`Indicators`], which drive the buzzer.
+static SNOOZED_UNTIL_MS: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+/// Whether the buzzer is currently snoozed
+fn snoozed() -> bool {
+    critical_section::with(|cs| SNOOZED_UNTIL_MS.borrow(cs).get())
+        .is_some_and(|until| (scheduler::now_ms().wrapping_sub(until) as i32) < 0)
+}
+
+/// Unmute the buzzer, if snoozed
+fn unsnooze() {
+    critical_section::with(|cs| SNOOZED_UNTIL_MS.borrow(cs).set(None));
+}
+
+/// [`scheduler::now_ms`] at which detection arms, once the signal has settled in
+/// [`SystemState::Calibrating`]. Shared with the [`Indicators`], which count down to it.
+static ARM_AT_MS: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+/// Milliseconds left before detection arms, if counting down
+fn arming_remaining_ms() -> Option<u32> {
+    critical_section::with(|cs| ARM_AT_MS.borrow(cs).get())
+        .map(|at| (at.wrapping_sub(scheduler::now_ms()) as i32).max(0) as u32)
+}
+
+/// [`Stage`] of each interlock's release sequence, published by the [`StateMachine`] for the
+/// console
+static STAGES: Mutex<Cell<[Stage; PROBES]>> = Mutex::new(Cell::new([Stage::Tripped; PROBES]));
+
+/// [`Stage`] of each interlock's release sequence, in probe order
+pub fn interlock_stages() -> [Stage; PROBES] {
+    critical_section::with(|cs| STAGES.borrow(cs).get())
+}
+
+/// Drive `interlock` to `level`. An open-drain interlock is only ever driven low: for high, its
+/// output is disabled through the SIO, as [`SafeState::apply`] does, and the pull-up takes the line
+/// high.
+///
+/// [`SafeState::apply`]: crate::safe_state::SafeState::apply
+fn drive_interlock(interlock: &mut Interlock, level: PinState) -> Result<()> {
+    if !INTERLOCK_OPEN_DRAIN {
+        return Ok(interlock.set_state(level)?);
+    }
+    let mask = 1 << interlock.id().num;
+    // SAFETY: the output enable set and clear registers are write-1 atomic, so other pins are
+    // unaffected
+    unsafe {
+        let sio = &*pac::SIO::ptr();
+        match level {
+            PinState::Low => {
+                interlock.set_low()?;
+                sio.gpio_oe_set().write(|w| w.bits(mask));
+            }
+            PinState::High => {
+                sio.gpio_oe_clr().write(|w| w.bits(mask));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Take `interlock` for the SIO, in the tripped state, to pass to [`StateMachine::new`]. A
+/// push-pull interlock is driven to [`INTERLOCK_TRIPPED_LEVEL`] straight away. An open-drain
+/// interlock is set low with its output disabled, so it is released to its pull-up and never
+/// driven high, until [`drive_interlock`] sets its level.
+pub fn init_interlock<I>(interlock: Pin<I, FunctionNull, PullDown>) -> Interlock
+where
+    I: PinId + ValidFunction<FunctionSio<SioOutput>>,
+{
+    let mut interlock = interlock.into_pull_type::<PullNone>();
+    if !INTERLOCK_OPEN_DRAIN {
+        return interlock
+            .into_push_pull_output_in_state(INTERLOCK_TRIPPED_LEVEL)
+            .into_dyn_pin();
+    }
+    // The SIO enables the output as it takes the pin, so hold it disabled until that is undone
+    interlock.set_output_enable_override(OutputEnableOverride::Disable);
+    let mut interlock = interlock.into_push_pull_output_in_state(PinState::Low);
+    // SAFETY: the output enable clear register is write-1 atomic, so other pins are unaffected
+    unsafe {
+        let sio = &*pac::SIO::ptr();
+        sio.gpio_oe_clr().write(|w| w.bits(1 << interlock.id().num));
+    }
+    interlock.set_output_enable_override(OutputEnableOverride::Normal);
+    interlock.into_dyn_pin()
+}
+
+/// Take `led` for the SIO, off, to pass to [`StateMachine::new`]
+pub fn init_probe_led<I>(led: Pin<I, FunctionNull, PullDown>) -> ProbeLed
+where
+    I: PinId + ValidFunction<FunctionSio<SioOutput>>,
+{
+    led.into_push_pull_output_in_state(PinState::Low)
+        .into_dyn_pin()
+}
+
+/// Why a [`SystemState`] transition happened, kept until it is logged
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub enum Reason {
+    /// Fixed message
+    Message(&'static str),
+    /// Contact was detected
+    Detection(DetectionMsg),
+    /// An error was raised
+    Error(Error),
+}
+
+impl Format for Reason {
+    fn format(&self, fmt: Formatter) {
+        match self {
+            Reason::Message(msg) => defmt::write!(fmt, "{=str}", msg),
+            Reason::Detection(msg) => defmt::write!(fmt, "{}", msg),
+            Reason::Error(err) => {
+                defmt::write!(fmt, "{} (code {=u16:#06x})", err, err.code().value())
+            }
+        }
+    }
+}
+
+impl From<&'static str> for Reason {
+    fn from(msg: &'static str) -> Self {
+        Reason::Message(msg)
+    }
+}
+
+impl From<DetectionMsg> for Reason {
+    fn from(msg: DetectionMsg) -> Self {
+        Reason::Detection(msg)
+    }
+}
+
+impl From<Error> for Reason {
+    fn from(err: Error) -> Self {
+        Reason::Error(err)
+    }
+}
+
+/// A committed transition, waiting to be logged and displayed
+#[derive(Copy, Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub struct StateChange {
+    /// Previous state
+    pub from: SystemState,
+    /// New state
+    pub to: SystemState,
+    /// Why the transition happened
+    pub reason: Reason,
+}
+
+/// Owns the [`SystemState`] and the interlocks.
+///
+/// Transitions are called from interrupt handlers, often while other resources are locked, so
+/// they only switch the interlocks and queue a [`StateChange`]. Logging and driving the LEDs and
+/// buzzer is left to [`Indicators::update`], which runs at a lower priority.
+pub struct StateMachine {
+    /// Current state
+    state: SystemState,
+    /// Interlock output of each probe. Switched immediately, so the saw never waits on the
+    /// indicators.
+    interlocks: [Interlock; PROBES],
+    /// Status LED of each probe, see [`StateMachine::show_probes`]
+    probe_leds: [ProbeLed; PROBES],
+    /// Probes currently in contact. Only set in [`SystemState::Alert`].
+    contact: [bool; PROBES],
+    /// Probes whose contact has ended while [`DetectionConfig::latch_alert`] is set. In
+    /// [`SystemState::Alert`], another probe is still in contact, and their interlocks stay
+    /// tripped. In [`SystemState::Latched`], they are the probes the alert latched on. Only set in
+    /// those two states.
+    ///
+    /// [`DetectionConfig::latch_alert`]: crate::config::DetectionConfig::latch_alert
+    held: [bool; PROBES],
+    /// Probes that have warned, and not yet cleared. Only set in [`SystemState::Warning`].
+    warning: [bool; PROBES],
+    /// [`scheduler::now_ms`] at which contact was last detected on each probe
+    contact_at_ms: [u32; PROBES],
+    /// [`scheduler::now_ms`] until which the interlock of each probe stays tripped after contact
+    /// ended early, see [`DetectionConfig::min_trip_ms`]. Kept across transitions.
+    ///
+    /// [`DetectionConfig::min_trip_ms`]: crate::config::DetectionConfig::min_trip_ms
+    stretch_until_ms: [Option<u32>; PROBES],
+    /// Release sequence of each interlock. Kept across transitions.
+    sequences: [Sequencer; PROBES],
+    /// Timing of the release sequences, as last passed to [`StateMachine::sequence_interlocks`]
+    timing: Timing,
+    /// [`scheduler::now_ms`] at which a [`SystemState::Latched`] alert clears by itself, see
+    /// [`DetectionConfig::auto_clear_ms`]. Only set in [`SystemState::Latched`].
+    ///
+    /// [`DetectionConfig::auto_clear_ms`]: crate::config::DetectionConfig::auto_clear_ms
+    clear_at_ms: Option<u32>,
+    /// The operator left while armed, so [`SystemState::Disabled`] keeps the interlocks tripped.
+    /// Only set in [`SystemState::Disabled`].
+    absent: bool,
+    /// Sends state changes to the [`Indicators`]
+    changes: Producer<'static, StateChange, STATE_CHANGE_QUEUE_SIZE>,
+}
+
+impl StateMachine {
+    /// Start in [`SystemState::Booting`], with the interlocks tripped, and create the
+    /// [`Indicators`] that display it. `interlocks` and `probe_leds` are in probe order, each
+    /// taken with [`init_interlock`] and [`init_probe_led`]. `leds` should already display [`SystemState::Booting`], see
+    /// [`LedControl::init`].
+    ///
+    /// Returns [`Error::AlreadyInitialized`] if called more than once, as the [`StateChangeQueue`]
+    /// is a [`singleton`].
+    pub fn new<C: LedControl>(
+        leds: C,
+        interlocks: [Interlock; PROBES],
+        probe_leds: [ProbeLed; PROBES],
+        buzzer: Pin<BuzzerPin, FunctionNull, PullDown>,
+    ) -> Result<(Self, Indicators<C>)> {
+        let (changes, pending) = singleton!(: StateChangeQueue = Queue::new())
+            .ok_or(Error::AlreadyInitialized)?
+            .split();
+        let mut machine = Self {
+            state: SystemState::Booting,
+            interlocks,
+            probe_leds,
+            contact: [false; PROBES],
+            held: [false; PROBES],
+            warning: [false; PROBES],
+            contact_at_ms: [0; PROBES],
+            stretch_until_ms: [None; PROBES],
+            sequences: [Sequencer::new(); PROBES],
+            timing: DetectionConfig::DEFAULT.sequence_timing(),
+            clear_at_ms: None,
+            absent: false,
+            changes,
+        };
+        machine.set_interlocks()?;
+        Ok((
+            machine,
+            Indicators {
+                pattern: StatusLedStates::Alert,
+                logged: SystemState::Booting,
+                leds,
+                buzzer: buzzer.into_push_pull_output_in_state(PinState::Low),
+                buzzing: false,
+                snoozed: false,
+                pending,
+                fault: None,
+            },
+        ))
+    }
+
+    /// Current state
+    pub fn state(&self) -> SystemState {
+        self.state
+    }
+
+    /// Detection phase of `probe`, or [`None`] if detection isn't running. In
+    /// [`SystemState::Alert`], probes that aren't in contact keep checking for it. In
+    /// [`SystemState::Warning`], probes that have warned also check for the end of the warning.
+    pub fn phase(&self, probe: usize) -> Option<Phase> {
+        match self.state {
+            SystemState::Calibrating => Some(Phase::Calibrating),
+            SystemState::Alert if self.contact[probe] => Some(Phase::Alert),
+            SystemState::Warning if self.warning[probe] => Some(Phase::Warning),
+            SystemState::Armed | SystemState::Warning | SystemState::Alert => Some(Phase::Armed),
+            SystemState::Booting
+            | SystemState::Latched
+            | SystemState::Error
+            | SystemState::Disabled
+            | SystemState::Standby => None,
+        }
+    }
+
+    /// Contact was detected on `probe`. Enters [`SystemState::Alert`], or if another probe is
+    /// already in contact, trips the interlock of `probe` as well and logs `reason`.
+    pub fn contact(
+        &mut self,
+        probe: usize,
+        sampler: &mut impl SamplerControl,
+        reason: impl Into<Reason>,
+    ) -> Result<()> {
+        #[cfg(not(feature = "minimal"))]
+        uptime::count_detection();
+        // A new contact is always heard
+        unsnooze();
+        self.contact[probe] = true;
+        self.held[probe] = false;
+        self.contact_at_ms[probe] = scheduler::now_ms();
+        self.stretch_until_ms[probe] = None;
+        if self.state == SystemState::Alert {
+            self.set_interlocks()?;
+            self.show_probes()?;
+            self.log_alert(reason.into());
+            Ok(())
+        } else {
+            let result = self.transition(SystemState::Alert, sampler, reason);
+            if result.is_err() {
+                self.contact[probe] = false;
+            }
+            result
+        }
+    }
+
+    /// `probe` raised a warning. Enters [`SystemState::Warning`] from [`SystemState::Armed`], and
+    /// is ignored in any other state, as a contact takes precedence.
+    pub fn warning(&mut self, probe: usize, sampler: &mut impl SamplerControl) -> Result<()> {
+        if !self.state.armed() {
+            return Ok(());
+        }
+        self.transition(
+            SystemState::Warning,
+            sampler,
+            "Signal change below the contact threshold",
+        )?;
+        self.warning[probe] = true;
+        Ok(())
+    }
+
+    /// The warning on `probe` cleared. Returns to [`SystemState::Armed`] once no probe is warning.
+    pub fn warning_ended(&mut self, probe: usize, sampler: &mut impl SamplerControl) -> Result<()> {
+        self.warning[probe] = false;
+        if self.state != SystemState::Warning || self.warning.contains(&true) {
+            return Ok(());
+        }
+        self.transition(SystemState::Armed, sampler, "Warning cleared")
+    }
+
+    /// Contact ended on `probe`. Once no probe is in contact, enters [`SystemState::Latched`] if
+    /// `latch` is set (see [`DetectionConfig::latch_alert`]), otherwise [`SystemState::Armed`].
+    /// A latched alert clears by itself after `auto_clear_ms`, unless it is 0 (see
+    /// [`StateMachine::auto_clear_due`]).
+    ///
+    /// While another probe is still in contact, the system stays in [`SystemState::Alert`]. The
+    /// interlock of `probe` is released, unless `latch` is set, in which case it stays tripped
+    /// until the latch is reset. Either way, it stays tripped until `min_trip_ms` have passed
+    /// since the detection (see [`StateMachine::release_stretched`]).
+    ///
+    /// [`DetectionConfig::latch_alert`]: crate::config::DetectionConfig::latch_alert
+    pub fn contact_ended(
+        &mut self,
+        probe: usize,
+        latch: bool,
+        auto_clear_ms: u32,
+        min_trip_ms: u32,
+        sampler: &mut impl SamplerControl,
+    ) -> Result<()> {
+        self.contact[probe] = false;
+        let until = self.contact_at_ms[probe].wrapping_add(min_trip_ms);
+        if until.wrapping_sub(scheduler::now_ms()) as i32 > 0 {
+            self.stretch_until_ms[probe] = Some(until);
+        }
+        self.held[probe] = latch;
+        if self.contact.contains(&true) {
+            self.set_interlocks()?;
+            self.show_probes()?;
+            self.log_alert(Reason::Message("Contact ended on one probe"));
+            Ok(())
+        } else if latch {
+            self.transition(
+                SystemState::Latched,
+                sampler,
+                "Contact ended, alert latched",
+            )?;
+            self.clear_at_ms =
+                (auto_clear_ms > 0).then(|| scheduler::now_ms().wrapping_add(auto_clear_ms));
+            Ok(())
+        } else {
+            self.transition(SystemState::Armed, sampler, "Contact ended")
+        }
+    }
+
+    /// The operator [presence](crate::presence) input was lost. Enters [`SystemState::Disabled`]
+    /// with the interlocks tripped if detection is armed, and is ignored in any other state.
+    pub fn presence_lost(&mut self, sampler: &mut impl SamplerControl) -> Result<()> {
+        if !self.state.armed() {
+            return Ok(());
+        }
+        self.absent = true;
+        self.transition(SystemState::Disabled, sampler, "Operator presence lost")
+    }
+
+    /// The operator returned after [`StateMachine::presence_lost`], rearming detection. Ignored
+    /// unless the system was disabled by their absence.
+    pub fn presence_restored(&mut self, sampler: &mut impl SamplerControl) -> Result<()> {
+        if !self.operator_absent() {
+            return Ok(());
+        }
+        self.transition(SystemState::Armed, sampler, "Operator presence restored")
+    }
+
+    /// Whether the system is [`SystemState::Disabled`] because the operator left, so only their
+    /// return rearms it
+    pub fn operator_absent(&self) -> bool {
+        self.state == SystemState::Disabled && self.absent
+    }
+
+    /// Move to state `to` and update the interlocks to match, then queue the change to be logged
+    /// with `reason` and displayed. Sampling is paused or resumed through `sampler` as needed.
+    ///
+    /// Transitions to the current state do nothing. Returns [`Error::InvalidTransition`] if the
+    /// transition is not permitted (see [`SystemState::can_transition`]).
+    pub fn transition(
+        &mut self,
+        to: SystemState,
+        sampler: &mut impl SamplerControl,
+        reason: impl Into<Reason>,
+    ) -> Result<()> {
+        let from = self.state;
+        if from == to {
+            return Ok(());
+        } else if !from.can_transition(to) {
+            return Err(Error::InvalidTransition { from, to });
+        }
+
+        if from.sampling() && !to.sampling() {
+            sampler.pause();
+        } else if !from.sampling() && to.sampling() {
+            sampler.resume();
+        }
+        // Commit the state first, so a failed output still leaves sampling consistent
+        self.state = to;
+        #[cfg(not(feature = "minimal"))]
+        uptime::enter(to);
+        if !to.audible() {
+            unsnooze();
+        }
+        critical_section::with(|cs| ARM_AT_MS.borrow(cs).set(None));
+        if to != SystemState::Alert {
+            self.contact = [false; PROBES];
+        }
+        if !matches!(to, SystemState::Alert | SystemState::Latched) {
+            self.held = [false; PROBES];
+        }
+        self.warning = [false; PROBES];
+        self.clear_at_ms = None;
+        if to != SystemState::Disabled {
+            self.absent = false;
+        }
+        self.set_interlocks()?;
+        self.show_probes()?;
+        // If the queue is full, the indicators still catch up to the current state, and log the
+        // gap
+        let _ = self.changes.enqueue(StateChange {
+            from,
+            to,
+            reason: reason.into(),
+        });
+        postmortem::record(TraceEvent::StateChange { from, to });
+        Ok(())
+    }
+
+    /// Drive each interlock to match the current state. In [`SystemState::Alert`], only the
+    /// interlocks of probes in contact or held are tripped, unless no probe
+    /// is marked in contact, in which case they all are. They all stay tripped in
+    /// [`SystemState::Disabled`] while the operator is absent. An interlock is never released
+    /// while its contact is being stretched, or after a power failure, even before the error is
+    /// latched. Otherwise, its release goes through its [`Sequencer`].
+    fn set_interlocks(&mut self) -> Result<()> {
+        let any_contact = self.contact.contains(&true);
+        let now = scheduler::now_ms();
+        for (probe, interlock) in self.interlocks.iter_mut().enumerate() {
+            let sequence = &mut self.sequences[probe];
+            let tripped = match self.state {
+                SystemState::Alert => {
+                    let tripped = self.contact[probe] || self.held[probe] || !any_contact;
+                    if tripped {
+                        sequence.alert();
+                    }
+                    tripped
+                }
+                SystemState::Disabled if self.absent => true,
+                state => state.interlock_tripped(),
+            };
+            let stretched = self.stretch_until_ms[probe].is_some();
+            let tripped = tripped || stretched || power::power_failed();
+            let tripped = sequence.update(tripped, now, self.timing);
+            drive_interlock(
+                interlock,
+                if tripped {
+                    INTERLOCK_TRIPPED_LEVEL
+                } else {
+                    !INTERLOCK_TRIPPED_LEVEL
+                },
+            )?;
+        }
+        let stages = self.sequences.map(|sequence| sequence.stage());
+        critical_section::with(|cs| STAGES.borrow(cs).set(stages));
+        Ok(())
+    }
+
+    /// Light the LED of each probe in contact, or whose contact is held by the latch, and turn the
+    /// rest off. Switched with the interlocks rather than by the [`Indicators`], as the
+    /// [`StateChange`] queue only carries the [`SystemState`], which covers every probe.
+    fn show_probes(&mut self) -> Result<()> {
+        for (probe, led) in self.probe_leds.iter_mut().enumerate() {
+            led.set_state(PinState::from(self.contact[probe] || self.held[probe]))?;
+        }
+        Ok(())
+    }
+
+    /// Release any interlock held tripped by [`DetectionConfig::min_trip_ms`] once it has passed.
+    /// Polled by SysTick, so each stretch lasts up to one poll period longer.
+    ///
+    /// [`DetectionConfig::min_trip_ms`]: crate::config::DetectionConfig::min_trip_ms
+    pub fn release_stretched(&mut self) -> Result<()> {
+        let now = scheduler::now_ms();
+        let mut released = false;
+        for until in self.stretch_until_ms.iter_mut() {
+            if until.is_some_and(|until| now.wrapping_sub(until) as i32 >= 0) {
+                *until = None;
+                released = true;
+            }
+        }
+        if released {
+            self.set_interlocks()?;
+        }
+        Ok(())
+    }
+
+    /// Move on the release sequence of any interlock in a timed [`Stage`] with `timing`, releasing
+    /// it once its cool-down and enable delay have passed. Polled by SysTick, so each stage lasts
+    /// up to one poll period longer. `timing` is kept for the sequences started by transitions.
+    pub fn sequence_interlocks(&mut self, timing: Timing) -> Result<()> {
+        self.timing = timing;
+        if self.sequences.iter().any(Sequencer::pending) {
+            self.set_interlocks()?;
+        }
+        Ok(())
+    }
+
+    /// Queue a change of the probes in contact, while staying in [`SystemState::Alert`], to be
+    /// logged with `reason`
+    fn log_alert(&mut self, reason: Reason) {
+        let _ = self.changes.enqueue(StateChange {
+            from: SystemState::Alert,
+            to: SystemState::Alert,
+            reason,
+        });
+    }
+
+    /// Begin normal operation once the board is initialized, entering
+    /// [`SystemState::Calibrating`] if every [`SelfTest`] check passed. Otherwise the system stays
+    /// in [`SystemState::Error`], and never arms.
+    pub fn begin(&mut self, sampler: &mut impl SamplerControl, self_test: SelfTest) {
+        match self_test.result() {
+            Ok(()) => self
+                .transition(
+                    SystemState::Calibrating,
+                    sampler,
+                    "System initialization complete",
+                )
+                .unwrap_or_else(|err| self.fail(sampler, err)),
+            Err(err) => self.fail(sampler, err),
+        }
+    }
+
+    /// Clear a [`SystemState::Latched`] alert and rearm detection, logging `reason`.
+    ///
+    /// Returns [`Error::InvalidTransition`] if no alert is latched.
+    pub fn reset_latch(
+        &mut self,
+        sampler: &mut impl SamplerControl,
+        reason: &'static str,
+    ) -> Result<()> {
+        if self.state != SystemState::Latched {
+            return Err(Error::InvalidTransition {
+                from: self.state,
+                to: SystemState::Armed,
+            });
+        }
+        self.transition(SystemState::Armed, sampler, reason)
+    }
+
+    /// Whether a [`SystemState::Latched`] alert has been held for its
+    /// [`DetectionConfig::auto_clear_ms`], and should be reset.
+    ///
+    /// [`DetectionConfig::auto_clear_ms`]: crate::config::DetectionConfig::auto_clear_ms
+    pub fn auto_clear_due(&self) -> bool {
+        self.clear_at_ms
+            .is_some_and(|clear_at| scheduler::now_ms().wrapping_sub(clear_at) as i32 >= 0)
+    }
+
+    /// Mute the buzzer for `duration_ms`, leaving the LEDs and interlocks as they are. It unmutes
+    /// by itself afterwards, as soon as a new contact is detected, or once the buzzer would stop
+    /// anyway.
+    ///
+    /// Returns `false`, and does nothing, if the buzzer isn't sounding in the current state (see
+    /// [`SystemState::audible`]).
+    pub fn snooze(&mut self, duration_ms: u32) -> bool {
+        if !self.state.audible() {
+            return false;
+        }
+        let until = scheduler::now_ms().wrapping_add(duration_ms);
+        critical_section::with(|cs| SNOOZED_UNTIL_MS.borrow(cs).set(Some(until)));
+        let _ = self.changes.enqueue(StateChange {
+            from: self.state,
+            to: self.state,
+            reason: Reason::Message("Buzzer snoozed by the operator"),
+        });
+        true
+    }
+
+    /// The signal has settled in [`SystemState::Calibrating`]. The first call starts an arming
+    /// delay of `delay_ms` (see [`DetectionConfig::arming_delay_ms`]), counted down on the LEDs
+    /// while the interlocks stay tripped. Returns whether it has passed, so detection can be armed,
+    /// which is never the case outside [`SystemState::Calibrating`].
+    ///
+    /// [`DetectionConfig::arming_delay_ms`]: crate::config::DetectionConfig::arming_delay_ms
+    pub fn settled(&mut self, delay_ms: u32) -> bool {
+        if self.state != SystemState::Calibrating {
+            return false;
+        }
+        let now = scheduler::now_ms();
+        let (arm_at, started) = critical_section::with(|cs| {
+            let arm_at = ARM_AT_MS.borrow(cs);
+            match arm_at.get() {
+                Some(at) => (at, false),
+                None => {
+                    let at = now.wrapping_add(delay_ms);
+                    arm_at.set(Some(at));
+                    (at, true)
+                }
+            }
+        });
+        if started && delay_ms > 0 {
+            let _ = self.changes.enqueue(StateChange {
+                from: self.state,
+                to: self.state,
+                reason: Reason::Message("Signal settled, counting down to arming"),
+            });
+        }
+        now.wrapping_sub(arm_at) as i32 >= 0
+    }
+
+    /// Enter [`SystemState::Error`] because of `err`. Used by the binaries to latch errors
+    /// returned by the handlers in [`interrupt`](crate::interrupt).
+    ///
+    /// The outputs are put in the [`SAFE_STATE`] straight away, without waiting for the
+    /// [`Indicators`]. The [`ErrorCode`](crate::error::ErrorCode) is kept across a reset, see
+    /// [`error::take_last`], journalled through the [`events`] queue, and counted by the
+    /// [`fault`] manager. Errors raised once already in [`SystemState::Error`] are only logged the
+    /// first time their code is seen, and aren't journalled.
+    pub fn fail(&mut self, sampler: &mut impl SamplerControl, err: Error) {
+        SAFE_STATE.apply();
+        let first = fault::raise(err.code());
+        if first {
+            postmortem::record(TraceEvent::Error(err.code()));
+        }
+        if self.state != SystemState::Error {
+            error::record(err.code());
+            events::push(SystemEvent::Fault(err.code()));
+        } else if first {
+            error!(
+                "Further error in error state: {} (code {=u16:#06x})",
+                err,
+                err.code().value()
+            );
+        }
+        if let Err(output_err) = self.transition(SystemState::Error, sampler, err) {
+            error!("Unable to update outputs for error state: {}", output_err);
+        }
+    }
+}
+
+/// Logs state changes and displays the [`SystemState`] on the LEDs and buzzer, outside of the
+/// [`StateMachine`]'s lock.
+///
+/// If an output fails, the indicators enter a degraded mode rather than stopping detection: the
+/// failure is logged, kept in [`Indicators::fault`] and raised as [`Error::IndicatorFailed`], so
+/// it shows in `FAULTS` and the journal. The LEDs are no longer driven, and the buzzer and logging
+/// carry on. The interlock is owned by the [`StateMachine`], so it is unaffected.
+pub struct Indicators<C: LedControl> {
+    /// LED pattern currently displayed
+    pattern: StatusLedStates,
+    /// Destination of the last logged state change
+    logged: SystemState,
+    /// Status LEDs
+    leds: C,
+    /// Buzzer output
+    buzzer: Buzzer,
+    /// Whether the buzzer is currently driven
+    buzzing: bool,
+    /// Whether the buzzer was snoozed as of the last update
+    snoozed: bool,
+    /// State changes waiting to be logged
+    pending: Consumer<'static, StateChange, STATE_CHANGE_QUEUE_SIZE>,
+    /// First output failure, after which the LEDs are no longer driven
+    fault: Option<Error>,
+}
+
+impl<C: LedControl> Indicators<C> {
+    /// Log the queued state changes, then display `current`, which should be read from
+    /// [`StateMachine::state`]. Call from the lowest-priority task, whenever it runs. In
+    /// [`SystemState::Warning`], call at least every [`WARNING_BEEP_MS`] so the beeps are heard.
+    ///
+    /// While counting down to arming, the yellow LED blinks every [`ARMING_BLINK_PERIOD_MS`], then
+    /// every [`ARMING_FINAL_BLINK_PERIOD_MS`] for the last [`ARMING_FINAL_MS`].
+    pub fn update(&mut self, current: SystemState) {
+        while let Some(StateChange { from, to, reason }) = self.pending.dequeue() {
+            if from != self.logged {
+                log!(
+                    Detection,
+                    warn,
+                    "State changes lost: {} -> {}",
+                    self.logged,
+                    from
+                );
+            }
+            match to {
+                SystemState::Error => error!("{} -> {}: {}{=str}", from, to, reason, RESET_MSG),
+                SystemState::Disabled => {
+                    log!(
+                        Detection,
+                        info,
+                        "{} -> {}: {}{=str}",
+                        from,
+                        to,
+                        reason,
+                        DISABLE_MSG
+                    )
+                }
+                SystemState::Latched => {
+                    log!(
+                        Detection,
+                        warn,
+                        "{} -> {}: {}{=str}",
+                        from,
+                        to,
+                        reason,
+                        LATCHED_MSG
+                    )
+                }
+                _ => log!(Detection, info, "{} -> {}: {}", from, to, reason),
+            }
+            self.logged = to;
+        }
+
+        let pattern = match arming_remaining_ms() {
+            Some(remaining) if current == SystemState::Calibrating => {
+                let period = if remaining <= ARMING_FINAL_MS {
+                    ARMING_FINAL_BLINK_PERIOD_MS
+                } else {
+                    ARMING_BLINK_PERIOD_MS
+                };
+                if remaining % period >= period / 2 {
+                    current.led()
+                } else {
+                    StatusLedStates::Disabled
+                }
+            }
+            _ => current.led(),
+        };
+        if pattern != self.pattern {
+            if self.fault.is_none() {
+                if let Err(err) = self.leds.set_led(&self.pattern, pattern) {
+                    self.degrade(err);
+                }
+            }
+            self.pattern = pattern;
+        }
+
+        let snoozed = snoozed();
+        if self.snoozed && !snoozed {
+            log!(Detection, info, "Buzzer unmuted");
+        }
+        self.snoozed = snoozed;
+        let buzzing = !snoozed
+            && (current.buzzer_on()
+                || (current == SystemState::Warning
+                    && scheduler::now_ms() % WARNING_BEEP_PERIOD_MS < WARNING_BEEP_MS));
+        if buzzing != self.buzzing {
+            if let Err(err) = self.buzzer.set_state(PinState::from(buzzing)) {
+                self.degrade(err.into());
+            }
+            self.buzzing = buzzing;
+        }
+    }
+
+    /// The output failure that put the indicators in degraded mode, if any
+    pub fn fault(&self) -> Option<Error> {
+        self.fault
+    }
+
+    /// Enter degraded mode because of `err`, if not already degraded, and raise
+    /// [`Error::IndicatorFailed`] with the [`fault`] manager and the journal
+    fn degrade(&mut self, err: Error) {
+        if self.fault.is_none() {
+            let code = Error::IndicatorFailed.code();
+            fault::raise(code);
+            events::push(SystemEvent::Fault(code));
+            log!(
+                Leds,
+                error,
+                "Indicator output failed ({}), status LEDs disabled. Detection and the interlock \
+                 continue to run.",
+                err
+            );
+            self.fault = Some(err);
+        }
+    }
+}
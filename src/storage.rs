@@ -0,0 +1,63 @@
+//! Persistent storage in the flash region reserved at the end of `memory.x`.
+//!
+//! This lays out the sectors of the region and checksums their records. Flash is read and written
+//! by the driver in [`pfpu2_rp2040::flash`], re-exported here.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub use pfpu2_rp2040::flash::{
+    program_pages, read, write_sector, PAGE_SIZE, SECTOR_SIZE, STORAGE_OFFSET, STORAGE_SIZE,
+    XIP_BASE,
+};
+
+pub use crate::protocol::StorageError;
+
+/// Offset of the sector holding the persisted [`Config`](crate::config::Config)
+pub const CONFIG_OFFSET: u32 = STORAGE_OFFSET;
+/// Offset of the sector holding the [usage totals](crate::uptime::Totals)
+pub const USAGE_OFFSET: u32 = CONFIG_OFFSET + SECTOR_SIZE as u32;
+/// Offset of the first of the [`JOURNAL_SECTORS`] holding the [`event_log`](crate::event_log)
+pub const JOURNAL_OFFSET: u32 = USAGE_OFFSET + SECTOR_SIZE as u32;
+/// Number of sectors the [`event_log`](crate::event_log) rotates through
+pub const JOURNAL_SECTORS: usize = 8;
+/// Offset of the sector holding the [configuration PIN](crate::lock)
+pub const LOCK_OFFSET: u32 = JOURNAL_OFFSET + (JOURNAL_SECTORS * SECTOR_SIZE) as u32;
+/// Offset of the sector holding the [provisioning](crate::provision) of the unit
+pub const PROVISION_OFFSET: u32 = LOCK_OFFSET + SECTOR_SIZE as u32;
+/// Offset of the sector holding the stored [command macros](crate::macros)
+pub const MACRO_OFFSET: u32 = PROVISION_OFFSET + SECTOR_SIZE as u32;
+
+// Every sector laid out above must fit in the storage region
+const _: () =
+    assert!(MACRO_OFFSET as usize + SECTOR_SIZE <= STORAGE_OFFSET as usize + STORAGE_SIZE);
+
+/// CRC-32 (IEEE 802.3) checksum used to validate stored records.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(u32::MAX, data)
+}
+
+/// Continue a CRC-32 over `data`, for data that arrives in pieces. Start from `u32::MAX`, and
+/// invert the final value, as [`crc32`] does.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
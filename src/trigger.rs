@@ -0,0 +1,62 @@
+//! External trigger input, marking the sample stream for analysis.
+//!
+//! Each rising edge, from a camera frame strobe or a robot step for example, is recorded in the
+//! [journal](crate::event_log) as a [`Kind::Marker`] holding the
+//! [`SampleCounter`](crate::buffer::SampleCounter) of the sample it arrived with, so it appears
+//! in every [`export`](crate::export) and can be aligned with the sample history.
+//!
+//! The edge is latched by the `IO_BANK0` raw interrupt status, without enabling the interrupt, and
+//! taken once per sample by [`process_sample`](crate::interrupt::process_sample). Edges closer
+//! together than a sample period are merged into one marker.
+//!
+//! [`Kind::Marker`]: crate::journal::Kind::Marker
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    config::board::{TriggerPin, TRIGGER_PIN},
+    hal::{
+        gpio::{FunctionNull, FunctionSio, Interrupt, Pin, PullDown, SioInput},
+        pac,
+    },
+};
+
+/// Trigger input, pulled down so an unconnected input never marks the stream
+pub type TriggerInput = Pin<TriggerPin, FunctionSio<SioInput>, PullDown>;
+
+/// Take the trigger input, pulled down with its Schmitt trigger enabled, and discard any edge
+/// latched before it was configured
+pub fn monitor(pin: Pin<TriggerPin, FunctionNull, PullDown>) -> TriggerInput {
+    let mut input = pin.into_pull_down_input();
+    input.set_schmitt_enabled(true);
+    input.clear_interrupt(Interrupt::EdgeHigh);
+    input
+}
+
+/// Whether a rising edge has arrived since the last call, clearing it if so
+pub fn take_edge() -> bool {
+    let pin = TRIGGER_PIN as usize;
+    let (reg, mask) = (pin / 8, 1 << (4 * (pin % 8) + 3));
+    // SAFETY: the raw interrupt status is read-only, and its clear register is write-1 atomic, so
+    // other owners of the IO_BANK0 block are unaffected
+    unsafe {
+        let io = &*pac::IO_BANK0::ptr();
+        if io.intr(reg).read().bits() & mask == 0 {
+            return false;
+        }
+        io.intr(reg).write(|w| w.bits(mask));
+    }
+    true
+}
@@ -0,0 +1,257 @@
+//! Uptime and duty-cycle statistics, for scheduling maintenance.
+//!
+//! Tracks the time spent in each [`SystemState`] and the number of detections since boot. The
+//! time armed and the detections are also kept as totals since the last factory reset, persisted
+//! in their own flash sector at [`USAGE_OFFSET`]. Writing flash stops every interrupt for tens of
+//! milliseconds, so the totals are only [persisted](persist) while sampling is stopped, such as
+//! before entering standby. Anything since the last save is lost on a power cut.
+//!
+//! The statistics are [logged](report) with the other periodic statistics, and printed by the
+//! `UPTIME` console command.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use defmt::{info, warn, Format};
+
+use crate::{
+    scheduler,
+    state::SystemState,
+    storage::{self, StorageError, USAGE_OFFSET},
+};
+
+/// Marks a valid usage record in flash ("USAG")
+const MAGIC: u32 = 0x4741_5355;
+/// Size of the usage record: magic, detections, seconds armed and CRC
+const RECORD_SIZE: usize = 16;
+
+/// Totals since the last factory reset
+#[derive(Copy, Clone, Debug, Default, Hash, Ord, PartialOrd, Eq, PartialEq, Format)]
+pub struct Totals {
+    /// Contacts detected
+    pub detections: u32,
+    /// Seconds spent armed, see [`UptimeStats::armed_ms`]
+    pub armed_s: u32,
+}
+
+impl Totals {
+    /// Decode a record written by [`Totals::encode`], or [`None`] if it is missing or corrupt
+    fn decode(record: &[u8]) -> Option<Self> {
+        let word = |idx: usize| u32::from_le_bytes(record[idx..idx + 4].try_into().unwrap());
+        (word(0) == MAGIC && storage::crc32(&record[..12]) == word(12)).then(|| Self {
+            detections: word(4),
+            armed_s: word(8),
+        })
+    }
+
+    /// Encode as a flash record
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0; RECORD_SIZE];
+        record[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4..8].copy_from_slice(&self.detections.to_le_bytes());
+        record[8..12].copy_from_slice(&self.armed_s.to_le_bytes());
+        let crc = storage::crc32(&record[..12]);
+        record[12..].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+}
+
+/// Statistics since boot, with the persisted [`Totals`]
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Format)]
+pub struct UptimeStats {
+    /// Milliseconds since boot
+    pub uptime_ms: u64,
+    /// Milliseconds spent in each state since boot, indexed as [`SystemState::ALL`]
+    pub state_ms: [u64; SystemState::ALL.len()],
+    /// Contacts detected since boot
+    pub detections: u32,
+    /// Totals since the last factory reset, including this boot
+    pub since_reset: Totals,
+}
+
+impl UptimeStats {
+    /// Milliseconds spent in `state` since boot
+    pub fn time_in(&self, state: SystemState) -> u64 {
+        self.state_ms[state as usize]
+    }
+
+    /// Milliseconds spent armed since boot, in [`SystemState::Armed`] or [`SystemState::Warning`]
+    pub fn armed_ms(&self) -> u64 {
+        self.time_in(SystemState::Armed) + self.time_in(SystemState::Warning)
+    }
+}
+
+/// Statistics tracked since boot
+struct Usage {
+    /// Current state
+    state: SystemState,
+    /// [`scheduler::uptime_ms`] when the current state was entered
+    entered_ms: u64,
+    /// Milliseconds spent in each state before the current one was entered
+    state_ms: [u64; SystemState::ALL.len()],
+    /// Contacts detected since boot
+    detections: u32,
+    /// Totals persisted before this boot, or zero after a factory reset
+    persisted: Totals,
+    /// Counts since boot at the last factory reset, if there was one during this boot
+    baseline: Totals,
+    /// Totals last written to flash, to skip writing them again unchanged
+    saved: Totals,
+}
+
+impl Usage {
+    /// Snapshot of the statistics at `now_ms`
+    fn stats(&self, now_ms: u64) -> UptimeStats {
+        let mut state_ms = self.state_ms;
+        state_ms[self.state as usize] += now_ms - self.entered_ms;
+        let armed_s = ((state_ms[SystemState::Armed as usize]
+            + state_ms[SystemState::Warning as usize])
+            / 1000) as u32;
+        UptimeStats {
+            uptime_ms: now_ms,
+            state_ms,
+            detections: self.detections,
+            since_reset: Totals {
+                detections: self
+                    .persisted
+                    .detections
+                    .saturating_add(self.detections - self.baseline.detections),
+                armed_s: self
+                    .persisted
+                    .armed_s
+                    .saturating_add(armed_s - self.baseline.armed_s),
+            },
+        }
+    }
+}
+
+/// Statistics since boot, updated by the [`StateMachine`](crate::state::StateMachine)
+static USAGE: Mutex<RefCell<Usage>> = Mutex::new(RefCell::new(Usage {
+    state: SystemState::Booting,
+    entered_ms: 0,
+    state_ms: [0; SystemState::ALL.len()],
+    detections: 0,
+    persisted: Totals {
+        detections: 0,
+        armed_s: 0,
+    },
+    baseline: Totals {
+        detections: 0,
+        armed_s: 0,
+    },
+    saved: Totals {
+        detections: 0,
+        armed_s: 0,
+    },
+}));
+
+/// Load the totals persisted by earlier boots. Call once during startup. A missing or corrupt
+/// record starts the totals from zero.
+pub fn load() {
+    let totals = Totals::decode(storage::read(USAGE_OFFSET, RECORD_SIZE));
+    if totals.is_none() {
+        warn!("No usage totals stored, counting from zero");
+    }
+    let totals = totals.unwrap_or_default();
+    critical_section::with(|cs| {
+        let mut usage = USAGE.borrow_ref_mut(cs);
+        usage.persisted = totals;
+        usage.saved = totals;
+    });
+}
+
+/// Record entering `state`. Called by
+/// [`StateMachine::transition`](crate::state::StateMachine::transition).
+pub fn enter(state: SystemState) {
+    let now = scheduler::uptime_ms();
+    critical_section::with(|cs| {
+        let mut usage = USAGE.borrow_ref_mut(cs);
+        let previous = usage.state as usize;
+        usage.state_ms[previous] += now - usage.entered_ms;
+        usage.state = state;
+        usage.entered_ms = now;
+    });
+}
+
+/// Count a detected contact. Called by
+/// [`StateMachine::contact`](crate::state::StateMachine::contact).
+pub fn count_detection() {
+    critical_section::with(|cs| {
+        let mut usage = USAGE.borrow_ref_mut(cs);
+        usage.detections = usage.detections.saturating_add(1);
+    });
+}
+
+/// Statistics up to now
+pub fn stats() -> UptimeStats {
+    let now = scheduler::uptime_ms();
+    critical_section::with(|cs| USAGE.borrow_ref(cs).stats(now))
+}
+
+/// Write the totals to flash if they have changed since the last write. Only call while sampling
+/// is stopped, as interrupts are disabled for the duration.
+pub fn persist() -> Result<(), StorageError> {
+    let totals = stats().since_reset;
+    if critical_section::with(|cs| USAGE.borrow_ref(cs).saved) == totals {
+        return Ok(());
+    }
+    storage::write_sector(USAGE_OFFSET, &totals.encode())?;
+    critical_section::with(|cs| USAGE.borrow_ref_mut(cs).saved = totals);
+    Ok(())
+}
+
+/// Clear the totals since the last factory reset, in flash as well. The statistics since boot
+/// are kept.
+pub fn factory_reset() -> Result<(), StorageError> {
+    storage::write_sector(USAGE_OFFSET, &Totals::default().encode())?;
+    let now = scheduler::uptime_ms();
+    critical_section::with(|cs| {
+        let mut usage = USAGE.borrow_ref_mut(cs);
+        let stats = usage.stats(now);
+        // Counted from now, so the time and detections before the reset are left out
+        usage.baseline = Totals {
+            detections: stats.detections,
+            armed_s: (stats.armed_ms() / 1000) as u32,
+        };
+        usage.persisted = Totals::default();
+        usage.saved = Totals::default();
+    });
+    Ok(())
+}
+
+/// Log the statistics, then [`persist`] the totals if `current` doesn't sample. Call
+/// periodically from the lowest-priority task, and before entering standby.
+pub fn report(current: SystemState) {
+    log_stats();
+    if !current.sampling() {
+        persist().unwrap_or_else(|err| warn!("Unable to save usage totals: {}", err));
+    }
+}
+
+/// Log the statistics, without persisting the totals
+pub fn log_stats() {
+    let stats = stats();
+    info!(
+        "Uptime {=u64} s, armed {=u64} s, {=u32} detections. Since factory reset: armed {=u32} s, \
+         {=u32} detections",
+        stats.uptime_ms / 1000,
+        stats.armed_ms() / 1000,
+        stats.detections,
+        stats.since_reset.armed_s,
+        stats.since_reset.detections
+    );
+}
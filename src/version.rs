@@ -0,0 +1,50 @@
+//! Firmware version, build information and configuration hash, so field data can always be traced
+//! to the exact firmware and settings that produced it.
+//!
+//! `build.rs` embeds the git commit, the build time and the enabled features. Together with the
+//! board variant and the [`config_hash`] of the active configuration, they are logged at boot in
+//! the [`banner`](crate::banner), printed by the `*IDN?` console command, and written at the start
+//! of every [`export`](crate::export) of the event log.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt::{self, Write};
+
+use crate::config::Config;
+
+include!(concat!(env!("OUT_DIR"), "/version_generated.rs"));
+
+/// Version of the firmware crate
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Hash of `config`: the CRC-32 of its serialized form, which is also the last 4 bytes
+/// (little-endian) of the `CONFIG EXPORT` blob. Units with the same hash have the same settings.
+pub fn config_hash(config: &Config) -> u32 {
+    let mut blob = [0u8; Config::MAX_BLOB_SIZE];
+    config.to_blob(&mut blob).map_or(0, |blob| {
+        u32::from_le_bytes(blob[blob.len() - 4..].try_into().unwrap())
+    })
+}
+
+/// Write [`FEATURES`] to `out`, separated by commas
+pub fn write_features(out: &mut impl Write) -> fmt::Result {
+    for (idx, feature) in FEATURES.iter().enumerate() {
+        if idx > 0 {
+            out.write_char(',')?;
+        }
+        out.write_str(feature)?;
+    }
+    Ok(())
+}
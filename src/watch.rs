@@ -0,0 +1,139 @@
+//! Live view of the detection signal for bench tuning, streamed over the console by `WATCH`.
+//!
+//! Every [`WATCH_PERIOD_MS`], a [`Watch`] writes one line with its number, counting from 0, and
+//! the state, then for each probe its latest averaged sample, the baseline (the mean of the last
+//! [`BASELINE_SAMPLES`]) and the difference between them, in codes and as a voltage at the ADC,
+//! then the contacts detected since boot:
+//!
+//! ```text
+//! 17 Armed avg 198 base 201 delta -3 -39mV detections 2
+//! ```
+//!
+//! A gap in the numbers shows lines lost on the way to the host. They can't be sent again, as
+//! the values they held are gone.
+//!
+//! The stream runs until any key is pressed. The console is only served when the USB device needs
+//! attention, so while watching, [`wake`] is called periodically to keep the lines coming.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use cortex_m::peripheral::NVIC;
+
+use crate::{
+    buffer::{Buffers, ProbeSamples, PROBES},
+    config,
+    console::ConsoleOutput,
+    hal::pac::Interrupt,
+    scheduler,
+    state::{StateMachine, SystemState},
+    uptime,
+};
+
+/// Interval between lines, for 10 lines per second
+pub const WATCH_PERIOD_MS: u32 = 100;
+/// Samples averaged into the baseline (0.5 s with 2 ms averaging)
+pub const BASELINE_SAMPLES: usize = 250;
+
+/// Whether a [`Watch`] is running, so [`wake`] serves the console
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// Pend `USBCTRL_IRQ` if a [`Watch`] is running, so its next line is written even while the USB
+/// device is idle. Call from a periodic task, at least every [`WATCH_PERIOD_MS`].
+pub fn wake() {
+    if WATCHING.load(Ordering::Relaxed) {
+        NVIC::pend(Interrupt::USBCTRL_IRQ);
+    }
+}
+
+/// Values shown on one line of a [`Watch`]
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Snapshot {
+    /// Current state
+    pub state: SystemState,
+    /// Latest averaged sample from each probe
+    pub average: ProbeSamples,
+    /// Mean of the last [`BASELINE_SAMPLES`] from each probe
+    pub baseline: ProbeSamples,
+}
+
+impl Snapshot {
+    /// Read the current values from `buffers` and `state`
+    pub fn take(buffers: &Buffers, state: &StateMachine) -> Self {
+        Self {
+            state: state.state(),
+            average: core::array::from_fn(|probe| buffers.latest(probe)),
+            baseline: core::array::from_fn(|probe| buffers.mean(probe, BASELINE_SAMPLES)),
+        }
+    }
+}
+
+/// Progress of a `WATCH` command, see the [module documentation](self)
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Watch {
+    /// [`scheduler::now_ms`] at which the next line is due
+    next_ms: u32,
+    /// Number of the next line
+    seq: u32,
+}
+
+impl Watch {
+    /// Start watching, with the first line due straight away
+    pub fn start() -> Self {
+        WATCHING.store(true, Ordering::Relaxed);
+        Self {
+            next_ms: scheduler::now_ms(),
+            seq: 0,
+        }
+    }
+
+    /// Write the next line to `out` if it is due, reading its values from `snapshot`
+    pub fn fill(&mut self, out: &mut ConsoleOutput, snapshot: impl FnOnce() -> Snapshot) {
+        let now = scheduler::now_ms();
+        if (now.wrapping_sub(self.next_ms) as i32) < 0 {
+            return;
+        }
+        self.next_ms = now.wrapping_add(WATCH_PERIOD_MS);
+
+        let snapshot = snapshot();
+        let _ = write!(out, "{} {:?}", self.seq, snapshot.state);
+        self.seq = self.seq.wrapping_add(1);
+        for probe in 0..PROBES {
+            if PROBES > 1 {
+                let _ = write!(out, " p{}", probe);
+            }
+            let (average, baseline) = (snapshot.average[probe], snapshot.baseline[probe]);
+            let delta = average as i16 - baseline as i16;
+            let _ = write!(
+                out,
+                " avg {} base {} delta {} {}mV",
+                average,
+                baseline,
+                delta,
+                config::adc_scale().millivolts(delta).0
+            );
+        }
+        let _ = write!(out, " detections {}\r\n", uptime::stats().detections);
+    }
+
+    /// Stop watching, so [`wake`] no longer serves the console
+    pub fn stop(self) {
+        WATCHING.store(false, Ordering::Relaxed);
+    }
+}
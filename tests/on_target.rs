@@ -0,0 +1,337 @@
+//! On-target tests, run on a Pico through a debug probe with
+//! [defmt-test](https://docs.rs/defmt-test):
+//!
+//! ```text
+//! cargo test --test on_target --no-default-features --features triple_status
+//! ```
+//!
+//! The `persist_panic` handler resets the chip instead of halting, so it is left out and
+//! `panic-probe` reports failed assertions. The tests share a single
+//! [`Board`](aps490_pfpu2_mini::board::Board), and run in order.
+
+// Copyright 2024 Cameron Rodriguez
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_std]
+#![no_main]
+// Like the host tests, each test is named after what it checks. defmt-test drops attributes on
+// the module, so this can't be limited to it.
+#![allow(clippy::missing_docs_in_private_items)]
+
+use aps490_pfpu2_mini::{
+    board::Board,
+    buffer::{ADC_CHANNELS, DMA_BUFFER_SIZE, PROBES},
+    config::board::{BUZZER_PIN, INTERLOCK_OPEN_DRAIN, INTERLOCK_PINS, INTERLOCK_TRIPPED_LEVEL},
+    hal,
+    sampler::AlignedAverages,
+    state::SystemState,
+};
+use defmt::{assert, assert_eq};
+use defmt_rtt as _;
+use embedded_hal::digital::PinState;
+#[cfg(not(feature = "persist_panic"))]
+use panic_probe as _;
+
+/// Second-stage bootloader, from [rp2040-boot2](https://docs.rs/rp2040-boot2)
+#[cfg(not(feature = "rp2350"))]
+#[link_section = ".boot2"]
+#[used]
+pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
+/// Image definition, which the RP2350 boot ROM looks for to run the firmware as a secure Arm
+/// executable
+#[cfg(feature = "rp2350")]
+#[link_section = ".start_block"]
+#[used]
+pub static IMAGE_DEF: hal::block::ImageDef = hal::block::ImageDef::secure_exe();
+
+/// Level driven on GPIO `pin`, read back from the SIO output register
+fn driven(pin: u8) -> bool {
+    // SAFETY: read-only access to the SIO GPIO output register
+    let sio = unsafe { &*hal::pac::SIO::ptr() };
+    sio.gpio_out().read().bits() & 1 << pin != 0
+}
+
+/// Whether the output of GPIO `pin` is enabled, read back from the SIO output enable register
+fn output_enabled(pin: u8) -> bool {
+    // SAFETY: read-only access to the SIO GPIO output enable register
+    let sio = unsafe { &*hal::pac::SIO::ptr() };
+    sio.gpio_oe().read().bits() & 1 << pin != 0
+}
+
+/// Averages of `readings` from partial sums of every fourth reading of each channel, taken one
+/// reading at a time, as before they were summed a word at a time
+// There is only one channel without `dual_channel`, `dual_probe` or `triple_channel`
+#[allow(clippy::modulo_one)]
+fn strided_averages(readings: &[u8; DMA_BUFFER_SIZE]) -> [AlignedAverages; ADC_CHANNELS] {
+    let mut partial_sums = [[0i32; 4]; ADC_CHANNELS];
+    for (idx, reading) in readings.iter().enumerate() {
+        partial_sums[idx % ADC_CHANNELS][idx / ADC_CHANNELS % 4] += *reading as i32;
+    }
+    partial_sums.map(|sums| {
+        if cfg!(feature = "lock_in") {
+            AlignedAverages::demodulate(&sums)
+        } else {
+            AlignedAverages::align_signal_timing(&sums)
+        }
+    })
+}
+
+/// Check the interlocks and buzzer match `state`, after updating the indicators
+fn check_outputs(board: &mut Board, state: SystemState) {
+    assert_eq!(board.state.state(), state);
+    board.indicators.update(board.state.state());
+    let level = if state.interlock_tripped() {
+        INTERLOCK_TRIPPED_LEVEL
+    } else {
+        !INTERLOCK_TRIPPED_LEVEL
+    };
+    for &pin in &INTERLOCK_PINS[..PROBES] {
+        if INTERLOCK_OPEN_DRAIN {
+            // Only ever driven low, and released to the pull-up for high
+            assert!(!driven(pin));
+            assert_eq!(output_enabled(pin), level == PinState::Low);
+        } else {
+            assert_eq!(driven(pin), level == PinState::High);
+            assert!(output_enabled(pin));
+        }
+    }
+    assert_eq!(driven(BUZZER_PIN), state.buzzer_on());
+}
+
+#[defmt_test::tests]
+mod tests {
+    use aps490_pfpu2_mini::{
+        board::Board,
+        buffer::{create_avg_buffers, Buffers, DMA_BUFFER_SIZE, LONGTERM_SIZE, PROBES},
+        components::{LedControl, StatusLeds},
+        config::{
+            board::STATUS_LED_PINS, Config, DetectionConfig, MillivoltThresholds, RestoreProfile,
+        },
+        detection_core::{Detector, Event, Phase, SETTLE_SAMPLES},
+        error::Error,
+        hal::pac,
+        sampler::AlignedAverages,
+        state::SystemState,
+    };
+    #[cfg(not(any(feature = "dual_core", feature = "minimal")))]
+    use aps490_pfpu2_mini::{
+        event_log,
+        journal::{Decoder, Kind},
+        storage::JOURNAL_SECTORS,
+    };
+    use defmt::{assert, assert_eq};
+    use embedded_hal::digital::PinState;
+
+    use super::{check_outputs, driven, strided_averages};
+
+    /// Readings buffer aligned like those from [`create_avg_buffers`]
+    #[repr(C, align(4))]
+    struct Readings([u8; DMA_BUFFER_SIZE]);
+
+    #[init]
+    fn init() -> Board {
+        Board::init(pac::Peripherals::take().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn board_init_is_singleton() {
+        // SAFETY: the peripherals are not touched, as the second call returns straight away
+        let pac = unsafe { pac::Peripherals::steal() };
+        assert!(matches!(Board::init(pac), Err(Error::AlreadyInitialized)));
+    }
+
+    #[test]
+    fn buffers_are_singletons() {
+        assert!(matches!(
+            Buffers::init(DetectionConfig::DEFAULT),
+            Err(Error::AlreadyInitialized)
+        ));
+        assert!(matches!(
+            create_avg_buffers(),
+            Err(Error::AlreadyInitialized)
+        ));
+    }
+
+    #[test]
+    fn self_test_passes(board: &mut Board) {
+        assert!(board.self_test.result().is_ok());
+    }
+
+    #[test]
+    fn buffer_insertion_wraps(board: &mut Board) {
+        let start = board.buffers.samples_recorded();
+        let head = board.buffers.head();
+        for sample in 0..LONGTERM_SIZE + 5 {
+            board.buffers.insert([sample as u8; PROBES]);
+        }
+        assert_eq!(
+            board.buffers.samples_recorded(),
+            start + LONGTERM_SIZE as u64 + 5
+        );
+        assert_eq!(board.buffers.head(), (head + 5) % LONGTERM_SIZE);
+        assert_eq!(
+            board.buffers.detection_idx(0).get_counter(),
+            board.buffers.samples_recorded() - 1
+        );
+    }
+
+    #[test]
+    fn detector_wraps() {
+        let mut detector = Detector::<8>::new(DetectionConfig::DEFAULT.thresholds());
+        for sample in 0..10 {
+            detector.insert(sample);
+        }
+        assert_eq!(detector.head(), 2);
+        assert_eq!(detector.back(3), 7);
+        assert_eq!(detector.samples()[detector.back(3)], 6);
+        assert_eq!(detector.samples_recorded(), 10);
+        assert!(SETTLE_SAMPLES > 10);
+        assert!(detector.step(Phase::Calibrating).is_none());
+    }
+
+    #[test]
+    fn detector_detects_step() {
+        let mut detector = Detector::<16>::new(DetectionConfig::DEFAULT.thresholds());
+        for _ in 0..SETTLE_SAMPLES {
+            detector.insert(200);
+        }
+        assert!(detector.step(Phase::Calibrating) == Some(Event::Settled));
+        detector.insert(200);
+        assert!(detector.step(Phase::Armed).is_none());
+        // A drop to 0 exceeds every default threshold, and is confirmed on the next sample
+        detector.insert(0);
+        assert!(detector.step(Phase::Armed).is_none());
+        detector.insert(0);
+        assert!(detector.step(Phase::Armed) == Some(Event::Contact));
+    }
+
+    /// Run with each channel feature and `unrolled_avg` to cover every way the lanes are summed
+    #[test]
+    fn word_sums_match_strided_sums() {
+        // Too large for the stack with several channels
+        let readings = cortex_m::singleton!(: Readings = Readings([0; DMA_BUFFER_SIZE])).unwrap();
+        // xorshift32, so every run checks the same buffers
+        let mut seed = 0x2545_F491u32;
+        for _ in 0..8 {
+            for reading in readings.0.iter_mut() {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                *reading = seed as u8;
+            }
+            assert_eq!(
+                AlignedAverages::from_readings(&readings.0),
+                strided_averages(&readings.0)
+            );
+        }
+        // Full-scale readings come closest to overflowing the lanes
+        readings.0.fill(u8::MAX);
+        assert_eq!(
+            AlignedAverages::from_readings(&readings.0),
+            strided_averages(&readings.0)
+        );
+    }
+
+    #[test]
+    fn widest_config_fits_blob() {
+        let config = Config {
+            detection: DetectionConfig {
+                trigger_delta: i16::MIN,
+                warning_delta: i16::MIN,
+                confirm_delta: i16::MIN,
+                restore_delta: i16::MIN,
+                alert_hold_samples: u32::MAX,
+                restore_profile: RestoreProfile::Slow,
+                restore_fast_samples: u32::MAX,
+                restore_slow_samples: u32::MAX,
+                latch_alert: true,
+                auto_clear_ms: u32::MAX,
+                min_trip_ms: u32::MAX,
+                enable_delay_ms: u32::MAX,
+                cooldown_ms: u32::MAX,
+                snooze_ms: u32::MAX,
+                arming_delay_ms: u32::MAX,
+                proof_test_hours: u16::MAX,
+                auto_zero_tau_s: u16::MAX,
+                plausible_min: u8::MAX,
+                plausible_max: u8::MAX,
+                implausible_samples: u16::MAX,
+                near_miss_percent: u8::MAX,
+                millivolts: Some(MillivoltThresholds {
+                    trigger_mv: i16::MIN,
+                    warning_mv: i16::MIN,
+                    confirm_mv: i16::MIN,
+                    restore_mv: i16::MIN,
+                }),
+            },
+        };
+        let mut blob = [0u8; Config::MAX_BLOB_SIZE];
+        assert_eq!(
+            config.to_blob(&mut blob).unwrap().len(),
+            Config::MAX_BLOB_SIZE
+        );
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "dual_core", feature = "minimal")))]
+    fn power_fail_flushes_journal() {
+        event_log::record(Kind::Marker, 0x1234);
+        event_log::flush_on_power_fail();
+        let current = Decoder::new(event_log::sector(JOURNAL_SECTORS - 1)).unwrap();
+        let last = current.fold([None; 2], |[_, prev], record| [prev, Some(record)]);
+        let [Some(marker), Some(power_fail)] = last else {
+            panic!("journal sector holds fewer than two events");
+        };
+        assert_eq!((marker.kind, marker.value), (Kind::Marker as u8, 0x1234));
+        assert_eq!(power_fail.kind, Kind::PowerFail as u8);
+    }
+
+    #[test]
+    fn state_transitions_drive_outputs(board: &mut Board) {
+        check_outputs(board, SystemState::Booting);
+        for state in [
+            SystemState::Calibrating,
+            SystemState::Armed,
+            SystemState::Alert,
+            SystemState::Armed,
+            SystemState::Disabled,
+            SystemState::Armed,
+        ] {
+            board
+                .state
+                .transition(state, &mut board.sampler, "On-target test")
+                .unwrap();
+            check_outputs(board, state);
+        }
+        assert!(matches!(
+            board
+                .state
+                .transition(SystemState::Latched, &mut board.sampler, "On-target test"),
+            Err(Error::InvalidTransition { .. })
+        ));
+    }
+
+    #[test]
+    fn error_shows_error_led(board: &mut Board) {
+        board.state.fail(&mut board.sampler, Error::NoTransfer);
+        check_outputs(board, SystemState::Error);
+        let (error_idx, error_level) = StatusLeds::ERROR_LED;
+        for (idx, pin) in STATUS_LED_PINS.iter().enumerate() {
+            assert_eq!(
+                driven(*pin),
+                (idx == error_idx) == (error_level == PinState::High)
+            );
+        }
+    }
+}